@@ -0,0 +1,11 @@
+pub mod available;
+pub mod busy;
+pub mod dataflow;
+pub mod liveness;
+pub mod reaching;
+
+pub use available::*;
+pub use busy::*;
+pub use dataflow::*;
+pub use liveness::*;
+pub use reaching::*;
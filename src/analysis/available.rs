@@ -0,0 +1,171 @@
+use std::collections::BTreeSet;
+use std::fmt::{self, Display, Formatter};
+
+use crate::analysis::{Direction, Lattice, Point, Transfer};
+use crate::ir::{Cfg, Inst, Operand, Var};
+
+/// Value computed by an instruction out of its operands alone, whatever its destination.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expression {
+    pub op: String,
+    pub args: Vec<Operand>,
+}
+
+impl Expression {
+    pub fn new(inst: &Inst) -> Option<Self> {
+        let args = match inst {
+            Inst::Binary { lhs, rhs, .. } => vec![lhs.clone(), rhs.clone()],
+            Inst::Inv { src, .. } | Inst::Not { src, .. } => vec![src.clone()],
+            Inst::Idx { index, array, .. } => vec![index.clone(), array.clone()],
+            _ => return None,
+        };
+
+        Some(Self {
+            op: inst.get_opcode(),
+            args,
+        })
+    }
+
+    pub fn reads(&self, var: Var) -> bool {
+        self.args.iter().any(|arg| arg.get_var() == Some(var))
+    }
+}
+
+impl Display for Expression {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let args: Vec<String> = self.args.iter().map(Operand::to_string).collect();
+        write!(f, "{} {}", self.op, args.join(" "))
+    }
+}
+
+/// Every distinct `Expression` of a `Cfg`, facts refer to them by position.
+#[derive(Debug, Clone, Default)]
+pub struct Expressions {
+    pub exprs: Vec<Expression>,
+}
+
+impl Expressions {
+    pub fn new(cfg: &Cfg) -> Self {
+        let mut exprs = Self::default();
+        for inst in cfg.blocks.iter().flat_map(|block| &block.insts) {
+            if let Some(expr) = Expression::new(inst) {
+                if exprs.find(&expr).is_none() {
+                    exprs.exprs.push(expr);
+                }
+            }
+        }
+
+        exprs
+    }
+
+    pub fn find(&self, expr: &Expression) -> Option<usize> {
+        self.exprs.iter().position(|other| other == expr)
+    }
+
+    /// Expression computed by `inst`, if any.
+    pub fn of(&self, inst: &Inst) -> Option<usize> {
+        Expression::new(inst).and_then(|expr| self.find(&expr))
+    }
+
+    pub fn all(&self) -> BTreeSet<usize> {
+        (0..self.exprs.len()).collect()
+    }
+
+    /// Expressions whose value changes after `inst`: the ones that read its
+    /// destination, or the elements of the array it stores to.
+    pub fn killed_by(&self, inst: &Inst) -> Vec<usize> {
+        let killed = |id: usize| {
+            let expr = &self.exprs[id];
+            match inst {
+                Inst::Sto { array, .. } => {
+                    expr.op == "idx" && array.get_var().is_some_and(|var| expr.reads(var))
+                }
+                inst => inst
+                    .get_def()
+                    .and_then(|def| def.get_var())
+                    .is_some_and(|var| expr.reads(var)),
+            }
+        };
+
+        (0..self.exprs.len()).filter(|&id| killed(id)).collect()
+    }
+}
+
+/// Expressions already computed on every path, and not changed since.
+#[derive(Debug, Clone, Default)]
+pub struct AvailableExprs {
+    pub exprs: Expressions,
+}
+
+impl AvailableExprs {
+    pub fn new(cfg: &Cfg) -> Self {
+        Self {
+            exprs: Expressions::new(cfg),
+        }
+    }
+}
+
+impl Lattice for AvailableExprs {
+    type Fact = BTreeSet<usize>;
+
+    fn top(&self) -> Self::Fact {
+        self.exprs.all()
+    }
+
+    fn meet(&self, lhs: &Self::Fact, rhs: &Self::Fact) -> Self::Fact {
+        lhs & rhs
+    }
+}
+
+impl Transfer for AvailableExprs {
+    const DIRECTION: Direction = Direction::Forward;
+
+    fn boundary(&self) -> Self::Fact {
+        BTreeSet::new()
+    }
+
+    fn transfer(&self, _: Point, inst: &Inst, fact: &mut Self::Fact) {
+        fact.extend(self.exprs.of(inst));
+        for id in self.exprs.killed_by(inst) {
+            fact.remove(&id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analysis::Dataflow;
+    use crate::ir::cfg::tests::function;
+
+    const CODE: &str = "fn f(a: i32, b: i32, c: bool) -> i32\n\tvar x: i32\n\tvar v: [4]i32\n\
+        \tadd x a b\n\tidx x 0 v\n\tjmpf L1 c\n\
+        \tmov a 1\n\tsto v 0 a\n\
+        L1\tadd x a b\n\tidx x 0 v\n\tmul x a b\n\tret x\n";
+
+    #[test]
+    fn available_expressions() {
+        let cfg = Cfg::new(&function(CODE).code);
+        let available = Dataflow::new(&cfg, AvailableExprs::new(&cfg));
+        let exprs = &available.analysis.exprs;
+
+        let names = |fact: &BTreeSet<usize>| -> Vec<String> {
+            fact.iter().map(|&id| exprs.exprs[id].to_string()).collect()
+        };
+
+        assert_eq!(exprs.exprs.len(), 3);
+        assert_eq!(names(available.block_out(1)), vec!["add a b", "idx 0 v"]);
+        assert!(available.block_in(3).is_empty());
+        assert_eq!(
+            names(&available.after(Point::new(3, 2))),
+            vec!["add a b", "idx 0 v"]
+        );
+
+        // `add x a b` leaves a + b available, `add a a b` would kill it
+        let inst = &cfg.blocks[1].insts[0];
+        assert_eq!(exprs.of(inst), Some(0));
+        assert!(exprs.killed_by(inst).is_empty());
+        assert_eq!(exprs.killed_by(&cfg.blocks[2].insts[0]), vec![0, 2]);
+        assert_eq!(exprs.killed_by(&cfg.blocks[2].insts[1]), vec![1]);
+    }
+}
@@ -0,0 +1,77 @@
+use std::collections::BTreeSet;
+
+use crate::analysis::{Direction, Expressions, Lattice, Point, Transfer};
+use crate::ir::{Cfg, Inst};
+
+/// Expressions that every path computes before any of their operands change.
+#[derive(Debug, Clone, Default)]
+pub struct BusyExprs {
+    pub exprs: Expressions,
+}
+
+impl BusyExprs {
+    pub fn new(cfg: &Cfg) -> Self {
+        Self {
+            exprs: Expressions::new(cfg),
+        }
+    }
+}
+
+impl Lattice for BusyExprs {
+    type Fact = BTreeSet<usize>;
+
+    fn top(&self) -> Self::Fact {
+        self.exprs.all()
+    }
+
+    fn meet(&self, lhs: &Self::Fact, rhs: &Self::Fact) -> Self::Fact {
+        lhs & rhs
+    }
+}
+
+impl Transfer for BusyExprs {
+    const DIRECTION: Direction = Direction::Backward;
+
+    fn boundary(&self) -> Self::Fact {
+        BTreeSet::new()
+    }
+
+    fn transfer(&self, _: Point, inst: &Inst, fact: &mut Self::Fact) {
+        for id in self.exprs.killed_by(inst) {
+            fact.remove(&id);
+        }
+        fact.extend(self.exprs.of(inst));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analysis::Dataflow;
+    use crate::ir::cfg::tests::function;
+
+    #[test]
+    fn very_busy_expressions() {
+        let code = "fn f(a: i32, b: i32, c: bool) -> i32\n\
+            \tjmpf L1 c\n\tadd __t0 a b\n\tret __t0\n\
+            L1\tsub __t1 a b\n\tadd __t2 a b\n\tret __t2\n\
+            fn g(a: i32, b: i32, c: bool) -> i32\n\
+            \tjmpf L1 c\n\tadd __t0 a b\n\tret __t0\n\
+            L1\tmov a 1\n\tadd __t1 a b\n\tret __t1\n";
+
+        let module = crate::ir::parse_module(code).unwrap();
+
+        let cfg = Cfg::new(&module.funcs[0].code);
+        let busy = Dataflow::new(&cfg, BusyExprs::new(&cfg));
+        let add = busy.analysis.exprs.of(&cfg.blocks[2].insts[0]).unwrap();
+        let sub = busy.analysis.exprs.of(&cfg.blocks[3].insts[1]).unwrap();
+        assert_eq!(busy.block_out(1), &[add].into());
+        assert_eq!(busy.block_in(3), &[add, sub].into());
+        assert_eq!(busy.block_in(cfg.entry), &[add].into());
+
+        let cfg = Cfg::new(&function(&code[code.find("fn g").unwrap()..]).code);
+        let busy = Dataflow::new(&cfg, BusyExprs::new(&cfg));
+        assert!(busy.block_out(1).is_empty());
+        assert_eq!(busy.before(Point::new(3, 2)).len(), 1);
+    }
+}
@@ -0,0 +1,202 @@
+use std::collections::VecDeque;
+
+use crate::ir::{Cfg, Inst};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Forward,
+    Backward,
+}
+
+/// Position of an instruction inside a `Cfg`, labels included.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Point {
+    pub block: usize,
+    pub index: usize,
+}
+
+impl Point {
+    pub fn new(block: usize, index: usize) -> Self {
+        Self { block, index }
+    }
+}
+
+pub trait Lattice {
+    type Fact: Clone + PartialEq;
+
+    /// Starting fact of every block, the identity of `meet`.
+    fn top(&self) -> Self::Fact;
+
+    fn meet(&self, lhs: &Self::Fact, rhs: &Self::Fact) -> Self::Fact;
+}
+
+pub trait Transfer: Lattice {
+    const DIRECTION: Direction;
+
+    /// Fact at `cfg.entry` for forward analyses, at `cfg.exit` for backward ones.
+    fn boundary(&self) -> Self::Fact;
+
+    /// Updates `fact` across `inst`, following the direction of the analysis.
+    fn transfer(&self, at: Point, inst: &Inst, fact: &mut Self::Fact);
+}
+
+/**
+ * Solution of a dataflow analysis over a `Cfg`, found with a worklist. Facts
+ * are always given in program order: `block_in` holds before the first
+ * instruction of the block and `block_out` after the last one, whatever the
+ * direction of the analysis.
+ */
+#[derive(Debug)]
+pub struct Dataflow<'a, A: Transfer> {
+    pub cfg: &'a Cfg,
+    pub analysis: A,
+    ins: Vec<A::Fact>,
+    outs: Vec<A::Fact>,
+}
+
+impl<'a, A: Transfer> Dataflow<'a, A> {
+    pub fn new(cfg: &'a Cfg, analysis: A) -> Self {
+        let len = cfg.blocks.len();
+        let mut dataflow = Self {
+            cfg,
+            ins: vec![analysis.top(); len],
+            outs: vec![analysis.top(); len],
+            analysis,
+        };
+
+        dataflow.solve();
+        dataflow
+    }
+
+    fn solve(&mut self) {
+        let cfg = self.cfg;
+        let forward = A::DIRECTION == Direction::Forward;
+
+        let mut order = if forward {
+            cfg.reverse_postorder()
+        } else {
+            cfg.reverse_postorder_rev()
+        };
+
+        let mut queued = vec![false; cfg.blocks.len()];
+        for &id in &order {
+            queued[id] = true;
+        }
+
+        order.extend((0..cfg.blocks.len()).filter(|&id| !queued[id]));
+        queued.iter_mut().for_each(|queued| *queued = true);
+
+        let mut work: VecDeque<usize> = order.into();
+        while let Some(id) = work.pop_front() {
+            queued[id] = false;
+
+            let block = &cfg.blocks[id];
+            let (boundary, sources, sinks) = if forward {
+                (cfg.entry, &block.preds, &block.succs)
+            } else {
+                (cfg.exit, &block.succs, &block.preds)
+            };
+
+            let input = if id == boundary {
+                self.analysis.boundary()
+            } else {
+                let facts = if forward { &self.outs } else { &self.ins };
+                sources.iter().fold(self.analysis.top(), |fact, &source| {
+                    self.analysis.meet(&fact, &facts[source])
+                })
+            };
+
+            let output = self.replay(id, 0, block.insts.len(), input.clone());
+
+            let (input_slot, output_slot) = if forward {
+                (&mut self.ins[id], &mut self.outs[id])
+            } else {
+                (&mut self.outs[id], &mut self.ins[id])
+            };
+
+            *input_slot = input;
+            if *output_slot != output {
+                *output_slot = output;
+                for &sink in sinks {
+                    if !queued[sink] {
+                        queued[sink] = true;
+                        work.push_back(sink);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Runs the transfer function over `insts[from..to]` of `block`, in the
+    /// direction of the analysis.
+    fn replay(&self, block: usize, from: usize, to: usize, mut fact: A::Fact) -> A::Fact {
+        let insts = &self.cfg.blocks[block].insts;
+        let mut step = |index: usize| {
+            self.analysis
+                .transfer(Point::new(block, index), &insts[index], &mut fact)
+        };
+
+        match A::DIRECTION {
+            Direction::Forward => (from..to).for_each(&mut step),
+            Direction::Backward => (from..to).rev().for_each(&mut step),
+        }
+
+        fact
+    }
+
+    pub fn block_in(&self, block: usize) -> &A::Fact {
+        &self.ins[block]
+    }
+
+    pub fn block_out(&self, block: usize) -> &A::Fact {
+        &self.outs[block]
+    }
+
+    /// Fact that holds right before the instruction at `at`.
+    pub fn before(&self, at: Point) -> A::Fact {
+        let len = self.cfg.blocks[at.block].insts.len();
+        match A::DIRECTION {
+            Direction::Forward => self.replay(at.block, 0, at.index, self.ins[at.block].clone()),
+            Direction::Backward => {
+                self.replay(at.block, at.index, len, self.outs[at.block].clone())
+            }
+        }
+    }
+
+    /// Fact that holds right after the instruction at `at`.
+    pub fn after(&self, at: Point) -> A::Fact {
+        self.before(Point::new(at.block, at.index + 1))
+    }
+
+    /// Facts between the instructions of `block`, `points[i]` holds before
+    /// instruction `i` and the last one after the whole block.
+    pub fn points(&self, block: usize) -> Vec<A::Fact> {
+        let insts = &self.cfg.blocks[block].insts;
+        let mut points = Vec::with_capacity(insts.len() + 1);
+
+        match A::DIRECTION {
+            Direction::Forward => {
+                let mut fact = self.ins[block].clone();
+                for (index, inst) in insts.iter().enumerate() {
+                    points.push(fact.clone());
+                    self.analysis
+                        .transfer(Point::new(block, index), inst, &mut fact);
+                }
+                points.push(fact);
+            }
+
+            Direction::Backward => {
+                let mut fact = self.outs[block].clone();
+                for (index, inst) in insts.iter().enumerate().rev() {
+                    points.push(fact.clone());
+                    self.analysis
+                        .transfer(Point::new(block, index), inst, &mut fact);
+                }
+                points.push(fact);
+                points.reverse();
+            }
+        }
+
+        points
+    }
+}
@@ -0,0 +1,70 @@
+use std::collections::BTreeSet;
+
+use crate::analysis::{Direction, Lattice, Point, Transfer};
+use crate::ir::{Inst, Var};
+
+/// Variables whose current value may still be read.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Liveness;
+
+impl Lattice for Liveness {
+    type Fact = BTreeSet<Var>;
+
+    fn top(&self) -> Self::Fact {
+        BTreeSet::new()
+    }
+
+    fn meet(&self, lhs: &Self::Fact, rhs: &Self::Fact) -> Self::Fact {
+        lhs | rhs
+    }
+}
+
+impl Transfer for Liveness {
+    const DIRECTION: Direction = Direction::Backward;
+
+    fn boundary(&self) -> Self::Fact {
+        BTreeSet::new()
+    }
+
+    fn transfer(&self, _: Point, inst: &Inst, fact: &mut Self::Fact) {
+        if let Some(var) = inst.get_def().and_then(|def| def.get_var()) {
+            fact.remove(&var);
+        }
+
+        fact.extend(
+            inst.get_uses()
+                .into_iter()
+                .filter_map(|operand| operand.get_var()),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analysis::Dataflow;
+    use crate::ir::cfg::tests::{function, LOOP};
+    use crate::ir::Cfg;
+
+    #[test]
+    fn live_variables() {
+        let cfg = Cfg::new(&function(LOOP).code);
+        let live = Dataflow::new(&cfg, Liveness);
+        let (n, i, s) = (Var::Ident(0), Var::Ident(4), Var::Ident(8));
+
+        assert_eq!(live.block_in(cfg.entry), &[n, s].into());
+        assert_eq!(live.block_in(2), &[n, i, s].into());
+        assert_eq!(live.block_out(6), &[n, i, s].into());
+        assert_eq!(live.block_in(7), &[s].into());
+        assert!(live.block_out(7).is_empty());
+
+        // L2 lt __t0 i n; jmpf L3 __t0
+        assert_eq!(live.after(Point::new(2, 1)), [n, i, s, Var::Temp(0)].into());
+        assert_eq!(live.after(Point::new(2, 2)), [n, i, s].into());
+
+        let points = live.points(2);
+        assert_eq!(points.len(), 4);
+        assert_eq!(&points[0], live.block_in(2));
+        assert_eq!(&points[3], live.block_out(2));
+    }
+}
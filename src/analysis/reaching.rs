@@ -0,0 +1,106 @@
+use std::collections::{BTreeSet, HashMap};
+
+use crate::analysis::{Direction, Lattice, Point, Transfer};
+use crate::ir::{Cfg, Inst, Var};
+
+/// Definitions that may reach a point without being overwritten on the way.
+#[derive(Debug, Clone, Default)]
+pub struct ReachingDefs {
+    defs: HashMap<Var, Vec<Point>>,
+}
+
+impl ReachingDefs {
+    pub fn new(cfg: &Cfg) -> Self {
+        let mut defs: HashMap<Var, Vec<Point>> = HashMap::new();
+        for (block, insts) in cfg.blocks.iter().map(|block| &block.insts).enumerate() {
+            for (index, inst) in insts.iter().enumerate() {
+                if let Some(var) = inst.get_def().and_then(|def| def.get_var()) {
+                    defs.entry(var).or_default().push(Point::new(block, index));
+                }
+            }
+        }
+
+        Self { defs }
+    }
+
+    /// Every definition of `var` in the function.
+    pub fn defs_of(&self, var: Var) -> &[Point] {
+        self.defs.get(&var).map_or(&[], Vec::as_slice)
+    }
+}
+
+impl Lattice for ReachingDefs {
+    type Fact = BTreeSet<Point>;
+
+    fn top(&self) -> Self::Fact {
+        BTreeSet::new()
+    }
+
+    fn meet(&self, lhs: &Self::Fact, rhs: &Self::Fact) -> Self::Fact {
+        lhs | rhs
+    }
+}
+
+impl Transfer for ReachingDefs {
+    const DIRECTION: Direction = Direction::Forward;
+
+    fn boundary(&self) -> Self::Fact {
+        BTreeSet::new()
+    }
+
+    fn transfer(&self, at: Point, inst: &Inst, fact: &mut Self::Fact) {
+        if let Some(var) = inst.get_def().and_then(|def| def.get_var()) {
+            for def in self.defs_of(var) {
+                fact.remove(def);
+            }
+
+            fact.insert(at);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analysis::Dataflow;
+    use crate::ir::cfg::tests::{function, LOOP};
+
+    #[test]
+    fn reaching_definitions() {
+        let cfg = Cfg::new(&function(LOOP).code);
+        let reaching = Dataflow::new(&cfg, ReachingDefs::new(&cfg));
+        let (i, s) = (Var::Ident(4), Var::Ident(8));
+
+        let defs = |at: &BTreeSet<Point>, var| -> Vec<Point> {
+            let all = reaching.analysis.defs_of(var);
+            at.iter().filter(|def| all.contains(def)).cloned().collect()
+        };
+
+        // L1 mov i 0 and L5 add i i 1
+        assert_eq!(
+            reaching.analysis.defs_of(i),
+            &[Point::new(1, 1), Point::new(6, 1)]
+        );
+        assert_eq!(defs(reaching.block_in(2), i), reaching.analysis.defs_of(i));
+        assert_eq!(defs(reaching.block_in(6), i), reaching.analysis.defs_of(i));
+        assert_eq!(
+            defs(&reaching.after(Point::new(6, 1)), i),
+            vec![Point::new(6, 1)]
+        );
+
+        // add s s i and L4 sub s s i both reach L5, none reaches the first iteration
+        assert_eq!(
+            defs(reaching.block_in(6), s),
+            vec![Point::new(4, 0), Point::new(5, 1)]
+        );
+        assert_eq!(
+            defs(reaching.block_in(2), s),
+            vec![Point::new(4, 0), Point::new(5, 1)]
+        );
+        assert!(defs(reaching.block_in(1), s).is_empty());
+        assert_eq!(
+            defs(&reaching.after(Point::new(4, 0)), s),
+            vec![Point::new(4, 0)]
+        );
+    }
+}
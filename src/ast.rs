@@ -1,19 +1,31 @@
 pub mod arithm;
+pub mod call;
 pub mod cons;
 pub mod expr;
+pub mod func;
 pub mod ident;
 pub mod index;
+pub mod logical;
 pub mod node;
+pub mod not;
+pub mod rel;
+pub mod stmt;
 pub mod temp;
 pub mod unary;
 pub mod util;
 
 pub use arithm::*;
+pub use call::*;
 pub use cons::*;
 pub use expr::*;
+pub use func::*;
 pub use ident::*;
 pub use index::*;
+pub use logical::*;
 pub use node::*;
+pub use not::*;
+pub use rel::*;
+pub use stmt::*;
 pub use temp::*;
 pub use unary::*;
 pub use util::*;
@@ -1,6 +1,7 @@
 use std::fmt::{self, Display, Formatter};
 
 use crate::ast::Expr;
+use crate::interp::Value;
 use crate::lex::Token;
 use crate::sym::Type;
 
@@ -13,18 +14,31 @@ pub struct Arithm {
 }
 
 impl Arithm {
-    pub fn new(op: &Token, expr1: &Expr, expr2: &Expr) -> Self {
+    /// Builds `expr1 op expr2`, folded into a `Cons` when both operands are
+    /// constants. Division by zero and results the tokens can't hold are left
+    /// for the runtime.
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(op: &Token, expr1: &Expr, expr2: &Expr) -> Expr {
         let tp = expr1
             .get_tp()
             .upcast(&expr2.get_tp())
             .expect("Failed to coherce");
 
-        Self {
+        if let (Expr::Cons(cons1), Expr::Cons(cons2)) = (expr1, expr2) {
+            let value = Value::arithm(op, &Value::from_cons(cons1), &Value::from_cons(cons2))
+                .and_then(|value| value.cast(&tp));
+
+            if let Some(cons) = value.ok().and_then(|value| value.to_cons()) {
+                return Expr::Cons(cons);
+            }
+        }
+
+        Expr::Arithm(Self {
             op: op.clone(),
             tp,
             expr1: Box::new(expr1.clone()),
             expr2: Box::new(expr2.clone()),
-        }
+        })
     }
 
     pub fn get_opcode(&self) -> String {
@@ -43,3 +57,78 @@ impl Display for Arithm {
         write!(f, "{} {}", self.expr1, self.expr2)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::node::tests::Buffer;
+    use crate::ast::{Cons, Ident};
+
+    fn int(num: i32, tp: Type) -> Expr {
+        Expr::Cons(Cons {
+            tok: Token::Integer(num),
+            tp,
+        })
+    }
+
+    #[test]
+    fn fold_constants() {
+        let six = Arithm::new(&Token::Asterisk, &int(2, Type::Int32), &int(3, Type::Int32));
+        assert!(matches!(
+            six,
+            Expr::Cons(Cons {
+                tok: Token::Integer(6),
+                tp: Type::Int32
+            })
+        ));
+
+        let wrap = Arithm::new(
+            &Token::Plus,
+            &int(i32::MAX, Type::Int32),
+            &int(1, Type::Int32),
+        );
+        assert!(matches!(
+            wrap,
+            Expr::Cons(Cons {
+                tok: Token::Integer(i32::MIN),
+                ..
+            })
+        ));
+
+        let wide = Arithm::new(
+            &Token::Plus,
+            &int(i32::MAX, Type::Int64),
+            &int(1, Type::Int32),
+        );
+        assert!(matches!(wide, Expr::Arithm(_)));
+
+        let wide = Arithm::new(&Token::Asterisk, &int(2, Type::Int64), &int(3, Type::Int32));
+        assert_eq!(wide.to_string(), "6i64");
+
+        let float = Expr::Cons(Cons {
+            tok: Token::Float(2.0),
+            tp: Type::Flt64,
+        });
+        let float = Arithm::new(&Token::Asterisk, &float, &int(3, Type::Int32));
+        assert_eq!(float.to_string(), "6.0");
+
+        let div = Arithm::new(&Token::Divide, &int(1, Type::Int32), &int(0, Type::Int32));
+        assert!(matches!(div, Expr::Arithm(_)));
+
+        let x = Expr::Ident(Ident {
+            id: "x".to_owned(),
+            tp: Type::Int32,
+            offset: 0,
+        });
+
+        let expr = Arithm::new(
+            &Token::Plus,
+            &Arithm::new(&Token::Asterisk, &int(2, Type::Int32), &int(3, Type::Int32)),
+            &x,
+        );
+
+        let buf = Buffer::default();
+        let temp = expr.reduce(&mut buf.visitor());
+        assert_eq!(buf.contents(), format!("\tadd {} 6 x\n", temp));
+    }
+}
@@ -0,0 +1,17 @@
+use std::fmt::{self, Display, Formatter};
+
+use crate::ast::Expr;
+use crate::sym::Type;
+
+#[derive(Debug, Clone)]
+pub struct Call {
+    pub func: String,
+    pub tp: Option<Type>,
+    pub args: Vec<Expr>,
+}
+
+impl Display for Call {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.func, self.args.len())
+    }
+}
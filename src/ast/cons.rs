@@ -3,14 +3,33 @@ use std::fmt::{self, Display, Formatter};
 use crate::lex::Token;
 use crate::sym::Type;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Cons {
     pub tok: Token,
     pub tp: Type,
 }
 
+/**
+ * Numbers are written the way the IR parser reads them back: floats keep
+ * their fraction, and a type suffix follows the ones that aren't `i32`
+ * integers or `f64` floats, as in `6i64` or `1.5f32`.
+ */
 impl Display for Cons {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-        write!(f, "{}", self.tok)
+        let tp = match (&self.tok, &self.tp) {
+            (Token::Integer(_), Type::Int32) | (Token::Float(_), Type::Flt64) => None,
+            (_, tp @ (Type::Int32 | Type::Int64 | Type::Flt32 | Type::Flt64)) => Some(tp),
+            _ => None,
+        };
+
+        match &self.tok {
+            Token::Float(num) => write!(f, "{:?}", num)?,
+            tok => write!(f, "{}", tok)?,
+        }
+
+        match tp {
+            Some(tp) => write!(f, "{}", tp),
+            None => Ok(()),
+        }
     }
 }
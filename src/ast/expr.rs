@@ -1,7 +1,8 @@
 use std::fmt::{self, Display, Formatter};
 
 use crate::ast::Visitor;
-use crate::ast::{arithm, cons, ident, index, temp, unary, util};
+use crate::ast::{arithm, call, cons, ident, index, logical, not, rel, temp, unary, util};
+use crate::lex::Token;
 use crate::sym::Type;
 
 #[derive(Debug, Clone)]
@@ -12,24 +13,36 @@ pub enum Expr {
     Arithm(arithm::Arithm),
     Unary(unary::Unary),
     Index(index::Index),
+    Rel(rel::Rel),
+    Logical(logical::Logical),
+    Not(not::Not),
+    Call(call::Call),
 }
 
 impl Expr {
     pub fn generate(&self, visitor: &mut Visitor) -> Self {
         match self {
-            Self::Arithm(arithm) => Self::Arithm(arithm::Arithm::new(
+            Self::Arithm(arithm) => arithm::Arithm::new(
                 &arithm.op,
                 &arithm.expr1.reduce(visitor),
                 &arithm.expr2.reduce(visitor),
+            ),
+
+            Self::Unary(unary) => unary::Unary::new(&unary.op, &unary.expr.reduce(visitor)),
+
+            Self::Index(index) => Self::Index(index.generate(visitor)),
+
+            Self::Rel(rel) => Self::Rel(rel::Rel::new(
+                &rel.op,
+                &rel.expr1.reduce(visitor),
+                &rel.expr2.reduce(visitor),
             )),
 
-            Self::Unary(unary) => {
-                Self::Unary(unary::Unary::new(&unary.op, &unary.expr.reduce(visitor)))
-            }
+            Self::Not(not) => Self::Not(not::Not::new(&not.expr.reduce(visitor))),
 
-            Self::Index(index) => Self::Index(index::Index {
-                index: Box::new(index.index.reduce(visitor)),
-                ..index.clone()
+            Self::Call(call) => Self::Call(call::Call {
+                args: call.args.iter().map(|arg| arg.reduce(visitor)).collect(),
+                ..call.clone()
             }),
 
             _ => self.clone(),
@@ -38,30 +51,118 @@ impl Expr {
 
     pub fn reduce(&self, visitor: &mut Visitor) -> Self {
         match self {
-            op @ Self::Arithm(_) | op @ Self::Unary(_) | op @ Self::Index(_) => {
+            Self::Ident(_) | Self::Cons(_) | Self::Temp(_) => self.clone(),
+
+            Self::Logical(_) => {
+                let temp = Self::Temp(temp::Temp {
+                    id: util::new_temp_id(),
+                    tp: self.get_tp(),
+                });
+
+                self.assign(visitor, &temp);
+                temp
+            }
+
+            _ => {
                 let expr = self.generate(visitor);
-                let temp = temp::Temp {
+                if let Self::Cons(_) = expr {
+                    return expr;
+                }
+
+                let temp = Self::Temp(temp::Temp {
                     id: util::new_temp_id(),
-                    tp: expr.get_tp().clone(),
-                };
+                    tp: expr.get_tp(),
+                });
 
-                let op_code = match op {
-                    Self::Arithm(arithm) => arithm.get_opcode(),
-                    Self::Unary(_) => "inv".to_owned(),
-                    Self::Index(_) => "idx".to_owned(),
-                    _ => unreachable!(),
-                };
+                expr.emit_op(visitor, &temp);
+                temp
+            }
+        }
+    }
 
-                visitor.emit_inst(&format!("{} {} {}", op_code, temp, expr));
-                Self::Temp(temp)
+    /// Emits the instructions that leave the value of this expression in `dst`.
+    pub fn assign(&self, visitor: &mut Visitor, dst: &Self) {
+        match self {
+            Self::Ident(_) | Self::Cons(_) | Self::Temp(_) => {
+                visitor.emit_inst(&format!("mov {} {}", dst, self))
             }
 
-            _ => self.clone(),
+            Self::Logical(_) => {
+                let (label, after) = (util::new_label_id(), util::new_label_id());
+                self.jumping(visitor, 0, label);
+                visitor.emit_inst(&format!("mov {} true", dst));
+                visitor.emit_inst(&format!("jmp L{}", after));
+                visitor.emit_label(label);
+                visitor.emit_inst(&format!("mov {} false", dst));
+                visitor.emit_label(after);
+            }
+
+            _ => match self.generate(visitor) {
+                cons @ Self::Cons(_) => cons.assign(visitor, dst),
+                expr => expr.emit_op(visitor, dst),
+            },
         }
     }
 
+    fn emit_op(&self, visitor: &mut Visitor, dst: &Self) {
+        let op_code = match self {
+            Self::Arithm(arithm) => arithm.get_opcode(),
+            Self::Unary(_) => "inv".to_owned(),
+            Self::Index(_) => "idx".to_owned(),
+            Self::Rel(rel) => rel.get_opcode(),
+            Self::Not(_) => "not".to_owned(),
+            Self::Call(call) => {
+                for arg in &call.args {
+                    visitor.emit_inst(&format!("param {}", arg));
+                }
+
+                "call".to_owned()
+            }
+            _ => unreachable!(),
+        };
+
+        visitor.emit_inst(&format!("{} {} {}", op_code, dst, self));
+    }
+
     pub fn jumping(&self, visitor: &mut Visitor, true_label: usize, false_label: usize) {
-        visitor.emit_jump(&self.to_string(), true_label, false_label)
+        match self {
+            Self::Ident(_) | Self::Cons(_) | Self::Temp(_) => {
+                visitor.emit_jump(&self.to_string(), true_label, false_label)
+            }
+
+            Self::Not(not) => not.expr.jumping(visitor, false_label, true_label),
+
+            Self::Logical(logical) if logical.op == Token::DoubleAmpersand => {
+                let label = match false_label {
+                    0 => util::new_label_id(),
+                    _ => false_label,
+                };
+
+                logical.expr1.jumping(visitor, 0, label);
+                logical.expr2.jumping(visitor, true_label, false_label);
+                if false_label == 0 {
+                    visitor.emit_label(label);
+                }
+            }
+
+            Self::Logical(logical) => {
+                let label = match true_label {
+                    0 => util::new_label_id(),
+                    _ => true_label,
+                };
+
+                logical.expr1.jumping(visitor, label, 0);
+                logical.expr2.jumping(visitor, true_label, false_label);
+                if true_label == 0 {
+                    visitor.emit_label(label);
+                }
+            }
+
+            _ => {
+                let test = self.reduce(visitor);
+                visitor.emit_jump(&test.to_string(), true_label, false_label)
+            }
+        }
     }
 
     pub fn get_tp(&self) -> Type {
@@ -71,7 +172,11 @@ impl Expr {
             Self::Temp(temp) => temp.tp.clone(),
             Self::Arithm(arithm) => arithm.tp.clone(),
             Self::Unary(unary) => unary.tp.clone(),
-            Self::Index(index) => index.array.tp.clone(),
+            Self::Index(index) => index.tp.clone(),
+            Self::Rel(rel) => rel.tp.clone(),
+            Self::Logical(logical) => logical.tp.clone(),
+            Self::Not(not) => not.tp.clone(),
+            Self::Call(call) => call.tp.clone().expect("Function does not return a value"),
         }
     }
 }
@@ -85,6 +190,10 @@ impl Display for Expr {
             Self::Arithm(arithm) => arithm.to_string(),
             Self::Unary(unary) => unary.to_string(),
             Self::Index(index) => index.to_string(),
+            Self::Rel(rel) => rel.to_string(),
+            Self::Logical(logical) => logical.to_string(),
+            Self::Not(not) => not.to_string(),
+            Self::Call(call) => call.to_string(),
         };
 
         write!(f, "{}", out)
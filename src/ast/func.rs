@@ -0,0 +1,43 @@
+use crate::ast::{util, Ident, Stmt, Visitor};
+use crate::sym::Type;
+
+#[derive(Debug, Clone)]
+pub struct Func {
+    pub name: String,
+    pub params: Vec<Ident>,
+    pub locals: Vec<Ident>,
+    pub ret: Option<Type>,
+    pub body: Stmt,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Program {
+    pub funcs: Vec<Func>,
+}
+
+impl Program {
+    pub fn get(&self, name: &str) -> Option<&Func> {
+        self.funcs.iter().find(|func| func.name == name)
+    }
+
+    pub fn gen(&self, visitor: &mut Visitor) {
+        for func in &self.funcs {
+            func.gen(visitor);
+        }
+    }
+}
+
+impl Func {
+    pub fn gen(&self, visitor: &mut Visitor) {
+        visitor.emit_func(&self.name, &self.params, &self.ret);
+        for local in &self.locals {
+            visitor.emit_var(local);
+        }
+
+        let (begin, after) = (util::new_label_id(), util::new_label_id());
+        visitor.emit_label(begin);
+        self.body.gen(visitor, begin, after);
+        visitor.emit_label(after);
+        visitor.emit_inst("ret");
+    }
+}
@@ -2,7 +2,7 @@ use std::fmt::{self, Display, Formatter};
 
 use crate::sym::Type;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Ident {
     pub id: String,
     pub tp: Type,
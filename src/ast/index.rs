@@ -1,15 +1,122 @@
 use std::fmt::{self, Display, Formatter};
 
-use crate::ast::{Expr, Ident};
+use crate::ast::{Arithm, Cons, Expr, Ident, Visitor};
+use crate::lex::Token;
+use crate::sym::Type;
 
+/// Scalar of `array` at `indices`, one per dimension from the outermost, with
+/// nested arrays laid out row after row as in `Type::get_len`.
 #[derive(Debug, Clone)]
 pub struct Index {
     pub array: Ident,
-    pub index: Box<Expr>,
+    pub indices: Vec<Expr>,
+    pub tp: Type,
+}
+
+impl Index {
+    pub fn new(array: &Ident, indices: &[Expr]) -> Self {
+        let mut tp = array.tp.clone();
+
+        for _ in indices {
+            tp = match tp {
+                Type::Array { of, .. } => *of,
+                tp => panic!("Cannot index into {}", tp),
+            };
+        }
+
+        if let Type::Array { .. } = tp {
+            panic!("Cannot use {} as a scalar", array);
+        }
+
+        Self {
+            array: array.clone(),
+            indices: indices.to_vec(),
+            tp,
+        }
+    }
+
+    /// Size of each dimension indexed, and width in scalars of its elements.
+    pub fn dims(&self) -> Vec<(usize, usize)> {
+        let mut tp = &self.array.tp;
+
+        self.indices
+            .iter()
+            .map(|_| match tp {
+                Type::Array { of, size } => {
+                    tp = of;
+                    (*size, of.get_len())
+                }
+                tp => panic!("Cannot index into {}", tp),
+            })
+            .collect()
+    }
+
+    /**
+     * Position of the scalar in `array`, given the values of `indices`: each
+     * one is multiplied by the width of its element, in scalars, and the
+     * products are summed.
+     */
+    pub fn position(&self, indices: &[Expr]) -> Expr {
+        let mut pos: Option<Expr> = None;
+
+        for (index, (_, width)) in indices.iter().zip(self.dims()) {
+            let offset = match width {
+                1 => index.clone(),
+                width => {
+                    let width = Expr::Cons(Cons {
+                        tok: Token::Integer(width as i32),
+                        tp: Type::Int32,
+                    });
+                    Arithm::new(&Token::Asterisk, index, &width)
+                }
+            };
+
+            pos = Some(match pos {
+                Some(pos) => Arithm::new(&Token::Plus, &pos, &offset),
+                None => offset,
+            });
+        }
+
+        pos.expect("Index without indices")
+    }
+
+    /**
+     * Emits the code of the indices and, with several dimensions, a `chk` of
+     * each one against its own before they're combined, so that no index
+     * spills over into the next row. Returns the same scalar, indexed by its
+     * position in `array` seen as a single dimension.
+     */
+    pub fn generate(&self, visitor: &mut Visitor) -> Self {
+        let indices: Vec<Expr> = self
+            .indices
+            .iter()
+            .map(|index| index.reduce(visitor))
+            .collect();
+
+        if indices.len() > 1 {
+            for (index, (size, _)) in indices.iter().zip(self.dims()) {
+                visitor.emit_inst(&format!("chk {} {}", index, size));
+            }
+        }
+
+        let array = Ident {
+            tp: Type::Array {
+                of: Box::new(self.tp.clone()),
+                size: self.array.tp.get_len(),
+            },
+            ..self.array.clone()
+        };
+
+        Self {
+            indices: vec![self.position(&indices).reduce(visitor)],
+            array,
+            tp: self.tp.clone(),
+        }
+    }
 }
 
 impl Display for Index {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-        write!(f, "{} {}", self.index, self.array)
+        write!(f, "{} {}", self.position(&self.indices), self.array)
     }
 }
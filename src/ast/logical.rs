@@ -0,0 +1,39 @@
+use std::fmt::{self, Display, Formatter};
+
+use crate::ast::Expr;
+use crate::lex::Token;
+use crate::sym::Type;
+
+#[derive(Debug, Clone)]
+pub struct Logical {
+    pub op: Token,
+    pub tp: Type,
+    pub expr1: Box<Expr>,
+    pub expr2: Box<Expr>,
+}
+
+impl Logical {
+    pub fn new(op: &Token, expr1: &Expr, expr2: &Expr) -> Self {
+        match op {
+            Token::DoubleAmpersand | Token::DoubleVerticalBar => {}
+            _ => panic!("Bad operator"),
+        }
+
+        if expr1.get_tp() != Type::Bool || expr2.get_tp() != Type::Bool {
+            panic!("Failed to coherce");
+        }
+
+        Self {
+            op: op.clone(),
+            tp: Type::Bool,
+            expr1: Box::new(expr1.clone()),
+            expr2: Box::new(expr2.clone()),
+        }
+    }
+}
+
+impl Display for Logical {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.expr1, self.expr2)
+    }
+}
@@ -1,10 +1,21 @@
 use std::io::Write;
 
+use crate::ast::Ident;
+use crate::sym::Type;
+
 pub struct Visitor {
     out: Box<dyn Write>,
+    loops: Vec<usize>,
 }
 
 impl Visitor {
+    pub fn new(out: Box<dyn Write>) -> Self {
+        Self {
+            out,
+            loops: Vec::new(),
+        }
+    }
+
     pub fn write(&mut self, string: &str) {
         write!(self.out, "{}", string).expect("Failed to write!");
     }
@@ -21,6 +32,35 @@ impl Visitor {
         self.write(&format!("L{}", label));
     }
 
+    pub fn emit_func(&mut self, name: &str, params: &[Ident], ret: &Option<Type>) {
+        let params: Vec<String> = params
+            .iter()
+            .map(|param| format!("{}: {}", param.id, param.tp))
+            .collect();
+
+        match ret {
+            Some(tp) => self.writeln(&format!("fn {}({}) -> {}", name, params.join(", "), tp)),
+            None => self.writeln(&format!("fn {}({})", name, params.join(", "))),
+        }
+    }
+
+    pub fn emit_var(&mut self, ident: &Ident) {
+        self.emit_inst(&format!("var {}: {}", ident.id, ident.tp));
+    }
+
+    /// Enters a loop whose `break`s jump to `after`.
+    pub fn push_loop(&mut self, after: usize) {
+        self.loops.push(after);
+    }
+
+    pub fn pop_loop(&mut self) {
+        self.loops.pop();
+    }
+
+    pub fn enclosing_loop(&self) -> Option<usize> {
+        self.loops.last().copied()
+    }
+
     pub fn emit_jump(&mut self, test: &str, true_label: usize, false_label: usize) {
         match (true_label, false_label) {
             (0, 0) => {}
@@ -33,3 +73,51 @@ impl Visitor {
         }
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::io;
+    use std::rc::Rc;
+
+    /// Shared in-memory sink, so tests can read back what a `Visitor` wrote.
+    #[derive(Debug, Clone, Default)]
+    pub(crate) struct Buffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Buffer {
+        pub(crate) fn visitor(&self) -> Visitor {
+            Visitor::new(Box::new(self.clone()))
+        }
+
+        pub(crate) fn contents(&self) -> String {
+            String::from_utf8(self.0.borrow().clone()).expect("Invalid utf8")
+        }
+    }
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn emit_jumps() {
+        let buf = Buffer::default();
+        let mut visitor = buf.visitor();
+        visitor.emit_label(1);
+        visitor.emit_jump("x", 2, 0);
+        visitor.emit_jump("x", 0, 3);
+        visitor.emit_jump("x", 2, 3);
+        visitor.emit_jump("x", 0, 0);
+
+        assert_eq!(
+            buf.contents(),
+            "L1\tjmpt L2 x\n\tjmpf L3 x\n\tjmpt L2 x\n\tjmp L3\n"
+        );
+    }
+}
@@ -0,0 +1,29 @@
+use std::fmt::{self, Display, Formatter};
+
+use crate::ast::Expr;
+use crate::sym::Type;
+
+#[derive(Debug, Clone)]
+pub struct Not {
+    pub tp: Type,
+    pub expr: Box<Expr>,
+}
+
+impl Not {
+    pub fn new(expr: &Expr) -> Self {
+        if expr.get_tp() != Type::Bool {
+            panic!("Failed to coherce");
+        }
+
+        Self {
+            tp: Type::Bool,
+            expr: Box::new(expr.clone()),
+        }
+    }
+}
+
+impl Display for Not {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}", self.expr)
+    }
+}
@@ -0,0 +1,50 @@
+use std::fmt::{self, Display, Formatter};
+
+use crate::ast::Expr;
+use crate::lex::Token;
+use crate::sym::Type;
+
+#[derive(Debug, Clone)]
+pub struct Rel {
+    pub op: Token,
+    pub tp: Type,
+    pub expr1: Box<Expr>,
+    pub expr2: Box<Expr>,
+}
+
+impl Rel {
+    pub fn new(op: &Token, expr1: &Expr, expr2: &Expr) -> Self {
+        let (tp1, tp2) = (expr1.get_tp(), expr2.get_tp());
+        match op {
+            Token::Equal | Token::NotEqual if tp1 == tp2 => {}
+            _ => {
+                tp1.upcast(&tp2).expect("Failed to coherce");
+            }
+        }
+
+        Self {
+            op: op.clone(),
+            tp: Type::Bool,
+            expr1: Box::new(expr1.clone()),
+            expr2: Box::new(expr2.clone()),
+        }
+    }
+
+    pub fn get_opcode(&self) -> String {
+        match self.op {
+            Token::LessThan => "lt".to_owned(),
+            Token::LessEqual => "le".to_owned(),
+            Token::GreaterThan => "gt".to_owned(),
+            Token::GreaterEqual => "ge".to_owned(),
+            Token::Equal => "eq".to_owned(),
+            Token::NotEqual => "ne".to_owned(),
+            _ => panic!("Bad operator"),
+        }
+    }
+}
+
+impl Display for Rel {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.expr1, self.expr2)
+    }
+}
@@ -0,0 +1,162 @@
+use crate::ast::{util, Call, Expr, Ident, Index, Visitor};
+
+#[derive(Debug, Clone)]
+pub enum Stmt {
+    Null,
+    Set {
+        id: Ident,
+        expr: Expr,
+    },
+    SetElem {
+        index: Index,
+        expr: Expr,
+    },
+    Seq(Vec<Stmt>),
+    If {
+        cond: Expr,
+        then: Box<Stmt>,
+        otherwise: Option<Box<Stmt>>,
+    },
+    While {
+        cond: Expr,
+        body: Box<Stmt>,
+    },
+    Do {
+        body: Box<Stmt>,
+        cond: Expr,
+    },
+    For {
+        init: Box<Stmt>,
+        cond: Expr,
+        step: Box<Stmt>,
+        body: Box<Stmt>,
+    },
+    Break,
+    Return(Option<Expr>),
+    Call(Call),
+}
+
+impl Stmt {
+    pub fn gen(&self, visitor: &mut Visitor, begin: usize, after: usize) {
+        match self {
+            Self::Null => {}
+
+            Self::Set { id, expr } => expr.assign(visitor, &Expr::Ident(id.clone())),
+
+            Self::SetElem { index, expr } => {
+                let index = index.generate(visitor);
+                let src = expr.reduce(visitor);
+                let line = format!("sto {} {} {}", index.array, index.indices[0], src);
+                visitor.emit_inst(&line);
+            }
+
+            Self::Seq(stmts) => {
+                let stmts: Vec<&Stmt> = stmts
+                    .iter()
+                    .filter(|stmt| !matches!(stmt, Self::Null))
+                    .collect();
+
+                let mut begin = begin;
+                for (i, stmt) in stmts.iter().enumerate() {
+                    if i + 1 == stmts.len() {
+                        stmt.gen(visitor, begin, after);
+                    } else {
+                        let label = util::new_label_id();
+                        stmt.gen(visitor, begin, label);
+                        visitor.emit_label(label);
+                        begin = label;
+                    }
+                }
+            }
+
+            Self::If {
+                cond,
+                then,
+                otherwise: None,
+            } => {
+                let label = util::new_label_id();
+                cond.jumping(visitor, 0, after);
+                visitor.emit_label(label);
+                then.gen(visitor, label, after);
+            }
+
+            Self::If {
+                cond,
+                then,
+                otherwise: Some(otherwise),
+            } => {
+                let (label1, label2) = (util::new_label_id(), util::new_label_id());
+                cond.jumping(visitor, 0, label2);
+                visitor.emit_label(label1);
+                then.gen(visitor, label1, after);
+                visitor.emit_inst(&format!("jmp L{}", after));
+                visitor.emit_label(label2);
+                otherwise.gen(visitor, label2, after);
+            }
+
+            Self::While { cond, body } => {
+                let label = util::new_label_id();
+                cond.jumping(visitor, 0, after);
+                visitor.emit_label(label);
+                visitor.push_loop(after);
+                body.gen(visitor, label, begin);
+                visitor.pop_loop();
+                visitor.emit_inst(&format!("jmp L{}", begin));
+            }
+
+            Self::Do { body, cond } => {
+                let label = util::new_label_id();
+                visitor.push_loop(after);
+                body.gen(visitor, begin, label);
+                visitor.pop_loop();
+                visitor.emit_label(label);
+                cond.jumping(visitor, begin, 0);
+            }
+
+            Self::For {
+                init,
+                cond,
+                step,
+                body,
+            } => {
+                let (test, label, next) = (
+                    util::new_label_id(),
+                    util::new_label_id(),
+                    util::new_label_id(),
+                );
+
+                init.gen(visitor, begin, test);
+                visitor.emit_label(test);
+                cond.jumping(visitor, 0, after);
+                visitor.emit_label(label);
+                visitor.push_loop(after);
+                body.gen(visitor, label, next);
+                visitor.pop_loop();
+                visitor.emit_label(next);
+                step.gen(visitor, next, test);
+                visitor.emit_inst(&format!("jmp L{}", test));
+            }
+
+            Self::Break => {
+                let after = visitor.enclosing_loop().expect("Unenclosed break");
+                visitor.emit_inst(&format!("jmp L{}", after));
+            }
+
+            Self::Return(Some(expr)) => {
+                let src = expr.reduce(visitor);
+                visitor.emit_inst(&format!("ret {}", src));
+            }
+
+            Self::Return(None) => visitor.emit_inst("ret"),
+
+            Self::Call(call) => {
+                let args: Vec<Expr> = call.args.iter().map(|arg| arg.reduce(visitor)).collect();
+                for arg in &args {
+                    visitor.emit_inst(&format!("param {}", arg));
+                }
+
+                visitor.emit_inst(&format!("call {}", call));
+            }
+        }
+    }
+}
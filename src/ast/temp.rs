@@ -2,7 +2,7 @@ use std::fmt::{self, Display, Formatter};
 
 use crate::sym::Type;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Temp {
     pub id: usize,
     pub tp: Type,
@@ -1,6 +1,7 @@
 use std::fmt::{self, Display, Formatter};
 
 use crate::ast::Expr;
+use crate::interp::Value;
 use crate::lex::Token;
 use crate::sym::Type;
 
@@ -12,16 +13,28 @@ pub struct Unary {
 }
 
 impl Unary {
-    pub fn new(op: &Token, expr: &Expr) -> Self {
+    /// Builds `op expr`, folded into a `Cons` when `expr` is a constant.
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(op: &Token, expr: &Expr) -> Expr {
         let tp = Type::Int64
             .upcast(&expr.get_tp())
             .expect("Failed to coherce");
 
-        Self {
+        if let Expr::Cons(cons) = expr {
+            let value = Value::from_cons(cons)
+                .negate()
+                .and_then(|value| value.cast(&tp));
+
+            if let Some(cons) = value.ok().and_then(|value| value.to_cons()) {
+                return Expr::Cons(cons);
+            }
+        }
+
+        Expr::Unary(Self {
             op: op.clone(),
             tp,
             expr: Box::new(expr.clone()),
-        }
+        })
     }
 }
 
@@ -1,21 +1,26 @@
-pub fn new_label_id() -> usize {
-    static mut LABEL_COUNT: usize = 1;
+use std::cell::Cell;
 
-    unsafe {
-        let id = LABEL_COUNT;
-        LABEL_COUNT += 1;
-        id
-    }
+thread_local! {
+    static LABEL_COUNT: Cell<usize> = const { Cell::new(1) };
+    static TEMP_COUNT: Cell<usize> = const { Cell::new(0) };
+}
+
+pub fn new_label_id() -> usize {
+    LABEL_COUNT.with(|count| count.replace(count.get() + 1))
 }
 
 pub fn new_temp_id() -> usize {
-    static mut TEMP_COUNT: usize = 0;
+    TEMP_COUNT.with(|count| count.replace(count.get() + 1))
+}
 
-    unsafe {
-        let id = TEMP_COUNT;
-        TEMP_COUNT += 1;
-        id
-    }
+/// Makes sure that the following `new_label_id`s are greater than `label`.
+pub fn reserve_label_id(label: usize) {
+    LABEL_COUNT.with(|count| count.set(count.get().max(label + 1)));
+}
+
+/// Makes sure that the following `new_temp_id`s are greater than `id`.
+pub fn reserve_temp_id(id: usize) {
+    TEMP_COUNT.with(|count| count.set(count.get().max(id + 1)));
 }
 
 #[cfg(test)]
@@ -28,6 +33,9 @@ mod tests {
         assert_eq!(2, new_label_id());
         assert_eq!(3, new_label_id());
         assert_eq!(4, new_label_id());
+        reserve_label_id(9);
+        reserve_label_id(2);
+        assert_eq!(10, new_label_id());
     }
 
     #[test]
@@ -36,5 +44,7 @@ mod tests {
         assert_eq!(1, new_temp_id());
         assert_eq!(2, new_temp_id());
         assert_eq!(3, new_temp_id());
+        reserve_temp_id(7);
+        assert_eq!(8, new_temp_id());
     }
 }
@@ -0,0 +1,25 @@
+pub mod asm;
+pub mod c;
+pub mod elf;
+pub mod frame;
+pub mod llvm;
+pub mod regalloc;
+pub mod riscv;
+pub mod riscv_asm;
+pub mod wasm;
+pub mod x86_64;
+
+pub use asm::*;
+pub use c::*;
+pub use elf::*;
+pub use frame::*;
+pub use llvm::*;
+pub use regalloc::*;
+pub use riscv::*;
+pub use riscv_asm::*;
+pub use wasm::*;
+pub use x86_64::*;
+
+use crate::error::CodegenError;
+
+pub type CodegenResult<T> = Result<T, CodegenError>;
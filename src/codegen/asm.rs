@@ -0,0 +1,1070 @@
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+
+use crate::codegen::{CodegenResult, Object, Reloc, RelocKind, SectionKind, Symbol, Target};
+use crate::error::CodegenError;
+
+const QUAD: [&str; 16] = [
+    "rax", "rcx", "rdx", "rbx", "rsp", "rbp", "rsi", "rdi", "r8", "r9", "r10", "r11", "r12", "r13",
+    "r14", "r15",
+];
+const LONG: [&str; 16] = [
+    "eax", "ecx", "edx", "ebx", "esp", "ebp", "esi", "edi", "r8d", "r9d", "r10d", "r11d", "r12d",
+    "r13d", "r14d", "r15d",
+];
+const BYTE: [&str; 16] = [
+    "al", "cl", "dl", "bl", "spl", "bpl", "sil", "dil", "r8b", "r9b", "r10b", "r11b", "r12b",
+    "r13b", "r14b", "r15b",
+];
+
+/// Condition codes, as they follow `j`, `set` and `cmov`.
+const CONDITIONS: [(&str, u8); 30] = [
+    ("o", 0x0),
+    ("no", 0x1),
+    ("b", 0x2),
+    ("c", 0x2),
+    ("nae", 0x2),
+    ("ae", 0x3),
+    ("nb", 0x3),
+    ("nc", 0x3),
+    ("e", 0x4),
+    ("z", 0x4),
+    ("ne", 0x5),
+    ("nz", 0x5),
+    ("be", 0x6),
+    ("na", 0x6),
+    ("a", 0x7),
+    ("nbe", 0x7),
+    ("s", 0x8),
+    ("ns", 0x9),
+    ("p", 0xa),
+    ("pe", 0xa),
+    ("np", 0xb),
+    ("po", 0xb),
+    ("l", 0xc),
+    ("nge", 0xc),
+    ("ge", 0xd),
+    ("nl", 0xd),
+    ("le", 0xe),
+    ("ng", 0xe),
+    ("g", 0xf),
+    ("nle", 0xf),
+];
+
+/// Integer instructions with the two operand forms of `add`: opcode of the
+/// register to r/m form, and extension of the immediate form.
+const ALU: [(&str, u8, u8); 6] = [
+    ("add", 0x00, 0),
+    ("or", 0x08, 1),
+    ("and", 0x20, 4),
+    ("sub", 0x28, 5),
+    ("xor", 0x30, 6),
+    ("cmp", 0x38, 7),
+];
+
+/// SSE instructions from an xmm register or memory to an xmm register.
+const SSE: [(&str, u8, u8); 12] = [
+    ("addsd", 0xf2, 0x58),
+    ("subsd", 0xf2, 0x5c),
+    ("mulsd", 0xf2, 0x59),
+    ("divsd", 0xf2, 0x5e),
+    ("addss", 0xf3, 0x58),
+    ("subss", 0xf3, 0x5c),
+    ("mulss", 0xf3, 0x59),
+    ("divss", 0xf3, 0x5e),
+    ("cvtss2sd", 0xf3, 0x5a),
+    ("cvtsd2ss", 0xf2, 0x5a),
+    ("xorpd", 0x66, 0x57),
+    ("ucomisd", 0x66, 0x2e),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Width {
+    Byte,
+    Long,
+    Quad,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Reg {
+    Gpr(u8, Width),
+    Xmm(u8),
+}
+
+impl Reg {
+    fn num(&self) -> u8 {
+        match self {
+            Self::Gpr(num, _) | Self::Xmm(num) => *num,
+        }
+    }
+
+    /// `%spl` to `%dil` only exist with a REX prefix, without one they name
+    /// `%ah` to `%bh`.
+    fn needs_rex(&self) -> bool {
+        matches!(self, Self::Gpr(4..=7, Width::Byte))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Base {
+    Reg(u8),
+    Rip,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Mem {
+    base: Base,
+    /// Index register and scale.
+    index: Option<(u8, u8)>,
+    disp: i64,
+    sym: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Arg {
+    Reg(Reg),
+    Imm(i64),
+    Mem(Mem),
+    Label(String),
+}
+
+/// What goes in the `reg` field of the ModRM byte.
+#[derive(Debug, Clone, Copy)]
+enum Field {
+    Reg(Reg),
+    Ext(u8),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FixupKind {
+    /// `call` or jump target, through the PLT when it's not in the object.
+    Branch,
+    /// `%rip` relative data.
+    Data,
+    /// Absolute address.
+    Abs,
+}
+
+#[derive(Debug, Clone)]
+struct Fixup {
+    section: SectionKind,
+    offset: usize,
+    /// End of the instruction, PC-relative values are relative to it.
+    end: usize,
+    target: String,
+    addend: i64,
+    kind: FixupKind,
+}
+
+fn parse_int(text: &str) -> Option<i64> {
+    let (neg, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    let num = match digits.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok()?,
+        None => digits.parse::<u64>().ok()?,
+    } as i64;
+
+    Some(if neg { num.wrapping_neg() } else { num })
+}
+
+fn parse_reg(text: &str) -> Option<Reg> {
+    let name = text.strip_prefix('%')?;
+    if let Some(num) = name.strip_prefix("xmm") {
+        return num.parse().ok().filter(|num| *num < 16).map(Reg::Xmm);
+    }
+
+    [
+        (QUAD, Width::Quad),
+        (LONG, Width::Long),
+        (BYTE, Width::Byte),
+    ]
+    .iter()
+    .find_map(|(names, width)| {
+        let num = names.iter().position(|reg| *reg == name)?;
+        Some(Reg::Gpr(num as u8, *width))
+    })
+}
+
+fn condition(cc: &str) -> Option<u8> {
+    CONDITIONS
+        .iter()
+        .find(|(name, _)| *name == cc)
+        .map(|(_, code)| *code)
+}
+
+fn fits_i8(num: i64) -> bool {
+    i8::try_from(num).is_ok()
+}
+
+fn fits_i32(num: i64) -> bool {
+    i32::try_from(num).is_ok()
+}
+
+/// Splits operands at the commas outside of parentheses and strings.
+fn split_args(text: &str) -> Vec<&str> {
+    let mut args = Vec::new();
+    let (mut depth, mut quoted, mut start) = (0, false, 0);
+    for (pos, chr) in text.char_indices() {
+        match chr {
+            '"' => quoted = !quoted,
+            '(' if !quoted => depth += 1,
+            ')' if !quoted => depth -= 1,
+            ',' if !quoted && depth == 0 => {
+                args.push(text[start..pos].trim());
+                start = pos + 1;
+            }
+            _ => {}
+        }
+    }
+
+    let last = text[start..].trim();
+    if !last.is_empty() || !args.is_empty() {
+        args.push(last);
+    }
+    args
+}
+
+fn unescape(text: &str) -> Option<Vec<u8>> {
+    let inner = text.strip_prefix('"')?.strip_suffix('"')?;
+    let mut bytes = Vec::new();
+    let mut chars = inner.chars();
+    while let Some(chr) = chars.next() {
+        let chr = match chr {
+            '\\' => match chars.next()? {
+                'n' => '\n',
+                't' => '\t',
+                '0' => '\0',
+                chr @ ('\\' | '"') => chr,
+                _ => return None,
+            },
+            chr => chr,
+        };
+        let mut buf = [0; 4];
+        bytes.extend_from_slice(chr.encode_utf8(&mut buf).as_bytes());
+    }
+
+    Some(bytes)
+}
+
+/**
+ * Assembler for the AT&T syntax `emit_x86_64` produces: the integer, SSE and
+ * control flow instructions it uses, `.text`, `.data` and `.rodata`, and the
+ * directives for symbols and strings. Jumps always take 32 bits offsets.
+ */
+struct Assembler {
+    object: Object,
+    section: SectionKind,
+    labels: HashMap<String, (SectionKind, usize)>,
+    globals: Vec<String>,
+    funcs: HashSet<String>,
+    sizes: HashMap<String, usize>,
+    /// Definitions so far of each numeric label, like `1:`.
+    numeric: HashMap<String, usize>,
+    fixups: Vec<Fixup>,
+}
+
+impl Assembler {
+    fn new() -> Self {
+        let mut object = Object::default();
+        object.section(SectionKind::Text);
+
+        Self {
+            object,
+            section: SectionKind::Text,
+            labels: HashMap::new(),
+            globals: Vec::new(),
+            funcs: HashSet::new(),
+            sizes: HashMap::new(),
+            numeric: HashMap::new(),
+            fixups: Vec::new(),
+        }
+    }
+
+    fn code(&mut self) -> &mut Vec<u8> {
+        &mut self.object.section(self.section).data
+    }
+
+    fn pos(&mut self) -> usize {
+        self.code().len()
+    }
+
+    /// Name of a label as written, numeric ones are made unique.
+    fn label_name(&self, text: &str) -> String {
+        let (num, dir) = text.split_at(text.len().saturating_sub(1));
+        if num.is_empty() || !num.chars().all(|chr| chr.is_ascii_digit()) {
+            return text.to_owned();
+        }
+
+        let count = self.numeric.get(num).copied().unwrap_or(0);
+        match dir {
+            "b" => format!("{}\u{1}{}", num, count.wrapping_sub(1)),
+            "f" => format!("{}\u{1}{}", num, count),
+            _ => text.to_owned(),
+        }
+    }
+
+    fn define(&mut self, name: &str) -> CodegenResult<()> {
+        let name = if name.chars().all(|chr| chr.is_ascii_digit()) {
+            let count = self.numeric.entry(name.to_owned()).or_insert(0);
+            *count += 1;
+            format!("{}\u{1}{}", name, *count - 1)
+        } else {
+            name.to_owned()
+        };
+
+        let pos = self.pos();
+        if self
+            .labels
+            .insert(name.clone(), (self.section, pos))
+            .is_some()
+        {
+            return Err(CodegenError::new(&format!("label {} defined twice", name)));
+        }
+        Ok(())
+    }
+
+    fn arg(&self, text: &str) -> CodegenResult<Arg> {
+        let bad = || CodegenError::new(&format!("bad operand {}", text));
+
+        if text.starts_with('%') {
+            return parse_reg(text).map(Arg::Reg).ok_or_else(bad);
+        }
+        if let Some(imm) = text.strip_prefix('$') {
+            return parse_int(imm).map(Arg::Imm).ok_or_else(bad);
+        }
+
+        let open = match text.find('(') {
+            Some(open) => open,
+            None => return Ok(Arg::Label(self.label_name(text))),
+        };
+        let inner = text[open + 1..].strip_suffix(')').ok_or_else(bad)?;
+        let (disp, sym) = match &text[..open] {
+            "" => (0, None),
+            disp => match parse_int(disp) {
+                Some(disp) => (disp, None),
+                None => (0, Some(self.label_name(disp))),
+            },
+        };
+
+        let parts: Vec<_> = inner.split(',').map(str::trim).collect();
+        let gpr = |text: &str| match parse_reg(text) {
+            Some(Reg::Gpr(num, Width::Quad)) => Ok(num),
+            _ => Err(bad()),
+        };
+        let base = match parts[0] {
+            "%rip" => Base::Rip,
+            reg => Base::Reg(gpr(reg)?),
+        };
+        let index = match &parts[1..] {
+            [] => None,
+            [index, scale] => {
+                let scale = match *scale {
+                    "1" | "2" | "4" | "8" => scale.parse().unwrap(),
+                    _ => return Err(bad()),
+                };
+                Some((gpr(index)?, scale))
+            }
+            _ => return Err(bad()),
+        };
+
+        if sym.is_some() && base != Base::Rip || base == Base::Rip && index.is_some() {
+            return Err(bad());
+        }
+        Ok(Arg::Mem(Mem {
+            base,
+            index,
+            disp,
+            sym,
+        }))
+    }
+
+    /// Emits an instruction with a ModRM byte: legacy `prefix`, REX, `opcode`,
+    /// then `reg` and `rm` with their SIB byte and displacement, then `imm`.
+    fn modrm(
+        &mut self,
+        prefix: &[u8],
+        wide: bool,
+        opcode: &[u8],
+        reg: Field,
+        rm: &Arg,
+        imm: &[u8],
+    ) -> CodegenResult<()> {
+        let (r, force) = match reg {
+            Field::Reg(reg) => (reg.num(), reg.needs_rex()),
+            Field::Ext(ext) => (ext, false),
+        };
+        let (x, b, force) = match rm {
+            Arg::Reg(reg) => (0, reg.num(), force || reg.needs_rex()),
+            Arg::Mem(mem) => {
+                let base = match mem.base {
+                    Base::Reg(num) => num,
+                    Base::Rip => 0,
+                };
+                (mem.index.map_or(0, |(index, _)| index), base, force)
+            }
+            _ => return Err(CodegenError::new("expected a register or memory operand")),
+        };
+
+        let rex = 0x40 | (wide as u8) << 3 | (r >> 3) << 2 | (x >> 3) << 1 | b >> 3;
+        self.code().extend_from_slice(prefix);
+        if rex != 0x40 || force {
+            self.code().push(rex);
+        }
+        self.code().extend_from_slice(opcode);
+
+        let r = (r & 7) << 3;
+        match rm {
+            Arg::Reg(reg) => self.code().push(0xc0 | r | reg.num() & 7),
+            Arg::Mem(Mem {
+                base: Base::Rip,
+                disp,
+                sym,
+                ..
+            }) => {
+                self.code().push(r | 5);
+                let offset = self.pos();
+                self.code().extend_from_slice(&[0; 4]);
+                match sym {
+                    Some(sym) => self.fixups.push(Fixup {
+                        section: self.section,
+                        offset,
+                        end: offset + 4 + imm.len(),
+                        target: sym.clone(),
+                        addend: *disp,
+                        kind: FixupKind::Data,
+                    }),
+                    None => return Err(CodegenError::new("%rip relative without a symbol")),
+                }
+            }
+            Arg::Mem(Mem {
+                base: Base::Reg(base),
+                index,
+                disp,
+                ..
+            }) => {
+                let sib = index.is_some() || base & 7 == 4;
+                let mode = if *disp == 0 && base & 7 != 5 {
+                    0
+                } else if fits_i8(*disp) {
+                    1
+                } else if fits_i32(*disp) {
+                    2
+                } else {
+                    return Err(CodegenError::new(&format!("displacement {} too big", disp)));
+                };
+
+                self.code()
+                    .push(mode << 6 | r | if sib { 4 } else { base & 7 });
+                if sib {
+                    let (index, scale) = match index {
+                        Some((index, scale)) => (index & 7, scale.trailing_zeros() as u8),
+                        None => (4, 0),
+                    };
+                    self.code().push(scale << 6 | index << 3 | base & 7);
+                }
+                match mode {
+                    1 => self.code().push(*disp as i8 as u8),
+                    2 => self.code().extend_from_slice(&(*disp as i32).to_le_bytes()),
+                    _ => {}
+                }
+            }
+            _ => unreachable!(),
+        }
+
+        self.code().extend_from_slice(imm);
+        Ok(())
+    }
+
+    /// Emits an instruction with the register in the low bits of the opcode.
+    fn plus_reg(&mut self, wide: bool, opcode: u8, reg: u8, imm: &[u8]) {
+        let rex = 0x40 | (wide as u8) << 3 | reg >> 3;
+        if rex != 0x40 {
+            self.code().push(rex);
+        }
+        self.code().push(opcode + (reg & 7));
+        self.code().extend_from_slice(imm);
+    }
+
+    /// Emits `opcode` followed by a 32 bits offset to `target`.
+    fn branch(&mut self, opcode: &[u8], target: &Arg) -> CodegenResult<()> {
+        let target = match target {
+            Arg::Label(label) => label.clone(),
+            _ => return Err(CodegenError::new("expected a label")),
+        };
+
+        self.code().extend_from_slice(opcode);
+        let offset = self.pos();
+        self.code().extend_from_slice(&[0; 4]);
+        self.fixups.push(Fixup {
+            section: self.section,
+            offset,
+            end: offset + 4,
+            target,
+            addend: 0,
+            kind: FixupKind::Branch,
+        });
+        Ok(())
+    }
+
+    fn inst(&mut self, mnemonic: &str, args: &[Arg]) -> CodegenResult<()> {
+        use Arg::{Imm, Mem as M, Reg as R};
+        use Reg::{Gpr, Xmm};
+        use Width::{Byte, Long, Quad};
+
+        let bad = || {
+            CodegenError::new(&format!(
+                "unsupported operands for {}: {:?}",
+                mnemonic, args
+            ))
+        };
+        let rm = |arg: &Arg, width: Width| match arg {
+            R(Gpr(_, w)) => *w == width,
+            M(_) => true,
+            _ => false,
+        };
+        let xmm_or_mem = |arg: &Arg| matches!(arg, R(Xmm(_)) | M(_));
+
+        if let Some((_, base, ext)) = ALU
+            .iter()
+            .find(|(name, ..)| mnemonic.len() == name.len() + 1 && mnemonic.starts_with(name))
+        {
+            let width = match mnemonic.as_bytes()[mnemonic.len() - 1] {
+                b'q' => Quad,
+                b'l' => Long,
+                b'b' => Byte,
+                _ => return Err(bad()),
+            };
+            let (wide, byte) = (width == Quad, width == Byte);
+            return match args {
+                [Imm(num), dst] if rm(dst, width) && byte => {
+                    self.modrm(&[], false, &[0x80], Field::Ext(*ext), dst, &[*num as u8])
+                }
+                [Imm(num), dst] if rm(dst, width) && fits_i8(*num) => {
+                    self.modrm(&[], wide, &[0x83], Field::Ext(*ext), dst, &[*num as u8])
+                }
+                [Imm(num), dst] if rm(dst, width) && fits_i32(*num) => {
+                    let imm = (*num as i32).to_le_bytes();
+                    self.modrm(&[], wide, &[0x81], Field::Ext(*ext), dst, &imm)
+                }
+                [R(src @ Gpr(_, w)), dst] if *w == width && rm(dst, width) => {
+                    let opcode = base + !byte as u8;
+                    self.modrm(&[], wide, &[opcode], Field::Reg(*src), dst, &[])
+                }
+                [src @ M(_), R(dst @ Gpr(_, w))] if *w == width => {
+                    let opcode = base + 2 + !byte as u8;
+                    self.modrm(&[], wide, &[opcode], Field::Reg(*dst), src, &[])
+                }
+                _ => Err(bad()),
+            };
+        }
+
+        if let Some((_, prefix, opcode)) = SSE.iter().find(|(name, ..)| *name == mnemonic) {
+            return match args {
+                [src, R(dst @ Xmm(_))] if xmm_or_mem(src) => self.modrm(
+                    &[*prefix],
+                    false,
+                    &[0x0f, *opcode],
+                    Field::Reg(*dst),
+                    src,
+                    &[],
+                ),
+                _ => Err(bad()),
+            };
+        }
+
+        match (mnemonic, args) {
+            ("movq", [R(src @ Gpr(_, Quad)), dst]) if rm(dst, Quad) => {
+                self.modrm(&[], true, &[0x89], Field::Reg(*src), dst, &[])
+            }
+            ("movq", [src @ M(_), R(dst @ Gpr(_, Quad))]) => {
+                self.modrm(&[], true, &[0x8b], Field::Reg(*dst), src, &[])
+            }
+            ("movq", [Imm(num), dst]) if rm(dst, Quad) && fits_i32(*num) => {
+                let imm = (*num as i32).to_le_bytes();
+                self.modrm(&[], true, &[0xc7], Field::Ext(0), dst, &imm)
+            }
+            ("movq", [src @ R(Gpr(_, Quad)), R(dst @ Xmm(_))]) => {
+                self.modrm(&[0x66], true, &[0x0f, 0x6e], Field::Reg(*dst), src, &[])
+            }
+            ("movq", [R(src @ Xmm(_)), dst @ R(Gpr(_, Quad))]) => {
+                self.modrm(&[0x66], true, &[0x0f, 0x7e], Field::Reg(*src), dst, &[])
+            }
+            ("movabsq", [Imm(num), R(Gpr(dst, Quad))]) => {
+                self.plus_reg(true, 0xb8, *dst, &num.to_le_bytes());
+                Ok(())
+            }
+            ("movl", [R(src @ Gpr(_, Long)), dst]) if rm(dst, Long) => {
+                self.modrm(&[], false, &[0x89], Field::Reg(*src), dst, &[])
+            }
+            ("movl", [src @ M(_), R(dst @ Gpr(_, Long))]) => {
+                self.modrm(&[], false, &[0x8b], Field::Reg(*dst), src, &[])
+            }
+            ("movl", [Imm(num), R(Gpr(dst, Long))]) => {
+                self.plus_reg(false, 0xb8, *dst, &(*num as i32).to_le_bytes());
+                Ok(())
+            }
+            ("movl", [Imm(num), dst @ M(_)]) => {
+                let imm = (*num as i32).to_le_bytes();
+                self.modrm(&[], false, &[0xc7], Field::Ext(0), dst, &imm)
+            }
+            ("movb", [R(src @ Gpr(_, Byte)), dst]) if rm(dst, Byte) => {
+                self.modrm(&[], false, &[0x88], Field::Reg(*src), dst, &[])
+            }
+            ("movb", [src @ M(_), R(dst @ Gpr(_, Byte))]) => {
+                self.modrm(&[], false, &[0x8a], Field::Reg(*dst), src, &[])
+            }
+            ("movb", [Imm(num), dst]) if rm(dst, Byte) => {
+                self.modrm(&[], false, &[0xc6], Field::Ext(0), dst, &[*num as u8])
+            }
+            ("movslq", [src, R(dst @ Gpr(_, Quad))]) if rm(src, Long) => {
+                self.modrm(&[], true, &[0x63], Field::Reg(*dst), src, &[])
+            }
+            ("movzbq", [src, R(dst @ Gpr(_, Quad))]) if rm(src, Byte) => {
+                self.modrm(&[], true, &[0x0f, 0xb6], Field::Reg(*dst), src, &[])
+            }
+            ("cvtsi2sdq", [src, R(dst @ Xmm(_))]) if rm(src, Quad) => {
+                self.modrm(&[0xf2], true, &[0x0f, 0x2a], Field::Reg(*dst), src, &[])
+            }
+            ("cvttsd2siq", [src, R(dst @ Gpr(_, Quad))]) if xmm_or_mem(src) => {
+                self.modrm(&[0xf2], true, &[0x0f, 0x2c], Field::Reg(*dst), src, &[])
+            }
+            ("movss", [src, R(dst @ Xmm(_))]) | ("movsd", [src, R(dst @ Xmm(_))])
+                if xmm_or_mem(src) =>
+            {
+                let prefix = if mnemonic == "movss" { 0xf3 } else { 0xf2 };
+                self.modrm(&[prefix], false, &[0x0f, 0x10], Field::Reg(*dst), src, &[])
+            }
+            ("movss", [R(src @ Xmm(_)), dst @ M(_)]) | ("movsd", [R(src @ Xmm(_)), dst @ M(_)]) => {
+                let prefix = if mnemonic == "movss" { 0xf3 } else { 0xf2 };
+                self.modrm(&[prefix], false, &[0x0f, 0x11], Field::Reg(*src), dst, &[])
+            }
+            ("movapd", [src @ R(Xmm(_)), R(dst @ Xmm(_))]) => {
+                self.modrm(&[0x66], false, &[0x0f, 0x28], Field::Reg(*dst), src, &[])
+            }
+            ("imulq", [src, R(dst @ Gpr(_, Quad))]) if rm(src, Quad) => {
+                self.modrm(&[], true, &[0x0f, 0xaf], Field::Reg(*dst), src, &[])
+            }
+            ("testq", [R(src @ Gpr(_, Quad)), dst]) if rm(dst, Quad) => {
+                self.modrm(&[], true, &[0x85], Field::Reg(*src), dst, &[])
+            }
+            ("leaq", [src @ M(_), R(dst @ Gpr(_, Quad))]) => {
+                self.modrm(&[], true, &[0x8d], Field::Reg(*dst), src, &[])
+            }
+            ("incq", [dst]) | ("decq", [dst]) if rm(dst, Quad) => {
+                let ext = if mnemonic == "incq" { 0 } else { 1 };
+                self.modrm(&[], true, &[0xff], Field::Ext(ext), dst, &[])
+            }
+            ("notq", [dst]) | ("negq", [dst]) | ("idivq", [dst]) if rm(dst, Quad) => {
+                let ext = match mnemonic {
+                    "notq" => 2,
+                    "negq" => 3,
+                    _ => 7,
+                };
+                self.modrm(&[], true, &[0xf7], Field::Ext(ext), dst, &[])
+            }
+            ("pushq", [R(Gpr(reg, Quad))]) => {
+                self.plus_reg(false, 0x50, *reg, &[]);
+                Ok(())
+            }
+            ("popq", [R(Gpr(reg, Quad))]) => {
+                self.plus_reg(false, 0x58, *reg, &[]);
+                Ok(())
+            }
+            ("cqto", []) => {
+                self.code().extend_from_slice(&[0x48, 0x99]);
+                Ok(())
+            }
+            ("leave", []) => {
+                self.code().push(0xc9);
+                Ok(())
+            }
+            ("ud2", []) => {
+                self.code().extend_from_slice(&[0x0f, 0x0b]);
+                Ok(())
+            }
+            ("ret", []) => {
+                self.code().push(0xc3);
+                Ok(())
+            }
+            ("call", [target]) => self.branch(&[0xe8], target),
+            ("jmp", [target]) => self.branch(&[0xe9], target),
+            _ => self
+                .conditional(mnemonic, args)
+                .unwrap_or_else(|| Err(bad())),
+        }
+    }
+
+    /// Jumps, sets and moves on condition codes.
+    fn conditional(&mut self, mnemonic: &str, args: &[Arg]) -> Option<CodegenResult<()>> {
+        if let Some(cc) = mnemonic.strip_prefix("cmov") {
+            let cc = condition(cc).or_else(|| condition(cc.strip_suffix('q')?))?;
+            return match args {
+                [src, Arg::Reg(dst @ Reg::Gpr(_, Width::Quad))]
+                    if matches!(src, Arg::Reg(Reg::Gpr(_, Width::Quad)) | Arg::Mem(_)) =>
+                {
+                    Some(self.modrm(&[], true, &[0x0f, 0x40 + cc], Field::Reg(*dst), src, &[]))
+                }
+                _ => None,
+            };
+        }
+        if let Some(cc) = mnemonic.strip_prefix("set") {
+            let cc = condition(cc)?;
+            return match args {
+                [dst] if matches!(dst, Arg::Reg(Reg::Gpr(_, Width::Byte)) | Arg::Mem(_)) => {
+                    Some(self.modrm(&[], false, &[0x0f, 0x90 + cc], Field::Ext(0), dst, &[]))
+                }
+                _ => None,
+            };
+        }
+
+        let cc = condition(mnemonic.strip_prefix('j')?)?;
+        match args {
+            [target] => Some(self.branch(&[0x0f, 0x80 + cc], target)),
+            _ => None,
+        }
+    }
+
+    fn directive(&mut self, name: &str, args: &[&str]) -> CodegenResult<()> {
+        let bad = || CodegenError::new(&format!("unsupported directive {} {:?}", name, args));
+
+        match (name, args) {
+            (".text", []) => self.section = SectionKind::Text,
+            (".data", []) => self.section = SectionKind::Data,
+            (".section", [section, ..]) => {
+                self.section = match *section {
+                    ".text" => SectionKind::Text,
+                    ".data" => SectionKind::Data,
+                    ".rodata" => SectionKind::Rodata,
+                    ".note.GNU-stack" => SectionKind::NoteStack,
+                    _ => return Err(bad()),
+                };
+                self.object.section(self.section);
+            }
+            (".globl", [sym]) => self.globals.push(sym.to_string()),
+            (".type", [sym, "@function"]) => {
+                self.funcs.insert(sym.to_string());
+            }
+            (".type", [_, "@object"]) => {}
+            (".size", [sym, size]) => {
+                let start = size
+                    .strip_prefix(".-")
+                    .filter(|start| start == sym)
+                    .and_then(|start| self.labels.get(start))
+                    .filter(|(section, _)| *section == self.section)
+                    .map(|(_, pos)| *pos)
+                    .ok_or_else(bad)?;
+                let size = self.pos() - start;
+                self.sizes.insert(sym.to_string(), size);
+            }
+            (".string", [string]) => {
+                let mut bytes = unescape(string).ok_or_else(bad)?;
+                bytes.push(0);
+                self.code().extend(bytes);
+            }
+            (".quad", [value]) => match parse_int(value) {
+                Some(num) => self.code().extend_from_slice(&num.to_le_bytes()),
+                None => {
+                    let offset = self.pos();
+                    self.code().extend_from_slice(&[0; 8]);
+                    self.fixups.push(Fixup {
+                        section: self.section,
+                        offset,
+                        end: offset + 8,
+                        target: self.label_name(value),
+                        addend: 0,
+                        kind: FixupKind::Abs,
+                    });
+                }
+            },
+            _ => return Err(bad()),
+        }
+
+        Ok(())
+    }
+
+    fn line(&mut self, line: &str) -> CodegenResult<()> {
+        let mut line = line.trim();
+        if let Some(colon) = line.find(':').filter(|colon| {
+            let label = &line[..*colon];
+            !label.is_empty() && !label.contains(char::is_whitespace) && !label.contains('"')
+        }) {
+            self.define(&line[..colon])?;
+            line = line[colon + 1..].trim();
+        }
+        if line.is_empty() || line.starts_with('#') {
+            return Ok(());
+        }
+
+        let (name, rest) = match line.find(char::is_whitespace) {
+            Some(pos) => (&line[..pos], line[pos..].trim()),
+            None => (line, ""),
+        };
+        let args = split_args(rest);
+        if name.starts_with('.') {
+            return self.directive(name, &args);
+        }
+
+        let args = args
+            .iter()
+            .map(|arg| self.arg(arg))
+            .collect::<CodegenResult<Vec<_>>>()?;
+        self.inst(name, &args)
+    }
+
+    /// Patches references within a section, and leaves relocations for the
+    /// rest.
+    fn finish(mut self) -> CodegenResult<Object> {
+        let is_local = |name: &str| name.starts_with(".L") || name.contains('\u{1}');
+        let globals: HashSet<_> = self.globals.iter().cloned().collect();
+
+        let mut undefined = Vec::new();
+        for fixup in std::mem::take(&mut self.fixups) {
+            let (target, plt) = match fixup.target.strip_suffix("@PLT") {
+                Some(target) => (target.to_owned(), true),
+                None => (fixup.target.clone(), false),
+            };
+            let def = self.labels.get(&target).copied();
+            let section = self.object.section(fixup.section);
+
+            if let (Some((kind, pos)), true) = (def, fixup.kind != FixupKind::Abs) {
+                if kind == fixup.section && !plt {
+                    let value = pos as i64 + fixup.addend - fixup.end as i64;
+                    let value = i32::try_from(value)
+                        .map_err(|_| CodegenError::new(&format!("{} is too far", target)))?;
+                    section.data[fixup.offset..fixup.offset + 4]
+                        .copy_from_slice(&value.to_le_bytes());
+                    continue;
+                }
+            }
+
+            let kind = match fixup.kind {
+                FixupKind::Branch => RelocKind::Plt32,
+                FixupKind::Data => RelocKind::Pc32,
+                FixupKind::Abs => RelocKind::Abs64,
+            };
+            let mut addend = fixup.addend - (fixup.end - fixup.offset) as i64;
+            if kind == RelocKind::Abs64 {
+                addend = fixup.addend;
+            }
+
+            let target = match def {
+                Some((kind, pos)) if is_local(&target) || !globals.contains(&target) => {
+                    addend += pos as i64;
+                    Target::Section(kind)
+                }
+                Some(_) => Target::Symbol(target),
+                None if is_local(&target) => {
+                    return Err(CodegenError::new(&format!("undefined label {}", target)))
+                }
+                None => {
+                    undefined.push(target.clone());
+                    Target::Symbol(target)
+                }
+            };
+
+            section.relocs.push(Reloc {
+                offset: fixup.offset as u64,
+                target,
+                kind,
+                addend,
+            });
+        }
+
+        let mut labels: Vec<_> = self.labels.iter().collect();
+        labels.sort_by_key(|(name, def)| (**def, (*name).clone()));
+        for (name, (kind, pos)) in labels {
+            if is_local(name) {
+                continue;
+            }
+
+            self.object.symbols.push(Symbol {
+                name: name.clone(),
+                def: Some((*kind, *pos as u64)),
+                global: globals.contains(name),
+                func: self.funcs.contains(name),
+                size: self.sizes.get(name).copied().unwrap_or(0) as u64,
+            });
+        }
+
+        undefined.extend(self.globals.iter().cloned());
+        for name in undefined {
+            if self.object.symbol(&name).is_none() {
+                self.object.symbols.push(Symbol {
+                    name,
+                    def: None,
+                    global: true,
+                    func: false,
+                    size: 0,
+                });
+            }
+        }
+
+        Ok(self.object)
+    }
+}
+
+/// Assembles `asm`, as emitted by `emit_x86_64`, into a relocatable object.
+pub fn assemble(asm: &str) -> CodegenResult<Object> {
+    let mut assembler = Assembler::new();
+    for (pos, line) in asm.lines().enumerate() {
+        assembler
+            .line(line)
+            .map_err(|error| CodegenError::new(&format!("line {}: {}", pos + 1, error)))?;
+    }
+
+    assembler.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodings() {
+        // As encoded by GNU as
+        let cases: &[(&str, &[u8])] = &[
+            ("movq %rax, %rbx", &[0x48, 0x89, 0xc3]),
+            ("movq -24(%rbp), %r12", &[0x4c, 0x8b, 0x65, 0xe8]),
+            ("movq %r15, 8(%rsp)", &[0x4c, 0x89, 0x7c, 0x24, 0x08]),
+            (
+                "movq $-5, %r11",
+                &[0x49, 0xc7, 0xc3, 0xfb, 0xff, 0xff, 0xff],
+            ),
+            ("movq $0, (%r10)", &[0x49, 0xc7, 0x02, 0, 0, 0, 0]),
+            ("movq %rdx, %xmm1", &[0x66, 0x48, 0x0f, 0x6e, 0xca]),
+            ("movq %xmm0, %rax", &[0x66, 0x48, 0x0f, 0x7e, 0xc0]),
+            (
+                "movabsq $0x8000000000000000, %rdx",
+                &[0x48, 0xba, 0, 0, 0, 0, 0, 0, 0, 0x80],
+            ),
+            ("movl %eax, -4(%rbp)", &[0x89, 0x45, 0xfc]),
+            ("movl $1, %eax", &[0xb8, 1, 0, 0, 0]),
+            ("movb %dil, -1(%rbp)", &[0x40, 0x88, 0x7d, 0xff]),
+            ("movb %r9b, (%rdx,%rcx,1)", &[0x44, 0x88, 0x0c, 0x0a]),
+            ("movslq -8(%r13), %rax", &[0x49, 0x63, 0x45, 0xf8]),
+            ("movzbq %al, %rax", &[0x48, 0x0f, 0xb6, 0xc0]),
+            (
+                "movzbq (%rax,%rcx,1), %rax",
+                &[0x48, 0x0f, 0xb6, 0x04, 0x08],
+            ),
+            (
+                "cvtss2sd (%rax,%rcx,4), %xmm0",
+                &[0xf3, 0x0f, 0x5a, 0x04, 0x88],
+            ),
+            ("cvtsd2ss %xmm0, %xmm0", &[0xf2, 0x0f, 0x5a, 0xc0]),
+            ("cvtsi2sdq %rax, %xmm14", &[0xf2, 0x4c, 0x0f, 0x2a, 0xf0]),
+            ("cvttsd2siq %xmm1, %rcx", &[0xf2, 0x48, 0x0f, 0x2c, 0xc9]),
+            (
+                "movss %xmm0, -300(%rbp)",
+                &[0xf3, 0x0f, 0x11, 0x85, 0xd4, 0xfe, 0xff, 0xff],
+            ),
+            (
+                "movsd 16(%rsp), %xmm7",
+                &[0xf2, 0x0f, 0x10, 0x7c, 0x24, 0x10],
+            ),
+            ("movapd %xmm15, %xmm0", &[0x66, 0x41, 0x0f, 0x28, 0xc7]),
+            ("xorpd %xmm1, %xmm0", &[0x66, 0x0f, 0x57, 0xc1]),
+            ("ucomisd %xmm0, %xmm1", &[0x66, 0x0f, 0x2e, 0xc8]),
+            ("divsd %xmm1, %xmm0", &[0xf2, 0x0f, 0x5e, 0xc1]),
+            ("addq $128, %rsp", &[0x48, 0x81, 0xc4, 0x80, 0, 0, 0]),
+            ("subq $16, %rsp", &[0x48, 0x83, 0xec, 0x10]),
+            ("addq %rcx, %rax", &[0x48, 0x01, 0xc8]),
+            ("cmpq %rcx, %rax", &[0x48, 0x39, 0xc8]),
+            ("cmpq $5, %rcx", &[0x48, 0x83, 0xf9, 0x05]),
+            ("cmpq $1000, %rcx", &[0x48, 0x81, 0xf9, 0xe8, 0x03, 0, 0]),
+            ("xorq $1, %rax", &[0x48, 0x83, 0xf0, 0x01]),
+            ("andb %cl, %al", &[0x20, 0xc8]),
+            ("orb %cl, %al", &[0x08, 0xc8]),
+            ("xorl %eax, %eax", &[0x31, 0xc0]),
+            ("imulq %rcx, %rax", &[0x48, 0x0f, 0xaf, 0xc1]),
+            ("cqto", &[0x48, 0x99]),
+            ("ud2", &[0x0f, 0x0b]),
+            ("idivq %rcx", &[0x48, 0xf7, 0xf9]),
+            ("negq %rax", &[0x48, 0xf7, 0xd8]),
+            ("decq %r11", &[0x49, 0xff, 0xcb]),
+            ("testq %rax, %rax", &[0x48, 0x85, 0xc0]),
+            ("seta %al", &[0x0f, 0x97, 0xc0]),
+            ("setnp %cl", &[0x0f, 0x9b, 0xc1]),
+            ("cmove %rdx, %rsi", &[0x48, 0x0f, 0x44, 0xf2]),
+            ("pushq %rbp", &[0x55]),
+            ("pushq %r12", &[0x41, 0x54]),
+            ("leaq -48(%rbp), %r10", &[0x4c, 0x8d, 0x55, 0xd0]),
+            ("leave", &[0xc9]),
+            ("ret", &[0xc3]),
+        ];
+
+        for (line, bytes) in cases {
+            let object = assemble(line).unwrap();
+            assert_eq!(&object.sections[&SectionKind::Text].data, bytes, "{}", line);
+        }
+    }
+
+    #[test]
+    fn labels_and_relocations() {
+        let object = assemble(
+            "\t.text\n\t.globl f\n\t.type f, @function\nf:\n\
+             1:\n\tjmp 1b\n\tjne .Lend\n\tcall f\n\tcall printf@PLT\n\
+             \tleaq .Lmsg(%rip), %rdi\n.Lend:\n\tret\n\t.size f, .-f\n\
+             \t.section .rodata\n.Lmsg:\n\t.string \"hi\\n\"\n\
+             \t.data\n\t.globl p\np:\n\t.quad f\n",
+        )
+        .unwrap();
+
+        let text = &object.sections[&SectionKind::Text];
+        assert_eq!(&text.data[..5], &[0xe9, 0xfb, 0xff, 0xff, 0xff]);
+        assert_eq!(&text.data[5..11], &[0x0f, 0x85, 0x11, 0, 0, 0]);
+        assert_eq!(&text.data[11..16], &[0xe8, 0xf0, 0xff, 0xff, 0xff]);
+        assert_eq!(
+            text.relocs,
+            vec![
+                Reloc {
+                    offset: 17,
+                    target: Target::Symbol("printf".to_owned()),
+                    kind: RelocKind::Plt32,
+                    addend: -4,
+                },
+                Reloc {
+                    offset: 24,
+                    target: Target::Section(SectionKind::Rodata),
+                    kind: RelocKind::Pc32,
+                    addend: -4,
+                },
+            ]
+        );
+        assert_eq!(object.sections[&SectionKind::Rodata].data, b"hi\n\0");
+
+        let data = &object.sections[&SectionKind::Data];
+        assert_eq!(data.relocs[0].target, Target::Symbol("f".to_owned()));
+        assert_eq!(data.relocs[0].kind, RelocKind::Abs64);
+
+        let f = object.symbol("f").unwrap();
+        assert_eq!((f.global, f.func, f.size), (true, true, 29));
+        assert!(object.symbol("printf").unwrap().def.is_none());
+        assert!(object.symbol(".Lend").is_none());
+
+        assert!(assemble("\tjmp .Lnowhere\n").is_err());
+        assert!(assemble("\tmovq %eax, %rbx\n").is_err());
+    }
+
+    #[test]
+    fn numeric_labels() {
+        // As in the saturating conversions of floats: `1f` and `1b` go to the
+        // nearest `1` after and before them, even once it's defined again
+        let object = assemble(
+            "1:\n\tjne 1f\n\tjp 2f\n\tud2\n2:\n\tjs 1b\n\tjmp 1f\n1:\n\
+             \tmovabsq $0x7fffffffffffffff, %rax\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            object.sections[&SectionKind::Text].data,
+            vec![
+                0x0f, 0x85, 19, 0, 0, 0, 0x0f, 0x8a, 2, 0, 0, 0, 0x0f, 0x0b, 0x0f, 0x88, 0xec,
+                0xff, 0xff, 0xff, 0xe9, 0, 0, 0, 0, 0x48, 0xb8, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+                0xff, 0x7f,
+            ]
+        );
+    }
+}
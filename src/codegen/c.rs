@@ -0,0 +1,589 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+use crate::codegen::CodegenResult;
+use crate::error::CodegenError;
+use crate::interp::Value;
+use crate::ir::{Function, Inst, Module, Opcode, Operand, Var};
+use crate::sym::Type;
+
+/// Runtime checks the VM does on its own, out of line so the generated code
+/// stays readable.
+const PRELUDE: &str = r#"#include <inttypes.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+
+static void ez_fail(const char *msg) {
+    fprintf(stderr, "%s\n", msg);
+    exit(EXIT_FAILURE);
+}
+
+static inline int64_t ez_check(int64_t index, int64_t size) {
+    if (index < 0 || index >= size) {
+        ez_fail("index out of bounds");
+    }
+    return index;
+}
+
+static inline int64_t ez_div(int64_t lhs, int64_t rhs) {
+    if (rhs == 0) {
+        ez_fail("division by zero");
+    }
+    return rhs == -1 ? (int64_t)(0 - (uint64_t)lhs) : lhs / rhs;
+}
+
+static inline int64_t ez_trunc(double value) {
+    if (value != value) {
+        return 0;
+    }
+    if (value <= -9223372036854775808.0) {
+        return INT64_MIN;
+    }
+    if (value >= 9223372036854775808.0) {
+        return INT64_MAX;
+    }
+    return (int64_t)value;
+}
+"#;
+
+fn c_type(tp: &Type) -> CodegenResult<&'static str> {
+    match tp {
+        Type::Int32 => Ok("int32_t"),
+        Type::Int64 => Ok("int64_t"),
+        Type::Flt32 => Ok("float"),
+        Type::Flt64 => Ok("double"),
+        Type::Char => Ok("uint8_t"),
+        Type::Bool => Ok("bool"),
+        tp => Err(CodegenError::new(&format!("no C scalar type for {}", tp))),
+    }
+}
+
+/// Declaration of `name` with type `tp`, arrays as fixed-size C arrays of
+/// their scalars, row after row.
+fn decl(tp: &Type, name: &str) -> CodegenResult<String> {
+    match tp {
+        Type::Array { .. } => Ok(format!(
+            "{} {}[{}]",
+            c_type(tp.get_scalar())?,
+            name,
+            tp.get_len()
+        )),
+        tp => Ok(format!("{} {}", c_type(tp)?, name)),
+    }
+}
+
+fn is_float(tp: &Type) -> bool {
+    matches!(tp, Type::Flt32 | Type::Flt64)
+}
+
+/// `expr` converted from `from` to `to`, like `Value::cast`.
+fn cast(expr: String, from: &Type, to: &Type) -> CodegenResult<String> {
+    if from == to {
+        return Ok(expr);
+    }
+
+    // Floats go to integers through a saturating `i64`, as in the VM, where
+    // a C cast out of range is undefined
+    if is_float(from) && matches!(to, Type::Int32 | Type::Int64 | Type::Char) {
+        return Ok(format!("({})ez_trunc({})", c_type(to)?, expr));
+    }
+
+    Ok(format!("({})({})", c_type(to)?, expr))
+}
+
+fn var(operand: &Operand) -> String {
+    match operand {
+        Operand::Ident(ident) => format!("{}_{}", ident.id, ident.offset),
+        Operand::Temp(temp) => format!("t{}", temp.id),
+        Operand::Cons(_) => unreachable!(),
+    }
+}
+
+fn expr(operand: &Operand) -> CodegenResult<String> {
+    let cons = match operand {
+        Operand::Cons(cons) => cons,
+        operand => return Ok(var(operand)),
+    };
+
+    let expr = match Value::from_cons(cons) {
+        Value::Int32(num) => format!("{}", num),
+        Value::Int64(num) => format!("INT64_C({})", num),
+        Value::Flt32(num) => format!("{:?}f", num),
+        Value::Flt64(num) => format!("{:?}", num),
+        Value::Char(chr) => format!("(uint8_t){}", chr as u32),
+        Value::Bool(value) => format!("{}", value),
+        value => return Err(CodegenError::new(&format!("no C constant for {}", value))),
+    };
+
+    Ok(expr)
+}
+
+/// `operand` converted to `to`.
+fn load(operand: &Operand, to: &Type) -> CodegenResult<String> {
+    cast(expr(operand)?, &operand.get_tp(), to)
+}
+
+fn binary(op: Opcode, lhs: &Operand, rhs: &Operand) -> CodegenResult<(String, Type)> {
+    let tp = match lhs.get_tp().upcast(&rhs.get_tp()) {
+        Some(tp) => tp,
+        // Booleans can only be compared
+        None => {
+            let (lhs, rhs) = (expr(lhs)?, expr(rhs)?);
+            return Ok((format!("{} {} {}", lhs, op.get_token(), rhs), Type::Bool));
+        }
+    };
+
+    let (lhs, rhs) = (load(lhs, &tp)?, load(rhs, &tp)?);
+    if op.is_rel() {
+        return Ok((format!("{} {} {}", lhs, op.get_token(), rhs), Type::Bool));
+    }
+
+    // Integers wrap around like in the VM, which signed arithmetic in C doesn't
+    let expr = match op {
+        _ if is_float(&tp) => format!("(double){} {} (double){}", lhs, op.get_token(), rhs),
+        Opcode::Div => format!("ez_div({}, {})", lhs, rhs),
+        op => format!("(uint64_t){} {} (uint64_t){}", lhs, op.get_token(), rhs),
+    };
+
+    Ok((format!("({})({})", c_type(&tp)?, expr), tp))
+}
+
+struct Writer<'a> {
+    module: &'a Module,
+    func: &'a Function,
+    out: String,
+}
+
+impl<'a> Writer<'a> {
+    fn line(&mut self, line: &str) {
+        self.out.push_str("    ");
+        self.out.push_str(line);
+        self.out.push('\n');
+    }
+
+    /// Fails like the VM when a function with a result returns without one.
+    fn missing_return(&mut self) {
+        let line = format!("ez_fail(\"missing return in {}\");", self.func.name);
+        self.line(&line);
+        self.line("abort();");
+    }
+
+    fn set(&mut self, dst: &Operand, expr: String, tp: &Type) -> CodegenResult<()> {
+        let line = format!("{} = {};", var(dst), cast(expr, tp, &dst.get_tp())?);
+        self.line(&line);
+        Ok(())
+    }
+
+    fn element(&self, array: &Operand, index: &Operand) -> CodegenResult<(String, Type)> {
+        match array.get_tp() {
+            tp @ Type::Array { .. } => Ok((
+                format!(
+                    "{}[ez_check({}, {})]",
+                    var(array),
+                    load(index, &Type::Int64)?,
+                    tp.get_len()
+                ),
+                tp.get_scalar().clone(),
+            )),
+            _ => Err(CodegenError::new(&format!("{} is not an array", array))),
+        }
+    }
+
+    fn call(&mut self, code: &[Inst], pos: usize) -> CodegenResult<()> {
+        let (dst, name, nargs) = match &code[pos] {
+            Inst::Call { dst, func, nargs } => (dst, func, *nargs),
+            _ => unreachable!(),
+        };
+
+        let callee = self
+            .module
+            .get(name)
+            .filter(|callee| callee.params.len() == nargs)
+            .ok_or_else(|| CodegenError::new(&format!("bad call to {}", name)))?;
+
+        // Arguments are only read when the call happens
+        let params = pos
+            .checked_sub(nargs)
+            .map(|start| &code[start..pos])
+            .filter(|params| params.iter().all(|inst| matches!(inst, Inst::Param { .. })))
+            .ok_or_else(|| {
+                CodegenError::new(&format!(
+                    "arguments of {} aren't right before the call",
+                    name
+                ))
+            })?;
+
+        let mut args = Vec::new();
+        for (inst, param) in params.iter().zip(&callee.params) {
+            if let Inst::Param { src } = inst {
+                args.push(load(src, &param.tp)?);
+            }
+        }
+
+        let call = format!("ez_{}({})", name, args.join(", "));
+        match (dst, &callee.ret) {
+            (Some(dst), Some(ret)) => self.set(dst, call, ret),
+            (None, _) => {
+                self.line(&format!("{};", call));
+                Ok(())
+            }
+            (Some(_), None) => Err(CodegenError::new(&format!(
+                "{} doesn't return a value",
+                name
+            ))),
+        }
+    }
+
+    fn inst(&mut self, code: &[Inst], pos: usize) -> CodegenResult<()> {
+        match &code[pos] {
+            // Labels need a statement after them, even at the end of a function
+            Inst::Label(label) => self.out.push_str(&format!("L{}:;\n", label)),
+
+            Inst::Binary { op, dst, lhs, rhs } => {
+                let (expr, tp) = binary(*op, lhs, rhs)?;
+                self.set(dst, expr, &tp)?;
+            }
+
+            Inst::Inv { dst, src } => {
+                let tp = Type::Int64
+                    .upcast(&src.get_tp())
+                    .ok_or_else(|| CodegenError::new(&format!("cannot negate {}", src)))?;
+
+                let expr = match tp {
+                    Type::Int64 => format!("(int64_t)(0 - (uint64_t){})", load(src, &tp)?),
+                    _ => format!("-{}", load(src, &tp)?),
+                };
+                self.set(dst, expr, &tp)?;
+            }
+
+            Inst::Not { dst, src } => {
+                let expr = format!("!{}", expr(src)?);
+                self.set(dst, expr, &Type::Bool)?;
+            }
+
+            Inst::Mov { dst, src } => self.set(dst, expr(src)?, &src.get_tp())?,
+
+            Inst::Idx { dst, index, array } => {
+                let (elem, of) = self.element(array, index)?;
+                self.set(dst, elem, &of)?;
+            }
+
+            Inst::Sto { array, index, src } => {
+                let (elem, of) = self.element(array, index)?;
+                let line = format!("{} = {};", elem, load(src, &of)?);
+                self.line(&line);
+            }
+
+            Inst::Chk { index, len } => {
+                let line = format!("ez_check({}, {});", load(index, &Type::Int64)?, len);
+                self.line(&line);
+            }
+
+            Inst::Jmp { label } => self.line(&format!("goto L{};", label)),
+            Inst::JmpT { label, test } => {
+                let line = format!("if ({}) goto L{};", expr(test)?, label);
+                self.line(&line);
+            }
+            Inst::JmpF { label, test } => {
+                let line = format!("if (!{}) goto L{};", expr(test)?, label);
+                self.line(&line);
+            }
+
+            // Passed by the call that follows
+            Inst::Param { .. } => {}
+            Inst::Call { .. } => self.call(code, pos)?,
+
+            Inst::Ret { src } => match (src, &self.func.ret) {
+                (Some(src), Some(ret)) => {
+                    let line = format!("return {};", load(src, ret)?);
+                    self.line(&line);
+                }
+                (None, Some(_)) => self.missing_return(),
+                _ => self.line("return;"),
+            },
+
+            Inst::Phi { .. } => {
+                return Err(CodegenError::new(
+                    "cannot lower phi, translate out of SSA first",
+                ))
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn signature(func: &Function) -> CodegenResult<String> {
+    let params = func
+        .params
+        .iter()
+        .map(|param| match param.tp {
+            // C would pass them by reference
+            Type::Array { .. } => Err(CodegenError::new(&format!(
+                "array parameter {} of {}",
+                param.id, func.name
+            ))),
+            _ => decl(&param.tp, &var(&Operand::Ident(param.clone()))),
+        })
+        .collect::<CodegenResult<Vec<_>>>()?;
+
+    let ret = match &func.ret {
+        Some(tp) => c_type(tp)?,
+        None => "void",
+    };
+
+    let params = if params.is_empty() {
+        "void".to_owned()
+    } else {
+        params.join(", ")
+    };
+
+    Ok(format!("static {} ez_{}({})", ret, func.name, params))
+}
+
+fn function(module: &Module, func: &Function) -> CodegenResult<String> {
+    let mut writer = Writer {
+        module,
+        func,
+        out: format!("{} {{\n", signature(func)?),
+    };
+
+    let used: HashSet<Var> = func
+        .code
+        .iter()
+        .flat_map(|inst| inst.get_uses().into_iter().chain(inst.get_def()))
+        .filter_map(Operand::get_var)
+        .collect();
+
+    // Locals start at zero, as in a new frame of the VM. The ones optimized
+    // away aren't declared, C compilers would warn about them
+    for local in &func.locals {
+        if !used.contains(&Var::Ident(local.offset)) {
+            continue;
+        }
+
+        let zero = if matches!(local.tp, Type::Array { .. }) {
+            "{0}"
+        } else {
+            "0"
+        };
+        let line = format!(
+            "{} = {};",
+            decl(&local.tp, &var(&Operand::Ident(local.clone())))?,
+            zero
+        );
+        writer.line(&line);
+    }
+
+    let mut temps = BTreeMap::new();
+    for inst in &func.code {
+        for operand in inst.get_uses().into_iter().chain(inst.get_def()) {
+            if let Operand::Temp(temp) = operand {
+                temps.insert(temp.id, operand);
+            }
+        }
+    }
+
+    for temp in temps.values() {
+        let line = format!("{};", decl(&temp.get_tp(), &var(temp))?);
+        writer.line(&line);
+    }
+
+    for pos in 0..func.code.len() {
+        writer.inst(&func.code, pos)?;
+    }
+
+    if func.ret.is_some() && !matches!(func.code.last(), Some(Inst::Ret { .. })) {
+        writer.missing_return();
+    }
+
+    writer.out.push_str("}\n");
+    Ok(writer.out)
+}
+
+/// C `main`: prints what `ez_main` returns, and exits with it when it's an integer.
+fn entry(main: &Function) -> String {
+    let body = match &main.ret {
+        Some(Type::Flt32) | Some(Type::Flt64) => {
+            "    printf(\"%g\\n\", (double)ez_main());\n    return 0;\n".to_owned()
+        }
+        Some(Type::Bool) => "    bool result = ez_main();\n    \
+             printf(\"%s\\n\", result ? \"true\" : \"false\");\n    return result;\n"
+            .to_owned(),
+        Some(Type::Char) => "    uint8_t result = ez_main();\n    \
+             printf(\"%c\\n\", result);\n    return result;\n"
+            .to_owned(),
+        Some(_) => "    int64_t result = ez_main();\n    \
+             printf(\"%\" PRId64 \"\\n\", result);\n    return (int)result;\n"
+            .to_owned(),
+        None => "    ez_main();\n    return 0;\n".to_owned(),
+    };
+
+    format!("int main(void) {{\n{}}}\n", body)
+}
+
+/**
+ * Translates `module` to portable C99. Parameters, locals and temporaries
+ * become typed C variables (`name_offset` and `tN`), arrays are fixed-size C
+ * arrays, and the code keeps its shape with labels and `goto`s. Integer
+ * arithmetic wraps around, and array accesses and divisions are checked, so
+ * programs behave like in the VM. Functions are named `ez_<name>`, and a
+ * `main` function without parameters gets a C `main` that prints its result
+ * and exits with it.
+ */
+pub fn emit_c(module: &Module) -> CodegenResult<String> {
+    let mut out = String::from(PRELUDE);
+
+    out.push('\n');
+    for func in &module.funcs {
+        out.push_str(&format!("{};\n", signature(func)?));
+    }
+
+    for func in &module.funcs {
+        out.push('\n');
+        out.push_str(&function(module, func)?);
+    }
+
+    if let Some(main) = module.get("main").filter(|main| main.params.is_empty()) {
+        out.push('\n');
+        out.push_str(&entry(main));
+    }
+
+    Ok(out)
+}
+
+/// Emits `module` next to `path` (as `.c`) and builds an executable at `path`
+/// with the system `cc`.
+pub fn link_c(module: &Module, path: &Path) -> CodegenResult<()> {
+    let source = path.with_extension("c");
+    fs::write(&source, emit_c(module)?).map_err(|error| CodegenError::new(&error.to_string()))?;
+
+    let output = Command::new("cc")
+        .args(["-std=c99", "-O1", "-o"])
+        .arg(path)
+        .arg(&source)
+        .output()
+        .map_err(|error| CodegenError::new(&format!("cannot run cc: {}", error)))?;
+
+    if !output.status.success() {
+        return Err(CodegenError::new(&String::from_utf8_lossy(&output.stderr)));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::RuntimeError;
+    use crate::ir::parse_module;
+    use crate::vm::machine::tests::for_each_program;
+    use crate::vm::Machine;
+
+    /// Output and exit code of `module` built as an executable.
+    fn run(module: &Module, name: &str) -> (String, i32) {
+        let dir = std::env::temp_dir().join(format!("ez-lang-c-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let path = dir.join(name);
+        link_c(module, &path).unwrap();
+        let output = Command::new(&path).output().unwrap();
+
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(path.with_extension("c")).unwrap();
+        (
+            String::from_utf8(output.stdout).unwrap(),
+            output.status.code().unwrap(),
+        )
+    }
+
+    #[test]
+    fn compiled_programs() {
+        for_each_program(|name, module, value| {
+            let (stdout, _) = run(module, name);
+            assert_eq!(stdout, format!("{}\n", value.unwrap()));
+        });
+    }
+
+    #[test]
+    fn casts() {
+        // Out of range and NaN are undefined for C casts, but saturate in the
+        // VM before the integer is truncated
+        let to_int = "fn to_int(x: f64) -> i32\n\tvar y: i32\n\tmov y x\n\tret y\n";
+        let args = ["1e19", "-1e19", "10000000000.0", "-3.7", "__t1"];
+
+        for (pos, arg) in args.iter().enumerate() {
+            let code = format!(
+                "{}fn main() -> i32\n\tdiv __t1 0.0 0.0\n\tparam {}\n\
+                 \tcall __t0 to_int 1\n\tret __t0\n",
+                to_int, arg
+            );
+            let module = parse_module(&code).unwrap();
+            let value = Machine::new(&module).run().unwrap().unwrap();
+            let (stdout, _) = run(&module, &format!("cast-{}", pos));
+            assert_eq!(stdout, format!("{}\n", value));
+        }
+    }
+
+    #[test]
+    fn machine_semantics() {
+        // Wrapping arithmetic, conversions, arrays and calls
+        let module = parse_module(
+            "fn half(x: f32, n: i64) -> f64\n\tdiv __t0 x n\n\tret __t0\n\
+             fn main() -> i32\n\tvar a: [3]char\n\tvar big: i32\n\
+             \tmov big 2147483647\n\tadd big big 2\n\
+             \tsto a 2 300\n\tidx __t1 2 a\n\
+             \tparam 5.5\n\tparam 2\n\tcall __t2 half 2\n\
+             \tinv __t3 __t2\n\tmul __t4 __t3 10\n\
+             \tadd __t5 big __t1\n\tadd __t6 __t5 __t4\n\tret __t6\n",
+        )
+        .unwrap();
+
+        let value = Machine::new(&module).run().unwrap();
+        assert_eq!(value, Some(Value::Int32(-2147483630)));
+        assert_eq!(run(&module, "semantics"), ("-2147483630\n".to_owned(), 18));
+
+        let source = emit_c(&module).unwrap();
+        assert!(source.contains("uint8_t a_0[3] = {0};"));
+        assert!(source.contains("static double ez_half(float x_0, int64_t n_4);"));
+    }
+
+    #[test]
+    fn missing_return() {
+        // The bare `ret` every function ends with is only reached for x >= 0
+        let module = parse_module(
+            "fn f(x: i32) -> i32\n\tlt __t0 x 0\n\tjmpf L1 __t0\n\tret x\nL1\tret\n\
+             fn main() -> i32\n\tparam 1\n\tcall __t1 f 1\n\tret __t1\n",
+        )
+        .unwrap();
+
+        let dir = std::env::temp_dir().join(format!("ez-lang-c-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("missing-return.c");
+        fs::write(&source, emit_c(&module).unwrap()).unwrap();
+
+        let output = Command::new("cc")
+            .args(["-std=c99", "-Werror", "-c", "-o"])
+            .arg(source.with_extension("o"))
+            .arg(&source)
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        fs::remove_file(&source).unwrap();
+        fs::remove_file(source.with_extension("o")).unwrap();
+
+        assert!(matches!(
+            Machine::new(&module).run(),
+            Err(RuntimeError::MissingReturn(_))
+        ));
+        assert_eq!(run(&module, "missing-return"), (String::new(), 1));
+    }
+}
@@ -0,0 +1,371 @@
+use std::collections::HashMap;
+
+/// Kinds of the sections an object can hold, in the order they are written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum SectionKind {
+    Text,
+    Data,
+    Rodata,
+    /// Empty `.note.GNU-stack`, asks the linker for a non-executable stack.
+    NoteStack,
+}
+
+impl SectionKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Text => ".text",
+            Self::Data => ".data",
+            Self::Rodata => ".rodata",
+            Self::NoteStack => ".note.GNU-stack",
+        }
+    }
+
+    fn flags(&self) -> u64 {
+        match self {
+            Self::Text => SHF_ALLOC | SHF_EXECINSTR,
+            Self::Data => SHF_ALLOC | SHF_WRITE,
+            Self::Rodata => SHF_ALLOC,
+            Self::NoteStack => 0,
+        }
+    }
+
+    fn align(&self) -> u64 {
+        match self {
+            Self::Text => 16,
+            Self::Data | Self::Rodata => 8,
+            Self::NoteStack => 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelocKind {
+    /// 64 bits absolute address, `R_X86_64_64`.
+    Abs64,
+    /// 32 bits PC-relative address, `R_X86_64_PC32`.
+    Pc32,
+    /// 32 bits PC-relative call through the PLT, `R_X86_64_PLT32`.
+    Plt32,
+}
+
+impl RelocKind {
+    fn code(&self) -> u64 {
+        match self {
+            Self::Abs64 => 1,
+            Self::Pc32 => 2,
+            Self::Plt32 => 4,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Target {
+    /// The start of a section, for references to local labels.
+    Section(SectionKind),
+    Symbol(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reloc {
+    pub offset: u64,
+    pub target: Target,
+    pub kind: RelocKind,
+    pub addend: i64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Section {
+    pub data: Vec<u8>,
+    pub relocs: Vec<Reloc>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Symbol {
+    pub name: String,
+    /// Section and offset it's defined at, undefined symbols are resolved by
+    /// the linker.
+    pub def: Option<(SectionKind, u64)>,
+    pub global: bool,
+    pub func: bool,
+    pub size: u64,
+}
+
+/**
+ * Relocatable ELF64 object for x86-64, as `as` would write it: sections,
+ * their relocations against symbols or section starts, and a symbol table
+ * with local symbols first.
+ */
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Object {
+    pub sections: HashMap<SectionKind, Section>,
+    pub symbols: Vec<Symbol>,
+}
+
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const SHT_RELA: u32 = 4;
+
+const SHF_WRITE: u64 = 0x1;
+const SHF_ALLOC: u64 = 0x2;
+const SHF_EXECINSTR: u64 = 0x4;
+const SHF_INFO_LINK: u64 = 0x40;
+
+const STB_LOCAL: u8 = 0;
+const STB_GLOBAL: u8 = 1;
+const STT_NOTYPE: u8 = 0;
+const STT_FUNC: u8 = 2;
+const STT_SECTION: u8 = 3;
+
+const HEADER_SIZE: u64 = 64;
+const SECTION_HEADER_SIZE: u64 = 64;
+const SYMBOL_SIZE: u64 = 24;
+const RELA_SIZE: u64 = 24;
+
+struct StrTab {
+    bytes: Vec<u8>,
+}
+
+impl StrTab {
+    fn new() -> Self {
+        Self { bytes: vec![0] }
+    }
+
+    fn add(&mut self, name: &str) -> u32 {
+        let pos = self.bytes.len() as u32;
+        self.bytes.extend_from_slice(name.as_bytes());
+        self.bytes.push(0);
+        pos
+    }
+}
+
+struct SymTab {
+    bytes: Vec<u8>,
+    count: u32,
+}
+
+impl SymTab {
+    fn new() -> Self {
+        Self {
+            bytes: vec![0; SYMBOL_SIZE as usize],
+            count: 1,
+        }
+    }
+
+    fn push(&mut self, name: u32, info: u8, shndx: u32, value: u64, size: u64) -> u32 {
+        self.bytes.extend_from_slice(&name.to_le_bytes());
+        self.bytes.push(info);
+        self.bytes.push(0);
+        self.bytes.extend_from_slice(&(shndx as u16).to_le_bytes());
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+        self.bytes.extend_from_slice(&size.to_le_bytes());
+        self.count += 1;
+        self.count - 1
+    }
+}
+
+struct SectionHeader {
+    name: u32,
+    kind: u32,
+    flags: u64,
+    offset: u64,
+    size: u64,
+    link: u32,
+    info: u32,
+    align: u64,
+    entsize: u64,
+}
+
+fn pad(out: &mut Vec<u8>, align: u64) {
+    let len = (out.len() as u64).div_ceil(align) * align;
+    out.resize(len as usize, 0);
+}
+
+impl Object {
+    pub fn section(&mut self, kind: SectionKind) -> &mut Section {
+        self.sections.entry(kind).or_default()
+    }
+
+    pub fn symbol(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.iter().find(|symbol| symbol.name == name)
+    }
+
+    /// Bytes of the object file.
+    pub fn write(&self) -> Vec<u8> {
+        let mut kinds: Vec<_> = self.sections.keys().copied().collect();
+        kinds.sort_unstable();
+
+        // Section indices: null, then ours, then the relocations, symbols and strings
+        let index: HashMap<_, _> = kinds
+            .iter()
+            .enumerate()
+            .map(|(pos, kind)| (*kind, pos as u32 + 1))
+            .collect();
+
+        // Symbols: null, one per section, locals, then globals
+        let mut strtab = StrTab::new();
+        let mut symtab = SymTab::new();
+        let section_symbol: HashMap<_, _> = kinds
+            .iter()
+            .map(|kind| (*kind, symtab.push(0, STT_SECTION, index[kind], 0, 0)))
+            .collect();
+
+        let locals = self.symbols.iter().filter(|symbol| !symbol.global);
+        let globals = self.symbols.iter().filter(|symbol| symbol.global);
+        let mut symbol_index = HashMap::new();
+        let mut first_global = symtab.count;
+        for symbol in locals.chain(globals) {
+            if !symbol.global {
+                first_global += 1;
+            }
+
+            let bind = if symbol.global { STB_GLOBAL } else { STB_LOCAL };
+            let tp = if symbol.func { STT_FUNC } else { STT_NOTYPE };
+            let (shndx, value) = match symbol.def {
+                Some((kind, value)) => (index[&kind], value),
+                None => (0, 0),
+            };
+            let name = strtab.add(&symbol.name);
+            let sym = symtab.push(name, bind << 4 | tp, shndx, value, symbol.size);
+            symbol_index.insert(symbol.name.as_str(), sym);
+        }
+
+        let mut shstrtab = StrTab::new();
+        let mut headers = Vec::new();
+        let mut out = vec![0; HEADER_SIZE as usize];
+
+        for kind in &kinds {
+            let data = &self.sections[kind].data;
+            pad(&mut out, kind.align());
+            headers.push(SectionHeader {
+                name: shstrtab.add(kind.name()),
+                kind: SHT_PROGBITS,
+                flags: kind.flags(),
+                offset: out.len() as u64,
+                size: data.len() as u64,
+                link: 0,
+                info: 0,
+                align: kind.align(),
+                entsize: 0,
+            });
+            out.extend_from_slice(data);
+        }
+
+        let symtab_index = (kinds.len()
+            + kinds
+                .iter()
+                .filter(|kind| !self.sections[kind].relocs.is_empty())
+                .count()
+            + 1) as u32;
+
+        for kind in &kinds {
+            let relocs = &self.sections[kind].relocs;
+            if relocs.is_empty() {
+                continue;
+            }
+
+            pad(&mut out, 8);
+            let offset = out.len() as u64;
+            for reloc in relocs {
+                let sym = match &reloc.target {
+                    Target::Section(kind) => section_symbol[kind],
+                    Target::Symbol(name) => symbol_index[name.as_str()],
+                };
+                out.extend_from_slice(&reloc.offset.to_le_bytes());
+                out.extend_from_slice(&((sym as u64) << 32 | reloc.kind.code()).to_le_bytes());
+                out.extend_from_slice(&reloc.addend.to_le_bytes());
+            }
+
+            headers.push(SectionHeader {
+                name: shstrtab.add(&format!(".rela{}", kind.name())),
+                kind: SHT_RELA,
+                flags: SHF_INFO_LINK,
+                offset,
+                size: relocs.len() as u64 * RELA_SIZE,
+                link: symtab_index,
+                info: index[kind],
+                align: 8,
+                entsize: RELA_SIZE,
+            });
+        }
+
+        pad(&mut out, 8);
+        headers.push(SectionHeader {
+            name: shstrtab.add(".symtab"),
+            kind: SHT_SYMTAB,
+            flags: 0,
+            offset: out.len() as u64,
+            size: symtab.bytes.len() as u64,
+            link: symtab_index + 1,
+            info: first_global,
+            align: 8,
+            entsize: SYMBOL_SIZE,
+        });
+        out.extend_from_slice(&symtab.bytes);
+
+        headers.push(SectionHeader {
+            name: shstrtab.add(".strtab"),
+            kind: SHT_STRTAB,
+            flags: 0,
+            offset: out.len() as u64,
+            size: strtab.bytes.len() as u64,
+            link: 0,
+            info: 0,
+            align: 1,
+            entsize: 0,
+        });
+        out.extend_from_slice(&strtab.bytes);
+
+        let name = shstrtab.add(".shstrtab");
+        headers.push(SectionHeader {
+            name,
+            kind: SHT_STRTAB,
+            flags: 0,
+            offset: out.len() as u64,
+            size: shstrtab.bytes.len() as u64,
+            link: 0,
+            info: 0,
+            align: 1,
+            entsize: 0,
+        });
+        out.extend_from_slice(&shstrtab.bytes);
+
+        pad(&mut out, 8);
+        let shoff = out.len() as u64;
+        out.extend_from_slice(&[0; SECTION_HEADER_SIZE as usize]);
+        for header in &headers {
+            out.extend_from_slice(&header.name.to_le_bytes());
+            out.extend_from_slice(&header.kind.to_le_bytes());
+            out.extend_from_slice(&header.flags.to_le_bytes());
+            out.extend_from_slice(&0u64.to_le_bytes());
+            out.extend_from_slice(&header.offset.to_le_bytes());
+            out.extend_from_slice(&header.size.to_le_bytes());
+            out.extend_from_slice(&header.link.to_le_bytes());
+            out.extend_from_slice(&header.info.to_le_bytes());
+            out.extend_from_slice(&header.align.to_le_bytes());
+            out.extend_from_slice(&header.entsize.to_le_bytes());
+        }
+
+        let shnum = headers.len() as u16 + 1;
+        let mut header = Vec::new();
+        header.extend_from_slice(b"\x7fELF");
+        // 64 bits, little-endian, version 1, System V ABI
+        header.extend_from_slice(&[2, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        header.extend_from_slice(&1u16.to_le_bytes()); // ET_REL
+        header.extend_from_slice(&62u16.to_le_bytes()); // EM_X86_64
+        header.extend_from_slice(&1u32.to_le_bytes());
+        header.extend_from_slice(&0u64.to_le_bytes()); // entry
+        header.extend_from_slice(&0u64.to_le_bytes()); // program headers
+        header.extend_from_slice(&shoff.to_le_bytes());
+        header.extend_from_slice(&0u32.to_le_bytes()); // flags
+        header.extend_from_slice(&(HEADER_SIZE as u16).to_le_bytes());
+        header.extend_from_slice(&0u16.to_le_bytes());
+        header.extend_from_slice(&0u16.to_le_bytes());
+        header.extend_from_slice(&(SECTION_HEADER_SIZE as u16).to_le_bytes());
+        header.extend_from_slice(&shnum.to_le_bytes());
+        header.extend_from_slice(&(shnum - 1).to_le_bytes()); // .shstrtab is last
+        out[..HEADER_SIZE as usize].copy_from_slice(&header);
+
+        out
+    }
+}
@@ -0,0 +1,286 @@
+use std::collections::HashMap;
+
+use crate::codegen::{allocate, frame_size, Allocation, Location, RegClass, RegisterSet};
+use crate::ir::{Function, Inst, Operand};
+use crate::sym::DataLayout;
+
+/// Bytes every outgoing argument takes, whatever its type.
+pub const ARG_SLOT: usize = 8;
+
+/// What the native backends need to know about the calling convention of
+/// their target.
+pub trait CallingConvention {
+    /// Registers the arguments of `class` are passed in, in order.
+    fn arg_regs(&self, class: RegClass) -> &[&'static str];
+
+    /// Registers a function has to restore before returning.
+    fn callee_saved(&self, class: RegClass) -> &[&'static str];
+
+    /// Registers a call may clobber.
+    fn caller_saved(&self, class: RegClass) -> &[&'static str];
+
+    /// Caller-saved registers kept out of allocation to reload spilled
+    /// values, at least two per class.
+    fn scratch(&self, class: RegClass) -> &[&'static str];
+
+    /// Alignment of the stack pointer at calls.
+    fn stack_align(&self) -> usize;
+
+    /// Sizes and alignments of the values of the target.
+    fn data_layout(&self) -> DataLayout;
+
+    /// Bytes at the top of the frame holding the return address and the
+    /// caller's frame pointer, when they're saved by the callee.
+    fn linkage(&self) -> usize;
+
+    /// Register each argument is passed in, given their classes in order,
+    /// `None` for the ones left without.
+    fn arg_registers(&self, classes: &[RegClass]) -> Vec<Option<&'static str>> {
+        let mut used = HashMap::new();
+        classes
+            .iter()
+            .map(|class| {
+                let next = used.entry(*class).or_insert(0);
+                *next += 1;
+                self.arg_regs(*class).get(*next - 1).copied()
+            })
+            .collect()
+    }
+
+    /**
+     * Registers for `allocate`: only callee-saved ones, so values in
+     * registers survive calls without saving anything around them.
+     */
+    fn registers(&self) -> RegisterSet {
+        RegisterSet {
+            int: self.callee_saved(RegClass::Int).to_vec(),
+            float: self.callee_saved(RegClass::Float).to_vec(),
+            int_scratch: self.scratch(RegClass::Int).to_vec(),
+            float_scratch: self.scratch(RegClass::Float).to_vec(),
+        }
+    }
+}
+
+pub fn align(offset: usize, align: usize) -> usize {
+    offset.div_ceil(align) * align
+}
+
+/**
+ * Gives the parameters and locals of `func` offsets from `start`, in the
+ * order they're declared and aligned as on `target`, and renames their
+ * operands in the code to match. Returns the new offset of each old one.
+ */
+pub fn assign_offsets(
+    func: &mut Function,
+    start: usize,
+    target: &DataLayout,
+) -> HashMap<usize, usize> {
+    let mut end = start;
+    let mut offsets = HashMap::new();
+
+    for ident in func.params.iter_mut().chain(&mut func.locals) {
+        let layout = ident.tp.layout(target);
+        let offset = align(end, layout.align);
+        end = offset + layout.size;
+        offsets.insert(ident.offset, offset);
+        ident.offset = offset;
+    }
+
+    let rename = |operand: &mut Operand| {
+        if let Operand::Ident(ident) = operand {
+            if let Some(offset) = offsets.get(&ident.offset) {
+                ident.offset = *offset;
+            }
+        }
+    };
+
+    for inst in &mut func.code {
+        inst.get_uses_mut().into_iter().for_each(rename);
+        inst.get_def_mut().into_iter().for_each(rename);
+    }
+
+    offsets
+}
+
+/// Most arguments pushed by `param` and not passed yet at any point of `code`.
+pub fn outgoing_args(code: &[Inst]) -> usize {
+    let mut pending: usize = 0;
+    let mut most = 0;
+
+    for inst in code {
+        match inst {
+            Inst::Param { .. } => {
+                pending += 1;
+                most = most.max(pending);
+            }
+            Inst::Call { nargs, .. } => pending = pending.saturating_sub(*nargs),
+            _ => (),
+        }
+    }
+
+    most
+}
+
+/**
+ * Frame of a function, from the stack pointer up: one slot per outgoing
+ * argument, the parameters, locals and spill slots at their `Ident.offset`,
+ * the callee-saved registers the function uses, and the linkage area. Its
+ * size keeps the stack aligned for calls.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    /// Outgoing argument slots at the bottom.
+    pub args: usize,
+    /// End of the parameters, locals and spill slots.
+    pub locals: usize,
+    pub saved: Vec<&'static str>,
+    pub linkage: usize,
+    pub size: usize,
+}
+
+impl Frame {
+    /**
+     * Lays out the frame of `func`: allocates its registers with the ones of
+     * `conv`, which adds the spill slots to its locals, then moves its
+     * parameters, locals and spill slots above the outgoing arguments.
+     */
+    pub fn new(func: &mut Function, conv: &dyn CallingConvention) -> (Self, Allocation) {
+        let mut alloc = allocate(func, &conv.registers());
+
+        let args = outgoing_args(&func.code) * ARG_SLOT;
+        let offsets = assign_offsets(func, args, &conv.data_layout());
+        for loc in alloc.locations.values_mut() {
+            if let Location::Stack(offset) = loc {
+                *offset = offsets[offset];
+            }
+        }
+        alloc.frame_size = frame_size(func).max(args);
+
+        let saved: Vec<&'static str> = [RegClass::Int, RegClass::Float]
+            .iter()
+            .flat_map(|class| conv.callee_saved(*class))
+            .copied()
+            .filter(|reg| {
+                alloc
+                    .locations
+                    .values()
+                    .any(|loc| *loc == Location::Reg(reg))
+            })
+            .collect();
+
+        let locals = align(alloc.frame_size, ARG_SLOT);
+        let size = align(
+            locals + ARG_SLOT * saved.len() + conv.linkage(),
+            conv.stack_align(),
+        );
+
+        let frame = Self {
+            args,
+            locals,
+            saved,
+            linkage: conv.linkage(),
+            size,
+        };
+        (frame, alloc)
+    }
+
+    /// Offset from the stack pointer of the `pos`th outgoing argument.
+    pub fn arg(&self, pos: usize) -> usize {
+        ARG_SLOT * pos
+    }
+
+    /// Offset from the stack pointer of the `pos`th saved register.
+    pub fn saved_slot(&self, pos: usize) -> usize {
+        self.size - self.linkage - ARG_SLOT * (pos + 1)
+    }
+
+    /// Bytes below the saved registers, zeroed on entry.
+    pub fn body(&self) -> usize {
+        self.size - self.linkage - ARG_SLOT * self.saved.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codegen::{Lp64d, SystemV};
+    use crate::error::IrError;
+    use crate::ir::parse_module;
+
+    #[test]
+    fn aligned_offsets() -> Result<(), IrError> {
+        let code = "fn f(c: char, x: f64, b: bool, v: [3]i32) -> f64\n\
+                    \tvar s: [5]char\n\tvar y: f32\n\tidx y 1 v\n\tadd x x y\n\tret x\n";
+        let mut func = parse_module(code)?.funcs.remove(0);
+        assert_eq!(func.params[1].offset, 1);
+
+        let offsets = assign_offsets(&mut func, 8, &DataLayout::LP64);
+        let params: Vec<_> = func.params.iter().map(|param| param.offset).collect();
+        let locals: Vec<_> = func.locals.iter().map(|local| local.offset).collect();
+        assert_eq!(params, vec![8, 16, 24, 28]);
+        assert_eq!(locals, vec![40, 48]);
+        assert_eq!(offsets[&1], 16);
+
+        // Operands follow their declarations
+        match &func.code[1] {
+            Inst::Binary { dst, rhs, .. } => {
+                assert_eq!(dst.get_var(), Some(crate::ir::Var::Ident(16)));
+                assert_eq!(rhs.get_var(), Some(crate::ir::Var::Ident(48)));
+            }
+            inst => panic!("unexpected {}", inst),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn frames() -> Result<(), IrError> {
+        let code = "fn g(a: i64, x: f64, b: i32) -> i64\n\tret a\n\
+                    fn f(n: i32) -> i64\n\tparam n\n\tparam 1.5\n\
+                    \tparam n\n\tparam 2.5\n\tparam 3\n\tcall __t0 g 3\n\
+                    \tcall __t1 g 3\n\tadd __t2 __t0 __t1\n\tret __t2\n";
+        let module = parse_module(code)?;
+        assert_eq!(outgoing_args(&module.get("f").unwrap().code), 5);
+
+        for conv in &[&SystemV as &dyn CallingConvention, &Lp64d] {
+            let mut func = module.get("f").unwrap().clone();
+            let (frame, alloc) = Frame::new(&mut func, *conv);
+
+            assert_eq!(frame.args, 5 * ARG_SLOT);
+            assert_eq!(frame.size % conv.stack_align(), 0);
+            assert!(func.params[0].offset >= frame.args);
+            assert!(frame.locals + ARG_SLOT * frame.saved.len() + frame.linkage <= frame.size);
+
+            for loc in alloc.locations.values() {
+                match loc {
+                    Location::Stack(offset) => {
+                        assert!(*offset >= frame.args && *offset < frame.locals)
+                    }
+                    Location::Reg(reg) => assert!(!conv.caller_saved(RegClass::Int).contains(reg)),
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn argument_registers() {
+        use RegClass::{Float, Int};
+
+        let classes = [Int, Float, Int, Int, Int, Int, Int, Float, Int];
+        let regs = SystemV.arg_registers(&classes);
+        assert_eq!(regs[1], Some("%xmm0"));
+        assert_eq!(regs[6], Some("%r9"));
+        assert_eq!(regs[7], Some("%xmm1"));
+        assert_eq!(regs[8], None);
+
+        let regs = Lp64d.arg_registers(&classes);
+        assert_eq!(regs[8], Some("a6"));
+        assert!(Lp64d
+            .registers()
+            .float
+            .iter()
+            .all(|reg| Lp64d.callee_saved(Float).contains(reg)));
+    }
+}
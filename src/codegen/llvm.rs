@@ -0,0 +1,810 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+use crate::codegen::CodegenResult;
+use crate::error::CodegenError;
+use crate::interp::Value;
+use crate::ir::{Function, Inst, Module, Opcode, Operand};
+use crate::sym::Type;
+
+fn llvm_type(tp: &Type) -> CodegenResult<String> {
+    let tp = match tp {
+        Type::Int32 => "i32",
+        Type::Int64 => "i64",
+        Type::Flt32 => "float",
+        Type::Flt64 => "double",
+        Type::Char => "i8",
+        Type::Bool => "i1",
+        // Nested arrays are flat, indexed by the position of their scalars
+        Type::Array { .. } => {
+            return Ok(format!(
+                "[{} x {}]",
+                tp.get_len(),
+                llvm_type(tp.get_scalar())?
+            ))
+        }
+        Type::String(_) => return Err(CodegenError::new("no LLVM type for strings")),
+    };
+
+    Ok(tp.to_owned())
+}
+
+fn is_float(tp: &Type) -> bool {
+    matches!(tp, Type::Flt32 | Type::Flt64)
+}
+
+/// Bits of the integer types, `char` and `bool` are unsigned.
+fn bits(tp: &Type) -> usize {
+    match tp {
+        Type::Bool => 1,
+        Type::Char => 8,
+        Type::Int32 => 32,
+        _ => 64,
+    }
+}
+
+/// Stack slot of a parameter, local or temporary.
+fn slot(operand: &Operand) -> String {
+    match operand {
+        Operand::Ident(ident) => format!("%{}.{}", ident.id, ident.offset),
+        Operand::Temp(temp) => format!("%t{}", temp.id),
+        Operand::Cons(_) => unreachable!(),
+    }
+}
+
+/// LLVM constant for `cons`, floats in hexadecimal so they are exact.
+fn constant(operand: &Operand) -> CodegenResult<Option<String>> {
+    let cons = match operand {
+        Operand::Cons(cons) => cons,
+        _ => return Ok(None),
+    };
+
+    let value = match Value::from_cons(cons) {
+        Value::Int32(num) => num.to_string(),
+        Value::Int64(num) => num.to_string(),
+        Value::Flt32(num) => format!("0x{:016X}", (num as f64).to_bits()),
+        Value::Flt64(num) => format!("0x{:016X}", num.to_bits()),
+        Value::Char(chr) => (chr as u32).to_string(),
+        Value::Bool(value) => value.to_string(),
+        value => {
+            return Err(CodegenError::new(&format!(
+                "no LLVM constant for {}",
+                value
+            )))
+        }
+    };
+
+    Ok(Some(value))
+}
+
+/**
+ * Emits one function. Every parameter, local and temporary gets an `alloca`
+ * in the entry block, so the IR doesn't need to be in SSA form: `mem2reg`
+ * (any `-O` level) turns them back into registers.
+ */
+struct Writer<'a> {
+    module: &'a Module,
+    func: &'a Function,
+    out: String,
+    next: usize,
+    /// Whether the current basic block still needs a terminator.
+    open: bool,
+    /// Whether some check branches to the `trap` block.
+    traps: bool,
+}
+
+impl<'a> Writer<'a> {
+    fn line(&mut self, line: &str) {
+        self.out.push_str("  ");
+        self.out.push_str(line);
+        self.out.push('\n');
+    }
+
+    fn fresh(&mut self) -> usize {
+        self.next += 1;
+        self.next
+    }
+
+    /// Emits `inst` into a new value and returns its name.
+    fn value(&mut self, inst: &str) -> String {
+        let name = format!("%v{}", self.fresh());
+        self.line(&format!("{} = {}", name, inst));
+        name
+    }
+
+    fn start(&mut self, label: &str) {
+        if self.open {
+            self.line(&format!("br label %{}", label));
+        }
+
+        self.out.push_str(&format!("{}:\n", label));
+        self.open = true;
+    }
+
+    fn terminate(&mut self, inst: &str) {
+        self.line(inst);
+        self.open = false;
+    }
+
+    /// Continues in a new block if `cond` holds and traps otherwise.
+    fn check(&mut self, cond: &str) {
+        let label = format!("ok{}", self.fresh());
+        self.terminate(&format!("br i1 {}, label %{}, label %trap", cond, label));
+        self.start(&label);
+        self.traps = true;
+    }
+
+    /// Converts `value` from `from` to `to`, like `Value::cast`.
+    fn cast(&mut self, value: String, from: &Type, to: &Type) -> CodegenResult<String> {
+        if from == to {
+            return Ok(value);
+        }
+
+        let (src, dst) = (llvm_type(from)?, llvm_type(to)?);
+        let signed = matches!(from, Type::Int32 | Type::Int64);
+
+        let inst = match (is_float(from), is_float(to)) {
+            (false, false) if bits(to) > bits(from) => {
+                let ext = if signed { "sext" } else { "zext" };
+                format!("{} {} {} to {}", ext, src, value, dst)
+            }
+            (false, false) if *to == Type::Bool => {
+                return Err(CodegenError::new(&format!("cannot cast {} to bool", from)))
+            }
+            (false, false) => format!("trunc {} {} to {}", src, value, dst),
+
+            // Through `f64` and `i64`, as in the VM
+            (false, true) => {
+                let conv = if signed { "sitofp" } else { "uitofp" };
+                let value = self.value(&format!("{} {} {} to double", conv, src, value));
+                return self.cast(value, &Type::Flt64, to);
+            }
+            (true, false) => {
+                let value = self.cast(value, from, &Type::Flt64)?;
+                let value = self.value(&format!(
+                    "call i64 @llvm.fptosi.sat.i64.f64(double {})",
+                    value
+                ));
+                return self.cast(value, &Type::Int64, to);
+            }
+
+            (true, true) if *to == Type::Flt64 => format!("fpext {} {} to {}", src, value, dst),
+            (true, true) => format!("fptrunc {} {} to {}", src, value, dst),
+        };
+
+        Ok(self.value(&inst))
+    }
+
+    /// `operand` loaded and converted to `to`.
+    fn load(&mut self, operand: &Operand, to: &Type) -> CodegenResult<String> {
+        let tp = operand.get_tp();
+        let value = match constant(operand)? {
+            Some(value) => value,
+            None => {
+                let inst = format!("load {0}, {0}* {1}", llvm_type(&tp)?, slot(operand));
+                self.value(&inst)
+            }
+        };
+
+        self.cast(value, &tp, to)
+    }
+
+    fn store(&mut self, dst: &Operand, value: String, tp: &Type) -> CodegenResult<()> {
+        let dst_tp = dst.get_tp();
+        let value = self.cast(value, tp, &dst_tp)?;
+        let line = format!(
+            "store {0} {1}, {0}* {2}",
+            llvm_type(&dst_tp)?,
+            value,
+            slot(dst)
+        );
+        self.line(&line);
+        Ok(())
+    }
+
+    fn binary(
+        &mut self,
+        op: Opcode,
+        dst: &Operand,
+        lhs: &Operand,
+        rhs: &Operand,
+    ) -> CodegenResult<()> {
+        let tp = match lhs.get_tp().upcast(&rhs.get_tp()) {
+            Some(tp) => tp,
+            // Booleans can only be compared
+            None => {
+                let (lhs, rhs) = (self.load(lhs, &Type::Bool)?, self.load(rhs, &Type::Bool)?);
+                let cond = if op == Opcode::Eq { "eq" } else { "ne" };
+                let value = self.value(&format!("icmp {} i1 {}, {}", cond, lhs, rhs));
+                return self.store(dst, value, &Type::Bool);
+            }
+        };
+
+        // Floats are computed in `f64` and integers divided in `i64`, as in
+        // the VM; the rest wraps around the same in the upcasted type
+        let work = match tp {
+            Type::Flt32 | Type::Flt64 => Type::Flt64,
+            _ if op == Opcode::Div => Type::Int64,
+            _ => tp.clone(),
+        };
+
+        let (lhs, rhs) = (self.load(lhs, &work)?, self.load(rhs, &work)?);
+        let ltp = llvm_type(&work)?;
+
+        if op.is_rel() {
+            let cond = match (op, is_float(&work), work == Type::Char) {
+                (Opcode::Eq, true, _) => "oeq",
+                (Opcode::Ne, true, _) => "une",
+                (Opcode::Lt, true, _) => "olt",
+                (Opcode::Le, true, _) => "ole",
+                (Opcode::Gt, true, _) => "ogt",
+                (Opcode::Ge, true, _) => "oge",
+                (Opcode::Eq, ..) => "eq",
+                (Opcode::Ne, ..) => "ne",
+                (Opcode::Lt, _, true) => "ult",
+                (Opcode::Le, _, true) => "ule",
+                (Opcode::Gt, _, true) => "ugt",
+                (Opcode::Ge, _, true) => "uge",
+                (Opcode::Lt, ..) => "slt",
+                (Opcode::Le, ..) => "sle",
+                (Opcode::Gt, ..) => "sgt",
+                _ => "sge",
+            };
+
+            let cmp = if is_float(&work) { "fcmp" } else { "icmp" };
+            let value = self.value(&format!("{} {} {} {}, {}", cmp, cond, ltp, lhs, rhs));
+            return self.store(dst, value, &Type::Bool);
+        }
+
+        let value = match op {
+            Opcode::Div if !is_float(&work) => {
+                let zero = self.value(&format!("icmp ne i64 {}, 0", rhs));
+                self.check(&zero);
+
+                // `sdiv` of the minimum by -1 overflows, the VM wraps around
+                let minus = self.value(&format!("icmp eq i64 {}, -1", rhs));
+                let safe = self.value(&format!("select i1 {}, i64 1, i64 {}", minus, rhs));
+                let quot = self.value(&format!("sdiv i64 {}, {}", lhs, safe));
+                let neg = self.value(&format!("sub i64 0, {}", lhs));
+                self.value(&format!("select i1 {}, i64 {}, i64 {}", minus, neg, quot))
+            }
+
+            op => {
+                let name = match op {
+                    Opcode::Add => "add",
+                    Opcode::Sub => "sub",
+                    Opcode::Mul => "mul",
+                    _ => "div",
+                };
+                let prefix = if is_float(&work) { "f" } else { "" };
+                self.value(&format!("{}{} {} {}, {}", prefix, name, ltp, lhs, rhs))
+            }
+        };
+
+        let value = self.cast(value, &work, &tp)?;
+        self.store(dst, value, &tp)
+    }
+
+    /// `index` as an `i64`, trapping unless `0 <= index < len`.
+    fn check_index(&mut self, index: &Operand, len: usize) -> CodegenResult<String> {
+        let index = self.load(index, &Type::Int64)?;
+        let inside = self.value(&format!("icmp ult i64 {}, {}", index, len));
+        self.check(&inside);
+        Ok(index)
+    }
+
+    /// Pointer to `array[index]`, trapping when it's out of bounds.
+    fn element(&mut self, array: &Operand, index: &Operand) -> CodegenResult<(String, Type)> {
+        let (of, size) = match array.get_tp() {
+            tp @ Type::Array { .. } => (tp.get_scalar().clone(), tp.get_len()),
+            _ => return Err(CodegenError::new(&format!("{} is not an array", array))),
+        };
+
+        let index = self.check_index(index, size)?;
+        let inst = format!(
+            "getelementptr inbounds {0}, {0}* {1}, i64 0, i64 {2}",
+            llvm_type(&array.get_tp())?,
+            slot(array),
+            index
+        );
+        Ok((self.value(&inst), of))
+    }
+
+    fn call(&mut self, insts: &[Inst], pos: usize) -> CodegenResult<()> {
+        let (dst, func, nargs) = match &insts[pos] {
+            Inst::Call { dst, func, nargs } => (dst, func, *nargs),
+            _ => unreachable!(),
+        };
+
+        let callee = self
+            .module
+            .get(func)
+            .filter(|callee| callee.params.len() == nargs)
+            .ok_or_else(|| CodegenError::new(&format!("bad call to {}", func)))?;
+
+        // Arguments are only read when the call happens
+        let params = pos
+            .checked_sub(nargs)
+            .map(|start| &insts[start..pos])
+            .filter(|params| params.iter().all(|inst| matches!(inst, Inst::Param { .. })))
+            .ok_or_else(|| {
+                CodegenError::new(&format!(
+                    "arguments of {} aren't right before the call",
+                    func
+                ))
+            })?;
+
+        let mut args = Vec::new();
+        for (inst, param) in params.iter().zip(&callee.params) {
+            if let Inst::Param { src } = inst {
+                let value = self.load(src, &param.tp)?;
+                args.push(format!("{} {}", llvm_type(&param.tp)?, value));
+            }
+        }
+
+        let call = format!("@ez_{}({})", func, args.join(", "));
+        match (dst, &callee.ret) {
+            (Some(dst), Some(ret)) => {
+                let value = self.value(&format!("call {} {}", llvm_type(ret)?, call));
+                self.store(dst, value, ret)
+            }
+            (None, Some(ret)) => {
+                let line = format!("call {} {}", llvm_type(ret)?, call);
+                self.line(&line);
+                Ok(())
+            }
+            (None, None) => {
+                self.line(&format!("call void {}", call));
+                Ok(())
+            }
+            (Some(_), None) => Err(CodegenError::new(&format!(
+                "{} doesn't return a value",
+                func
+            ))),
+        }
+    }
+
+    fn inst(&mut self, insts: &[Inst], pos: usize) -> CodegenResult<()> {
+        let inst = &insts[pos];
+        match inst {
+            Inst::Label(label) => {
+                self.start(&format!("L{}", label));
+                return Ok(());
+            }
+
+            // Code after a jump, that nothing jumps to
+            _ if !self.open => {
+                let label = format!("dead{}", self.fresh());
+                self.start(&label);
+            }
+
+            _ => {}
+        }
+
+        match inst {
+            Inst::Binary { op, dst, lhs, rhs } => self.binary(*op, dst, lhs, rhs)?,
+
+            Inst::Inv { dst, src } => {
+                let tp = Type::Int64
+                    .upcast(&src.get_tp())
+                    .ok_or_else(|| CodegenError::new(&format!("cannot negate {}", src)))?;
+
+                let value = self.load(src, &tp)?;
+                let value = if is_float(&tp) {
+                    self.value(&format!("fneg {} {}", llvm_type(&tp)?, value))
+                } else {
+                    self.value(&format!("sub i64 0, {}", value))
+                };
+                self.store(dst, value, &tp)?;
+            }
+
+            Inst::Not { dst, src } => {
+                let value = self.load(src, &Type::Bool)?;
+                let value = self.value(&format!("xor i1 {}, true", value));
+                self.store(dst, value, &Type::Bool)?;
+            }
+
+            Inst::Mov { dst, src } => {
+                let value = self.load(src, &src.get_tp())?;
+                self.store(dst, value, &src.get_tp())?;
+            }
+
+            Inst::Idx { dst, index, array } => {
+                let (ptr, of) = self.element(array, index)?;
+                let value = self.value(&format!("load {0}, {0}* {1}", llvm_type(&of)?, ptr));
+                self.store(dst, value, &of)?;
+            }
+
+            Inst::Sto { array, index, src } => {
+                let (ptr, of) = self.element(array, index)?;
+                let value = self.load(src, &of)?;
+                let line = format!("store {0} {1}, {0}* {2}", llvm_type(&of)?, value, ptr);
+                self.line(&line);
+            }
+
+            Inst::Chk { index, len } => {
+                self.check_index(index, *len)?;
+            }
+
+            Inst::Jmp { label } => self.terminate(&format!("br label %L{}", label)),
+
+            Inst::JmpT { label, test } | Inst::JmpF { label, test } => {
+                let test = self.load(test, &Type::Bool)?;
+                let next = format!("next{}", self.fresh());
+                let (then, other) = match inst {
+                    Inst::JmpT { .. } => (format!("L{}", label), next.clone()),
+                    _ => (next.clone(), format!("L{}", label)),
+                };
+
+                self.terminate(&format!(
+                    "br i1 {}, label %{}, label %{}",
+                    test, then, other
+                ));
+                self.start(&next);
+            }
+
+            // Passed by the call that follows
+            Inst::Param { .. } => {}
+            Inst::Call { .. } => self.call(insts, pos)?,
+
+            Inst::Ret { src } => match (src, &self.func.ret) {
+                (Some(src), Some(ret)) => {
+                    let value = self.load(src, ret)?;
+                    self.terminate(&format!("ret {} {}", llvm_type(ret)?, value));
+                }
+                (_, None) => self.terminate("ret void"),
+                // Missing return value, as in the VM
+                _ => {
+                    self.line("call void @llvm.trap()");
+                    self.terminate("unreachable");
+                }
+            },
+
+            Inst::Label(_) => {}
+            Inst::Phi { .. } => {
+                return Err(CodegenError::new(
+                    "cannot lower phi, translate out of SSA first",
+                ))
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn function(module: &Module, func: &Function) -> CodegenResult<String> {
+    let mut params = Vec::new();
+    for param in &func.params {
+        if let Type::Array { .. } = param.tp {
+            return Err(CodegenError::new(&format!(
+                "array parameter {} of {}",
+                param.id, func.name
+            )));
+        }
+
+        let operand = Operand::Ident(param.clone());
+        params.push(format!("{} {}.arg", llvm_type(&param.tp)?, slot(&operand)));
+    }
+
+    let ret = match &func.ret {
+        Some(tp) => llvm_type(tp)?,
+        None => "void".to_owned(),
+    };
+
+    let mut writer = Writer {
+        module,
+        func,
+        out: format!(
+            "define internal {} @ez_{}({}) {{\nentry:\n",
+            ret,
+            func.name,
+            params.join(", ")
+        ),
+        next: 0,
+        open: true,
+        traps: false,
+    };
+
+    // Locals start at zero, as in a new frame of the VM
+    let mut slots = BTreeMap::new();
+    for ident in func.params.iter().chain(&func.locals) {
+        let operand = Operand::Ident(ident.clone());
+        slots.insert(slot(&operand), ident.tp.clone());
+    }
+
+    for inst in &func.code {
+        for operand in inst.get_uses().into_iter().chain(inst.get_def()) {
+            if let Operand::Temp(temp) = operand {
+                slots.insert(slot(operand), temp.tp.clone());
+            }
+        }
+    }
+
+    for (slot, tp) in &slots {
+        writer.line(&format!("{} = alloca {}", slot, llvm_type(tp)?));
+    }
+
+    for param in &func.params {
+        let slot = slot(&Operand::Ident(param.clone()));
+        writer.line(&format!(
+            "store {0} {1}.arg, {0}* {1}",
+            llvm_type(&param.tp)?,
+            slot
+        ));
+    }
+
+    for local in &func.locals {
+        let slot = slot(&Operand::Ident(local.clone()));
+        let tp = llvm_type(&local.tp)?;
+        writer.line(&format!("store {0} zeroinitializer, {0}* {1}", tp, slot));
+    }
+
+    for pos in 0..func.code.len() {
+        writer.inst(&func.code, pos)?;
+    }
+
+    if writer.open {
+        match func.ret {
+            Some(_) => writer.terminate("unreachable"),
+            None => writer.terminate("ret void"),
+        }
+    }
+
+    if writer.traps {
+        writer.out.push_str("trap:\n");
+        writer.line("call void @llvm.trap()");
+        writer.line("unreachable");
+    }
+
+    writer.out.push_str("}\n");
+    Ok(writer.out)
+}
+
+/// C `main`: prints what `ez_main` returns, and exits with it when it's an integer.
+fn entry(main: &Function) -> CodegenResult<String> {
+    let mut out = String::from("define i32 @main() {\nentry:\n");
+    let ret = match &main.ret {
+        Some(ret) => ret,
+        None => {
+            out.push_str("  call void @ez_main()\n  ret i32 0\n}\n");
+            return Ok(out);
+        }
+    };
+
+    out.push_str(&format!("  %ret = call {} @ez_main()\n", llvm_type(ret)?));
+    let (format, arg, code) = match ret {
+        Type::Flt32 => (
+            "flt",
+            "  %arg = fpext float %ret to double\n",
+            "  %code = add i32 0, 0\n",
+        ),
+        Type::Flt64 => (
+            "flt",
+            "  %arg = fadd double %ret, 0.0\n",
+            "  %code = add i32 0, 0\n",
+        ),
+        Type::Bool => (
+            "str",
+            "  %arg = select i1 %ret, i8* getelementptr ([5 x i8], [5 x i8]* @.true, i64 0, i64 0), \
+             i8* getelementptr ([6 x i8], [6 x i8]* @.false, i64 0, i64 0)\n",
+            "  %code = zext i1 %ret to i32\n",
+        ),
+        Type::Char => (
+            "chr",
+            "  %arg = zext i8 %ret to i32\n",
+            "  %code = add i32 %arg, 0\n",
+        ),
+        Type::Int32 => (
+            "int",
+            "  %arg = sext i32 %ret to i64\n",
+            "  %code = add i32 %ret, 0\n",
+        ),
+        _ => (
+            "int",
+            "  %arg = add i64 %ret, 0\n",
+            "  %code = trunc i64 %ret to i32\n",
+        ),
+    };
+
+    let arg_tp = match format {
+        "flt" => "double",
+        "str" => "i8*",
+        "chr" => "i32",
+        _ => "i64",
+    };
+
+    out.push_str(arg);
+    let len = if format == "int" { 5 } else { 4 };
+    out.push_str(&format!(
+        "  %fmt = getelementptr [{0} x i8], [{0} x i8]* @.fmt.{1}, i64 0, i64 0\n",
+        len, format
+    ));
+    out.push_str(&format!(
+        "  call i32 (i8*, ...) @printf(i8* %fmt, {} %arg)\n",
+        arg_tp
+    ));
+    out.push_str(code);
+    out.push_str("  ret i32 %code\n}\n");
+    Ok(out)
+}
+
+/**
+ * Translates `module` to textual LLVM IR, with typed pointers as LLVM 14 and
+ * earlier expect them. Parameters, locals and temporaries live in `alloca`s,
+ * array elements are reached with `getelementptr`, and jumps become `br`s
+ * between basic blocks named after the labels. Arithmetic follows the VM:
+ * integers wrap around (`char` is unsigned), floats are computed in `double`,
+ * and division by zero or indexing out of bounds calls `llvm.trap`.
+ * Functions are named `ez_<name>`, and a `main` function without parameters
+ * gets a C `main` that prints its result and exits with it.
+ */
+pub fn emit_llvm(module: &Module) -> CodegenResult<String> {
+    let mut out = String::new();
+
+    for func in &module.funcs {
+        out.push_str(&function(module, func)?);
+        out.push('\n');
+    }
+
+    if let Some(main) = module.get("main").filter(|main| main.params.is_empty()) {
+        out.push_str(&entry(main)?);
+        out.push('\n');
+        out.push_str(concat!(
+            "@.fmt.int = private unnamed_addr constant [5 x i8] c\"%ld\\0A\\00\"\n",
+            "@.fmt.flt = private unnamed_addr constant [4 x i8] c\"%g\\0A\\00\"\n",
+            "@.fmt.chr = private unnamed_addr constant [4 x i8] c\"%c\\0A\\00\"\n",
+            "@.fmt.str = private unnamed_addr constant [4 x i8] c\"%s\\0A\\00\"\n",
+            "@.true = private unnamed_addr constant [5 x i8] c\"true\\00\"\n",
+            "@.false = private unnamed_addr constant [6 x i8] c\"false\\00\"\n",
+            "\n",
+            "declare i32 @printf(i8*, ...)\n",
+        ));
+    }
+
+    out.push_str("declare i64 @llvm.fptosi.sat.i64.f64(double)\n");
+    out.push_str("declare void @llvm.trap()\n");
+    Ok(out)
+}
+
+/// Emits `module` next to `path` (as `.ll`), compiles it with `llc` and
+/// builds an executable at `path` with the system `cc`.
+pub fn link_llvm(module: &Module, path: &Path) -> CodegenResult<()> {
+    let source = path.with_extension("ll");
+    let object = path.with_extension("o");
+    fs::write(&source, emit_llvm(module)?)
+        .map_err(|error| CodegenError::new(&error.to_string()))?;
+
+    let run = |command: &mut Command| {
+        let output = command
+            .output()
+            .map_err(|error| CodegenError::new(&format!("cannot run {:?}: {}", command, error)))?;
+
+        match output.status.success() {
+            true => Ok(()),
+            false => Err(CodegenError::new(&String::from_utf8_lossy(&output.stderr))),
+        }
+    };
+
+    run(Command::new("llc")
+        .args(["-O2", "-filetype=obj", "-relocation-model=pic", "-o"])
+        .arg(&object)
+        .arg(&source))?;
+    run(Command::new("cc").arg("-o").arg(path).arg(&object))?;
+
+    fs::remove_file(&object).map_err(|error| CodegenError::new(&error.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::parse_module;
+    use crate::vm::machine::tests::for_each_program;
+    use crate::vm::Machine;
+
+    fn temp_dir() -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("ez-lang-llvm-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Output and exit code of `module` run by `lli`, `None` when a signal
+    /// killed it.
+    fn lli(module: &Module, name: &str) -> (String, Option<i32>) {
+        let path = temp_dir().join(format!("{}.ll", name));
+        fs::write(&path, emit_llvm(module).unwrap()).unwrap();
+        let output = Command::new("lli").arg(&path).output().unwrap();
+        fs::remove_file(&path).unwrap();
+
+        (
+            String::from_utf8(output.stdout).unwrap(),
+            output.status.code(),
+        )
+    }
+
+    #[test]
+    fn compiled_programs() {
+        for_each_program(|name, module, value| {
+            let path = temp_dir().join(name);
+            link_llvm(module, &path).unwrap();
+            let output = Command::new(&path).output().unwrap();
+            assert_eq!(
+                String::from_utf8(output.stdout).unwrap(),
+                format!("{}\n", value.unwrap())
+            );
+
+            fs::remove_file(&path).unwrap();
+            fs::remove_file(path.with_extension("ll")).unwrap();
+        });
+    }
+
+    #[test]
+    fn machine_semantics() {
+        // Wrapping arithmetic, conversions, arrays and calls, run by `lli`
+        let module = parse_module(
+            "fn half(x: f32, n: i64) -> f64\n\tdiv __t0 x n\n\tret __t0\n\
+             fn main() -> i32\n\tvar a: [3]char\n\tvar big: i32\n\
+             \tmov big 2147483647\n\tadd big big 2\n\
+             \tsto a 2 300\n\tidx __t1 2 a\n\
+             \tparam 5.5\n\tparam 2\n\tcall __t2 half 2\n\
+             \tinv __t3 __t2\n\tmul __t4 __t3 10\n\
+             \tadd __t5 big __t1\n\tadd __t6 __t5 __t4\n\tret __t6\n",
+        )
+        .unwrap();
+
+        let value = Machine::new(&module).run().unwrap();
+        assert_eq!(value, Some(Value::Int32(-2147483630)));
+
+        assert_eq!(
+            lli(&module, "semantics"),
+            ("-2147483630\n".to_owned(), Some(-2147483630 & 0xff))
+        );
+
+        let source = emit_llvm(&module).unwrap();
+        assert!(source.contains("fdiv double"));
+        assert!(source.contains("getelementptr inbounds [3 x i8], [3 x i8]* %a.0"));
+    }
+
+    #[test]
+    fn lowering() {
+        // `sdiv` and `fptosi` are undefined on i32::MIN / -1 and on floats out
+        // of range, where the VM wraps and saturates
+        let funcs = "fn div(a: i32, b: i32) -> i32\n\tdiv __t0 a b\n\tret __t0\n\
+                     fn trunc(x: f64) -> i32\n\tvar y: i32\n\tmov y x\n\tret y\n\
+                     fn get(i: i32, j: i32) -> i32\n\tvar m: [3][4]i32\n\
+                     \tchk i 3\n\tchk j 4\n\tmul __t0 i 4\n\tadd __t1 __t0 j\n\
+                     \tidx __t2 __t1 m\n\tret __t2\n\
+                     fn none(x: i32) -> i32\n\tlt __t0 x 0\n\tjmpf L1 __t0\n\tret x\nL1\tret\n";
+        let main = |call: &str| {
+            let code = format!("{}fn main() -> i32\n{}\tret __t0\n", funcs, call);
+            parse_module(&code).unwrap()
+        };
+
+        let calls = [
+            "\tparam -2147483648\n\tparam -1\n\tcall __t0 div 2\n",
+            "\tparam 1e19\n\tcall __t0 trunc 1\n",
+            "\tparam -1e19\n\tcall __t0 trunc 1\n",
+            "\tdiv __t1 0.0 0.0\n\tparam __t1\n\tcall __t0 trunc 1\n",
+        ];
+        for (pos, call) in calls.iter().enumerate() {
+            let module = main(call);
+            let value = Machine::new(&module).run().unwrap().unwrap();
+            let (stdout, _) = lli(&module, &format!("lowering-{}", pos));
+            assert_eq!(stdout, format!("{}\n", value));
+        }
+
+        // An index past its own dimension and a missing return trap
+        let calls = [
+            "\tparam 0\n\tparam 5\n\tcall __t0 get 2\n",
+            "\tparam 1\n\tcall __t0 none 1\n",
+        ];
+        for (pos, call) in calls.iter().enumerate() {
+            let module = main(call);
+            assert!(Machine::new(&module).run().is_err());
+            let (stdout, code) = lli(&module, &format!("trap-{}", pos));
+            assert_eq!(stdout, "");
+            assert_ne!(code, Some(0));
+        }
+    }
+}
@@ -0,0 +1,461 @@
+use std::collections::{BTreeSet, HashMap};
+
+use crate::analysis::{Dataflow, Liveness};
+use crate::ast::{new_temp_id, Ident, Temp};
+use crate::codegen::align;
+use crate::ir::{Cfg, Function, Inst, Operand, Var};
+use crate::sym::{DataLayout, Type};
+
+/// Kind of register a value needs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RegClass {
+    Int,
+    Float,
+}
+
+impl RegClass {
+    /// Class of the values of `tp`, `None` for the ones that only live in memory.
+    pub fn of(tp: &Type) -> Option<Self> {
+        match tp {
+            Type::Int32 | Type::Int64 | Type::Char | Type::Bool => Some(Self::Int),
+            Type::Flt32 | Type::Flt64 => Some(Self::Float),
+            Type::String(_) | Type::Array { .. } => None,
+        }
+    }
+}
+
+/// Registers of a target, split by class. Scratch registers are kept out of
+/// allocation to reload spilled values, each class needs at least two.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RegisterSet {
+    pub int: Vec<&'static str>,
+    pub float: Vec<&'static str>,
+    pub int_scratch: Vec<&'static str>,
+    pub float_scratch: Vec<&'static str>,
+}
+
+impl RegisterSet {
+    pub fn regs(&self, class: RegClass) -> &[&'static str] {
+        match class {
+            RegClass::Int => &self.int,
+            RegClass::Float => &self.float,
+        }
+    }
+
+    pub fn scratch(&self, class: RegClass) -> &[&'static str] {
+        match class {
+            RegClass::Int => &self.int_scratch,
+            RegClass::Float => &self.float_scratch,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Location {
+    Reg(&'static str),
+    /// Offset in the frame, like `Ident.offset`.
+    Stack(usize),
+}
+
+/// Instructions (by position in the linear code) where `var` is live or accessed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Interval {
+    pub var: Var,
+    pub class: RegClass,
+    pub start: usize,
+    pub end: usize,
+}
+
+/// Where every temporary of a function lives after `allocate`, and the size of
+/// its frame, spill slots included.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Allocation {
+    pub locations: HashMap<Var, Location>,
+    pub frame_size: usize,
+    pub spilled: usize,
+}
+
+impl Allocation {
+    pub fn location(&self, operand: &Operand) -> Option<Location> {
+        operand
+            .get_var()
+            .and_then(|var| self.locations.get(&var).cloned())
+    }
+}
+
+fn new_temp(tp: Type) -> Temp {
+    Temp {
+        id: new_temp_id(),
+        tp,
+    }
+}
+
+/// End of the frame of `func`, past its last parameter or local.
+pub fn frame_size(func: &Function) -> usize {
+    func.params
+        .iter()
+        .chain(&func.locals)
+        .map(|ident| ident.offset + ident.tp.get_width())
+        .max()
+        .unwrap_or(0)
+}
+
+/**
+ * Turns the scalar parameters and locals of `func` into temporaries, so that
+ * they can get a register too. The ones read before being written are loaded
+ * from the frame at the start of the function.
+ */
+fn promote(func: &mut Function) {
+    let cfg = Cfg::new(&func.code);
+    let live = Dataflow::new(&cfg, Liveness);
+
+    let mut temps = HashMap::new();
+    let mut loads = Vec::new();
+    for ident in func.params.iter().chain(&func.locals) {
+        if RegClass::of(&ident.tp).is_none() {
+            continue;
+        }
+
+        let temp = new_temp(ident.tp.clone());
+        if live.block_in(cfg.entry).contains(&Var::Ident(ident.offset)) {
+            loads.push(Inst::Mov {
+                dst: Operand::Temp(temp.clone()),
+                src: Operand::Ident(ident.clone()),
+            });
+        }
+
+        temps.insert(ident.offset, temp);
+    }
+
+    let rename = |operand: &mut Operand| {
+        if let Operand::Ident(ident) = operand {
+            if let Some(temp) = temps.get(&ident.offset) {
+                *operand = Operand::Temp(temp.clone());
+            }
+        }
+    };
+
+    for inst in &mut func.code {
+        inst.get_uses_mut().into_iter().for_each(rename);
+        inst.get_def_mut().into_iter().for_each(rename);
+    }
+
+    func.code.splice(0..0, loads);
+}
+
+/// Live intervals of the temporaries of `code` that fit in a register.
+pub fn intervals(code: &[Inst]) -> Vec<Interval> {
+    let cfg = Cfg::new(code);
+    let live = Dataflow::new(&cfg, Liveness);
+
+    let mut classes = HashMap::new();
+    let mut ranges: HashMap<Var, (usize, usize)> = HashMap::new();
+    let mut pos = 0;
+
+    for (id, block) in cfg.blocks.iter().enumerate() {
+        let points = live.points(id);
+        for (index, inst) in block.insts.iter().enumerate() {
+            let mut vars: BTreeSet<Var> = &points[index] | &points[index + 1];
+            for operand in inst.get_uses().into_iter().chain(inst.get_def()) {
+                if let (Operand::Temp(temp), Some(class)) =
+                    (operand, RegClass::of(&operand.get_tp()))
+                {
+                    classes.insert(Var::Temp(temp.id), class);
+                    vars.insert(Var::Temp(temp.id));
+                }
+            }
+
+            for var in vars {
+                let range = ranges.entry(var).or_insert((pos, pos));
+                range.0 = range.0.min(pos);
+                range.1 = range.1.max(pos);
+            }
+
+            pos += 1;
+        }
+    }
+
+    let mut intervals: Vec<Interval> = ranges
+        .into_iter()
+        .filter_map(|(var, (start, end))| {
+            classes.get(&var).map(|&class| Interval {
+                var,
+                class,
+                start,
+                end,
+            })
+        })
+        .collect();
+
+    intervals.sort_by_key(|interval| (interval.start, interval.end, interval.var));
+    intervals
+}
+
+/// Linear scan (Poletto and Sarkar) over `intervals` of one class. Returns
+/// the register of every interval that got one, the rest are spilled.
+fn linear_scan(intervals: &[&Interval], regs: &[&'static str]) -> HashMap<Var, &'static str> {
+    let mut assigned = HashMap::new();
+    let mut active: Vec<(&Interval, &'static str)> = Vec::new();
+    let mut free: Vec<&'static str> = regs.iter().rev().cloned().collect();
+
+    for &interval in intervals {
+        active.retain(|(other, reg)| {
+            let expired = other.end < interval.start;
+            if expired {
+                free.push(reg);
+            }
+            !expired
+        });
+
+        if let Some(reg) = free.pop() {
+            assigned.insert(interval.var, reg);
+            active.push((interval, reg));
+            continue;
+        }
+
+        // Spill whichever lives the longest, the new interval or an active one
+        let last = active
+            .iter()
+            .enumerate()
+            .max_by_key(|(_, (other, _))| other.end)
+            .map(|(pos, _)| pos);
+
+        match last {
+            Some(pos) if active[pos].0.end > interval.end => {
+                let (spilled, reg) = active.remove(pos);
+                assigned.remove(&spilled.var);
+                assigned.insert(interval.var, reg);
+                active.push((interval, reg));
+            }
+            _ => {}
+        }
+    }
+
+    assigned
+}
+
+/**
+ * Register allocation for `func` with the registers of a target. Scalar
+ * parameters and locals are turned into temporaries first, then every
+ * temporary gets a register by linear scan over its live interval. The ones
+ * left without are spilled to new slots at the end of the frame (added to
+ * `func.locals`): each use reloads them into a scratch register with a `mov`,
+ * and each definition writes a scratch register that is stored right after.
+ *
+ * Afterwards every scalar operand is a temporary in a register, and frame
+ * slots are only accessed by `mov`s and by array instructions. Registers
+ * aren't preserved across calls, that's up to the calling convention.
+ */
+pub fn allocate(func: &mut Function, regs: &RegisterSet) -> Allocation {
+    promote(func);
+
+    let intervals = intervals(&func.code);
+    let mut locations = HashMap::new();
+
+    for &class in &[RegClass::Int, RegClass::Float] {
+        let of_class: Vec<&Interval> = intervals
+            .iter()
+            .filter(|interval| interval.class == class)
+            .collect();
+
+        for (var, reg) in linear_scan(&of_class, regs.regs(class)) {
+            locations.insert(var, Location::Reg(reg));
+        }
+    }
+
+    let mut size = frame_size(func);
+    let mut slots: HashMap<Var, Ident> = HashMap::new();
+    let mut code = Vec::with_capacity(func.code.len());
+
+    for mut inst in func.code.drain(..) {
+        let mut spill = |operand: &mut Operand,
+                         scratch: usize,
+                         locations: &mut HashMap<Var, Location>| {
+            let temp = match operand {
+                Operand::Temp(temp) if !locations.contains_key(&Var::Temp(temp.id)) => temp.clone(),
+                _ => return None,
+            };
+
+            let class = RegClass::of(&temp.tp).unwrap();
+            let slot = slots.entry(Var::Temp(temp.id)).or_insert_with(|| {
+                let layout = temp.tp.layout(&DataLayout::default());
+                let offset = align(size, layout.align);
+                size = offset + layout.size;
+
+                Ident {
+                    id: format!("spill{}", temp.id),
+                    tp: temp.tp.clone(),
+                    offset,
+                }
+            });
+
+            let reload = new_temp(temp.tp.clone());
+            let reg = regs.scratch(class)[scratch];
+            locations.insert(Var::Temp(reload.id), Location::Reg(reg));
+            *operand = Operand::Temp(reload);
+            Some(Operand::Ident(slot.clone()))
+        };
+
+        let mut before = Vec::new();
+        let mut scratch = HashMap::new();
+        for operand in inst.get_uses_mut() {
+            let class = RegClass::of(&operand.get_tp());
+            let next = class.map(|class| *scratch.get(&class).unwrap_or(&0));
+
+            if let (Some(class), Some(next)) = (class, next) {
+                if let Some(slot) = spill(operand, next, &mut locations) {
+                    scratch.insert(class, next + 1);
+                    before.push(Inst::Mov {
+                        dst: operand.clone(),
+                        src: slot,
+                    });
+                }
+            }
+        }
+
+        // Operands are read before the result is written, so the first scratch
+        // register is free again
+        let mut after = None;
+        if let Some(def) = inst.get_def_mut() {
+            if let Some(slot) = spill(def, 0, &mut locations) {
+                after = Some(Inst::Mov {
+                    dst: slot,
+                    src: def.clone(),
+                });
+            }
+        }
+
+        code.extend(before);
+        code.push(inst);
+        code.extend(after);
+    }
+
+    func.code = code;
+
+    let spilled = slots.len();
+    for (var, slot) in slots {
+        locations.insert(var, Location::Stack(slot.offset));
+        func.locals.push(slot);
+    }
+
+    func.locals.sort_by_key(|local| local.offset);
+
+    Allocation {
+        locations,
+        frame_size: size,
+        spilled,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interp::interpreter::tests::{fact_program, loops_program};
+    use crate::interp::{Interpreter, RunResult};
+    use crate::ir::Module;
+    use crate::vm::machine::tests::compile;
+    use crate::vm::Machine;
+
+    fn registers(int: usize, float: usize) -> RegisterSet {
+        const INT: [&str; 8] = ["r0", "r1", "r2", "r3", "r4", "r5", "r6", "r7"];
+        const FLOAT: [&str; 8] = ["f0", "f1", "f2", "f3", "f4", "f5", "f6", "f7"];
+
+        RegisterSet {
+            int: INT[..int].to_vec(),
+            float: FLOAT[..float].to_vec(),
+            int_scratch: vec!["s0", "s1"],
+            float_scratch: vec!["fs0", "fs1"],
+        }
+    }
+
+    /// No two values that are live at once share a register.
+    fn check(func: &Function, allocation: &Allocation) {
+        let cfg = Cfg::new(&func.code);
+        let live = Dataflow::new(&cfg, Liveness);
+
+        for (id, block) in cfg.blocks.iter().enumerate() {
+            let points = live.points(id);
+            for (index, inst) in block.insts.iter().enumerate() {
+                let mut vars = points[index + 1].clone();
+                vars.extend(inst.get_def().and_then(Operand::get_var));
+
+                let mut used = HashMap::new();
+                for var in vars {
+                    if let Some(Location::Reg(reg)) = allocation.locations.get(&var) {
+                        if let Some(other) = used.insert(reg, var) {
+                            panic!("{:?} and {:?} share {} at {}", var, other, reg, inst);
+                        }
+                    }
+                }
+
+                for operand in inst.get_uses().into_iter().chain(inst.get_def()) {
+                    if let Operand::Temp(temp) = operand {
+                        let location = allocation.locations.get(&Var::Temp(temp.id));
+                        assert!(matches!(location, Some(Location::Reg(_))), "{}", inst);
+                    }
+                }
+            }
+        }
+    }
+
+    fn allocated(program: &crate::ast::Program, regs: &RegisterSet) -> (Module, usize) {
+        let mut module = compile(program);
+        let mut spilled = 0;
+
+        for func in &mut module.funcs {
+            let allocation = allocate(func, regs);
+            assert_eq!(allocation.frame_size, frame_size(func));
+            check(func, &allocation);
+            spilled += allocation.spilled;
+        }
+
+        (module, spilled)
+    }
+
+    #[test]
+    fn enough_registers() -> RunResult<()> {
+        for program in &[fact_program(), loops_program()] {
+            let (module, spilled) = allocated(program, &registers(8, 8));
+            assert_eq!(spilled, 0);
+            assert_eq!(
+                Machine::new(&module).run()?,
+                Interpreter::new(program).run()?
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn spill_and_reload() -> RunResult<()> {
+        let program = loops_program();
+        let (module, spilled) = allocated(&program, &registers(1, 1));
+        assert!(spilled > 0);
+
+        let main = module.get("main").unwrap();
+        assert!(main
+            .locals
+            .iter()
+            .any(|local| local.id.starts_with("spill")));
+        assert_eq!(
+            Machine::new(&module).run()?,
+            Interpreter::new(&program).run()?
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn live_intervals() {
+        let func = crate::ir::cfg::tests::function(
+            "fn f(a: i32) -> i32\n\
+             \tadd __t0 a 1\n\tadd __t1 a 2\n\tmul __t2 __t0 __t1\n\tret __t2\n",
+        );
+
+        let spans: Vec<(usize, usize)> = intervals(&func.code)
+            .iter()
+            .map(|interval| (interval.start, interval.end))
+            .collect();
+        assert_eq!(spans, vec![(0, 2), (1, 2), (2, 3)]);
+    }
+}
@@ -0,0 +1,752 @@
+use crate::ast::Ident;
+use crate::codegen::{
+    assemble_riscv, Allocation, CallingConvention, CodegenResult, Frame, Location, RegClass,
+    RegisterSet, RiscvImage,
+};
+use crate::error::CodegenError;
+use crate::interp::Value;
+use crate::ir::{Function, Inst, Module, Opcode, Operand};
+use crate::sym::{DataLayout, Type};
+
+/// Argument registers of the LP64D calling convention, in order.
+const INT_ARGS: [&str; 8] = ["a0", "a1", "a2", "a3", "a4", "a5", "a6", "a7"];
+const FLOAT_ARGS: [&str; 8] = ["fa0", "fa1", "fa2", "fa3", "fa4", "fa5", "fa6", "fa7"];
+
+/// Registers where operands are loaded, converted and combined before the
+/// result is stored. None of them is handed to the allocator.
+const INT_WORK: [&str; 2] = ["t0", "t1"];
+const FLOAT_WORK: [&str; 2] = ["ft0", "ft1"];
+
+/// Holds addresses that don't fit an immediate offset, and constants.
+const ADDR: &str = "t2";
+
+/// `s0` is the frame pointer, so it isn't in the set.
+const INT_CALLEE_SAVED: [&str; 11] = [
+    "s1", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11",
+];
+const FLOAT_CALLEE_SAVED: [&str; 12] = [
+    "fs0", "fs1", "fs2", "fs3", "fs4", "fs5", "fs6", "fs7", "fs8", "fs9", "fs10", "fs11",
+];
+const INT_CALLER_SAVED: [&str; 16] = [
+    "ra", "t0", "t1", "t2", "t3", "t4", "t5", "t6", "a0", "a1", "a2", "a3", "a4", "a5", "a6", "a7",
+];
+const FLOAT_CALLER_SAVED: [&str; 20] = [
+    "ft0", "ft1", "ft2", "ft3", "ft4", "ft5", "ft6", "ft7", "ft8", "ft9", "ft10", "ft11", "fa0",
+    "fa1", "fa2", "fa3", "fa4", "fa5", "fa6", "fa7",
+];
+
+/// The LP64D calling convention for RV64. Unlike on x86-64, there are
+/// callee-saved floating point registers too.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Lp64d;
+
+impl CallingConvention for Lp64d {
+    fn arg_regs(&self, class: RegClass) -> &[&'static str] {
+        match class {
+            RegClass::Int => &INT_ARGS,
+            RegClass::Float => &FLOAT_ARGS,
+        }
+    }
+
+    fn callee_saved(&self, class: RegClass) -> &[&'static str] {
+        match class {
+            RegClass::Int => &INT_CALLEE_SAVED,
+            RegClass::Float => &FLOAT_CALLEE_SAVED,
+        }
+    }
+
+    fn caller_saved(&self, class: RegClass) -> &[&'static str] {
+        match class {
+            RegClass::Int => &INT_CALLER_SAVED,
+            RegClass::Float => &FLOAT_CALLER_SAVED,
+        }
+    }
+
+    fn scratch(&self, class: RegClass) -> &[&'static str] {
+        match class {
+            RegClass::Int => &["t3", "t4"],
+            RegClass::Float => &["ft8", "ft9"],
+        }
+    }
+
+    fn stack_align(&self) -> usize {
+        16
+    }
+
+    fn data_layout(&self) -> DataLayout {
+        DataLayout::LP64
+    }
+
+    /// `ra` and the caller's `s0`, at the top of the frame.
+    fn linkage(&self) -> usize {
+        16
+    }
+}
+
+/// Registers for `allocate`, the callee-saved ones of `Lp64d`.
+pub fn riscv_registers() -> RegisterSet {
+    Lp64d.registers()
+}
+
+fn class(tp: &Type) -> CodegenResult<RegClass> {
+    RegClass::of(tp)
+        .ok_or_else(|| CodegenError::new(&format!("values of type {} don't fit a register", tp)))
+}
+
+fn fits_i12(num: i64) -> bool {
+    (-2048..2048).contains(&num)
+}
+
+/**
+ * Emits one function. As for x86-64, integer values are kept in registers as
+ * 64 bits, sign-extended (`i32`) or zero-extended (`char`, `bool`), and
+ * floating point values as `f64`, rounded to `f32` precision when that's
+ * their type. They only take their own width in memory.
+ */
+struct Emitter<'a> {
+    module: &'a Module,
+    func: Function,
+    alloc: Allocation,
+    /// Reserved below `s0`.
+    frame: Frame,
+    /// Types of the arguments stored by `param` and not passed yet.
+    params: Vec<Type>,
+    out: String,
+}
+
+impl<'a> Emitter<'a> {
+    fn new(module: &'a Module, func: &Function) -> Self {
+        let mut func = func.clone();
+        let (frame, alloc) = Frame::new(&mut func, &Lp64d);
+        Self {
+            module,
+            func,
+            alloc,
+            frame,
+            params: Vec::new(),
+            out: String::new(),
+        }
+    }
+
+    fn asm(&mut self, line: &str) {
+        self.out.push('\t');
+        self.out.push_str(line);
+        self.out.push('\n');
+    }
+
+    fn label(&self, label: usize) -> String {
+        format!(".L{}_{}", self.func.name, label)
+    }
+
+    /// Address of `offset` bytes from `s0`, through `ADDR` when it's too far
+    /// for an immediate.
+    fn addr(&mut self, offset: i64) -> String {
+        if fits_i12(offset) {
+            return format!("{}(s0)", offset);
+        }
+
+        self.asm(&format!("li {}, {}", ADDR, offset));
+        self.asm(&format!("add {}, {}, s0", ADDR, ADDR));
+        format!("0({})", ADDR)
+    }
+
+    /// Address of the frame slot at `offset`.
+    fn slot(&mut self, offset: usize) -> String {
+        self.addr(offset as i64 - self.frame.size as i64)
+    }
+
+    fn work(class: RegClass, n: usize) -> &'static str {
+        match class {
+            RegClass::Int => INT_WORK[n],
+            RegClass::Float => FLOAT_WORK[n],
+        }
+    }
+
+    fn load_mem(&mut self, tp: &Type, mem: &str, n: usize) -> CodegenResult<()> {
+        let reg = Self::work(class(tp)?, n);
+        let inst = match tp {
+            Type::Int32 => "lw",
+            Type::Int64 => "ld",
+            Type::Char | Type::Bool => "lbu",
+            Type::Flt32 => "flw",
+            _ => "fld",
+        };
+
+        self.asm(&format!("{} {}, {}", inst, reg, mem));
+        if *tp == Type::Flt32 {
+            self.asm(&format!("fcvt.d.s {}, {}", reg, reg));
+        }
+        Ok(())
+    }
+
+    fn store_mem(&mut self, tp: &Type, mem: &str, n: usize) -> CodegenResult<()> {
+        let reg = Self::work(class(tp)?, n);
+        let inst = match tp {
+            Type::Int32 => "sw",
+            Type::Int64 => "sd",
+            Type::Char | Type::Bool => "sb",
+            Type::Flt32 => {
+                self.asm(&format!("fcvt.s.d {}, {}", reg, reg));
+                "fsw"
+            }
+            _ => "fsd",
+        };
+
+        self.asm(&format!("{} {}, {}", inst, reg, mem));
+        Ok(())
+    }
+
+    /// Brings the integer in `INT_WORK[n]` back to the 64 bits form of `tp`,
+    /// or rounds the float in `FLOAT_WORK[n]` to `f32` precision.
+    fn normalize(&mut self, tp: &Type, n: usize) {
+        let (int, float) = (INT_WORK[n], FLOAT_WORK[n]);
+        match tp {
+            Type::Int32 => self.asm(&format!("sext.w {}, {}", int, int)),
+            Type::Char | Type::Bool => self.asm(&format!("andi {}, {}, 255", int, int)),
+            Type::Flt32 => {
+                self.asm(&format!("fcvt.s.d {}, {}", float, float));
+                self.asm(&format!("fcvt.d.s {}, {}", float, float));
+            }
+            _ => {}
+        }
+    }
+
+    /// Converts the value in the `n`th working register from `from` to `to`,
+    /// like `Value::cast`: conversions to integers truncate and saturate, and
+    /// NaN becomes zero.
+    fn convert(&mut self, from: &Type, to: &Type, n: usize) -> CodegenResult<()> {
+        let (int, float) = (INT_WORK[n], FLOAT_WORK[n]);
+        match (class(from)?, class(to)?) {
+            (RegClass::Int, RegClass::Float) => self.asm(&format!("fcvt.d.l {}, {}", float, int)),
+            (RegClass::Float, RegClass::Int) => {
+                self.asm(&format!("fcvt.l.d {}, {}, rtz", int, float));
+                self.asm(&format!("feq.d {}, {}, {}", ADDR, float, float));
+                self.asm(&format!("neg {}, {}", ADDR, ADDR));
+                self.asm(&format!("and {}, {}, {}", int, int, ADDR));
+            }
+            _ if from == to => return Ok(()),
+            _ => {}
+        }
+
+        self.normalize(to, n);
+        Ok(())
+    }
+
+    /// Loads `operand` into the `n`th working register of the class of `to`,
+    /// converted to `to`.
+    fn load(&mut self, operand: &Operand, to: &Type, n: usize) -> CodegenResult<()> {
+        let from = operand.get_tp();
+        let (int, float) = (INT_WORK[n], FLOAT_WORK[n]);
+
+        match operand {
+            Operand::Cons(cons) => {
+                let num = match Value::from_cons(cons) {
+                    Value::Int32(num) => num as i64,
+                    Value::Int64(num) => num,
+                    Value::Char(chr) => chr as i64,
+                    Value::Bool(value) => value as i64,
+                    Value::Flt32(num) => (num as f64).to_bits() as i64,
+                    Value::Flt64(num) => num.to_bits() as i64,
+                    value => {
+                        return Err(CodegenError::new(&format!(
+                            "constant {} doesn't fit a register",
+                            value
+                        )))
+                    }
+                };
+
+                match class(&from)? {
+                    RegClass::Int => self.asm(&format!("li {}, {}", int, num)),
+                    RegClass::Float => {
+                        self.asm(&format!("li {}, {}", ADDR, num));
+                        self.asm(&format!("fmv.d.x {}, {}", float, ADDR));
+                    }
+                }
+            }
+
+            Operand::Temp(_) => match (self.alloc.location(operand), class(&from)?) {
+                (Some(Location::Reg(reg)), RegClass::Int) => {
+                    self.asm(&format!("mv {}, {}", int, reg))
+                }
+                (Some(Location::Reg(reg)), RegClass::Float) => {
+                    self.asm(&format!("fmv.d {}, {}", float, reg))
+                }
+                _ => return Err(CodegenError::new(&format!("{} has no register", operand))),
+            },
+
+            Operand::Ident(ident) => {
+                let slot = self.slot(ident.offset);
+                self.load_mem(&ident.tp, &slot, n)?;
+            }
+        }
+
+        self.convert(&from, to, n)
+    }
+
+    /// Stores the first working register into `dst`, the value must be of its type.
+    fn store(&mut self, dst: &Operand) -> CodegenResult<()> {
+        let tp = dst.get_tp();
+        match dst {
+            Operand::Temp(_) => match (self.alloc.location(dst), class(&tp)?) {
+                (Some(Location::Reg(reg)), RegClass::Int) => self.asm(&format!("mv {}, t0", reg)),
+                (Some(Location::Reg(reg)), RegClass::Float) => {
+                    self.asm(&format!("fmv.d {}, ft0", reg))
+                }
+                _ => return Err(CodegenError::new(&format!("{} has no register", dst))),
+            },
+
+            Operand::Ident(ident) => {
+                let slot = self.slot(ident.offset);
+                self.store_mem(&tp, &slot, 0)?;
+            }
+
+            Operand::Cons(_) => return Err(CodegenError::new("cannot store into a constant")),
+        }
+
+        Ok(())
+    }
+
+    /// Traps with `ebreak` unless the index in `reg` is in `0..len`, compared
+    /// unsigned so that negative indices are out of bounds too.
+    fn check(&mut self, reg: &str, len: usize) {
+        self.asm(&format!("li {}, {}", ADDR, len));
+        self.asm(&format!("bltu {}, {}, 1f", reg, ADDR));
+        self.asm("ebreak");
+        self.out.push_str("1:\n");
+    }
+
+    /// Element type of an array in the frame, once the address of the element
+    /// at the index in `t1` is in `ADDR`.
+    fn element(&mut self, operand: &Operand) -> CodegenResult<Type> {
+        let (of, offset) = match operand {
+            Operand::Ident(Ident {
+                tp: tp @ Type::Array { .. },
+                offset,
+                ..
+            }) => (tp.get_scalar().clone(), *offset),
+            _ => return Err(CodegenError::new(&format!("{} is not an array", operand))),
+        };
+
+        let stride = of.layout(&Lp64d.data_layout()).stride();
+        if stride.is_power_of_two() {
+            self.asm(&format!("slli t1, t1, {}", stride.trailing_zeros()));
+        } else {
+            self.asm(&format!("li {}, {}", ADDR, stride));
+            self.asm(&format!("mul t1, t1, {}", ADDR));
+        }
+
+        let base = offset as i64 - self.frame.size as i64;
+        if fits_i12(base) {
+            self.asm(&format!("addi {}, s0, {}", ADDR, base));
+        } else {
+            self.asm(&format!("li {}, {}", ADDR, base));
+            self.asm(&format!("add {}, {}, s0", ADDR, ADDR));
+        }
+        self.asm(&format!("add {}, {}, t1", ADDR, ADDR));
+        Ok(of)
+    }
+
+    fn binary(
+        &mut self,
+        op: Opcode,
+        dst: &Operand,
+        lhs: &Operand,
+        rhs: &Operand,
+    ) -> CodegenResult<()> {
+        // Booleans can only be compared, as integers
+        let tp = lhs.get_tp().upcast(&rhs.get_tp()).unwrap_or(Type::Int64);
+        let class = class(&tp)?;
+        self.load(lhs, &tp, 0)?;
+        self.load(rhs, &tp, 1)?;
+
+        if op.is_rel() {
+            // Comparisons of floats are false when either is NaN, only `ne`
+            // holds for it
+            let lines: &[&str] = match (class, op) {
+                (RegClass::Int, Opcode::Lt) => &["slt t0, t0, t1"],
+                (RegClass::Int, Opcode::Le) => &["slt t0, t1, t0", "xori t0, t0, 1"],
+                (RegClass::Int, Opcode::Gt) => &["slt t0, t1, t0"],
+                (RegClass::Int, Opcode::Ge) => &["slt t0, t0, t1", "xori t0, t0, 1"],
+                (RegClass::Int, Opcode::Eq) => &["sub t0, t0, t1", "seqz t0, t0"],
+                (RegClass::Int, _) => &["sub t0, t0, t1", "snez t0, t0"],
+                (RegClass::Float, Opcode::Lt) => &["flt.d t0, ft0, ft1"],
+                (RegClass::Float, Opcode::Le) => &["fle.d t0, ft0, ft1"],
+                (RegClass::Float, Opcode::Gt) => &["flt.d t0, ft1, ft0"],
+                (RegClass::Float, Opcode::Ge) => &["fle.d t0, ft1, ft0"],
+                (RegClass::Float, Opcode::Eq) => &["feq.d t0, ft0, ft1"],
+                (RegClass::Float, _) => &["feq.d t0, ft0, ft1", "xori t0, t0, 1"],
+            };
+
+            for line in lines {
+                self.asm(line);
+            }
+            return self.store(dst);
+        }
+
+        let inst = match op {
+            Opcode::Add => "add",
+            Opcode::Sub => "sub",
+            Opcode::Mul => "mul",
+            _ => "div",
+        };
+
+        match class {
+            RegClass::Int => {
+                // Division by zero doesn't trap, it gives all ones
+                if op == Opcode::Div {
+                    self.asm("bnez t1, 1f");
+                    self.asm("ebreak");
+                    self.out.push_str("1:\n");
+                }
+                self.asm(&format!("{} t0, t0, t1", inst));
+            }
+            RegClass::Float => self.asm(&format!("f{}.d ft0, ft0, ft1", inst)),
+        }
+
+        // Wrap around (or round) in the upcasted type, then convert
+        self.normalize(&tp, 0);
+        self.convert(&tp, &dst.get_tp(), 0)?;
+        self.store(dst)
+    }
+
+    fn call(&mut self, dst: &Option<Operand>, name: &str, nargs: usize) -> CodegenResult<()> {
+        let callee = self
+            .module
+            .get(name)
+            .ok_or_else(|| CodegenError::new(&format!("call to undefined function {}", name)))?;
+
+        if callee.params.len() != nargs || self.params.len() < nargs {
+            return Err(CodegenError::new(&format!(
+                "{} takes {} arguments",
+                name,
+                callee.params.len()
+            )));
+        }
+
+        // Arguments were stored in order, convert them in place first so that
+        // moving them to their registers doesn't clobber anything
+        let base = self.params.len() - nargs;
+        let args = self.params.split_off(base);
+        let frame = self.frame.clone();
+        let slot = |pos: usize| format!("{}(sp)", frame.arg(base + pos));
+
+        for (pos, (from, param)) in args.iter().zip(&callee.params).enumerate() {
+            self.load_mem(&widest(from), &slot(pos), 0)?;
+            self.convert(from, &param.tp, 0)?;
+            self.store_mem(&widest(&param.tp), &slot(pos), 0)?;
+        }
+
+        let regs = arg_registers(&callee.params)?;
+        for (pos, (param, reg)) in callee.params.iter().zip(regs).enumerate() {
+            let reg = reg.ok_or_else(|| {
+                CodegenError::new(&format!("{} takes too many arguments for registers", name))
+            })?;
+
+            match param.tp {
+                Type::Flt32 => {
+                    self.asm(&format!("fld ft0, {}", slot(pos)));
+                    self.asm(&format!("fcvt.s.d {}, ft0", reg));
+                }
+                Type::Flt64 => self.asm(&format!("fld {}, {}", reg, slot(pos))),
+                _ => self.asm(&format!("ld {}, {}", reg, slot(pos))),
+            }
+        }
+
+        self.asm(&format!("jal ez_{}", name));
+
+        let dst = match dst {
+            Some(dst) => dst,
+            None => return Ok(()),
+        };
+
+        let ret = callee
+            .ret
+            .clone()
+            .ok_or_else(|| CodegenError::new(&format!("{} doesn't return a value", name)))?;
+
+        match ret {
+            Type::Flt32 => self.asm("fcvt.d.s ft0, fa0"),
+            Type::Flt64 => self.asm("fmv.d ft0, fa0"),
+            _ => {
+                self.asm("mv t0, a0");
+                self.normalize(&ret, 0);
+            }
+        }
+
+        self.convert(&ret, &dst.get_tp(), 0)?;
+        self.store(dst)
+    }
+
+    fn inst(&mut self, inst: &Inst) -> CodegenResult<()> {
+        match inst {
+            Inst::Label(label) => {
+                let label = self.label(*label);
+                self.out.push_str(&format!("{}:\n", label));
+            }
+
+            Inst::Binary { op, dst, lhs, rhs } => self.binary(*op, dst, lhs, rhs)?,
+
+            Inst::Inv { dst, src } => {
+                let tp = Type::Int64
+                    .upcast(&src.get_tp())
+                    .ok_or_else(|| CodegenError::new(&format!("cannot negate {}", src)))?;
+
+                self.load(src, &tp, 0)?;
+                match class(&tp)? {
+                    RegClass::Int => self.asm("neg t0, t0"),
+                    RegClass::Float => self.asm("fneg.d ft0, ft0"),
+                }
+
+                self.convert(&tp, &dst.get_tp(), 0)?;
+                self.store(dst)?;
+            }
+
+            Inst::Not { dst, src } => {
+                self.load(src, &Type::Bool, 0)?;
+                self.asm("xori t0, t0, 1");
+                self.store(dst)?;
+            }
+
+            Inst::Mov { dst, src } => {
+                self.load(src, &dst.get_tp(), 0)?;
+                self.store(dst)?;
+            }
+
+            Inst::Idx { dst, index, array } => {
+                self.load(index, &Type::Int64, 1)?;
+                self.check("t1", array.get_tp().get_len());
+                let of = self.element(array)?;
+                self.load_mem(&of, &format!("0({})", ADDR), 0)?;
+                self.convert(&of, &dst.get_tp(), 0)?;
+                self.store(dst)?;
+            }
+
+            Inst::Sto { array, index, src } => {
+                let of = match array.get_tp() {
+                    tp @ Type::Array { .. } => tp.get_scalar().clone(),
+                    _ => return Err(CodegenError::new(&format!("{} is not an array", array))),
+                };
+                self.load(src, &of, 0)?;
+                self.load(index, &Type::Int64, 1)?;
+                self.check("t1", array.get_tp().get_len());
+                self.element(array)?;
+                self.store_mem(&of, &format!("0({})", ADDR), 0)?;
+            }
+
+            Inst::Chk { index, len } => {
+                self.load(index, &Type::Int64, 0)?;
+                self.check("t0", *len);
+            }
+
+            Inst::Jmp { label } => {
+                let label = self.label(*label);
+                self.asm(&format!("j {}", label));
+            }
+
+            // Conditional branches only reach 4 KiB away, so they skip over a
+            // jump to the label
+            Inst::JmpT { label, test } | Inst::JmpF { label, test } => {
+                self.load(test, &Type::Bool, 0)?;
+
+                let skip = if matches!(inst, Inst::JmpT { .. }) {
+                    "beqz"
+                } else {
+                    "bnez"
+                };
+                let label = self.label(*label);
+                self.asm(&format!("{} t0, 1f", skip));
+                self.asm(&format!("j {}", label));
+                self.out.push_str("1:\n");
+            }
+
+            Inst::Param { src } => {
+                let tp = src.get_tp();
+                self.load(src, &tp, 0)?;
+
+                let slot = self.frame.arg(self.params.len());
+                match class(&tp)? {
+                    RegClass::Int => self.asm(&format!("sd t0, {}(sp)", slot)),
+                    RegClass::Float => self.asm(&format!("fsd ft0, {}(sp)", slot)),
+                }
+                self.params.push(tp);
+            }
+
+            Inst::Call { dst, func, nargs } => self.call(dst, func, *nargs)?,
+
+            Inst::Ret { src } => {
+                match (src, self.func.ret.clone()) {
+                    (Some(src), Some(ret)) => {
+                        self.load(src, &ret, 0)?;
+                        match ret {
+                            Type::Flt32 => self.asm("fcvt.s.d fa0, ft0"),
+                            Type::Flt64 => self.asm("fmv.d fa0, ft0"),
+                            _ => self.asm("mv a0, t0"),
+                        }
+                    }
+                    // No value to return, where the VM fails
+                    (None, Some(_)) => {
+                        self.asm("ebreak");
+                        return Ok(());
+                    }
+                    _ => (),
+                }
+
+                let label = format!(".L{}_ret", self.func.name);
+                self.asm(&format!("j {}", label));
+            }
+
+            Inst::Phi { .. } => {
+                return Err(CodegenError::new(
+                    "cannot lower phi, translate out of SSA first",
+                ))
+            }
+        }
+
+        Ok(())
+    }
+
+    /**
+     * Prologue: saves `ra` and `s0`, reserves the frame, zeroes it (locals
+     * start at zero, as in the VM), saves the callee-saved registers it uses,
+     * and stores the arguments in the slots of their parameters.
+     */
+    fn prologue(&mut self) -> CodegenResult<()> {
+        let name = format!("ez_{}", self.func.name);
+        self.asm(&format!(".globl {}", name));
+        self.asm(&format!(".type {}, @function", name));
+        self.out.push_str(&format!("{}:\n", name));
+
+        self.asm("addi sp, sp, -16");
+        self.asm("sd ra, 8(sp)");
+        self.asm("sd s0, 0(sp)");
+        self.asm("addi s0, sp, 16");
+
+        let rest = self.frame.size as i64 - 16;
+        if fits_i12(-rest) {
+            self.asm(&format!("addi sp, sp, {}", -rest));
+        } else {
+            self.asm(&format!("li t0, {}", rest));
+            self.asm("sub sp, sp, t0");
+        }
+
+        let words = self.frame.body() / 8;
+        if words > 0 {
+            self.asm("mv t0, sp");
+            self.asm(&format!("li t1, {}", words));
+            self.out.push_str("1:\n");
+            self.asm("sd zero, 0(t0)");
+            self.asm("addi t0, t0, 8");
+            self.asm("addi t1, t1, -1");
+            self.asm("bnez t1, 1b");
+        }
+
+        for (pos, reg) in self.frame.saved.clone().iter().enumerate() {
+            let inst = if reg.starts_with('f') { "fsd" } else { "sd" };
+            let slot = self.slot(self.frame.saved_slot(pos));
+            self.asm(&format!("{} {}, {}", inst, reg, slot));
+        }
+
+        let params = self.func.params.clone();
+        for (param, reg) in params.iter().zip(arg_registers(&params)?) {
+            let reg = reg.ok_or_else(|| {
+                CodegenError::new(&format!(
+                    "{} takes too many arguments for registers",
+                    self.func.name
+                ))
+            })?;
+
+            let inst = match param.tp {
+                Type::Int32 => "sw",
+                Type::Int64 => "sd",
+                Type::Flt32 => "fsw",
+                Type::Flt64 => "fsd",
+                _ => "sb",
+            };
+            let slot = self.slot(param.offset);
+            self.asm(&format!("{} {}, {}", inst, reg, slot));
+        }
+
+        Ok(())
+    }
+
+    fn epilogue(&mut self) {
+        let name = self.func.name.clone();
+        self.out.push_str(&format!(".L{}_ret:\n", name));
+
+        for (pos, reg) in self.frame.saved.clone().iter().enumerate() {
+            let inst = if reg.starts_with('f') { "fld" } else { "ld" };
+            let slot = self.slot(self.frame.saved_slot(pos));
+            self.asm(&format!("{} {}, {}", inst, reg, slot));
+        }
+
+        self.asm("addi sp, s0, -16");
+        self.asm("ld ra, 8(sp)");
+        self.asm("ld s0, 0(sp)");
+        self.asm("addi sp, sp, 16");
+        self.asm("ret");
+        self.asm(&format!(".size ez_{}, .-ez_{}", name, name));
+    }
+
+    fn emit(mut self) -> CodegenResult<String> {
+        self.prologue()?;
+
+        let code = std::mem::take(&mut self.func.code);
+        for inst in &code {
+            self.inst(inst)?;
+        }
+
+        // Falling off the end of a function with a result is a missing return
+        if self.func.ret.is_some() {
+            self.asm("ebreak");
+        }
+
+        self.epilogue();
+        Ok(self.out)
+    }
+}
+
+/// Registers of `Lp64d` the arguments of `params` are passed in.
+fn arg_registers(params: &[Ident]) -> CodegenResult<Vec<Option<&'static str>>> {
+    let classes = params
+        .iter()
+        .map(|param| class(&param.tp))
+        .collect::<CodegenResult<Vec<_>>>()?;
+    Ok(Lp64d.arg_registers(&classes))
+}
+
+/// Type a value of class `tp` takes in an 8 bytes stack slot.
+fn widest(tp: &Type) -> Type {
+    match class(tp) {
+        Ok(RegClass::Float) => Type::Flt64,
+        _ => Type::Int64,
+    }
+}
+
+/**
+ * Lowers `module` to RV64IMFD assembly for the GNU assembler, following the
+ * LP64D calling convention. Every function gets its registers from
+ * `allocate` and keeps its parameters and locals in a frame below `s0`, at
+ * their `Ident.offset`. Functions are named `ez_<name>`, there's no C entry
+ * point: run them with `Simulator` or call them from C.
+ *
+ * Arguments only go in registers, so functions with more than 8 integer or 8
+ * floating point parameters are rejected. Array accesses out of bounds,
+ * failed `chk`s, integer division by zero and a function with a result that
+ * doesn't return one trap with `ebreak`.
+ */
+pub fn emit_riscv(module: &Module) -> CodegenResult<String> {
+    let mut out = String::from("\t.text\n");
+
+    for func in &module.funcs {
+        out.push_str(&Emitter::new(module, func).emit()?);
+        out.push('\n');
+    }
+
+    out.push_str("\t.section .note.GNU-stack,\"\",@progbits\n");
+    Ok(out)
+}
+
+/// Like `emit_riscv`, but assembled into machine code.
+pub fn emit_riscv_image(module: &Module) -> CodegenResult<RiscvImage> {
+    assemble_riscv(&emit_riscv(module)?)
+}
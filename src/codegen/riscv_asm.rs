@@ -0,0 +1,872 @@
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+
+use crate::codegen::CodegenResult;
+use crate::error::CodegenError;
+
+/// ABI names of `x0` to `x31`.
+const INT_REGS: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4",
+    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+    "t5", "t6",
+];
+
+/// ABI names of `f0` to `f31`.
+const FLOAT_REGS: [&str; 32] = [
+    "ft0", "ft1", "ft2", "ft3", "ft4", "ft5", "ft6", "ft7", "fs0", "fs1", "fa0", "fa1", "fa2",
+    "fa3", "fa4", "fa5", "fa6", "fa7", "fs2", "fs3", "fs4", "fs5", "fs6", "fs7", "fs8", "fs9",
+    "fs10", "fs11", "ft8", "ft9", "ft10", "ft11",
+];
+
+/// Rounding modes of floating point instructions, `dyn` reads `frm`.
+const ROUNDING: [(&str, u32); 6] = [
+    ("rne", 0),
+    ("rtz", 1),
+    ("rdn", 2),
+    ("rup", 3),
+    ("rmm", 4),
+    ("dyn", 7),
+];
+
+const ZERO: u32 = 0;
+const RA: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    /// `rd, rs1, rs2`
+    R,
+    /// `rd, rs1, rs2`, then an optional rounding mode
+    RoundR,
+    /// `rd, rs1`, `rs2` is part of the opcode
+    Unary,
+    /// `rd, rs1`, then an optional rounding mode
+    RoundUnary,
+    /// `rd, rs1, rs2, rs3`, then an optional rounding mode
+    Fused,
+    /// `rd, rs1, imm`
+    I,
+    /// `rd, rs1, shamt`, below the given bound
+    Shift(i64),
+    /// `rd, imm(rs1)`
+    Load,
+    /// `rs2, imm(rs1)`
+    Store,
+    /// `rs1, rs2, label`
+    Branch,
+    /// `rd, imm`
+    Upper,
+    /// `rd, label`
+    Jump,
+    /// `rd, imm(rs1)`
+    Jalr,
+    System,
+}
+
+/// An instruction: its format, the fixed bits of its encoding, and whether
+/// each register operand, in the order they're written, is an integer (`x`)
+/// or floating point (`f`) one.
+struct Op {
+    name: &'static str,
+    format: Format,
+    bits: u32,
+    regs: &'static str,
+}
+
+const fn op(name: &'static str, format: Format, bits: u32, regs: &'static str) -> Op {
+    Op {
+        name,
+        format,
+        bits,
+        regs,
+    }
+}
+
+const fn enc(opcode: u32, funct3: u32, funct7: u32) -> u32 {
+    opcode | funct3 << 12 | funct7 << 25
+}
+
+/// Floating point instruction with a single source, `rs2` selects the operation.
+const fn fp1(funct7: u32, rs2: u32) -> u32 {
+    enc(0x53, 0, funct7) | rs2 << 20
+}
+
+use Format::*;
+
+const OPS: &[Op] = &[
+    // RV64I
+    op("lui", Upper, 0x37, "x"),
+    op("auipc", Upper, 0x17, "x"),
+    op("jal", Jump, 0x6f, "x"),
+    op("jalr", Jalr, enc(0x67, 0, 0), "xx"),
+    op("beq", Branch, enc(0x63, 0, 0), "xx"),
+    op("bne", Branch, enc(0x63, 1, 0), "xx"),
+    op("blt", Branch, enc(0x63, 4, 0), "xx"),
+    op("bge", Branch, enc(0x63, 5, 0), "xx"),
+    op("bltu", Branch, enc(0x63, 6, 0), "xx"),
+    op("bgeu", Branch, enc(0x63, 7, 0), "xx"),
+    op("lb", Load, enc(0x03, 0, 0), "xx"),
+    op("lh", Load, enc(0x03, 1, 0), "xx"),
+    op("lw", Load, enc(0x03, 2, 0), "xx"),
+    op("ld", Load, enc(0x03, 3, 0), "xx"),
+    op("lbu", Load, enc(0x03, 4, 0), "xx"),
+    op("lhu", Load, enc(0x03, 5, 0), "xx"),
+    op("lwu", Load, enc(0x03, 6, 0), "xx"),
+    op("sb", Store, enc(0x23, 0, 0), "xx"),
+    op("sh", Store, enc(0x23, 1, 0), "xx"),
+    op("sw", Store, enc(0x23, 2, 0), "xx"),
+    op("sd", Store, enc(0x23, 3, 0), "xx"),
+    op("addi", I, enc(0x13, 0, 0), "xx"),
+    op("slti", I, enc(0x13, 2, 0), "xx"),
+    op("sltiu", I, enc(0x13, 3, 0), "xx"),
+    op("xori", I, enc(0x13, 4, 0), "xx"),
+    op("ori", I, enc(0x13, 6, 0), "xx"),
+    op("andi", I, enc(0x13, 7, 0), "xx"),
+    op("slli", Shift(64), enc(0x13, 1, 0), "xx"),
+    op("srli", Shift(64), enc(0x13, 5, 0), "xx"),
+    op("srai", Shift(64), enc(0x13, 5, 0x20), "xx"),
+    op("addiw", I, enc(0x1b, 0, 0), "xx"),
+    op("slliw", Shift(32), enc(0x1b, 1, 0), "xx"),
+    op("srliw", Shift(32), enc(0x1b, 5, 0), "xx"),
+    op("sraiw", Shift(32), enc(0x1b, 5, 0x20), "xx"),
+    op("add", R, enc(0x33, 0, 0), "xxx"),
+    op("sub", R, enc(0x33, 0, 0x20), "xxx"),
+    op("sll", R, enc(0x33, 1, 0), "xxx"),
+    op("slt", R, enc(0x33, 2, 0), "xxx"),
+    op("sltu", R, enc(0x33, 3, 0), "xxx"),
+    op("xor", R, enc(0x33, 4, 0), "xxx"),
+    op("srl", R, enc(0x33, 5, 0), "xxx"),
+    op("sra", R, enc(0x33, 5, 0x20), "xxx"),
+    op("or", R, enc(0x33, 6, 0), "xxx"),
+    op("and", R, enc(0x33, 7, 0), "xxx"),
+    op("addw", R, enc(0x3b, 0, 0), "xxx"),
+    op("subw", R, enc(0x3b, 0, 0x20), "xxx"),
+    op("sllw", R, enc(0x3b, 1, 0), "xxx"),
+    op("srlw", R, enc(0x3b, 5, 0), "xxx"),
+    op("sraw", R, enc(0x3b, 5, 0x20), "xxx"),
+    op("ecall", System, 0x73, ""),
+    op("ebreak", System, 0x0010_0073, ""),
+    // M
+    op("mul", R, enc(0x33, 0, 1), "xxx"),
+    op("mulh", R, enc(0x33, 1, 1), "xxx"),
+    op("mulhsu", R, enc(0x33, 2, 1), "xxx"),
+    op("mulhu", R, enc(0x33, 3, 1), "xxx"),
+    op("div", R, enc(0x33, 4, 1), "xxx"),
+    op("divu", R, enc(0x33, 5, 1), "xxx"),
+    op("rem", R, enc(0x33, 6, 1), "xxx"),
+    op("remu", R, enc(0x33, 7, 1), "xxx"),
+    op("mulw", R, enc(0x3b, 0, 1), "xxx"),
+    op("divw", R, enc(0x3b, 4, 1), "xxx"),
+    op("divuw", R, enc(0x3b, 5, 1), "xxx"),
+    op("remw", R, enc(0x3b, 6, 1), "xxx"),
+    op("remuw", R, enc(0x3b, 7, 1), "xxx"),
+    // F and D
+    op("flw", Load, enc(0x07, 2, 0), "fx"),
+    op("fld", Load, enc(0x07, 3, 0), "fx"),
+    op("fsw", Store, enc(0x27, 2, 0), "fx"),
+    op("fsd", Store, enc(0x27, 3, 0), "fx"),
+    op("fmadd.s", Fused, enc(0x43, 0, 0), "ffff"),
+    op("fmsub.s", Fused, enc(0x47, 0, 0), "ffff"),
+    op("fnmsub.s", Fused, enc(0x4b, 0, 0), "ffff"),
+    op("fnmadd.s", Fused, enc(0x4f, 0, 0), "ffff"),
+    op("fmadd.d", Fused, enc(0x43, 0, 1), "ffff"),
+    op("fmsub.d", Fused, enc(0x47, 0, 1), "ffff"),
+    op("fnmsub.d", Fused, enc(0x4b, 0, 1), "ffff"),
+    op("fnmadd.d", Fused, enc(0x4f, 0, 1), "ffff"),
+    op("fadd.s", RoundR, enc(0x53, 0, 0x00), "fff"),
+    op("fsub.s", RoundR, enc(0x53, 0, 0x04), "fff"),
+    op("fmul.s", RoundR, enc(0x53, 0, 0x08), "fff"),
+    op("fdiv.s", RoundR, enc(0x53, 0, 0x0c), "fff"),
+    op("fadd.d", RoundR, enc(0x53, 0, 0x01), "fff"),
+    op("fsub.d", RoundR, enc(0x53, 0, 0x05), "fff"),
+    op("fmul.d", RoundR, enc(0x53, 0, 0x09), "fff"),
+    op("fdiv.d", RoundR, enc(0x53, 0, 0x0d), "fff"),
+    op("fsqrt.s", RoundUnary, fp1(0x2c, 0), "ff"),
+    op("fsqrt.d", RoundUnary, fp1(0x2d, 0), "ff"),
+    op("fsgnj.s", R, enc(0x53, 0, 0x10), "fff"),
+    op("fsgnjn.s", R, enc(0x53, 1, 0x10), "fff"),
+    op("fsgnjx.s", R, enc(0x53, 2, 0x10), "fff"),
+    op("fsgnj.d", R, enc(0x53, 0, 0x11), "fff"),
+    op("fsgnjn.d", R, enc(0x53, 1, 0x11), "fff"),
+    op("fsgnjx.d", R, enc(0x53, 2, 0x11), "fff"),
+    op("fmin.s", R, enc(0x53, 0, 0x14), "fff"),
+    op("fmax.s", R, enc(0x53, 1, 0x14), "fff"),
+    op("fmin.d", R, enc(0x53, 0, 0x15), "fff"),
+    op("fmax.d", R, enc(0x53, 1, 0x15), "fff"),
+    op("fcvt.s.d", RoundUnary, fp1(0x20, 1), "ff"),
+    op("fcvt.d.s", RoundUnary, fp1(0x21, 0), "ff"),
+    op("feq.s", R, enc(0x53, 2, 0x50), "xff"),
+    op("flt.s", R, enc(0x53, 1, 0x50), "xff"),
+    op("fle.s", R, enc(0x53, 0, 0x50), "xff"),
+    op("feq.d", R, enc(0x53, 2, 0x51), "xff"),
+    op("flt.d", R, enc(0x53, 1, 0x51), "xff"),
+    op("fle.d", R, enc(0x53, 0, 0x51), "xff"),
+    op("fclass.s", Unary, fp1(0x70, 0) | 1 << 12, "xf"),
+    op("fclass.d", Unary, fp1(0x71, 0) | 1 << 12, "xf"),
+    op("fmv.x.w", Unary, fp1(0x70, 0), "xf"),
+    op("fmv.x.d", Unary, fp1(0x71, 0), "xf"),
+    op("fmv.w.x", Unary, fp1(0x78, 0), "fx"),
+    op("fmv.d.x", Unary, fp1(0x79, 0), "fx"),
+    op("fcvt.w.s", RoundUnary, fp1(0x60, 0), "xf"),
+    op("fcvt.wu.s", RoundUnary, fp1(0x60, 1), "xf"),
+    op("fcvt.l.s", RoundUnary, fp1(0x60, 2), "xf"),
+    op("fcvt.lu.s", RoundUnary, fp1(0x60, 3), "xf"),
+    op("fcvt.w.d", RoundUnary, fp1(0x61, 0), "xf"),
+    op("fcvt.wu.d", RoundUnary, fp1(0x61, 1), "xf"),
+    op("fcvt.l.d", RoundUnary, fp1(0x61, 2), "xf"),
+    op("fcvt.lu.d", RoundUnary, fp1(0x61, 3), "xf"),
+    op("fcvt.s.w", RoundUnary, fp1(0x68, 0), "fx"),
+    op("fcvt.s.wu", RoundUnary, fp1(0x68, 1), "fx"),
+    op("fcvt.s.l", RoundUnary, fp1(0x68, 2), "fx"),
+    op("fcvt.s.lu", RoundUnary, fp1(0x68, 3), "fx"),
+    op("fcvt.d.w", RoundUnary, fp1(0x69, 0), "fx"),
+    op("fcvt.d.wu", RoundUnary, fp1(0x69, 1), "fx"),
+    op("fcvt.d.l", RoundUnary, fp1(0x69, 2), "fx"),
+    op("fcvt.d.lu", RoundUnary, fp1(0x69, 3), "fx"),
+];
+
+/// Conversions that are always exact, the rounding mode defaults to `rne`
+/// rather than `dyn` for them.
+const EXACT: [&str; 3] = ["fcvt.d.s", "fcvt.d.w", "fcvt.d.wu"];
+
+#[derive(Debug, Clone, PartialEq)]
+enum Arg {
+    Reg(u32),
+    FReg(u32),
+    Imm(i64),
+    /// `imm(reg)`
+    Mem(i64, u32),
+    /// A label, or a name like a rounding mode.
+    Label(String),
+}
+
+impl Arg {
+    fn int(&self) -> Option<u32> {
+        match self {
+            Self::Reg(num) => Some(*num),
+            _ => None,
+        }
+    }
+}
+
+fn parse_int(text: &str) -> Option<i64> {
+    let (neg, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    let num = match digits.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok()?,
+        None => digits.parse::<u64>().ok()?,
+    } as i64;
+
+    Some(if neg { num.wrapping_neg() } else { num })
+}
+
+fn parse_reg(text: &str) -> Option<Arg> {
+    let numbered = |prefix: &str| {
+        text.strip_prefix(prefix)
+            .and_then(|num| num.parse::<u32>().ok())
+            .filter(|num| *num < 32)
+    };
+
+    if let Some(num) = INT_REGS.iter().position(|reg| *reg == text) {
+        return Some(Arg::Reg(num as u32));
+    }
+    if let Some(num) = FLOAT_REGS.iter().position(|reg| *reg == text) {
+        return Some(Arg::FReg(num as u32));
+    }
+
+    match text {
+        "fp" => Some(Arg::Reg(8)),
+        _ => numbered("x")
+            .map(Arg::Reg)
+            .or_else(|| numbered("f").map(Arg::FReg)),
+    }
+}
+
+fn fits(num: i64, bits: u32) -> bool {
+    let half = 1 << (bits - 1);
+    (-half..half).contains(&num)
+}
+
+fn i_imm(imm: i64) -> u32 {
+    (imm as u32 & 0xfff) << 20
+}
+
+fn s_imm(imm: i64) -> u32 {
+    let imm = imm as u32;
+    (imm >> 5 & 0x7f) << 25 | (imm & 0x1f) << 7
+}
+
+fn b_imm(imm: i64) -> u32 {
+    let imm = imm as u32;
+    (imm >> 12 & 1) << 31 | (imm >> 5 & 0x3f) << 25 | (imm >> 1 & 0xf) << 8 | (imm >> 11 & 1) << 7
+}
+
+fn j_imm(imm: i64) -> u32 {
+    let imm = imm as u32;
+    (imm >> 20 & 1) << 31
+        | (imm >> 1 & 0x3ff) << 21
+        | (imm >> 11 & 1) << 20
+        | (imm >> 12 & 0xff) << 12
+}
+
+/// Splits operands at the commas.
+fn split_args(text: &str) -> Vec<&str> {
+    if text.is_empty() {
+        return Vec::new();
+    }
+
+    text.split(',').map(str::trim).collect()
+}
+
+/**
+ * Instructions loading `value` into `rd`: `lui` and `addiw` for 32 bits
+ * values, and for the rest, the upper bits loaded the same way then shifted
+ * in place before adding the low 12 bits.
+ */
+fn load_imm(rd: u32, value: i64, words: &mut Vec<u32>) {
+    let lo = (value << 52) >> 52;
+
+    if i32::try_from(value).is_ok() {
+        let hi = (value.wrapping_sub(lo) >> 12) & 0xfffff;
+        if hi == 0 {
+            words.push(enc(0x13, 0, 0) | rd << 7 | i_imm(lo));
+            return;
+        }
+
+        words.push(0x37 | rd << 7 | (hi as u32) << 12);
+        if lo != 0 {
+            words.push(enc(0x1b, 0, 0) | rd << 7 | rd << 15 | i_imm(lo));
+        }
+        return;
+    }
+
+    let hi = value.wrapping_sub(lo) >> 12;
+    let zeros = hi.trailing_zeros();
+    load_imm(rd, hi >> zeros, words);
+    words.push(enc(0x13, 1, 0) | rd << 7 | rd << 15 | (12 + zeros) << 20);
+    if lo != 0 {
+        words.push(enc(0x13, 0, 0) | rd << 7 | rd << 15 | i_imm(lo));
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FixupKind {
+    Branch,
+    Jump,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Fixup {
+    /// Index of the instruction in the code.
+    index: usize,
+    target: String,
+    kind: FixupKind,
+}
+
+/// Position independent RV64 machine code, as `assemble_riscv` lays it out:
+/// little-endian instructions, and the offsets of the global symbols.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RiscvImage {
+    pub code: Vec<u8>,
+    pub symbols: HashMap<String, u64>,
+}
+
+/**
+ * Assembler for the syntax `emit_riscv` produces, the one of GNU as: the
+ * instructions of RV64IMFD, the usual pseudo-instructions, and the
+ * directives for symbols in `.text`. Every reference must resolve within the
+ * code, so that it's position independent: there are no relocations.
+ */
+struct Assembler {
+    words: Vec<u32>,
+    labels: HashMap<String, usize>,
+    globals: Vec<String>,
+    /// Definitions so far of each numeric label, like `1:`.
+    numeric: HashMap<String, usize>,
+    fixups: Vec<Fixup>,
+}
+
+impl Assembler {
+    fn new() -> Self {
+        Self {
+            words: Vec::new(),
+            labels: HashMap::new(),
+            globals: Vec::new(),
+            numeric: HashMap::new(),
+            fixups: Vec::new(),
+        }
+    }
+
+    /// Name of a label as written, numeric ones are made unique.
+    fn label_name(&self, text: &str) -> String {
+        let (num, dir) = text.split_at(text.len().saturating_sub(1));
+        if num.is_empty() || !num.chars().all(|chr| chr.is_ascii_digit()) {
+            return text.to_owned();
+        }
+
+        let count = self.numeric.get(num).copied().unwrap_or(0);
+        match dir {
+            "b" => format!("{}\u{1}{}", num, count.wrapping_sub(1)),
+            "f" => format!("{}\u{1}{}", num, count),
+            _ => text.to_owned(),
+        }
+    }
+
+    fn define(&mut self, name: &str) -> CodegenResult<()> {
+        let name = if name.chars().all(|chr| chr.is_ascii_digit()) {
+            let count = self.numeric.entry(name.to_owned()).or_insert(0);
+            *count += 1;
+            format!("{}\u{1}{}", name, *count - 1)
+        } else {
+            name.to_owned()
+        };
+
+        if self.labels.insert(name.clone(), self.words.len()).is_some() {
+            return Err(CodegenError::new(&format!("label {} defined twice", name)));
+        }
+        Ok(())
+    }
+
+    fn arg(&self, text: &str) -> CodegenResult<Arg> {
+        let bad = || CodegenError::new(&format!("bad operand {}", text));
+
+        if let Some(reg) = parse_reg(text) {
+            return Ok(reg);
+        }
+        if let Some(num) = parse_int(text) {
+            return Ok(Arg::Imm(num));
+        }
+
+        if let Some(open) = text.find('(') {
+            let imm = match text[..open].trim() {
+                "" => 0,
+                imm => parse_int(imm).ok_or_else(bad)?,
+            };
+            let base = text[open + 1..]
+                .strip_suffix(')')
+                .and_then(|reg| parse_reg(reg.trim()))
+                .and_then(|reg| reg.int())
+                .ok_or_else(bad)?;
+            return Ok(Arg::Mem(imm, base));
+        }
+
+        let valid = |chr: char| chr.is_ascii_alphanumeric() || chr == '_' || chr == '.';
+        if text.is_empty() || !text.chars().all(valid) {
+            return Err(bad());
+        }
+        Ok(Arg::Label(self.label_name(text)))
+    }
+
+    fn fixup(&mut self, target: &Arg, kind: FixupKind) -> CodegenResult<()> {
+        match target {
+            Arg::Label(name) => {
+                self.fixups.push(Fixup {
+                    index: self.words.len(),
+                    target: name.clone(),
+                    kind,
+                });
+                Ok(())
+            }
+            _ => Err(CodegenError::new("expected a label")),
+        }
+    }
+
+    /// Rewrites pseudo-instructions to the instruction they stand for, `None`
+    /// for the ones that aren't.
+    fn pseudo(mnemonic: &str, args: &[Arg]) -> Option<(&'static str, Vec<Arg>)> {
+        use Arg::{FReg, Mem, Reg};
+
+        let zero = Reg(ZERO);
+        let ra = Reg(RA);
+        let inst = match (mnemonic, args) {
+            ("nop", []) => ("addi", vec![zero.clone(), zero, Arg::Imm(0)]),
+            ("mv", [rd, rs]) => ("addi", vec![rd.clone(), rs.clone(), Arg::Imm(0)]),
+            ("not", [rd, rs]) => ("xori", vec![rd.clone(), rs.clone(), Arg::Imm(-1)]),
+            ("neg", [rd, rs]) => ("sub", vec![rd.clone(), zero, rs.clone()]),
+            ("negw", [rd, rs]) => ("subw", vec![rd.clone(), zero, rs.clone()]),
+            ("sext.w", [rd, rs]) => ("addiw", vec![rd.clone(), rs.clone(), Arg::Imm(0)]),
+            ("seqz", [rd, rs]) => ("sltiu", vec![rd.clone(), rs.clone(), Arg::Imm(1)]),
+            ("snez", [rd, rs]) => ("sltu", vec![rd.clone(), zero, rs.clone()]),
+            ("sltz", [rd, rs]) => ("slt", vec![rd.clone(), rs.clone(), zero]),
+            ("sgtz", [rd, rs]) => ("slt", vec![rd.clone(), zero, rs.clone()]),
+            ("beqz", [rs, label]) => ("beq", vec![rs.clone(), zero, label.clone()]),
+            ("bnez", [rs, label]) => ("bne", vec![rs.clone(), zero, label.clone()]),
+            ("blez", [rs, label]) => ("bge", vec![zero, rs.clone(), label.clone()]),
+            ("bgez", [rs, label]) => ("bge", vec![rs.clone(), zero, label.clone()]),
+            ("bltz", [rs, label]) => ("blt", vec![rs.clone(), zero, label.clone()]),
+            ("bgtz", [rs, label]) => ("blt", vec![zero, rs.clone(), label.clone()]),
+            ("bgt", [rs, rt, label]) => ("blt", vec![rt.clone(), rs.clone(), label.clone()]),
+            ("ble", [rs, rt, label]) => ("bge", vec![rt.clone(), rs.clone(), label.clone()]),
+            ("bgtu", [rs, rt, label]) => ("bltu", vec![rt.clone(), rs.clone(), label.clone()]),
+            ("bleu", [rs, rt, label]) => ("bgeu", vec![rt.clone(), rs.clone(), label.clone()]),
+            ("j", [label]) => ("jal", vec![zero, label.clone()]),
+            ("jal", [label @ Arg::Label(_)]) => ("jal", vec![ra, label.clone()]),
+            ("jr", [Reg(rs)]) => ("jalr", vec![zero, Mem(0, *rs)]),
+            ("jalr", [Reg(rs)]) => ("jalr", vec![ra, Mem(0, *rs)]),
+            ("ret", []) => ("jalr", vec![zero, Mem(0, RA)]),
+            ("fmv.s", [rd @ FReg(_), rs]) => ("fsgnj.s", vec![rd.clone(), rs.clone(), rs.clone()]),
+            ("fneg.s", [rd @ FReg(_), rs]) => {
+                ("fsgnjn.s", vec![rd.clone(), rs.clone(), rs.clone()])
+            }
+            ("fabs.s", [rd @ FReg(_), rs]) => {
+                ("fsgnjx.s", vec![rd.clone(), rs.clone(), rs.clone()])
+            }
+            ("fmv.d", [rd @ FReg(_), rs]) => ("fsgnj.d", vec![rd.clone(), rs.clone(), rs.clone()]),
+            ("fneg.d", [rd @ FReg(_), rs]) => {
+                ("fsgnjn.d", vec![rd.clone(), rs.clone(), rs.clone()])
+            }
+            ("fabs.d", [rd @ FReg(_), rs]) => {
+                ("fsgnjx.d", vec![rd.clone(), rs.clone(), rs.clone()])
+            }
+            _ => return None,
+        };
+
+        Some(inst)
+    }
+
+    fn inst(&mut self, mnemonic: &str, args: &[Arg]) -> CodegenResult<()> {
+        let bad = || CodegenError::new(&format!("bad operands for {}", mnemonic));
+
+        if mnemonic == "li" {
+            return match args {
+                [Arg::Reg(rd), Arg::Imm(value)] => {
+                    load_imm(*rd, *value, &mut self.words);
+                    Ok(())
+                }
+                _ => Err(bad()),
+            };
+        }
+
+        let (mnemonic, args) = match Self::pseudo(mnemonic, args) {
+            Some((mnemonic, args)) => (mnemonic, args),
+            None => (mnemonic, args.to_vec()),
+        };
+        let op = OPS
+            .iter()
+            .find(|op| op.name == mnemonic)
+            .ok_or_else(|| CodegenError::new(&format!("unknown instruction {}", mnemonic)))?;
+
+        // Register operands, checked against the files they belong to
+        let mut regs = Vec::new();
+        let mut files = op.regs.chars();
+        for arg in &args {
+            let (num, float) = match arg {
+                Arg::Reg(num) => (*num, false),
+                Arg::FReg(num) => (*num, true),
+                Arg::Mem(_, base) => (*base, false),
+                _ => continue,
+            };
+            match files.next() {
+                Some(file) if (file == 'f') == float => regs.push(num),
+                _ => return Err(bad()),
+            }
+        }
+        if files.next().is_some() {
+            return Err(bad());
+        }
+
+        let rounding = |arg: Option<&Arg>| match arg {
+            None if EXACT.contains(&op.name) => Ok(0),
+            None => Ok(7 << 12),
+            Some(Arg::Label(name)) => ROUNDING
+                .iter()
+                .find(|(mode, _)| mode == name)
+                .map(|(_, rm)| *rm << 12)
+                .ok_or_else(bad),
+            Some(_) => Err(bad()),
+        };
+
+        let word = match (op.format, args.as_slice(), regs.as_slice()) {
+            (R, [_, _, _], [rd, rs1, rs2]) => op.bits | rd << 7 | rs1 << 15 | rs2 << 20,
+            (RoundR, [_, _, _, rm @ ..], [rd, rs1, rs2]) if rm.len() < 2 => {
+                op.bits | rd << 7 | rs1 << 15 | rs2 << 20 | rounding(rm.first())?
+            }
+            (Unary, [_, _], [rd, rs1]) => op.bits | rd << 7 | rs1 << 15,
+            (RoundUnary, [_, _, rm @ ..], [rd, rs1]) if rm.len() < 2 => {
+                op.bits | rd << 7 | rs1 << 15 | rounding(rm.first())?
+            }
+            (Fused, [_, _, _, _, rm @ ..], [rd, rs1, rs2, rs3]) if rm.len() < 2 => {
+                op.bits | rd << 7 | rs1 << 15 | rs2 << 20 | rs3 << 27 | rounding(rm.first())?
+            }
+            (I, [_, _, Arg::Imm(imm)], [rd, rs1]) if fits(*imm, 12) => {
+                op.bits | rd << 7 | rs1 << 15 | i_imm(*imm)
+            }
+            (Shift(bound), [_, _, Arg::Imm(shamt)], [rd, rs1]) if (0..bound).contains(shamt) => {
+                op.bits | rd << 7 | rs1 << 15 | (*shamt as u32) << 20
+            }
+            (Load, [_, Arg::Mem(imm, _)], [rd, rs1]) | (Jalr, [_, Arg::Mem(imm, _)], [rd, rs1])
+                if fits(*imm, 12) =>
+            {
+                op.bits | rd << 7 | rs1 << 15 | i_imm(*imm)
+            }
+            (Store, [_, Arg::Mem(imm, _)], [rs2, rs1]) if fits(*imm, 12) => {
+                op.bits | rs1 << 15 | rs2 << 20 | s_imm(*imm)
+            }
+            (Branch, [_, _, target], [rs1, rs2]) => {
+                self.fixup(target, FixupKind::Branch)?;
+                op.bits | rs1 << 15 | rs2 << 20
+            }
+            (Upper, [_, Arg::Imm(imm)], [rd]) if (-(1 << 19)..1 << 20).contains(imm) => {
+                op.bits | rd << 7 | (*imm as u32 & 0xfffff) << 12
+            }
+            (Jump, [_, target], [rd]) => {
+                self.fixup(target, FixupKind::Jump)?;
+                op.bits | rd << 7
+            }
+            (System, [], []) => op.bits,
+            _ => return Err(bad()),
+        };
+
+        self.words.push(word);
+        Ok(())
+    }
+
+    fn directive(&mut self, name: &str, args: &[&str]) -> CodegenResult<()> {
+        match (name, args) {
+            (".text", []) | (".type", [_, _]) | (".size", [_, _]) => {}
+            (".globl", [name]) => self.globals.push((*name).to_owned()),
+            // Only asks for a non-executable stack
+            (".section", [".note.GNU-stack", ..]) => {}
+            _ => {
+                return Err(CodegenError::new(&format!(
+                    "unsupported directive {} {}",
+                    name,
+                    args.join(", ")
+                )))
+            }
+        }
+
+        Ok(())
+    }
+
+    fn line(&mut self, line: &str) -> CodegenResult<()> {
+        let line = match line.find('#') {
+            Some(pos) => &line[..pos],
+            None => line,
+        };
+        let mut line = line.trim();
+        if let Some(colon) = line.find(':').filter(|colon| {
+            let label = &line[..*colon];
+            !label.is_empty() && !label.contains(char::is_whitespace)
+        }) {
+            self.define(&line[..colon])?;
+            line = line[colon + 1..].trim();
+        }
+        if line.is_empty() {
+            return Ok(());
+        }
+
+        let (name, rest) = match line.find(char::is_whitespace) {
+            Some(pos) => (&line[..pos], line[pos..].trim()),
+            None => (line, ""),
+        };
+        let args = split_args(rest);
+        if name.starts_with('.') {
+            return self.directive(name, &args);
+        }
+
+        let args = args
+            .iter()
+            .map(|arg| self.arg(arg))
+            .collect::<CodegenResult<Vec<_>>>()?;
+        self.inst(name, &args)
+    }
+
+    /// Patches the offsets of branches and jumps to their labels.
+    fn finish(mut self) -> CodegenResult<RiscvImage> {
+        for fixup in std::mem::take(&mut self.fixups) {
+            let pos = *self
+                .labels
+                .get(&fixup.target)
+                .ok_or_else(|| CodegenError::new(&format!("undefined label {}", fixup.target)))?;
+            let offset = 4 * (pos as i64 - fixup.index as i64);
+
+            let (bits, imm) = match fixup.kind {
+                FixupKind::Branch => (13, b_imm(offset)),
+                FixupKind::Jump => (21, j_imm(offset)),
+            };
+            if !fits(offset, bits) {
+                return Err(CodegenError::new(&format!("{} is too far", fixup.target)));
+            }
+            self.words[fixup.index] |= imm;
+        }
+
+        let globals: HashSet<_> = self.globals.iter().collect();
+        let mut symbols = HashMap::new();
+        for name in globals {
+            let pos = self
+                .labels
+                .get(name)
+                .ok_or_else(|| CodegenError::new(&format!("undefined symbol {}", name)))?;
+            symbols.insert(name.clone(), 4 * *pos as u64);
+        }
+
+        Ok(RiscvImage {
+            code: self
+                .words
+                .iter()
+                .flat_map(|word| word.to_le_bytes())
+                .collect(),
+            symbols,
+        })
+    }
+}
+
+/// Assembles `asm`, as emitted by `emit_riscv`, into machine code.
+pub fn assemble_riscv(asm: &str) -> CodegenResult<RiscvImage> {
+    let mut assembler = Assembler::new();
+    for (pos, line) in asm.lines().enumerate() {
+        assembler
+            .line(line)
+            .map_err(|error| CodegenError::new(&format!("line {}: {}", pos + 1, error)))?;
+    }
+
+    assembler.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(asm: &str) -> Vec<u32> {
+        assemble_riscv(asm)
+            .unwrap()
+            .code
+            .chunks(4)
+            .map(|word| u32::from_le_bytes([word[0], word[1], word[2], word[3]]))
+            .collect()
+    }
+
+    #[test]
+    fn encodings() {
+        // As encoded by the LLVM assembler
+        let cases = [
+            ("lui a0, 0xfffff", 0xfffff537),
+            ("jalr a0, 12(a1)", 0x00c58567),
+            ("lb a0, -1(sp)", 0xfff10503),
+            ("lw a0, 4(sp)", 0x00412503),
+            ("lbu a0, 2047(sp)", 0x7ff14503),
+            ("lhu a0, -2048(sp)", 0x80015503),
+            ("sb a0, -1(sp)", 0xfea10fa3),
+            ("sd a0, -2048(sp)", 0x80a13023),
+            ("addi a0, a1, -5", 0xffb58513),
+            ("sltiu a0, a1, 5", 0x0055b513),
+            ("andi a0, a1, 255", 0x0ff5f513),
+            ("slli a0, a1, 63", 0x03f59513),
+            ("srai a0, a1, 1", 0x4015d513),
+            ("sraiw a0, a1, 3", 0x4035d51b),
+            ("sub a0, a1, a2", 0x40c58533),
+            ("slt a0, a1, a2", 0x00c5a533),
+            ("sraw a0, a1, a2", 0x40c5d53b),
+            ("ebreak", 0x00100073),
+            ("mul a0, a1, a2", 0x02c58533),
+            ("mulhsu a0, a1, a2", 0x02c5a533),
+            ("div a0, a1, a2", 0x02c5c533),
+            ("remuw a0, a1, a2", 0x02c5f53b),
+            ("flw fa0, 4(sp)", 0x00412507),
+            ("fsd fa0, 8(sp)", 0x00a13427),
+            ("fmadd.d fa0, fa1, fa2, fa3, rtz", 0x6ac59543),
+            ("fnmsub.s fa0, fa1, fa2, fa3", 0x68c5f54b),
+            ("fadd.d fa0, fa1, fa2, rne", 0x02c58553),
+            ("fdiv.d fa0, fa1, fa2", 0x1ac5f553),
+            ("fsqrt.s fa0, fa1", 0x5805f553),
+            ("fsgnjx.d fa0, fa1, fa2", 0x22c5a553),
+            ("fmax.s fa0, fa1, fa2", 0x28c59553),
+            ("fcvt.s.d fa0, fa1", 0x4015f553),
+            ("fcvt.d.s fa0, fa1", 0x42058553),
+            ("flt.d a0, fa1, fa2", 0xa2c59553),
+            ("fclass.d a0, fa1", 0xe2059553),
+            ("fmv.x.w a0, fa1", 0xe0058553),
+            ("fmv.d.x fa0, a1", 0xf2058553),
+            ("fcvt.l.d a0, fa1, rmm", 0xc225c553),
+            ("fcvt.wu.d a0, fa1, rup", 0xc215b553),
+            ("fcvt.s.w fa0, a1", 0xd005f553),
+            ("fcvt.d.w fa0, a1", 0xd2058553),
+            ("fcvt.d.lu fa0, a1", 0xd235f553),
+            ("neg a0, a1", 0x40b00533),
+            ("sext.w a0, a1", 0x0005851b),
+            ("seqz a0, a1", 0x0015b513),
+            ("snez a0, a1", 0x00b03533),
+            ("ret", 0x00008067),
+            ("fneg.d fa0, fa1", 0x22b59553),
+            ("ld x5, 0(fp)", 0x00043283),
+            ("fsd f31, 0(x2)", 0x01f13027),
+        ];
+
+        for (line, word) in &cases {
+            assert_eq!(words(line), vec![*word], "{}", line);
+        }
+    }
+
+    /// Value `li` leaves in its register.
+    fn eval(words: &[u32]) -> i64 {
+        let mut value = 0i64;
+        for word in words {
+            let imm = (*word as i32 >> 20) as i64;
+            value = match word & 0x707f {
+                0x0013 if word >> 15 & 31 == 0 => imm,
+                0x0013 => value.wrapping_add(imm),
+                0x1013 => value << (word >> 20 & 63),
+                0x001b => value.wrapping_add(imm) as i32 as i64,
+                _ if word & 0x7f == 0x37 => (word & 0xffff_f000) as i32 as i64,
+                _ => panic!("Unexpected instruction {:#x}", word),
+            };
+        }
+        value
+    }
+
+    #[test]
+    fn immediates_and_labels() {
+        for value in &[
+            0,
+            -1,
+            2047,
+            -2048,
+            4096,
+            0x7fff_f800,
+            0x7fff_ffff,
+            i32::MIN as i64,
+            0x8000_0000,
+            0x1234_5678_9abc_def0,
+            -0x1234_5678_9abc_def0,
+            i64::MAX,
+            i64::MIN,
+            1.5f64.to_bits() as i64,
+        ] {
+            let words = words(&format!("li t0, {}", value));
+            assert_eq!(eval(&words), *value, "{:#x}", value);
+            assert!(words.len() <= 8);
+        }
+
+        let image = assemble_riscv(
+            "\t.text\n\t.globl f\n\t.type f, @function\nf:\n\
+             1:\n\tbnez t0, 1b\n\tbeqz t0, 1f\n\tj .Lend\n1:\n\tjal f\n\
+             .Lend:\n\tret\n\t.size f, .-f\n\
+             \t.globl g\ng:\n\tj f\n\
+             \t.section .note.GNU-stack,\"\",@progbits\n",
+        )
+        .unwrap();
+        assert_eq!(image.symbols["f"], 0);
+        assert_eq!(image.symbols["g"], 20);
+        assert!(!image.symbols.contains_key(".Lend"));
+
+        let code: Vec<u32> = image
+            .code
+            .chunks(4)
+            .map(|word| u32::from_le_bytes([word[0], word[1], word[2], word[3]]))
+            .collect();
+        assert_eq!(code[0], 0x00029063); // bnez t0, .
+        assert_eq!(code[1], 0x00028463); // beqz t0, .+8
+        assert_eq!(code[2], 0x0080006f); // j .+8
+        assert_eq!(code[3], 0xff5ff0ef); // jal ra, .-12
+        assert_eq!(code[5], 0xfedff06f); // j .-20
+
+        assert!(assemble_riscv("\tj .Lnowhere\n").is_err());
+        assert!(assemble_riscv("\taddi a0, a1, 2048\n").is_err());
+        assert!(assemble_riscv("\tadd a0, fa1, a2\n").is_err());
+        assert!(assemble_riscv("\tfadd.d fa0, fa1, fa2, up\n").is_err());
+        assert!(assemble_riscv("\tslliw a0, a1, 32\n").is_err());
+        assert!(assemble_riscv("\t.globl h\n").is_err());
+    }
+}
@@ -0,0 +1,781 @@
+use std::collections::BTreeMap;
+
+use crate::codegen::{frame_size, CodegenResult};
+use crate::error::CodegenError;
+use crate::interp::Value;
+use crate::ir::{Cfg, DomTree, Function, Inst, Module, Opcode, Operand};
+use crate::sym::{DataLayout, Type};
+
+/// Linear memory of the module, in 64 KiB pages. The frames of functions with
+/// arrays are stacked downwards from the top.
+const PAGES: usize = 16;
+
+fn wasm_type(tp: &Type) -> CodegenResult<&'static str> {
+    match tp {
+        Type::Int32 | Type::Char | Type::Bool => Ok("i32"),
+        Type::Int64 => Ok("i64"),
+        Type::Flt32 => Ok("f32"),
+        Type::Flt64 => Ok("f64"),
+        tp => Err(CodegenError::new(&format!("no wasm value type for {}", tp))),
+    }
+}
+
+fn name(operand: &Operand) -> String {
+    match operand {
+        Operand::Ident(ident) => format!("${}_{}", ident.id, ident.offset),
+        Operand::Temp(temp) => format!("$t{}", temp.id),
+        Operand::Cons(_) => unreachable!(),
+    }
+}
+
+/// Instructions that convert the value on top of the stack from `from` to
+/// `to`, like `Value::cast`.
+fn cast(from: &Type, to: &Type) -> CodegenResult<Vec<&'static str>> {
+    if from == to {
+        return Ok(vec![]);
+    }
+
+    // Everything goes through `i64` or `f64`, as in the VM
+    let mut insts = match (wasm_type(from)?, from) {
+        ("i32", Type::Int32) => vec!["i64.extend_i32_s"],
+        ("i32", _) => vec!["i64.extend_i32_u"],
+        ("f32", _) => vec!["f64.promote_f32"],
+        _ => vec![],
+    };
+
+    let float = |tp: &Type| matches!(tp, Type::Flt32 | Type::Flt64);
+    match (float(from), float(to)) {
+        (false, true) => insts.push("f64.convert_i64_s"),
+        (true, false) => insts.push("i64.trunc_sat_f64_s"),
+        _ => {}
+    }
+
+    insts.extend(match to {
+        Type::Int32 => vec!["i32.wrap_i64"],
+        Type::Char => vec!["i32.wrap_i64", "i32.const 255", "i32.and"],
+        Type::Flt32 => vec!["f32.demote_f64"],
+        Type::Bool => return Err(CodegenError::new(&format!("cannot cast {} to bool", from))),
+        _ => vec![],
+    });
+
+    Ok(insts)
+}
+
+/// Enclosing constructs of the code being emitted, innermost last.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Context {
+    /// `block` that ends right before the code of a merge block.
+    Block(usize),
+    /// `loop` that starts with the code of a loop header.
+    Loop(usize),
+    If,
+}
+
+/**
+ * Emits one function. Blocks are placed following "Beyond Relooper"
+ * (Ramsey): the code of a block is nested in the code of its immediate
+ * dominator, loop headers open a `loop` that back edges branch to, and
+ * blocks reached by more than one forward edge (merge blocks) come right
+ * after a `block` that the edges branch out of. Every other block is only
+ * reached from its dominator, so its code goes right where the branch is.
+ */
+struct Writer<'a> {
+    module: &'a Module,
+    func: &'a Function,
+    cfg: Cfg,
+    dom: DomTree,
+    /// Position of every reachable block in reverse postorder.
+    order: Vec<Option<usize>>,
+    /// Bytes of linear memory the arrays of `func` take.
+    frame: usize,
+    out: String,
+    depth: usize,
+}
+
+impl<'a> Writer<'a> {
+    fn line(&mut self, line: &str) {
+        for _ in 0..self.depth {
+            self.out.push_str("  ");
+        }
+
+        self.out.push_str(line);
+        self.out.push('\n');
+    }
+
+    fn lines(&mut self, lines: Vec<&str>) {
+        lines.into_iter().for_each(|line| self.line(line));
+    }
+
+    fn is_forward(&self, from: usize, to: usize) -> bool {
+        self.order[from] < self.order[to]
+    }
+
+    fn is_merge(&self, block: usize) -> bool {
+        let preds = &self.cfg.blocks[block].preds;
+        preds
+            .iter()
+            .filter(|&&pred| self.order[pred].is_some() && self.is_forward(pred, block))
+            .count()
+            > 1
+    }
+
+    fn is_loop_header(&self, block: usize) -> bool {
+        let preds = &self.cfg.blocks[block].preds;
+        preds
+            .iter()
+            .any(|&pred| self.order[pred].is_some() && !self.is_forward(pred, block))
+    }
+
+    /// Whether every back edge goes to a block that dominates its source,
+    /// which is what makes the nesting possible.
+    fn is_reducible(&self) -> bool {
+        self.cfg.blocks.iter().enumerate().all(|(id, block)| {
+            self.order[id].is_none()
+                || block
+                    .succs
+                    .iter()
+                    .all(|&succ| self.is_forward(id, succ) || self.dom.dominates(succ, id))
+        })
+    }
+
+    fn push(&mut self, operand: &Operand, to: &Type) -> CodegenResult<()> {
+        let from = operand.get_tp();
+        let line = match operand {
+            Operand::Cons(cons) => match Value::from_cons(cons) {
+                Value::Int32(num) => format!("i32.const {}", num),
+                Value::Int64(num) => format!("i64.const {}", num),
+                Value::Flt32(num) => format!("f32.const {:?}", num),
+                Value::Flt64(num) => format!("f64.const {:?}", num),
+                Value::Char(chr) => format!("i32.const {}", chr as u32),
+                Value::Bool(value) => format!("i32.const {}", value as i32),
+                value => {
+                    return Err(CodegenError::new(&format!(
+                        "no wasm constant for {}",
+                        value
+                    )))
+                }
+            },
+            operand => format!("local.get {}", name(operand)),
+        };
+
+        self.line(&line);
+        let insts = cast(&from, to)?;
+        self.lines(insts);
+        Ok(())
+    }
+
+    /// Stores the value on top of the stack, of type `tp`, into `dst`.
+    fn set(&mut self, dst: &Operand, tp: &Type) -> CodegenResult<()> {
+        let insts = cast(tp, &dst.get_tp())?;
+        self.lines(insts);
+        self.line(&format!("local.set {}", name(dst)));
+        Ok(())
+    }
+
+    /// Traps unless `0 <= index < len`, and leaves `index` in `$index`.
+    fn check(&mut self, index: &Operand, len: usize) -> CodegenResult<()> {
+        self.push(index, &Type::Int64)?;
+        self.line("local.tee $index");
+        self.line(&format!("i64.const {}", len));
+        self.lines(vec!["i64.ge_u", "if", "  unreachable", "end"]);
+        Ok(())
+    }
+
+    /// Pushes the address of `array[index]`, trapping when it's out of bounds.
+    /// Returns the element type and the offset of the array in the frame.
+    fn address(&mut self, array: &Operand, index: &Operand) -> CodegenResult<(Type, usize)> {
+        let (of, size, offset) = match array {
+            Operand::Ident(ident) => match &ident.tp {
+                tp @ Type::Array { .. } => (tp.get_scalar().clone(), tp.get_len(), ident.offset),
+                _ => return Err(CodegenError::new(&format!("{} is not an array", array))),
+            },
+            _ => return Err(CodegenError::new(&format!("{} is not an array", array))),
+        };
+
+        self.check(index, size)?;
+        self.line("local.get $fp");
+        self.lines(vec!["local.get $index", "i32.wrap_i64"]);
+        let stride = of.layout(&DataLayout::ILP32).stride();
+        self.line(&format!("i32.const {}", stride));
+        self.lines(vec!["i32.mul", "i32.add"]);
+
+        Ok((of, offset))
+    }
+
+    /// Pops the frame of the function off the stack of linear memory.
+    fn leave(&mut self) {
+        if self.frame > 0 {
+            self.line("local.get $fp");
+            self.line(&format!("i32.const {}", self.frame));
+            self.lines(vec!["i32.add", "global.set $sp"]);
+        }
+    }
+
+    fn binary(
+        &mut self,
+        op: Opcode,
+        dst: &Operand,
+        lhs: &Operand,
+        rhs: &Operand,
+    ) -> CodegenResult<()> {
+        let tp = match lhs.get_tp().upcast(&rhs.get_tp()) {
+            Some(tp) => tp,
+            // Booleans can only be compared
+            None => {
+                self.push(lhs, &lhs.get_tp())?;
+                self.push(rhs, &rhs.get_tp())?;
+                self.line(if op == Opcode::Eq { "i32.eq" } else { "i32.ne" });
+                return self.set(dst, &Type::Bool);
+            }
+        };
+
+        let (name, signed) = match op {
+            Opcode::Add => ("add", false),
+            Opcode::Sub => ("sub", false),
+            Opcode::Mul => ("mul", false),
+            Opcode::Div => ("div", true),
+            Opcode::Lt => ("lt", true),
+            Opcode::Le => ("le", true),
+            Opcode::Gt => ("gt", true),
+            Opcode::Ge => ("ge", true),
+            Opcode::Eq => ("eq", false),
+            Opcode::Ne => ("ne", false),
+        };
+
+        // Floats are computed in `f64` and integers are divided in `i64`, as
+        // in the VM; the rest wraps around the same in the upcasted type
+        let (work, suffix) = match tp {
+            Type::Flt32 | Type::Flt64 => (Type::Flt64, ""),
+            _ if op == Opcode::Div => (Type::Int64, "_s"),
+            _ => (tp.clone(), if signed { "_s" } else { "" }),
+        };
+
+        self.push(lhs, &work)?;
+        self.push(rhs, &work)?;
+        self.line(&format!("{}.{}{}", wasm_type(&work)?, name, suffix));
+
+        if op.is_rel() {
+            return self.set(dst, &Type::Bool);
+        }
+
+        let insts = match tp {
+            Type::Char if work == Type::Char => vec!["i32.const 255", "i32.and"],
+            _ => cast(&work, &tp)?,
+        };
+        self.lines(insts);
+        self.set(dst, &tp)
+    }
+
+    fn call(&mut self, insts: &[Inst], pos: usize) -> CodegenResult<()> {
+        let (dst, func, nargs) = match &insts[pos] {
+            Inst::Call { dst, func, nargs } => (dst, func, *nargs),
+            _ => unreachable!(),
+        };
+
+        let callee = self
+            .module
+            .get(func)
+            .filter(|callee| callee.params.len() == nargs)
+            .ok_or_else(|| CodegenError::new(&format!("bad call to {}", func)))?;
+
+        // Arguments go on the stack in order, right before the call
+        let params = pos
+            .checked_sub(nargs)
+            .map(|start| &insts[start..pos])
+            .filter(|params| params.iter().all(|inst| matches!(inst, Inst::Param { .. })))
+            .ok_or_else(|| {
+                CodegenError::new(&format!(
+                    "arguments of {} aren't right before the call",
+                    func
+                ))
+            })?;
+
+        for (inst, param) in params.iter().zip(&callee.params) {
+            if let Inst::Param { src } = inst {
+                self.push(src, &param.tp)?;
+            }
+        }
+
+        self.line(&format!("call ${}", func));
+        match (dst, &callee.ret) {
+            (Some(dst), Some(ret)) => self.set(dst, ret),
+            (None, Some(_)) => {
+                self.line("drop");
+                Ok(())
+            }
+            (None, None) => Ok(()),
+            (Some(_), None) => Err(CodegenError::new(&format!(
+                "{} doesn't return a value",
+                func
+            ))),
+        }
+    }
+
+    fn inst(&mut self, insts: &[Inst], pos: usize) -> CodegenResult<()> {
+        match &insts[pos] {
+            Inst::Binary { op, dst, lhs, rhs } => self.binary(*op, dst, lhs, rhs)?,
+
+            Inst::Inv { dst, src } => {
+                let tp = Type::Int64
+                    .upcast(&src.get_tp())
+                    .ok_or_else(|| CodegenError::new(&format!("cannot negate {}", src)))?;
+
+                if tp == Type::Int64 {
+                    self.line("i64.const 0");
+                    self.push(src, &tp)?;
+                    self.line("i64.sub");
+                } else {
+                    self.push(src, &tp)?;
+                    self.line(&format!("{}.neg", wasm_type(&tp)?));
+                }
+
+                self.set(dst, &tp)?;
+            }
+
+            Inst::Not { dst, src } => {
+                self.push(src, &Type::Bool)?;
+                self.line("i32.eqz");
+                self.set(dst, &Type::Bool)?;
+            }
+
+            Inst::Mov { dst, src } => {
+                self.push(src, &dst.get_tp())?;
+                self.line(&format!("local.set {}", name(dst)));
+            }
+
+            Inst::Idx { dst, index, array } => {
+                let (of, offset) = self.address(array, index)?;
+                let load = match of {
+                    Type::Char | Type::Bool => "i32.load8_u".to_owned(),
+                    _ => format!("{}.load", wasm_type(&of)?),
+                };
+
+                self.line(&format!("{} offset={}", load, offset));
+                self.set(dst, &of)?;
+            }
+
+            Inst::Sto { array, index, src } => {
+                let (of, offset) = self.address(array, index)?;
+                self.push(src, &of)?;
+
+                let store = match of {
+                    Type::Char | Type::Bool => "i32.store8".to_owned(),
+                    _ => format!("{}.store", wasm_type(&of)?),
+                };
+                self.line(&format!("{} offset={}", store, offset));
+            }
+
+            Inst::Chk { index, len } => self.check(index, *len)?,
+
+            Inst::Call { .. } => self.call(insts, pos)?,
+
+            // Labels are gone, jumps and returns end blocks, and arguments
+            // are passed by the call
+            Inst::Label(_)
+            | Inst::Jmp { .. }
+            | Inst::JmpT { .. }
+            | Inst::JmpF { .. }
+            | Inst::Ret { .. }
+            | Inst::Param { .. } => {}
+
+            Inst::Phi { .. } => {
+                return Err(CodegenError::new(
+                    "cannot lower phi, translate out of SSA first",
+                ))
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Code for `block` and every block it dominates.
+    fn tree(&mut self, block: usize, context: &mut Vec<Context>) -> CodegenResult<()> {
+        let mut merges: Vec<usize> = self
+            .dom
+            .children(block)
+            .iter()
+            .cloned()
+            .filter(|&child| self.is_merge(child))
+            .collect();
+        merges.sort_by_key(|&child| self.order[child]);
+
+        if !self.is_loop_header(block) {
+            return self.within(block, &merges, context);
+        }
+
+        self.line("loop");
+        self.depth += 1;
+        context.push(Context::Loop(block));
+        self.within(block, &merges, context)?;
+        context.pop();
+        self.depth -= 1;
+        self.line("end");
+        Ok(())
+    }
+
+    /// Code for `block`, followed by its dominated merge blocks: the last one
+    /// goes after a `block` with the rest inside.
+    fn within(
+        &mut self,
+        block: usize,
+        merges: &[usize],
+        context: &mut Vec<Context>,
+    ) -> CodegenResult<()> {
+        if let Some((&merge, rest)) = merges.split_last() {
+            self.line("block");
+            self.depth += 1;
+            context.push(Context::Block(merge));
+            self.within(block, rest, context)?;
+            context.pop();
+            self.depth -= 1;
+            self.line("end");
+            return self.tree(merge, context);
+        }
+
+        if block == self.cfg.exit {
+            // Falling off the end
+            if self.func.ret.is_some() {
+                self.line("unreachable");
+            } else {
+                self.leave();
+                self.line("return");
+            }
+
+            return Ok(());
+        }
+
+        let insts = self.cfg.blocks[block].insts.clone();
+        for pos in 0..insts.len() {
+            self.inst(&insts, pos)?;
+        }
+
+        let next = block + 1;
+        match insts.last() {
+            // Returning without a result traps, as it fails in the VM
+            Some(Inst::Ret { src: None }) if self.func.ret.is_some() => {
+                self.line("unreachable");
+                Ok(())
+            }
+
+            Some(Inst::Ret { src }) => {
+                if let (Some(src), Some(ret)) = (src, &self.func.ret) {
+                    self.push(src, ret)?;
+                }
+
+                self.leave();
+                self.line("return");
+                Ok(())
+            }
+
+            Some(Inst::Jmp { label }) => {
+                let target = self.target(*label)?;
+                self.branch(block, target, context)
+            }
+
+            Some(Inst::JmpT { label, test }) | Some(Inst::JmpF { label, test }) => {
+                let target = self.target(*label)?;
+                self.push(test, &Type::Bool)?;
+                if matches!(insts.last(), Some(Inst::JmpF { .. })) {
+                    self.line("i32.eqz");
+                }
+
+                self.line("if");
+                self.depth += 1;
+                context.push(Context::If);
+                self.branch(block, target, context)?;
+                self.depth -= 1;
+                self.line("else");
+                self.depth += 1;
+                self.branch(block, next, context)?;
+                context.pop();
+                self.depth -= 1;
+                self.line("end");
+                Ok(())
+            }
+
+            _ => self.branch(block, next, context),
+        }
+    }
+
+    fn target(&self, label: usize) -> CodegenResult<usize> {
+        self.cfg
+            .block_of(label)
+            .ok_or_else(|| CodegenError::new(&format!("jump to undefined label L{}", label)))
+    }
+
+    fn branch(&mut self, from: usize, to: usize, context: &mut Vec<Context>) -> CodegenResult<()> {
+        let wanted = if !self.is_forward(from, to) {
+            Context::Loop(to)
+        } else if self.is_merge(to) {
+            Context::Block(to)
+        } else {
+            return self.tree(to, context);
+        };
+
+        let depth = context
+            .iter()
+            .rev()
+            .position(|&other| other == wanted)
+            .ok_or_else(|| CodegenError::new("control flow can't be structured"))?;
+
+        self.line(&format!("br {}", depth));
+        Ok(())
+    }
+}
+
+fn function(module: &Module, func: &Function) -> CodegenResult<String> {
+    let cfg = Cfg::new(&func.code);
+    let dom = DomTree::new(&cfg);
+
+    let mut order = vec![None; cfg.blocks.len()];
+    for (pos, block) in cfg.reverse_postorder().into_iter().enumerate() {
+        order[block] = Some(pos);
+    }
+
+    let arrays = func
+        .locals
+        .iter()
+        .any(|local| matches!(local.tp, Type::Array { .. }));
+    let frame = if arrays {
+        frame_size(func).div_ceil(8) * 8
+    } else {
+        0
+    };
+
+    let mut writer = Writer {
+        module,
+        func,
+        cfg,
+        dom,
+        order,
+        frame,
+        out: String::new(),
+        depth: 2,
+    };
+
+    if !writer.is_reducible() {
+        return Err(CodegenError::new(&format!(
+            "irreducible control flow in {}",
+            func.name
+        )));
+    }
+
+    let mut header = format!("(func ${} (export \"{}\")", func.name, func.name);
+    for param in &func.params {
+        let param = Operand::Ident(param.clone());
+        header.push_str(&format!(
+            " (param {} {})",
+            name(&param),
+            wasm_type(&param.get_tp())?
+        ));
+    }
+
+    if let Some(ret) = &func.ret {
+        header.push_str(&format!(" (result {})", wasm_type(ret)?));
+    }
+
+    writer.depth = 1;
+    writer.line(&header);
+    writer.depth = 2;
+
+    // Scalar locals and temporaries are wasm locals, which start at zero
+    let mut locals = BTreeMap::new();
+    for local in &func.locals {
+        if !matches!(local.tp, Type::Array { .. }) {
+            let local = Operand::Ident(local.clone());
+            locals.insert(name(&local), wasm_type(&local.get_tp())?);
+        }
+    }
+
+    for inst in &func.code {
+        for operand in inst.get_uses().into_iter().chain(inst.get_def()) {
+            if let Operand::Temp(_) = operand {
+                locals.insert(name(operand), wasm_type(&operand.get_tp())?);
+            }
+        }
+    }
+
+    if frame > 0 {
+        locals.insert("$fp".to_owned(), "i32");
+    }
+    if frame > 0
+        || func
+            .code
+            .iter()
+            .any(|inst| matches!(inst, Inst::Chk { .. }))
+    {
+        locals.insert("$index".to_owned(), "i64");
+    }
+
+    for (local, tp) in locals {
+        writer.line(&format!("(local {} {})", local, tp));
+    }
+
+    // Arrays start at zero too
+    if frame > 0 {
+        writer.line("global.get $sp");
+        writer.line(&format!("i32.const {}", frame));
+        writer.lines(vec!["i32.sub", "local.tee $fp", "global.set $sp"]);
+        writer.lines(vec!["local.get $fp", "i32.const 0"]);
+        writer.line(&format!("i32.const {}", frame));
+        writer.line("memory.fill");
+    }
+
+    writer.tree(writer.cfg.entry, &mut Vec::new())?;
+
+    // Every path ends with a branch or a return
+    if func.ret.is_some() {
+        writer.line("unreachable");
+    }
+
+    writer.depth = 1;
+    writer.line(")");
+    Ok(writer.out)
+}
+
+/**
+ * Translates `module` to a WebAssembly text module. Every function is
+ * exported by name, values map to `i32` (also `char` and `bool`), `i64`,
+ * `f32` and `f64`, and arithmetic and conversions behave like in the VM.
+ * Scalars are wasm locals; arrays live in a frame of linear memory, pushed on
+ * a stack that grows down from the top (`$sp`) and zeroed on entry. Array
+ * accesses out of bounds trap.
+ *
+ * Jumps are turned into `block`, `loop`, `if` and `br`, which needs reducible
+ * control flow: every loop has to be entered through its header.
+ */
+pub fn emit_wat(module: &Module) -> CodegenResult<String> {
+    let mut out = String::from("(module\n");
+    out.push_str(&format!("  (memory (export \"memory\") {})\n", PAGES));
+    out.push_str(&format!(
+        "  (global $sp (mut i32) (i32.const {}))\n",
+        PAGES * 65536
+    ));
+
+    for func in &module.funcs {
+        out.push_str(&function(module, func)?);
+    }
+
+    out.push_str(")\n");
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::parse_module;
+    use crate::vm::machine::tests::for_each_program;
+    use crate::vm::Machine;
+    use wasmi::{Engine, Linker, Store};
+
+    /// Result of `main` in `module`, compiled to wasm and run by wasmi, or
+    /// the trap it stopped at.
+    fn run(module: &Module) -> Result<Option<Value>, String> {
+        let text = emit_wat(module).unwrap();
+        let wasm = wat::parse_str(&text).unwrap_or_else(|error| panic!("{}\n{}", error, text));
+
+        let engine = Engine::default();
+        let wasm_module = wasmi::Module::new(&engine, &wasm[..]).unwrap();
+        let mut store = Store::new(&engine, ());
+        let instance = Linker::<()>::new(&engine)
+            .instantiate(&mut store, &wasm_module)
+            .unwrap()
+            .start(&mut store)
+            .unwrap();
+
+        let main = instance.get_func(&store, "main").unwrap();
+        let ret = module.get("main").unwrap().ret.clone();
+        let mut results = match &ret {
+            Some(tp) => vec![wasmi::Value::default(wasm_value_type(tp))],
+            None => vec![],
+        };
+        main.call(&mut store, &[], &mut results)
+            .map_err(|error| error.to_string())?;
+
+        Ok(ret.map(|tp| match (tp, &results[0]) {
+            (Type::Int32, wasmi::Value::I32(num)) => Value::Int32(*num),
+            (Type::Int64, wasmi::Value::I64(num)) => Value::Int64(*num),
+            (Type::Flt32, wasmi::Value::F32(num)) => Value::Flt32(num.to_float()),
+            (Type::Flt64, wasmi::Value::F64(num)) => Value::Flt64(num.to_float()),
+            (Type::Char, wasmi::Value::I32(num)) => Value::Char(*num as u8 as char),
+            (Type::Bool, wasmi::Value::I32(num)) => Value::Bool(*num != 0),
+            (tp, value) => panic!("{:?} returned for {}", value, tp),
+        }))
+    }
+
+    fn wasm_value_type(tp: &Type) -> wasmi::core::ValueType {
+        match wasm_type(tp).unwrap() {
+            "i32" => wasmi::core::ValueType::I32,
+            "i64" => wasmi::core::ValueType::I64,
+            "f32" => wasmi::core::ValueType::F32,
+            _ => wasmi::core::ValueType::F64,
+        }
+    }
+
+    #[test]
+    fn compiled_programs() {
+        for_each_program(|_, module, expected| assert_eq!(run(module), Ok(expected)));
+    }
+
+    #[test]
+    fn machine_semantics() {
+        // Wrapping arithmetic, conversions, and arrays in recursive frames
+        let module = parse_module(
+            "fn half(x: f32, n: i64) -> f64\n\tdiv __t0 x n\n\tret __t0\n\
+             fn rec(n: i32) -> i32\n\tvar a: [2]i32\n\tsto a 1 n\n\
+             \tle __t1 n 0\n\tjmpf L1 __t1\n\tret 0\n\
+             L1\tsub __t2 n 1\n\tparam __t2\n\tcall __t3 rec 1\n\
+             \tidx __t4 1 a\n\tadd __t5 __t3 __t4\n\tret __t5\n\
+             fn main() -> i32\n\tvar a: [3]char\n\tvar big: i32\n\
+             \tmov big 2147483647\n\tadd big big 2\n\
+             \tsto a 2 300\n\tidx __t6 2 a\n\
+             \tparam 5.5\n\tparam 2\n\tcall __t7 half 2\n\
+             \tinv __t8 __t7\n\tmul __t9 __t8 10\n\
+             \tparam 4\n\tcall __t10 rec 1\n\
+             \tadd __t11 big __t6\n\tadd __t12 __t11 __t9\n\tadd __t13 __t12 __t10\n\
+             \tret __t13\n",
+        )
+        .unwrap();
+
+        let expected = Machine::new(&module).run().unwrap();
+        assert_eq!(expected, Some(Value::Int32(-2147483620)));
+        assert_eq!(run(&module), Ok(expected));
+    }
+
+    #[test]
+    fn traps() {
+        // Where the VM fails, wasm reaches `unreachable`: an index past its
+        // own dimension, and a bare `ret` in a function with a result, which
+        // the validator would reject as it is
+        let funcs = "fn get(i: i32, j: i32) -> i32\n\tvar m: [3][4]i32\n\
+                     \tchk i 3\n\tchk j 4\n\tmul __t0 i 4\n\tadd __t1 __t0 j\n\
+                     \tidx __t2 __t1 m\n\tret __t2\n\
+                     fn none(x: i32) -> i32\n\tlt __t0 x 0\n\tjmpf L1 __t0\n\tret x\nL1\tret\n";
+        let calls = [
+            "\tparam 0\n\tparam 5\n\tcall __t0 get 2\n",
+            "\tparam 1\n\tcall __t0 none 1\n",
+        ];
+
+        for call in &calls {
+            let code = format!("{}fn main() -> i32\n{}\tret __t0\n", funcs, call);
+            let module = parse_module(&code).unwrap();
+            assert!(Machine::new(&module).run().is_err());
+            let error = run(&module).unwrap_err();
+            assert!(error.contains("unreachable"), "{}", error);
+        }
+    }
+
+    #[test]
+    fn irreducible_loops() {
+        // Both L1 and L2 can be entered first
+        let module = parse_module(
+            "fn main(a: bool) -> i32\n\tvar n: i32\n\tjmpt L1 a\n\
+             L2\tadd n n 1\n\tjmp L1\n\
+             L1\tadd n n 2\n\tlt __t0 n 10\n\tjmpt L2 __t0\n\tret n\n",
+        )
+        .unwrap();
+
+        let error = emit_wat(&module).unwrap_err();
+        assert_eq!(error.msg, "irreducible control flow in main");
+    }
+}
@@ -0,0 +1,1017 @@
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+use crate::ast::Ident;
+use crate::codegen::{
+    assemble, Allocation, CallingConvention, CodegenResult, Frame, Location, RegClass, RegisterSet,
+};
+use crate::error::CodegenError;
+use crate::interp::Value;
+use crate::ir::{Function, Inst, Module, Opcode, Operand};
+use crate::sym::{DataLayout, Type};
+
+/// Argument registers of the System V calling convention, in order.
+const INT_ARGS: [&str; 6] = ["%rdi", "%rsi", "%rdx", "%rcx", "%r8", "%r9"];
+const FLOAT_ARGS: [&str; 8] = [
+    "%xmm0", "%xmm1", "%xmm2", "%xmm3", "%xmm4", "%xmm5", "%xmm6", "%xmm7",
+];
+
+/// Registers where operands are loaded, converted and combined before the
+/// result is stored. None of them is handed to the allocator.
+const INT_WORK: [&str; 2] = ["%rax", "%rcx"];
+const FLOAT_WORK: [&str; 2] = ["%xmm0", "%xmm1"];
+
+/// Names of the integer registers that are read or written with a width
+/// other than 64 bits.
+const NAMES: [[&str; 3]; 8] = [
+    ["%rax", "%eax", "%al"],
+    ["%rcx", "%ecx", "%cl"],
+    ["%rdx", "%edx", "%dl"],
+    ["%rdi", "%edi", "%dil"],
+    ["%rsi", "%esi", "%sil"],
+    ["%r8", "%r8d", "%r8b"],
+    ["%r9", "%r9d", "%r9b"],
+    ["%r10", "%r10d", "%r10b"],
+];
+
+const INT_CALLEE_SAVED: [&str; 5] = ["%rbx", "%r12", "%r13", "%r14", "%r15"];
+const INT_CALLER_SAVED: [&str; 9] = [
+    "%rax", "%rcx", "%rdx", "%rsi", "%rdi", "%r8", "%r9", "%r10", "%r11",
+];
+const FLOAT_CALLER_SAVED: [&str; 16] = [
+    "%xmm0", "%xmm1", "%xmm2", "%xmm3", "%xmm4", "%xmm5", "%xmm6", "%xmm7", "%xmm8", "%xmm9",
+    "%xmm10", "%xmm11", "%xmm12", "%xmm13", "%xmm14", "%xmm15",
+];
+
+/// The System V calling convention for x86-64. SSE registers are all
+/// caller-saved, so floating point temporaries always live in the frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SystemV;
+
+impl CallingConvention for SystemV {
+    fn arg_regs(&self, class: RegClass) -> &[&'static str] {
+        match class {
+            RegClass::Int => &INT_ARGS,
+            RegClass::Float => &FLOAT_ARGS,
+        }
+    }
+
+    fn callee_saved(&self, class: RegClass) -> &[&'static str] {
+        match class {
+            RegClass::Int => &INT_CALLEE_SAVED,
+            RegClass::Float => &[],
+        }
+    }
+
+    fn caller_saved(&self, class: RegClass) -> &[&'static str] {
+        match class {
+            RegClass::Int => &INT_CALLER_SAVED,
+            RegClass::Float => &FLOAT_CALLER_SAVED,
+        }
+    }
+
+    fn scratch(&self, class: RegClass) -> &[&'static str] {
+        match class {
+            RegClass::Int => &["%r10", "%r11"],
+            RegClass::Float => &["%xmm14", "%xmm15"],
+        }
+    }
+
+    fn stack_align(&self) -> usize {
+        16
+    }
+
+    fn data_layout(&self) -> DataLayout {
+        DataLayout::LP64
+    }
+
+    /// The return address and `%rbp` are pushed above the frame.
+    fn linkage(&self) -> usize {
+        0
+    }
+}
+
+/// Registers for `allocate`, the callee-saved ones of `SystemV`.
+pub fn x86_64_registers() -> RegisterSet {
+    SystemV.registers()
+}
+
+fn sized(reg: &str, width: usize) -> &str {
+    let pos = match width {
+        4 => 1,
+        1 => 2,
+        _ => return reg,
+    };
+
+    NAMES
+        .iter()
+        .find(|names| names[0] == reg)
+        .map_or(reg, |names| names[pos])
+}
+
+fn class(tp: &Type) -> CodegenResult<RegClass> {
+    RegClass::of(tp)
+        .ok_or_else(|| CodegenError::new(&format!("values of type {} don't fit a register", tp)))
+}
+
+/**
+ * Emits one function. Integer values are kept in registers as 64 bits,
+ * sign-extended (`i32`) or zero-extended (`char`, `bool`), and floating point
+ * values as `f64`, rounded to `f32` precision when that's their type. They
+ * only take their own width in memory.
+ */
+struct Emitter<'a> {
+    module: &'a Module,
+    func: Function,
+    alloc: Allocation,
+    /// Reserved below `%rbp`.
+    frame: Frame,
+    /// Types of the arguments stored by `param` and not passed yet.
+    params: Vec<Type>,
+    out: String,
+}
+
+impl<'a> Emitter<'a> {
+    fn new(module: &'a Module, func: &Function) -> Self {
+        let mut func = func.clone();
+        let (frame, alloc) = Frame::new(&mut func, &SystemV);
+        Self {
+            module,
+            func,
+            alloc,
+            frame,
+            params: Vec::new(),
+            out: String::new(),
+        }
+    }
+
+    fn asm(&mut self, line: &str) {
+        self.out.push('\t');
+        self.out.push_str(line);
+        self.out.push('\n');
+    }
+
+    fn label(&self, label: usize) -> String {
+        format!(".L{}_{}", self.func.name, label)
+    }
+
+    /// Address of the frame slot at `offset`.
+    fn slot(&self, offset: usize) -> String {
+        format!("{}(%rbp)", offset as i64 - self.frame.size as i64)
+    }
+
+    fn work(class: RegClass, n: usize) -> &'static str {
+        match class {
+            RegClass::Int => INT_WORK[n],
+            RegClass::Float => FLOAT_WORK[n],
+        }
+    }
+
+    fn load_mem(&mut self, tp: &Type, mem: &str, n: usize) -> CodegenResult<()> {
+        let reg = Self::work(class(tp)?, n);
+        let inst = match tp {
+            Type::Int32 => "movslq",
+            Type::Int64 => "movq",
+            Type::Char | Type::Bool => "movzbq",
+            Type::Flt32 => "cvtss2sd",
+            _ => "movsd",
+        };
+
+        self.asm(&format!("{} {}, {}", inst, mem, reg));
+        Ok(())
+    }
+
+    fn store_mem(&mut self, tp: &Type, mem: &str, n: usize) -> CodegenResult<()> {
+        let reg = Self::work(class(tp)?, n);
+        let line = match tp {
+            Type::Int32 => format!("movl {}, {}", sized(reg, 4), mem),
+            Type::Int64 => format!("movq {}, {}", reg, mem),
+            Type::Char | Type::Bool => format!("movb {}, {}", sized(reg, 1), mem),
+            Type::Flt32 => {
+                self.asm(&format!("cvtsd2ss {}, {}", reg, reg));
+                format!("movss {}, {}", reg, mem)
+            }
+            _ => format!("movsd {}, {}", reg, mem),
+        };
+
+        self.asm(&line);
+        Ok(())
+    }
+
+    /// Brings the integer in `INT_WORK[n]` back to the 64 bits form of `tp`,
+    /// or rounds the float in `FLOAT_WORK[n]` to `f32` precision.
+    fn normalize(&mut self, tp: &Type, n: usize) {
+        let (int, float) = (INT_WORK[n], FLOAT_WORK[n]);
+        match tp {
+            Type::Int32 => self.asm(&format!("movslq {}, {}", sized(int, 4), int)),
+            Type::Char | Type::Bool => self.asm(&format!("movzbq {}, {}", sized(int, 1), int)),
+            Type::Flt32 => {
+                self.asm(&format!("cvtsd2ss {}, {}", float, float));
+                self.asm(&format!("cvtss2sd {}, {}", float, float));
+            }
+            _ => {}
+        }
+    }
+
+    /// Traps with `ud2` unless the index in `reg` is in `0..len`, compared
+    /// unsigned so that negative indices are out of bounds too.
+    fn check(&mut self, reg: &str, len: usize) {
+        self.asm(&format!("cmpq ${}, {}", len, reg));
+        self.asm("jb 1f");
+        self.asm("ud2");
+        self.out.push_str("1:\n");
+    }
+
+    /**
+     * Truncates the float in `FLOAT_WORK[n]` into `INT_WORK[n]` as `as i64`
+     * does: `cvttsd2siq` gives `i64::MIN` for NaN and values out of range,
+     * so NaN becomes zero and positive values saturate to `i64::MAX`.
+     */
+    fn truncate(&mut self, n: usize) {
+        let (int, float) = (INT_WORK[n], FLOAT_WORK[n]);
+        self.asm(&format!("cvttsd2siq {}, {}", float, int));
+        self.asm("movabsq $0x8000000000000000, %rdx");
+        self.asm(&format!("cmpq %rdx, {}", int));
+        self.asm("jne 1f");
+        self.asm(&format!("ucomisd {}, {}", float, float));
+        self.asm("jp 2f");
+        self.asm(&format!("movq {}, %rdx", float));
+        self.asm("testq %rdx, %rdx");
+        self.asm("js 1f");
+        self.asm(&format!("movabsq $0x7fffffffffffffff, {}", int));
+        self.asm("jmp 1f");
+        self.out.push_str("2:\n");
+        self.asm(&format!("xorl {0}, {0}", sized(int, 4)));
+        self.out.push_str("1:\n");
+    }
+
+    /// Converts the value in the `n`th working register from `from` to `to`,
+    /// like `Value::cast`.
+    fn convert(&mut self, from: &Type, to: &Type, n: usize) -> CodegenResult<()> {
+        let (int, float) = (INT_WORK[n], FLOAT_WORK[n]);
+        match (class(from)?, class(to)?) {
+            (RegClass::Int, RegClass::Float) => self.asm(&format!("cvtsi2sdq {}, {}", int, float)),
+            (RegClass::Float, RegClass::Int) => self.truncate(n),
+            _ if from == to => return Ok(()),
+            _ => {}
+        }
+
+        self.normalize(to, n);
+        Ok(())
+    }
+
+    /// Loads `operand` into the `n`th working register of the class of `to`,
+    /// converted to `to`.
+    fn load(&mut self, operand: &Operand, to: &Type, n: usize) -> CodegenResult<()> {
+        let from = operand.get_tp();
+        let (int, float) = (INT_WORK[n], FLOAT_WORK[n]);
+
+        match operand {
+            Operand::Cons(cons) => {
+                let num = match Value::from_cons(cons) {
+                    Value::Int32(num) => num as i64,
+                    Value::Int64(num) => num,
+                    Value::Char(chr) => chr as i64,
+                    Value::Bool(value) => value as i64,
+                    Value::Flt32(num) => (num as f64).to_bits() as i64,
+                    Value::Flt64(num) => num.to_bits() as i64,
+                    value => {
+                        return Err(CodegenError::new(&format!(
+                            "constant {} doesn't fit a register",
+                            value
+                        )))
+                    }
+                };
+
+                match class(&from)? {
+                    RegClass::Int => self.asm(&format!("movq ${}, {}", num, int)),
+                    RegClass::Float => {
+                        self.asm(&format!("movabsq ${}, %rdx", num));
+                        self.asm(&format!("movq %rdx, {}", float));
+                    }
+                }
+            }
+
+            Operand::Temp(_) => match (self.alloc.location(operand), class(&from)?) {
+                (Some(Location::Reg(reg)), RegClass::Int) => {
+                    self.asm(&format!("movq {}, {}", reg, int))
+                }
+                (Some(Location::Reg(reg)), RegClass::Float) => {
+                    self.asm(&format!("movapd {}, {}", reg, float))
+                }
+                _ => return Err(CodegenError::new(&format!("{} has no register", operand))),
+            },
+
+            Operand::Ident(ident) => {
+                let slot = self.slot(ident.offset);
+                self.load_mem(&ident.tp, &slot, n)?;
+            }
+        }
+
+        self.convert(&from, to, n)
+    }
+
+    /// Stores the first working register into `dst`, the value must be of its type.
+    fn store(&mut self, dst: &Operand) -> CodegenResult<()> {
+        let tp = dst.get_tp();
+        match dst {
+            Operand::Temp(_) => match (self.alloc.location(dst), class(&tp)?) {
+                (Some(Location::Reg(reg)), RegClass::Int) => {
+                    self.asm(&format!("movq %rax, {}", reg))
+                }
+                (Some(Location::Reg(reg)), RegClass::Float) => {
+                    self.asm(&format!("movapd %xmm0, {}", reg))
+                }
+                _ => return Err(CodegenError::new(&format!("{} has no register", dst))),
+            },
+
+            Operand::Ident(ident) => {
+                let slot = self.slot(ident.offset);
+                self.store_mem(&tp, &slot, 0)?;
+            }
+
+            Operand::Cons(_) => return Err(CodegenError::new("cannot store into a constant")),
+        }
+
+        Ok(())
+    }
+
+    /// Scalar type and address of an array in the frame.
+    fn array(&self, operand: &Operand) -> CodegenResult<(Type, String)> {
+        match operand {
+            Operand::Ident(Ident {
+                tp: tp @ Type::Array { .. },
+                offset,
+                ..
+            }) => Ok((tp.get_scalar().clone(), self.slot(*offset))),
+            _ => Err(CodegenError::new(&format!("{} is not an array", operand))),
+        }
+    }
+
+    fn binary(
+        &mut self,
+        op: Opcode,
+        dst: &Operand,
+        lhs: &Operand,
+        rhs: &Operand,
+    ) -> CodegenResult<()> {
+        // Booleans can only be compared, as integers
+        let tp = lhs.get_tp().upcast(&rhs.get_tp()).unwrap_or(Type::Int64);
+        let class = class(&tp)?;
+        self.load(lhs, &tp, 0)?;
+        self.load(rhs, &tp, 1)?;
+
+        if op.is_rel() {
+            let cond = match op {
+                Opcode::Lt => "l",
+                Opcode::Le => "le",
+                Opcode::Gt => "g",
+                Opcode::Ge => "ge",
+                Opcode::Eq => "e",
+                _ => "ne",
+            };
+
+            match class {
+                RegClass::Int => {
+                    self.asm("cmpq %rcx, %rax");
+                    self.asm(&format!("set{} %al", cond));
+                }
+
+                // Unordered compares set ZF, PF and CF: only `ne` holds for NaN
+                RegClass::Float => match op {
+                    Opcode::Lt | Opcode::Le => {
+                        self.asm("ucomisd %xmm0, %xmm1");
+                        self.asm(if op == Opcode::Lt {
+                            "seta %al"
+                        } else {
+                            "setae %al"
+                        });
+                    }
+                    Opcode::Gt | Opcode::Ge => {
+                        self.asm("ucomisd %xmm1, %xmm0");
+                        self.asm(if op == Opcode::Gt {
+                            "seta %al"
+                        } else {
+                            "setae %al"
+                        });
+                    }
+                    Opcode::Eq => {
+                        self.asm("ucomisd %xmm1, %xmm0");
+                        self.asm("sete %al");
+                        self.asm("setnp %cl");
+                        self.asm("andb %cl, %al");
+                    }
+                    _ => {
+                        self.asm("ucomisd %xmm1, %xmm0");
+                        self.asm("setne %al");
+                        self.asm("setp %cl");
+                        self.asm("orb %cl, %al");
+                    }
+                },
+            }
+
+            self.asm("movzbq %al, %rax");
+            return self.store(dst);
+        }
+
+        let (inst, suffix) = match op {
+            Opcode::Add => ("add", "q"),
+            Opcode::Sub => ("sub", "q"),
+            Opcode::Mul => ("imul", "q"),
+            _ => ("div", ""),
+        };
+
+        match class {
+            RegClass::Int if op == Opcode::Div => {
+                self.asm("cqto");
+                self.asm("idivq %rcx");
+            }
+            RegClass::Int => self.asm(&format!("{}{} %rcx, %rax", inst, suffix)),
+            RegClass::Float => {
+                let inst = if inst == "imul" { "mul" } else { inst };
+                self.asm(&format!("{}sd %xmm1, %xmm0", inst));
+            }
+        }
+
+        // Wrap around (or round) in the upcasted type, then convert
+        self.normalize(&tp, 0);
+        self.convert(&tp, &dst.get_tp(), 0)?;
+        self.store(dst)
+    }
+
+    fn call(&mut self, dst: &Option<Operand>, name: &str, nargs: usize) -> CodegenResult<()> {
+        let callee = self
+            .module
+            .get(name)
+            .ok_or_else(|| CodegenError::new(&format!("call to undefined function {}", name)))?;
+
+        if callee.params.len() != nargs || self.params.len() < nargs {
+            return Err(CodegenError::new(&format!(
+                "{} takes {} arguments",
+                name,
+                callee.params.len()
+            )));
+        }
+
+        // Arguments were stored in order, convert them in place first so that
+        // moving them to their registers doesn't clobber anything
+        let base = self.params.len() - nargs;
+        let args = self.params.split_off(base);
+        let frame = self.frame.clone();
+        let slot = |pos: usize| format!("{}(%rsp)", frame.arg(base + pos));
+
+        for (pos, (from, param)) in args.iter().zip(&callee.params).enumerate() {
+            self.load_mem(&widest(from), &slot(pos), 0)?;
+            self.convert(from, &param.tp, 0)?;
+            self.store_mem(&widest(&param.tp), &slot(pos), 0)?;
+        }
+
+        let regs = arg_registers(&callee.params)?;
+        for (pos, (param, reg)) in callee.params.iter().zip(regs).enumerate() {
+            let reg = reg.ok_or_else(|| {
+                CodegenError::new(&format!("{} takes too many arguments for registers", name))
+            })?;
+
+            let inst = match param.tp {
+                Type::Flt32 => "cvtsd2ss",
+                Type::Flt64 => "movsd",
+                _ => "movq",
+            };
+            self.asm(&format!("{} {}, {}", inst, slot(pos), reg));
+        }
+
+        self.asm(&format!("call ez_{}", name));
+
+        let dst = match dst {
+            Some(dst) => dst,
+            None => return Ok(()),
+        };
+
+        let ret = callee
+            .ret
+            .clone()
+            .ok_or_else(|| CodegenError::new(&format!("{} doesn't return a value", name)))?;
+
+        match ret {
+            Type::Flt32 => self.asm("cvtss2sd %xmm0, %xmm0"),
+            _ => self.normalize(&ret, 0),
+        }
+
+        self.convert(&ret, &dst.get_tp(), 0)?;
+        self.store(dst)
+    }
+
+    fn inst(&mut self, inst: &Inst) -> CodegenResult<()> {
+        match inst {
+            Inst::Label(label) => {
+                let label = self.label(*label);
+                self.out.push_str(&format!("{}:\n", label));
+            }
+
+            Inst::Binary { op, dst, lhs, rhs } => self.binary(*op, dst, lhs, rhs)?,
+
+            Inst::Inv { dst, src } => {
+                let tp = Type::Int64
+                    .upcast(&src.get_tp())
+                    .ok_or_else(|| CodegenError::new(&format!("cannot negate {}", src)))?;
+
+                self.load(src, &tp, 0)?;
+                match class(&tp)? {
+                    RegClass::Int => self.asm("negq %rax"),
+                    RegClass::Float => {
+                        self.asm("movabsq $0x8000000000000000, %rdx");
+                        self.asm("movq %rdx, %xmm1");
+                        self.asm("xorpd %xmm1, %xmm0");
+                    }
+                }
+
+                self.convert(&tp, &dst.get_tp(), 0)?;
+                self.store(dst)?;
+            }
+
+            Inst::Not { dst, src } => {
+                self.load(src, &Type::Bool, 0)?;
+                self.asm("xorq $1, %rax");
+                self.store(dst)?;
+            }
+
+            Inst::Mov { dst, src } => {
+                self.load(src, &dst.get_tp(), 0)?;
+                self.store(dst)?;
+            }
+
+            Inst::Idx { dst, index, array } => {
+                let (of, base) = self.array(array)?;
+                self.load(index, &Type::Int64, 1)?;
+                self.check("%rcx", array.get_tp().get_len());
+                self.asm(&format!("leaq {}, %rax", base));
+                let stride = of.layout(&SystemV.data_layout()).stride();
+                self.load_mem(&of, &format!("(%rax,%rcx,{})", stride), 0)?;
+                self.convert(&of, &dst.get_tp(), 0)?;
+                self.store(dst)?;
+            }
+
+            Inst::Sto { array, index, src } => {
+                let (of, base) = self.array(array)?;
+                self.load(src, &of, 0)?;
+                self.load(index, &Type::Int64, 1)?;
+                self.check("%rcx", array.get_tp().get_len());
+                self.asm(&format!("leaq {}, %rdx", base));
+                let stride = of.layout(&SystemV.data_layout()).stride();
+                self.store_mem(&of, &format!("(%rdx,%rcx,{})", stride), 0)?;
+            }
+
+            Inst::Chk { index, len } => {
+                self.load(index, &Type::Int64, 0)?;
+                self.check("%rax", *len);
+            }
+
+            Inst::Jmp { label } => {
+                let label = self.label(*label);
+                self.asm(&format!("jmp {}", label));
+            }
+
+            Inst::JmpT { label, test } | Inst::JmpF { label, test } => {
+                self.load(test, &Type::Bool, 0)?;
+                self.asm("testq %rax, %rax");
+
+                let jump = if matches!(inst, Inst::JmpT { .. }) {
+                    "jne"
+                } else {
+                    "je"
+                };
+                let label = self.label(*label);
+                self.asm(&format!("{} {}", jump, label));
+            }
+
+            Inst::Param { src } => {
+                let tp = src.get_tp();
+                self.load(src, &tp, 0)?;
+                if class(&tp)? == RegClass::Float {
+                    self.asm("movq %xmm0, %rax");
+                }
+
+                let slot = self.frame.arg(self.params.len());
+                self.asm(&format!("movq %rax, {}(%rsp)", slot));
+                self.params.push(tp);
+            }
+
+            Inst::Call { dst, func, nargs } => self.call(dst, func, *nargs)?,
+
+            Inst::Ret { src } => {
+                match (src, self.func.ret.clone()) {
+                    (Some(src), Some(ret)) => {
+                        self.load(src, &ret, 0)?;
+                        if ret == Type::Flt32 {
+                            self.asm("cvtsd2ss %xmm0, %xmm0");
+                        }
+                    }
+                    // No value to return, where the VM fails
+                    (None, Some(_)) => {
+                        self.asm("ud2");
+                        return Ok(());
+                    }
+                    _ => (),
+                }
+
+                let label = format!(".L{}_ret", self.func.name);
+                self.asm(&format!("jmp {}", label));
+            }
+
+            Inst::Phi { .. } => {
+                return Err(CodegenError::new(
+                    "cannot lower phi, translate out of SSA first",
+                ))
+            }
+        }
+
+        Ok(())
+    }
+
+    /**
+     * Prologue: reserves the frame, zeroes it (locals start at zero, as in the
+     * VM), saves the callee-saved registers it uses, and stores the arguments
+     * in the slots of their parameters.
+     */
+    fn prologue(&mut self) -> CodegenResult<()> {
+        let name = format!("ez_{}", self.func.name);
+        self.asm(&format!(".globl {}", name));
+        self.asm(&format!(".type {}, @function", name));
+        self.out.push_str(&format!("{}:\n", name));
+
+        self.asm("pushq %rbp");
+        self.asm("movq %rsp, %rbp");
+        if self.frame.size > 0 {
+            self.asm(&format!("subq ${}, %rsp", self.frame.size));
+        }
+
+        let words = self.frame.body() / 8;
+        if words > 0 {
+            self.asm("movq %rsp, %r10");
+            self.asm(&format!("movq ${}, %r11", words));
+            self.out.push_str("1:\n");
+            self.asm("movq $0, (%r10)");
+            self.asm("addq $8, %r10");
+            self.asm("decq %r11");
+            self.asm("jnz 1b");
+        }
+
+        for (pos, reg) in self.frame.saved.clone().iter().enumerate() {
+            let slot = self.slot(self.frame.saved_slot(pos));
+            self.asm(&format!("movq {}, {}", reg, slot));
+        }
+
+        let params = self.func.params.clone();
+        for (param, reg) in params.iter().zip(arg_registers(&params)?) {
+            let slot = self.slot(param.offset);
+            let line = match (&param.tp, class(&param.tp)?) {
+                (tp, RegClass::Int) => reg.map(|reg| {
+                    let width = tp.get_width();
+                    let inst = ["movb", "", "", "movl", "", "", "", "movq"][width - 1];
+                    format!("{} {}, {}", inst, sized(reg, width), slot)
+                }),
+                (tp, RegClass::Float) => {
+                    let inst = if *tp == Type::Flt32 { "movss" } else { "movsd" };
+                    reg.map(|reg| format!("{} {}, {}", inst, reg, slot))
+                }
+            };
+
+            let line = line.ok_or_else(|| {
+                CodegenError::new(&format!(
+                    "{} takes too many arguments for registers",
+                    self.func.name
+                ))
+            })?;
+            self.asm(&line);
+        }
+
+        Ok(())
+    }
+
+    fn epilogue(&mut self) {
+        let name = self.func.name.clone();
+        self.out.push_str(&format!(".L{}_ret:\n", name));
+
+        for (pos, reg) in self.frame.saved.clone().iter().enumerate() {
+            let slot = self.slot(self.frame.saved_slot(pos));
+            self.asm(&format!("movq {}, {}", slot, reg));
+        }
+
+        self.asm("leave");
+        self.asm("ret");
+        self.asm(&format!(".size ez_{}, .-ez_{}", name, name));
+    }
+
+    fn emit(mut self) -> CodegenResult<String> {
+        self.prologue()?;
+
+        let code = std::mem::take(&mut self.func.code);
+        for inst in &code {
+            self.inst(inst)?;
+        }
+
+        // Falling off the end of a function with a result is a missing return
+        if self.func.ret.is_some() {
+            self.asm("ud2");
+        }
+
+        self.epilogue();
+        Ok(self.out)
+    }
+}
+
+/// Registers of `SystemV` the arguments of `params` are passed in.
+fn arg_registers(params: &[Ident]) -> CodegenResult<Vec<Option<&'static str>>> {
+    let classes = params
+        .iter()
+        .map(|param| class(&param.tp))
+        .collect::<CodegenResult<Vec<_>>>()?;
+    Ok(SystemV.arg_registers(&classes))
+}
+
+/// Type a value of class `tp` takes in an 8 bytes stack slot.
+fn widest(tp: &Type) -> Type {
+    match class(tp) {
+        Ok(RegClass::Float) => Type::Flt64,
+        _ => Type::Int64,
+    }
+}
+
+/// Entry point for the C runtime: prints what `ez_main` returns, and exits
+/// with it when it's an integer.
+fn entry(main: &Function) -> String {
+    let mut out = String::from("\t.globl main\n\t.type main, @function\nmain:\n");
+    let mut asm = |line: &str| {
+        out.push('\t');
+        out.push_str(line);
+        out.push('\n');
+    };
+
+    asm("pushq %rbp");
+    asm("movq %rsp, %rbp");
+    asm("subq $16, %rsp");
+    asm("call ez_main");
+
+    match &main.ret {
+        Some(Type::Flt32) | Some(Type::Flt64) => {
+            if main.ret == Some(Type::Flt32) {
+                asm("cvtss2sd %xmm0, %xmm0");
+            }
+            asm("leaq .Lfmt_flt(%rip), %rdi");
+            asm("movl $1, %eax");
+            asm("call printf@PLT");
+            asm("xorl %eax, %eax");
+        }
+
+        Some(tp) => {
+            asm("movq %rax, -8(%rbp)");
+            match tp {
+                Type::Bool => {
+                    asm("leaq .Ltrue(%rip), %rsi");
+                    asm("leaq .Lfalse(%rip), %rdx");
+                    asm("testq %rax, %rax");
+                    asm("cmove %rdx, %rsi");
+                    asm("leaq .Lfmt_str(%rip), %rdi");
+                }
+                Type::Char => {
+                    asm("movq %rax, %rsi");
+                    asm("leaq .Lfmt_chr(%rip), %rdi");
+                }
+                _ => {
+                    asm("movq %rax, %rsi");
+                    asm("leaq .Lfmt_int(%rip), %rdi");
+                }
+            }
+
+            asm("xorl %eax, %eax");
+            asm("call printf@PLT");
+            asm("movq -8(%rbp), %rax");
+        }
+
+        None => asm("xorl %eax, %eax"),
+    }
+
+    asm("leave");
+    asm("ret");
+    asm(".size main, .-main");
+    out.push_str(concat!(
+        "\t.section .rodata\n",
+        ".Lfmt_int:\n\t.string \"%ld\\n\"\n",
+        ".Lfmt_flt:\n\t.string \"%g\\n\"\n",
+        ".Lfmt_chr:\n\t.string \"%c\\n\"\n",
+        ".Lfmt_str:\n\t.string \"%s\\n\"\n",
+        ".Ltrue:\n\t.string \"true\"\n",
+        ".Lfalse:\n\t.string \"false\"\n",
+    ));
+
+    out
+}
+
+/**
+ * Lowers `module` to x86-64 assembly for the GNU assembler (AT&T syntax),
+ * following the System V calling convention. Every function gets its
+ * registers from `allocate` and keeps its parameters and locals in a frame
+ * below `%rbp`, at their `Ident.offset`. Functions are named `ez_<name>`,
+ * and a `main` function without parameters gets a C `main` that prints its
+ * result and exits with it.
+ *
+ * Arguments only go in registers, so functions with more than 6 integer or 8
+ * floating point parameters are rejected. Array accesses out of bounds and
+ * failed `chk`s trap with `ud2`, as does a function with a result that
+ * doesn't return one.
+ */
+pub fn emit_x86_64(module: &Module) -> CodegenResult<String> {
+    let mut out = String::from("\t.text\n");
+
+    for func in &module.funcs {
+        out.push_str(&Emitter::new(module, func).emit()?);
+        out.push('\n');
+    }
+
+    if let Some(main) = module.get("main").filter(|main| main.params.is_empty()) {
+        out.push_str(&entry(main));
+    }
+
+    out.push_str("\t.section .note.GNU-stack,\"\",@progbits\n");
+    Ok(out)
+}
+
+/// Like `emit_x86_64`, but assembled into a relocatable ELF object without
+/// going through an external assembler.
+pub fn emit_x86_64_object(module: &Module) -> CodegenResult<Vec<u8>> {
+    Ok(assemble(&emit_x86_64(module)?)?.write())
+}
+
+/// Emits `module` next to `path` (as `.s`) and builds an executable at `path`
+/// with the system `cc`.
+pub fn link_x86_64(module: &Module, path: &Path) -> CodegenResult<()> {
+    let asm = path.with_extension("s");
+    fs::write(&asm, emit_x86_64(module)?).map_err(|error| CodegenError::new(&error.to_string()))?;
+    cc(&asm, path)
+}
+
+/// Like `link_x86_64`, but writes an object (as `.o`) for `cc` to link.
+pub fn link_x86_64_object(module: &Module, path: &Path) -> CodegenResult<()> {
+    let object = path.with_extension("o");
+    fs::write(&object, emit_x86_64_object(module)?)
+        .map_err(|error| CodegenError::new(&error.to_string()))?;
+    cc(&object, path)
+}
+
+fn cc(input: &Path, path: &Path) -> CodegenResult<()> {
+    let output = Command::new("cc")
+        .arg("-o")
+        .arg(path)
+        .arg(input)
+        .output()
+        .map_err(|error| CodegenError::new(&format!("cannot run cc: {}", error)))?;
+
+    if !output.status.success() {
+        return Err(CodegenError::new(&String::from_utf8_lossy(&output.stderr)));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::parse_module;
+    use crate::vm::machine::tests::for_each_program;
+    use crate::vm::Machine;
+
+    /// Output and exit code of `module` built as an executable, through an
+    /// object file or through `as`, `None` when a signal killed it.
+    fn run(module: &Module, name: &str, object: bool) -> (String, Option<i32>) {
+        let dir = std::env::temp_dir().join(format!("ez-lang-x86-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let path = dir.join(name);
+        if object {
+            link_x86_64_object(module, &path).unwrap();
+        } else {
+            link_x86_64(module, &path).unwrap();
+        }
+        let output = Command::new(&path).output().unwrap();
+
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(path.with_extension(if object { "o" } else { "s" })).unwrap();
+        (
+            String::from_utf8(output.stdout).unwrap(),
+            output.status.code(),
+        )
+    }
+
+    fn expected(value: Option<Value>) -> (String, Option<i32>) {
+        match value {
+            Some(Value::Int32(num)) => (format!("{}\n", num), Some(num & 0xff)),
+            Some(Value::Int64(num)) => (format!("{}\n", num), Some((num & 0xff) as i32)),
+            value => panic!("Unexpected result: {:?}", value),
+        }
+    }
+
+    #[test]
+    fn compiled_programs() {
+        for_each_program(|name, module, value| {
+            let expected = expected(value);
+            assert_eq!(run(module, name, false), expected);
+            assert_eq!(run(module, &format!("{}-obj", name), true), expected);
+        });
+    }
+
+    #[test]
+    fn lowering() {
+        // `idiv` faults on i32::MIN / -1 and `cvttsd2siq` gives i64::MIN for
+        // NaN and values out of range, where the VM wraps and saturates
+        let funcs = "fn div(a: i32, b: i32) -> i32\n\tdiv __t0 a b\n\tret __t0\n\
+                     fn trunc(x: f64) -> i32\n\tvar y: i32\n\tmov y x\n\tret y\n\
+                     fn none(x: i32) -> i32\n\tlt __t0 x 0\n\tjmpf L1 __t0\n\tret x\nL1\tret\n";
+        let cases = [
+            (
+                "div",
+                "\tparam -2147483648\n\tparam -1\n\tcall __t0 div 2\n",
+            ),
+            ("big", "\tparam 1e19\n\tcall __t0 trunc 1\n"),
+            ("small", "\tparam -1e19\n\tcall __t0 trunc 1\n"),
+            ("wide", "\tparam 10000000000.0\n\tcall __t0 trunc 1\n"),
+            (
+                "nan",
+                "\tdiv __t1 0.0 0.0\n\tparam __t1\n\tcall __t0 trunc 1\n",
+            ),
+        ];
+
+        for (name, main) in &cases {
+            let code = format!("{}fn main() -> i32\n{}\tret __t0\n", funcs, main);
+            let module = parse_module(&code).unwrap();
+            let value = expected(Machine::new(&module).run().unwrap());
+            assert_eq!(run(&module, name, false), value);
+            assert_eq!(run(&module, &format!("{}-obj", name), true), value);
+        }
+
+        // A missing return traps, where the VM fails
+        let code = format!(
+            "{}fn main() -> i32\n\tparam 1\n\tcall __t0 none 1\n\tret __t0\n",
+            funcs
+        );
+        let module = parse_module(&code).unwrap();
+        assert!(Machine::new(&module).run().is_err());
+        assert_eq!(run(&module, "none", false), (String::new(), None));
+        assert_eq!(run(&module, "none-obj", true), (String::new(), None));
+    }
+
+    #[test]
+    fn out_of_bounds() {
+        // Reads and writes past the end, or before the start, trap instead of
+        // touching the rest of the frame
+        let funcs = "fn get(i: i32) -> i32\n\tvar a: [5]i32\n\tidx __t0 i a\n\tret __t0\n\
+                     fn put(i: i32) -> i32\n\tvar a: [5]i32\n\tsto a i 7\n\tret 0\n";
+        let cases = [("get", 4), ("get", 5), ("put", 5), ("put", -1)];
+
+        for (func, index) in &cases {
+            let code = format!(
+                "{}fn main() -> i32\n\tparam {}\n\tcall __t0 {} 1\n\tret __t0\n",
+                funcs, index, func
+            );
+            let module = parse_module(&code).unwrap();
+            let name = format!("{}{}", func, index);
+            let expected = match Machine::new(&module).run() {
+                Ok(value) => expected(value),
+                Err(_) => (String::new(), None),
+            };
+
+            assert_eq!(run(&module, &name, false), expected);
+            assert_eq!(run(&module, &format!("{}-obj", name), true), expected);
+        }
+    }
+
+    #[test]
+    fn floats_and_calls() {
+        // Mixed types across calls, float arguments, arrays of chars and more
+        // live values than registers
+        let module = parse_module(
+            "fn scale(x: f32, n: i64, y: f64) -> f64\n\
+             \tmul __t0 x n\n\tdiv __t1 __t0 y\n\tret __t1\n\
+             fn main() -> i32\n\tvar s: [4]char\n\tvar v: f32\n\
+             \tsto s 1 97\n\tidx __t3 1 s\n\tadd __t4 __t3 1\n\
+             \tmov v 2.5\n\tparam v\n\tparam 7\n\tparam 0.5\n\tcall __t5 scale 3\n\
+             \tinv __t6 __t5\n\tlt __t7 __t6 0.0\n\tjmpf L1 __t7\n\
+             \tadd __t8 __t4 1\n\tadd __t9 __t4 2\n\tadd __t10 __t4 3\n\
+             \tadd __t11 __t4 4\n\tadd __t12 __t4 5\n\tadd __t13 __t4 6\n\
+             \tadd __t14 __t8 __t9\n\tadd __t15 __t14 __t10\n\tadd __t16 __t15 __t11\n\
+             \tadd __t17 __t16 __t12\n\tadd __t18 __t17 __t13\n\
+             \tdiv __t19 __t18 -3\n\tsub __t20 __t19 __t6\n\tret __t20\n\
+             L1\tret 0\n",
+        );
+
+        let module = match module {
+            Ok(module) => module,
+            Err(error) => panic!("{}", error),
+        };
+
+        let value = Machine::new(&module).run().unwrap();
+        assert_ne!(value, Some(Value::Int32(0)));
+        assert_eq!(run(&module, "floats", false), expected(value.clone()));
+        assert_eq!(run(&module, "floats-obj", true), expected(value));
+    }
+}
@@ -2,6 +2,7 @@ use std::error::Error;
 use std::fmt;
 
 use crate::lex::token::Token;
+use crate::sym::Type;
 
 #[derive(Debug, Clone)]
 pub struct SyntaxError {
@@ -22,3 +23,197 @@ impl fmt::Display for SyntaxError {
         write!(f, "expected {}\ngot {}", expected, self.got)
     }
 }
+
+#[derive(Debug, Clone)]
+pub struct IrError {
+    pub line: usize,
+    pub msg: String,
+}
+
+impl IrError {
+    pub fn new(line: usize, msg: &str) -> Self {
+        Self {
+            line,
+            msg: msg.to_owned(),
+        }
+    }
+}
+
+impl Error for IrError {}
+
+impl fmt::Display for IrError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.msg)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RuntimeError {
+    DivisionByZero,
+    IndexOutOfBounds {
+        index: i64,
+        len: usize,
+    },
+    TypeMismatch {
+        expected: Type,
+        got: String,
+    },
+    UndefinedFunction(String),
+    ArityMismatch {
+        func: String,
+        expected: usize,
+        got: usize,
+    },
+    MissingReturn(String),
+    StackOverflow,
+}
+
+impl Error for RuntimeError {}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::DivisionByZero => write!(f, "division by zero"),
+            Self::IndexOutOfBounds { index, len } => {
+                write!(f, "index {} out of bounds for length {}", index, len)
+            }
+            Self::TypeMismatch { expected, got } => {
+                write!(f, "expected value of type {}, got {}", expected, got)
+            }
+            Self::UndefinedFunction(func) => write!(f, "undefined function `{}`", func),
+            Self::ArityMismatch {
+                func,
+                expected,
+                got,
+            } => write!(
+                f,
+                "function `{}` takes {} arguments, got {}",
+                func, expected, got
+            ),
+            Self::MissingReturn(func) => {
+                write!(f, "function `{}` finished without returning a value", func)
+            }
+            Self::StackOverflow => write!(f, "stack overflow"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum PassError {
+    /// A pass broke an invariant of the IR of `func`.
+    Invalid {
+        pass: String,
+        func: String,
+        error: IrError,
+    },
+    /// A pass can't run on the form the IR is in.
+    Form {
+        pass: String,
+        msg: String,
+    },
+    Dump(String),
+}
+
+impl Error for PassError {}
+
+impl fmt::Display for PassError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Invalid { pass, func, error } => {
+                write!(f, "after pass `{}`, in `{}`: {}", pass, func, error)
+            }
+            Self::Form { pass, msg } => write!(f, "pass `{}` {}", pass, msg),
+            Self::Dump(msg) => write!(f, "cannot dump IR: {}", msg),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BytecodeError {
+    /// The bytes at `offset` aren't a valid `.ezb` file.
+    Malformed {
+        offset: usize,
+        msg: String,
+    },
+    /// Instruction `inst` of `func`, counting from 1, breaks an invariant of
+    /// the VM. Instruction 0 stands for the function itself.
+    Invalid {
+        func: String,
+        inst: usize,
+        msg: String,
+    },
+    Io(String),
+}
+
+impl Error for BytecodeError {}
+
+impl fmt::Display for BytecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Malformed { offset, msg } => write!(f, "at byte {}: {}", offset, msg),
+            Self::Invalid { func, inst, msg } => {
+                write!(f, "in `{}`, instruction {}: {}", func, inst, msg)
+            }
+            Self::Io(msg) => write!(f, "cannot access bytecode: {}", msg),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SimError {
+    /// The call itself is wrong, as it would be for the VM.
+    Runtime(RuntimeError),
+    /// The word at `pc` isn't an instruction of RV64IMFD.
+    IllegalInstruction { pc: u64, word: u32 },
+    /// Access outside of the memory of the simulator, or store into the code.
+    MemoryFault { pc: u64, addr: u64 },
+    /// `ecall` or `ebreak`, there's no environment to handle them.
+    Trap { pc: u64 },
+    /// Ran more instructions than allowed.
+    StepLimit(u64),
+}
+
+impl From<RuntimeError> for SimError {
+    fn from(error: RuntimeError) -> Self {
+        Self::Runtime(error)
+    }
+}
+
+impl Error for SimError {}
+
+impl fmt::Display for SimError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Runtime(error) => write!(f, "{}", error),
+            Self::IllegalInstruction { pc, word } => {
+                write!(f, "illegal instruction {:#010x} at {:#x}", word, pc)
+            }
+            Self::MemoryFault { pc, addr } => {
+                write!(f, "memory fault at {:#x}, accessing {:#x}", pc, addr)
+            }
+            Self::Trap { pc } => write!(f, "trap at {:#x}", pc),
+            Self::StepLimit(steps) => write!(f, "stopped after {} instructions", steps),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CodegenError {
+    pub msg: String,
+}
+
+impl CodegenError {
+    pub fn new(msg: &str) -> Self {
+        Self {
+            msg: msg.to_owned(),
+        }
+    }
+}
+
+impl Error for CodegenError {}
+
+impl fmt::Display for CodegenError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.msg)
+    }
+}
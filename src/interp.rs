@@ -0,0 +1,5 @@
+pub mod interpreter;
+pub mod value;
+
+pub use interpreter::*;
+pub use value::*;
@@ -0,0 +1,601 @@
+use std::collections::HashMap;
+
+use crate::ast::{Call, Expr, Index, Program, Stmt};
+use crate::error::RuntimeError;
+use crate::interp::{RunResult, Value};
+use crate::lex::Token;
+
+type Frame = HashMap<String, Value>;
+
+enum Flow {
+    Next,
+    Break,
+    Return(Option<Value>),
+}
+
+/// Tree-walking interpreter over the AST of a `Program`.
+#[derive(Debug)]
+pub struct Interpreter<'a> {
+    program: &'a Program,
+    depth: usize,
+}
+
+impl<'a> Interpreter<'a> {
+    const MAX_DEPTH: usize = 256;
+
+    pub fn new(program: &'a Program) -> Self {
+        Self { program, depth: 0 }
+    }
+
+    pub fn run(&mut self) -> RunResult<Option<Value>> {
+        self.call("main", &[])
+    }
+
+    pub fn call(&mut self, name: &str, args: &[Value]) -> RunResult<Option<Value>> {
+        let func = self
+            .program
+            .get(name)
+            .ok_or_else(|| RuntimeError::UndefinedFunction(name.to_owned()))?;
+
+        if args.len() != func.params.len() {
+            return Err(RuntimeError::ArityMismatch {
+                func: name.to_owned(),
+                expected: func.params.len(),
+                got: args.len(),
+            });
+        }
+
+        if self.depth >= Self::MAX_DEPTH {
+            return Err(RuntimeError::StackOverflow);
+        }
+
+        let mut frame = Frame::new();
+        for local in &func.locals {
+            frame.insert(local.id.clone(), Value::zero(&local.tp));
+        }
+
+        for (param, arg) in func.params.iter().zip(args) {
+            frame.insert(param.id.clone(), arg.cast(&param.tp)?);
+        }
+
+        self.depth += 1;
+        let flow = self.exec(&func.body, &mut frame);
+        self.depth -= 1;
+
+        match (flow?, &func.ret) {
+            (_, None) => Ok(None),
+            (Flow::Return(Some(value)), Some(tp)) => Ok(Some(value.cast(tp)?)),
+            (_, Some(_)) => Err(RuntimeError::MissingReturn(name.to_owned())),
+        }
+    }
+
+    fn exec(&mut self, stmt: &Stmt, frame: &mut Frame) -> RunResult<Flow> {
+        match stmt {
+            Stmt::Null => {}
+
+            Stmt::Set { id, expr } => {
+                let value = self.eval(expr, frame)?.cast(&id.tp)?;
+                frame.insert(id.id.clone(), value);
+            }
+
+            // The indices are evaluated first, each checked against its own
+            // dimension, and the position is checked last, as in the TAC
+            Stmt::SetElem { index, expr } => {
+                let pos = self.eval_position(index, frame)?;
+                let value = self.eval(expr, frame)?;
+
+                let array = frame
+                    .entry(index.array.id.clone())
+                    .or_insert_with(|| Value::zero(&index.array.tp));
+
+                if let Value::Array(elems) = array {
+                    let pos = Self::position(elems, pos)?;
+                    elems[pos] = value.cast(&index.tp)?;
+                }
+            }
+
+            Stmt::Seq(stmts) => {
+                for stmt in stmts {
+                    match self.exec(stmt, frame)? {
+                        Flow::Next => {}
+                        flow => return Ok(flow),
+                    }
+                }
+            }
+
+            Stmt::If {
+                cond,
+                then,
+                otherwise,
+            } => {
+                if self.eval(cond, frame)?.as_bool()? {
+                    return self.exec(then, frame);
+                } else if let Some(otherwise) = otherwise {
+                    return self.exec(otherwise, frame);
+                }
+            }
+
+            Stmt::While { cond, body } => {
+                while self.eval(cond, frame)?.as_bool()? {
+                    match self.exec(body, frame)? {
+                        Flow::Next => {}
+                        Flow::Break => break,
+                        flow => return Ok(flow),
+                    }
+                }
+            }
+
+            Stmt::Do { body, cond } => loop {
+                match self.exec(body, frame)? {
+                    Flow::Next => {}
+                    Flow::Break => break,
+                    flow => return Ok(flow),
+                }
+
+                if !self.eval(cond, frame)?.as_bool()? {
+                    break;
+                }
+            },
+
+            Stmt::For {
+                init,
+                cond,
+                step,
+                body,
+            } => {
+                self.exec(init, frame)?;
+                while self.eval(cond, frame)?.as_bool()? {
+                    match self.exec(body, frame)? {
+                        Flow::Next => {}
+                        Flow::Break => break,
+                        flow => return Ok(flow),
+                    }
+
+                    self.exec(step, frame)?;
+                }
+            }
+
+            Stmt::Break => return Ok(Flow::Break),
+
+            Stmt::Return(expr) => {
+                let value = match expr {
+                    Some(expr) => Some(self.eval(expr, frame)?),
+                    None => None,
+                };
+
+                return Ok(Flow::Return(value));
+            }
+
+            Stmt::Call(call) => {
+                self.eval_call(call, frame)?;
+            }
+        }
+
+        Ok(Flow::Next)
+    }
+
+    fn position(elems: &[Value], index: i64) -> RunResult<usize> {
+        if index < 0 || index as usize >= elems.len() {
+            Err(RuntimeError::IndexOutOfBounds {
+                index,
+                len: elems.len(),
+            })
+        } else {
+            Ok(index as usize)
+        }
+    }
+
+    /// Position of the scalar `index` reads, with several dimensions each
+    /// index is checked against its own first, as the TAC does with `chk`.
+    fn eval_position(&mut self, index: &Index, frame: &mut Frame) -> RunResult<i64> {
+        let indices = index
+            .indices
+            .iter()
+            .map(|index| self.eval(index, frame).and_then(|value| value.as_index()))
+            .collect::<RunResult<Vec<_>>>()?;
+
+        let dims = index.dims();
+        if indices.len() > 1 {
+            for (&index, &(size, _)) in indices.iter().zip(&dims) {
+                if index < 0 || index as usize >= size {
+                    return Err(RuntimeError::IndexOutOfBounds { index, len: size });
+                }
+            }
+        }
+
+        Ok(indices
+            .iter()
+            .zip(&dims)
+            .map(|(index, (_, width))| index * *width as i64)
+            .sum())
+    }
+
+    fn eval_call(&mut self, call: &Call, frame: &mut Frame) -> RunResult<Option<Value>> {
+        let args = call
+            .args
+            .iter()
+            .map(|arg| self.eval(arg, frame))
+            .collect::<RunResult<Vec<_>>>()?;
+
+        self.call(&call.func, &args)
+    }
+
+    fn eval_index(&mut self, index: &Index, frame: &mut Frame) -> RunResult<Value> {
+        let pos = self.eval_position(index, frame)?;
+        match frame.get(&index.array.id) {
+            Some(Value::Array(elems)) => Ok(elems[Self::position(elems, pos)?].clone()),
+            _ => Err(RuntimeError::TypeMismatch {
+                expected: index.array.tp.clone(),
+                got: index.array.id.clone(),
+            }),
+        }
+    }
+
+    fn eval(&mut self, expr: &Expr, frame: &mut Frame) -> RunResult<Value> {
+        let value = match expr {
+            Expr::Ident(ident) => frame
+                .get(&ident.id)
+                .cloned()
+                .unwrap_or_else(|| Value::zero(&ident.tp)),
+
+            Expr::Cons(cons) => Value::from_cons(cons),
+
+            Expr::Temp(_) => panic!("Temporaries only appear in generated code"),
+
+            Expr::Arithm(arithm) => {
+                let lhs = self.eval(&arithm.expr1, frame)?;
+                let rhs = self.eval(&arithm.expr2, frame)?;
+                Value::arithm(&arithm.op, &lhs, &rhs)?.cast(&arithm.tp)?
+            }
+
+            Expr::Unary(unary) => self.eval(&unary.expr, frame)?.negate()?,
+
+            Expr::Index(index) => self.eval_index(index, frame)?,
+
+            Expr::Rel(rel) => {
+                let lhs = self.eval(&rel.expr1, frame)?;
+                let rhs = self.eval(&rel.expr2, frame)?;
+                Value::Bool(Value::compare(&rel.op, &lhs, &rhs)?)
+            }
+
+            Expr::Logical(logical) => {
+                let lhs = self.eval(&logical.expr1, frame)?.as_bool()?;
+                let value = match logical.op {
+                    Token::DoubleAmpersand if !lhs => false,
+                    Token::DoubleVerticalBar if lhs => true,
+                    _ => self.eval(&logical.expr2, frame)?.as_bool()?,
+                };
+
+                Value::Bool(value)
+            }
+
+            Expr::Not(not) => Value::Bool(!self.eval(&not.expr, frame)?.as_bool()?),
+
+            Expr::Call(call) => self
+                .eval_call(call, frame)?
+                .ok_or_else(|| RuntimeError::MissingReturn(call.func.clone()))?,
+        };
+
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::ast::{Arithm, Cons, Func, Ident, Logical, Not, Rel};
+    use crate::sym::Type;
+
+    pub(crate) fn ident(id: &str, tp: Type, offset: usize) -> Ident {
+        Ident {
+            id: id.to_owned(),
+            tp,
+            offset,
+        }
+    }
+
+    pub(crate) fn var(ident: &Ident) -> Expr {
+        Expr::Ident(ident.clone())
+    }
+
+    pub(crate) fn int(num: i32) -> Expr {
+        Expr::Cons(Cons {
+            tok: Token::Integer(num),
+            tp: Type::Int32,
+        })
+    }
+
+    pub(crate) fn arithm(op: Token, expr1: Expr, expr2: Expr) -> Expr {
+        Arithm::new(&op, &expr1, &expr2)
+    }
+
+    pub(crate) fn rel(op: Token, expr1: Expr, expr2: Expr) -> Expr {
+        Expr::Rel(Rel::new(&op, &expr1, &expr2))
+    }
+
+    pub(crate) fn set(id: &Ident, expr: Expr) -> Stmt {
+        Stmt::Set {
+            id: id.clone(),
+            expr,
+        }
+    }
+
+    pub(crate) fn elem(array: &Ident, index: Expr) -> Index {
+        Index::new(array, &[index])
+    }
+
+    pub(crate) fn main(locals: Vec<Ident>, body: Vec<Stmt>) -> Func {
+        Func {
+            name: "main".to_owned(),
+            params: vec![],
+            locals,
+            ret: Some(Type::Int64),
+            body: Stmt::Seq(body),
+        }
+    }
+
+    pub(crate) fn fact_program() -> Program {
+        let n = ident("n", Type::Int32, 0);
+        let fact = |arg| {
+            Expr::Call(Call {
+                func: "fact".to_owned(),
+                tp: Some(Type::Int32),
+                args: vec![arg],
+            })
+        };
+
+        Program {
+            funcs: vec![
+                Func {
+                    name: "fact".to_owned(),
+                    params: vec![n.clone()],
+                    locals: vec![],
+                    ret: Some(Type::Int32),
+                    body: Stmt::Seq(vec![
+                        Stmt::If {
+                            cond: rel(Token::LessEqual, var(&n), int(1)),
+                            then: Box::new(Stmt::Return(Some(int(1)))),
+                            otherwise: None,
+                        },
+                        Stmt::Return(Some(arithm(
+                            Token::Asterisk,
+                            var(&n),
+                            fact(arithm(Token::Minus, var(&n), int(1))),
+                        ))),
+                    ]),
+                },
+                main(vec![], vec![Stmt::Return(Some(fact(int(10))))]),
+            ],
+        }
+    }
+
+    #[test]
+    fn recursive_calls() -> RunResult<()> {
+        let program = fact_program();
+        let mut interp = Interpreter::new(&program);
+        assert_eq!(interp.run()?, Some(Value::Int64(3_628_800)));
+        assert_eq!(
+            interp.call("fact", &[Value::Int32(5)])?,
+            Some(Value::Int32(120))
+        );
+        assert_eq!(
+            interp.call("fact", &[]),
+            Err(RuntimeError::ArityMismatch {
+                func: "fact".to_owned(),
+                expected: 1,
+                got: 0
+            })
+        );
+        assert_eq!(
+            interp.call("fib", &[]),
+            Err(RuntimeError::UndefinedFunction("fib".to_owned()))
+        );
+
+        Ok(())
+    }
+
+    pub(crate) fn loops_program() -> Program {
+        let tp = Type::Array {
+            of: Box::new(Type::Int32),
+            size: 5,
+        };
+        let a = ident("a", tp, 0);
+        let i = ident("i", Type::Int32, 20);
+        let s = ident("s", Type::Int64, 24);
+        let x = ident("x", Type::Int32, 32);
+
+        Program {
+            funcs: vec![main(
+                vec![a.clone(), i.clone(), s.clone(), x.clone()],
+                vec![
+                    Stmt::For {
+                        init: Box::new(set(&i, int(0))),
+                        cond: rel(Token::LessThan, var(&i), int(5)),
+                        step: Box::new(set(&i, arithm(Token::Plus, var(&i), int(1)))),
+                        body: Box::new(Stmt::SetElem {
+                            index: elem(&a, var(&i)),
+                            expr: arithm(Token::Asterisk, var(&i), var(&i)),
+                        }),
+                    },
+                    set(&i, int(0)),
+                    Stmt::While {
+                        cond: Expr::Logical(Logical::new(
+                            &Token::DoubleAmpersand,
+                            &rel(Token::LessThan, var(&i), int(100)),
+                            &rel(Token::NotEqual, var(&s), int(1000)),
+                        )),
+                        body: Box::new(Stmt::Seq(vec![
+                            Stmt::If {
+                                cond: rel(Token::GreaterEqual, var(&i), int(5)),
+                                then: Box::new(Stmt::Break),
+                                otherwise: None,
+                            },
+                            set(&x, Expr::Index(elem(&a, var(&i)))),
+                            set(&s, arithm(Token::Plus, var(&s), var(&x))),
+                            set(&i, arithm(Token::Plus, var(&i), int(1))),
+                        ])),
+                    },
+                    Stmt::Do {
+                        body: Box::new(set(&s, arithm(Token::Plus, var(&s), int(1)))),
+                        cond: Expr::Not(Not::new(&rel(Token::GreaterEqual, var(&s), int(33)))),
+                    },
+                    Stmt::If {
+                        cond: rel(Token::Equal, var(&s), int(33)),
+                        then: Box::new(set(&s, arithm(Token::Asterisk, var(&s), int(2)))),
+                        otherwise: Some(Box::new(set(&s, int(0)))),
+                    },
+                    Stmt::Return(Some(var(&s))),
+                ],
+            )],
+        }
+    }
+
+    #[test]
+    fn loops_and_arrays() -> RunResult<()> {
+        let program = loops_program();
+        assert_eq!(Interpreter::new(&program).run()?, Some(Value::Int64(66)));
+        Ok(())
+    }
+
+    /// Fills `m[i][j]` with `10 * i + j` and folds it row after row, so a
+    /// transposed layout gives another result.
+    pub(crate) fn matrix_program() -> Program {
+        let tp: Type = "[3][4]i32".parse().unwrap();
+        let m = ident("m", tp, 0);
+        let i = ident("i", Type::Int32, 48);
+        let j = ident("j", Type::Int32, 52);
+        let s = ident("s", Type::Int64, 56);
+        let at = |row, col| Index::new(&m, &[row, col]);
+
+        let each = |body: Stmt| Stmt::For {
+            init: Box::new(set(&i, int(0))),
+            cond: rel(Token::LessThan, var(&i), int(3)),
+            step: Box::new(set(&i, arithm(Token::Plus, var(&i), int(1)))),
+            body: Box::new(Stmt::For {
+                init: Box::new(set(&j, int(0))),
+                cond: rel(Token::LessThan, var(&j), int(4)),
+                step: Box::new(set(&j, arithm(Token::Plus, var(&j), int(1)))),
+                body: Box::new(body),
+            }),
+        };
+
+        Program {
+            funcs: vec![main(
+                vec![m.clone(), i.clone(), j.clone(), s.clone()],
+                vec![
+                    each(Stmt::SetElem {
+                        index: at(var(&i), var(&j)),
+                        expr: arithm(
+                            Token::Plus,
+                            arithm(Token::Asterisk, var(&i), int(10)),
+                            var(&j),
+                        ),
+                    }),
+                    each(set(
+                        &s,
+                        arithm(
+                            Token::Plus,
+                            arithm(Token::Asterisk, var(&s), int(3)),
+                            Expr::Index(at(var(&i), var(&j))),
+                        ),
+                    )),
+                    Stmt::Return(Some(arithm(
+                        Token::Plus,
+                        var(&s),
+                        Expr::Index(at(int(2), int(1))),
+                    ))),
+                ],
+            )],
+        }
+    }
+
+    #[test]
+    fn nested_arrays() -> RunResult<()> {
+        let program = matrix_program();
+        assert_eq!(
+            Interpreter::new(&program).run()?,
+            Some(Value::Int64(152_795))
+        );
+
+        let m = &program.funcs[0].locals[0];
+        let at = Index::new(m, &[var(&program.funcs[0].locals[1]), int(3)]);
+        assert_eq!(Type::Int32, Expr::Index(at.clone()).get_tp());
+        match &at.position(&at.indices) {
+            Expr::Arithm(sum) if sum.op == Token::Plus => {
+                assert_eq!("i 4", sum.expr1.to_string());
+                assert_eq!("3", sum.expr2.to_string());
+            }
+            index => panic!("unexpected {}", index),
+        }
+
+        let run = |index| {
+            let program = Program {
+                funcs: vec![main(
+                    program.funcs[0].locals.clone(),
+                    vec![Stmt::Return(Some(Expr::Index(index)))],
+                )],
+            };
+            Interpreter::new(&program).run()
+        };
+        assert_eq!(
+            run(Index::new(m, &[int(2), int(3)])),
+            Ok(Some(Value::Int64(0)))
+        );
+        assert_eq!(
+            run(Index::new(m, &[int(3), int(0)])),
+            Err(RuntimeError::IndexOutOfBounds { index: 3, len: 3 })
+        );
+
+        // Still inside the array, but past the end of its row
+        assert_eq!(
+            run(Index::new(m, &[int(0), int(5)])),
+            Err(RuntimeError::IndexOutOfBounds { index: 5, len: 4 })
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn runtime_errors() {
+        let a = ident(
+            "a",
+            Type::Array {
+                of: Box::new(Type::Flt64),
+                size: 2,
+            },
+            0,
+        );
+        let x = ident("x", Type::Int32, 16);
+
+        let run = |body| {
+            let program = Program {
+                funcs: vec![main(vec![a.clone(), x.clone()], body)],
+            };
+            Interpreter::new(&program).run()
+        };
+
+        assert_eq!(
+            run(vec![Stmt::Return(Some(arithm(
+                Token::Divide,
+                int(1),
+                var(&x)
+            )))]),
+            Err(RuntimeError::DivisionByZero)
+        );
+        assert_eq!(
+            run(vec![Stmt::Return(Some(Expr::Index(elem(&a, int(2)))))]),
+            Err(RuntimeError::IndexOutOfBounds { index: 2, len: 2 })
+        );
+        assert_eq!(
+            run(vec![Stmt::SetElem {
+                index: elem(&a, int(-1)),
+                expr: int(3),
+            }]),
+            Err(RuntimeError::IndexOutOfBounds { index: -1, len: 2 })
+        );
+        assert_eq!(
+            run(vec![set(&x, int(1))]),
+            Err(RuntimeError::MissingReturn("main".to_owned()))
+        );
+    }
+}
@@ -0,0 +1,304 @@
+use std::convert::TryFrom;
+use std::fmt::{self, Display, Formatter};
+
+use crate::ast::Cons;
+use crate::error::RuntimeError;
+use crate::lex::Token;
+use crate::sym::Type;
+
+pub type RunResult<T> = Result<T, RuntimeError>;
+
+/// Runtime value, one variant per `sym::Type`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Int32(i32),
+    Int64(i64),
+    Flt32(f32),
+    Flt64(f64),
+    Char(char),
+    Bool(bool),
+    String(String),
+    Array(Vec<Value>),
+}
+
+impl Value {
+    pub fn zero(tp: &Type) -> Self {
+        match tp {
+            Type::Int32 => Self::Int32(0),
+            Type::Int64 => Self::Int64(0),
+            Type::Flt32 => Self::Flt32(0.0),
+            Type::Flt64 => Self::Flt64(0.0),
+            Type::Char => Self::Char('\0'),
+            Type::Bool => Self::Bool(false),
+            Type::String(_) => Self::String(String::new()),
+            Type::Array { .. } => Self::Array(vec![Self::zero(tp.get_scalar()); tp.get_len()]),
+        }
+    }
+
+    pub fn from_cons(cons: &Cons) -> Self {
+        let value = match &cons.tok {
+            Token::Integer(num) => Self::Int32(*num),
+            Token::Float(num) => Self::Flt64(*num),
+            Token::ReservedWord(word) if word == "true" || word == "false" => {
+                Self::Bool(word == "true")
+            }
+            Token::String(string) if cons.tp == Type::Char => {
+                Self::Char(string.chars().next().unwrap_or('\0'))
+            }
+            Token::String(string) => Self::String(string.clone()),
+            tok => panic!("Bad constant {}", tok),
+        };
+
+        value.cast(&cons.tp).expect("Bad constant")
+    }
+
+    /// Constant that `from_cons` turns back into this value, if the tokens can
+    /// represent it.
+    pub fn to_cons(&self) -> Option<Cons> {
+        let (tok, tp) = match self {
+            Self::Int32(num) => (Token::Integer(*num), Type::Int32),
+            Self::Int64(num) => (Token::Integer(i32::try_from(*num).ok()?), Type::Int64),
+            Self::Flt32(num) if num.is_finite() => (Token::Float(*num as f64), Type::Flt32),
+            Self::Flt64(num) if num.is_finite() => (Token::Float(*num), Type::Flt64),
+            Self::Char(chr) => (Token::String(chr.to_string()), Type::Char),
+            Self::Bool(value) => (Token::ReservedWord(value.to_string()), Type::Bool),
+            _ => return None,
+        };
+
+        Some(Cons { tok, tp })
+    }
+
+    fn numeric_tp(&self) -> Option<Type> {
+        match self {
+            Self::Int32(_) => Some(Type::Int32),
+            Self::Int64(_) => Some(Type::Int64),
+            Self::Flt32(_) => Some(Type::Flt32),
+            Self::Flt64(_) => Some(Type::Flt64),
+            Self::Char(_) => Some(Type::Char),
+            _ => None,
+        }
+    }
+
+    fn as_i64(&self) -> Option<i64> {
+        match self {
+            Self::Int32(num) => Some(*num as i64),
+            Self::Int64(num) => Some(*num),
+            Self::Flt32(num) => Some(*num as i64),
+            Self::Flt64(num) => Some(*num as i64),
+            Self::Char(chr) => Some(*chr as i64),
+            _ => None,
+        }
+    }
+
+    fn as_f64(&self) -> Option<f64> {
+        match self {
+            Self::Flt32(num) => Some(*num as f64),
+            Self::Flt64(num) => Some(*num),
+            other => other.as_i64().map(|num| num as f64),
+        }
+    }
+
+    fn mismatch(&self, expected: &Type) -> RuntimeError {
+        RuntimeError::TypeMismatch {
+            expected: expected.clone(),
+            got: self.to_string(),
+        }
+    }
+
+    /// Converts the value to `tp`, following the numeric conversions of `Type::upcast`.
+    /// Narrowing integer conversions wrap around.
+    pub fn cast(&self, tp: &Type) -> RunResult<Self> {
+        let value = match (tp, self) {
+            (Type::Bool, Self::Bool(_)) | (Type::String(_), Self::String(_)) => self.clone(),
+
+            (Type::Array { .. }, Self::Array(elems)) if elems.len() == tp.get_len() => Self::Array(
+                elems
+                    .iter()
+                    .map(|elem| elem.cast(tp.get_scalar()))
+                    .collect::<RunResult<_>>()?,
+            ),
+
+            (Type::Flt32, _) if self.numeric_tp().is_some() => {
+                Self::Flt32(self.as_f64().unwrap() as f32)
+            }
+            (Type::Flt64, _) if self.numeric_tp().is_some() => Self::Flt64(self.as_f64().unwrap()),
+            (Type::Int32, _) if self.numeric_tp().is_some() => {
+                Self::Int32(self.as_i64().unwrap() as i32)
+            }
+            (Type::Int64, _) if self.numeric_tp().is_some() => Self::Int64(self.as_i64().unwrap()),
+            (Type::Char, _) if self.numeric_tp().is_some() => {
+                Self::Char(self.as_i64().unwrap() as u8 as char)
+            }
+
+            _ => return Err(self.mismatch(tp)),
+        };
+
+        Ok(value)
+    }
+
+    fn upcast(lhs: &Self, rhs: &Self) -> RunResult<Type> {
+        let tp1 = lhs.numeric_tp().ok_or_else(|| lhs.mismatch(&Type::Flt64))?;
+        let tp2 = rhs.numeric_tp().ok_or_else(|| rhs.mismatch(&Type::Flt64))?;
+        Ok(tp1.upcast(&tp2).unwrap())
+    }
+
+    /// Applies `+ - * /` in the upcasted type of both operands. Integer arithmetic wraps.
+    pub fn arithm(op: &Token, lhs: &Self, rhs: &Self) -> RunResult<Self> {
+        let tp = Self::upcast(lhs, rhs)?;
+
+        match tp {
+            Type::Flt32 | Type::Flt64 => {
+                let (num1, num2) = (lhs.as_f64().unwrap(), rhs.as_f64().unwrap());
+                let result = match op {
+                    Token::Plus => num1 + num2,
+                    Token::Minus => num1 - num2,
+                    Token::Asterisk => num1 * num2,
+                    Token::Divide => num1 / num2,
+                    _ => panic!("Bad operator"),
+                };
+
+                Self::Flt64(result).cast(&tp)
+            }
+
+            _ => {
+                let (num1, num2) = (lhs.as_i64().unwrap(), rhs.as_i64().unwrap());
+                let result = match op {
+                    Token::Plus => num1.wrapping_add(num2),
+                    Token::Minus => num1.wrapping_sub(num2),
+                    Token::Asterisk => num1.wrapping_mul(num2),
+                    Token::Divide if num2 == 0 => return Err(RuntimeError::DivisionByZero),
+                    Token::Divide => num1.wrapping_div(num2),
+                    _ => panic!("Bad operator"),
+                };
+
+                Self::Int64(result).cast(&tp)
+            }
+        }
+    }
+
+    /// Negates the value, the result is at least as wide as `i64` (see `Unary::new`).
+    pub fn negate(&self) -> RunResult<Self> {
+        let tp = self
+            .numeric_tp()
+            .and_then(|tp| Type::Int64.upcast(&tp))
+            .ok_or_else(|| self.mismatch(&Type::Int64))?;
+
+        Self::arithm(&Token::Minus, &Self::zero(&tp), self)
+    }
+
+    pub fn compare(op: &Token, lhs: &Self, rhs: &Self) -> RunResult<bool> {
+        let ordering = if lhs.numeric_tp().is_some() && rhs.numeric_tp().is_some() {
+            match Self::upcast(lhs, rhs)? {
+                Type::Flt32 | Type::Flt64 => {
+                    lhs.as_f64().unwrap().partial_cmp(&rhs.as_f64().unwrap())
+                }
+                _ => Some(lhs.as_i64().unwrap().cmp(&rhs.as_i64().unwrap())),
+            }
+        } else {
+            match op {
+                Token::Equal => return Ok(lhs == rhs),
+                Token::NotEqual => return Ok(lhs != rhs),
+                _ => return Err(rhs.mismatch(&Type::Flt64)),
+            }
+        };
+
+        let result = match (op, ordering) {
+            (Token::NotEqual, None) => true,
+            (_, None) => false,
+            (Token::LessThan, Some(ord)) => ord.is_lt(),
+            (Token::LessEqual, Some(ord)) => ord.is_le(),
+            (Token::GreaterThan, Some(ord)) => ord.is_gt(),
+            (Token::GreaterEqual, Some(ord)) => ord.is_ge(),
+            (Token::Equal, Some(ord)) => ord.is_eq(),
+            (Token::NotEqual, Some(ord)) => ord.is_ne(),
+            _ => panic!("Bad operator"),
+        };
+
+        Ok(result)
+    }
+
+    pub fn as_bool(&self) -> RunResult<bool> {
+        match self {
+            Self::Bool(value) => Ok(*value),
+            other => Err(other.mismatch(&Type::Bool)),
+        }
+    }
+
+    pub fn as_index(&self) -> RunResult<i64> {
+        match self {
+            Self::Int32(_) | Self::Int64(_) | Self::Char(_) => Ok(self.as_i64().unwrap()),
+            other => Err(other.mismatch(&Type::Int64)),
+        }
+    }
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::Int32(num) => write!(f, "{}", num),
+            Self::Int64(num) => write!(f, "{}", num),
+            Self::Flt32(num) => write!(f, "{}", num),
+            Self::Flt64(num) => write!(f, "{}", num),
+            Self::Char(chr) => write!(f, "{}", chr),
+            Self::Bool(value) => write!(f, "{}", value),
+            Self::String(string) => write!(f, "{}", string),
+            Self::Array(elems) => {
+                let elems: Vec<String> = elems.iter().map(Value::to_string).collect();
+                write!(f, "[{}]", elems.join(", "))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn arithmetic() -> RunResult<()> {
+        let add = Value::arithm(&Token::Plus, &Value::Int32(2), &Value::Flt32(0.5))?;
+        assert_eq!(add, Value::Flt32(2.5));
+
+        let wrap = Value::arithm(&Token::Asterisk, &Value::Int32(i32::MAX), &Value::Int32(2))?;
+        assert_eq!(wrap, Value::Int32(-2));
+
+        let div = Value::arithm(&Token::Divide, &Value::Int64(7), &Value::Char('\u{2}'))?;
+        assert_eq!(div, Value::Int64(3));
+
+        assert_eq!(Value::Int32(-3).negate()?, Value::Int64(3));
+        assert_eq!(
+            Value::arithm(&Token::Divide, &Value::Int32(1), &Value::Int32(0)),
+            Err(RuntimeError::DivisionByZero)
+        );
+        assert!(Value::arithm(&Token::Plus, &Value::Bool(true), &Value::Int32(1)).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn casting() -> RunResult<()> {
+        assert_eq!(Value::Flt64(3.9).cast(&Type::Int32)?, Value::Int32(3));
+        assert_eq!(Value::Int32(65).cast(&Type::Char)?, Value::Char('A'));
+        assert_eq!(
+            Value::zero(&Type::Array {
+                of: Box::new(Type::Flt32),
+                size: 2
+            }),
+            Value::Array(vec![Value::Flt32(0.0), Value::Flt32(0.0)])
+        );
+        assert!(Value::Bool(true).cast(&Type::Int32).is_err());
+
+        for value in &[
+            Value::Int64(-7),
+            Value::Flt32(0.5),
+            Value::Bool(true),
+            Value::Char('x'),
+        ] {
+            assert_eq!(&Value::from_cons(&value.to_cons().unwrap()), value);
+        }
+        assert_eq!(Value::Int64(i64::MAX).to_cons(), None);
+        assert_eq!(Value::Flt64(f64::NAN).to_cons(), None);
+
+        Ok(())
+    }
+}
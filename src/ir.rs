@@ -0,0 +1,15 @@
+pub mod cfg;
+pub mod dom;
+pub mod inst;
+pub mod module;
+pub mod parser;
+pub mod ssa;
+pub mod verify;
+
+pub use cfg::*;
+pub use dom::*;
+pub use inst::*;
+pub use module::*;
+pub use parser::*;
+pub use ssa::*;
+pub use verify::*;
@@ -0,0 +1,223 @@
+use std::collections::HashMap;
+
+use crate::ir::Inst;
+
+/// Straight-line run of instructions, only its leading labels can be jumped to.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Block {
+    pub insts: Vec<Inst>,
+    pub preds: Vec<usize>,
+    pub succs: Vec<usize>,
+}
+
+impl Block {
+    pub fn labels(&self) -> impl Iterator<Item = usize> + '_ {
+        self.insts.iter().map_while(|inst| match inst {
+            Inst::Label(label) => Some(*label),
+            _ => None,
+        })
+    }
+
+    /// Instructions after the leading labels.
+    pub fn body(&self) -> &[Inst] {
+        let labels = self.labels().count();
+        &self.insts[labels..]
+    }
+}
+
+/**
+ * Control-flow graph of a function. `entry` and `exit` are empty blocks that
+ * wrap the code: `entry` falls into the first block, and every `ret` (or
+ * falling off the end of the function) leads to `exit`.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cfg {
+    pub blocks: Vec<Block>,
+    pub entry: usize,
+    pub exit: usize,
+}
+
+impl Cfg {
+    pub fn new(code: &[Inst]) -> Self {
+        let mut blocks = vec![Block::default(), Block::default()];
+
+        for inst in code {
+            let leader = match (inst, blocks.last().unwrap().insts.last()) {
+                (_, None) => false,
+                (Inst::Label(_), Some(Inst::Label(_))) => false,
+                (Inst::Label(_), _) => true,
+                (_, Some(last)) => last.is_terminator(),
+            };
+
+            if leader {
+                blocks.push(Block::default());
+            }
+
+            blocks.last_mut().unwrap().insts.push(inst.clone());
+        }
+
+        blocks.push(Block::default());
+
+        let mut cfg = Self {
+            blocks,
+            entry: 0,
+            exit: 0,
+        };
+
+        cfg.exit = cfg.blocks.len() - 1;
+        cfg.link();
+        cfg
+    }
+
+    pub fn block_of(&self, label: usize) -> Option<usize> {
+        self.blocks
+            .iter()
+            .position(|block| block.labels().any(|other| other == label))
+    }
+
+    /// Recomputes predecessors and successors from the instructions.
+    pub fn link(&mut self) {
+        let labels: HashMap<usize, usize> = self
+            .blocks
+            .iter()
+            .enumerate()
+            .flat_map(|(id, block)| block.labels().map(move |label| (label, id)))
+            .collect();
+
+        for block in &mut self.blocks {
+            block.preds.clear();
+            block.succs.clear();
+        }
+
+        for id in 0..self.blocks.len() {
+            if id == self.exit {
+                continue;
+            }
+
+            let next = id + 1;
+            let succs = match self.blocks[id].insts.last() {
+                Some(Inst::Jmp { label }) => vec![labels[label]],
+                Some(Inst::JmpT { label, .. }) | Some(Inst::JmpF { label, .. }) => {
+                    vec![labels[label], next]
+                }
+                Some(Inst::Ret { .. }) => vec![self.exit],
+                _ => vec![next],
+            };
+
+            for succ in succs {
+                if !self.blocks[id].succs.contains(&succ) {
+                    self.blocks[id].succs.push(succ);
+                    self.blocks[succ].preds.push(id);
+                }
+            }
+        }
+    }
+
+    /// Blocks reachable from `entry`, in reverse postorder.
+    pub fn reverse_postorder(&self) -> Vec<usize> {
+        Self::order(self.entry, |id| &self.blocks[id].succs, self.blocks.len())
+    }
+
+    /// Blocks that reach `exit`, in reverse postorder of the reversed graph.
+    pub fn reverse_postorder_rev(&self) -> Vec<usize> {
+        Self::order(self.exit, |id| &self.blocks[id].preds, self.blocks.len())
+    }
+
+    fn order<'a, F>(root: usize, next: F, len: usize) -> Vec<usize>
+    where
+        F: Fn(usize) -> &'a Vec<usize>,
+    {
+        let mut visited = vec![false; len];
+        let mut order = Vec::with_capacity(len);
+        let mut stack = vec![(root, 0)];
+        visited[root] = true;
+
+        while let Some((id, child)) = stack.pop() {
+            match next(id).get(child) {
+                Some(&succ) => {
+                    stack.push((id, child + 1));
+                    if !visited[succ] {
+                        visited[succ] = true;
+                        stack.push((succ, 0));
+                    }
+                }
+                None => order.push(id),
+            }
+        }
+
+        order.reverse();
+        order
+    }
+
+    pub fn to_code(&self) -> Vec<Inst> {
+        self.blocks
+            .iter()
+            .flat_map(|block| block.insts.iter().cloned())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::ir;
+
+    /// Loop with a conditional in its body:
+    ///  B1 -> B2 -> { B3 -> B4 | B5 } -> B6 -> B2, and B2 -> B7 -> exit
+    pub(crate) const LOOP: &str = "fn f(n: i32) -> i32\n\tvar i: i32\n\tvar s: i32\n\
+        L1\tmov i 0\n\
+        L2\tlt __t0 i n\n\tjmpf L3 __t0\n\
+        \tlt __t1 i 5\n\tjmpf L4 __t1\n\
+        \tadd s s i\n\tjmp L5\n\
+        L4\tsub s s i\n\
+        L5\tadd i i 1\n\tjmp L2\n\
+        L3\tret s\n";
+
+    pub(crate) fn function(code: &str) -> ir::Function {
+        ir::parse_module(code).unwrap().funcs.remove(0)
+    }
+
+    #[test]
+    fn partition_blocks() {
+        let func = function(LOOP);
+        let cfg = Cfg::new(&func.code);
+
+        assert_eq!(cfg.blocks.len(), 9);
+        assert_eq!((cfg.entry, cfg.exit), (0, 8));
+        assert_eq!(cfg.block_of(2), Some(2));
+        assert_eq!(cfg.block_of(3), Some(7));
+        assert_eq!(cfg.blocks[4].body().len(), 2);
+
+        let succs: Vec<&Vec<usize>> = cfg.blocks.iter().map(|block| &block.succs).collect();
+        assert_eq!(
+            succs,
+            vec![
+                &vec![1],
+                &vec![2],
+                &vec![7, 3],
+                &vec![5, 4],
+                &vec![6],
+                &vec![6],
+                &vec![2],
+                &vec![8],
+                &vec![]
+            ]
+        );
+        assert_eq!(cfg.blocks[2].preds, vec![1, 6]);
+        assert_eq!(cfg.blocks[8].preds, vec![7]);
+        assert_eq!(cfg.to_code(), func.code);
+        assert_eq!(cfg.reverse_postorder().len(), 9);
+    }
+
+    #[test]
+    fn adjacent_labels_share_a_block() {
+        let func = function("fn f()\nL1L2\tjmp L2\n\tret\nL3\tret\n");
+        let cfg = Cfg::new(&func.code);
+
+        assert_eq!(cfg.blocks.len(), 5);
+        assert_eq!(cfg.block_of(1), cfg.block_of(2));
+        assert_eq!(cfg.blocks[1].succs, vec![1]);
+        assert!(cfg.blocks[2].preds.is_empty());
+        assert_eq!(cfg.reverse_postorder(), vec![0, 1]);
+    }
+}
@@ -0,0 +1,230 @@
+use crate::ir::Cfg;
+
+/**
+ * Dominator tree of a `Cfg`, computed with the iterative algorithm from
+ * Cooper, Harvey and Kennedy. Blocks unreachable from the root are left out
+ * of the tree.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct DomTree {
+    pub root: usize,
+    idom: Vec<Option<usize>>,
+    children: Vec<Vec<usize>>,
+}
+
+impl DomTree {
+    /// Dominators, rooted at `cfg.entry`.
+    pub fn new(cfg: &Cfg) -> Self {
+        let order = cfg.reverse_postorder();
+        Self::build(
+            cfg.entry,
+            &order,
+            |id| &cfg.blocks[id].preds,
+            cfg.blocks.len(),
+        )
+    }
+
+    /// Post-dominators, rooted at `cfg.exit`.
+    pub fn post(cfg: &Cfg) -> Self {
+        let order = cfg.reverse_postorder_rev();
+        Self::build(
+            cfg.exit,
+            &order,
+            |id| &cfg.blocks[id].succs,
+            cfg.blocks.len(),
+        )
+    }
+
+    fn build<'a, F>(root: usize, order: &[usize], preds: F, len: usize) -> Self
+    where
+        F: Fn(usize) -> &'a Vec<usize>,
+    {
+        let mut position = vec![usize::MAX; len];
+        for (pos, &id) in order.iter().enumerate() {
+            position[id] = pos;
+        }
+
+        let mut idom = vec![None; len];
+        idom[root] = Some(root);
+
+        let intersect = |idom: &[Option<usize>], mut a: usize, mut b: usize| {
+            while a != b {
+                while position[a] > position[b] {
+                    a = idom[a].unwrap();
+                }
+                while position[b] > position[a] {
+                    b = idom[b].unwrap();
+                }
+            }
+            a
+        };
+
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for &id in order.iter().skip(1) {
+                let mut new = None;
+                for &pred in preds(id) {
+                    if idom[pred].is_none() {
+                        continue;
+                    }
+
+                    new = match new {
+                        None => Some(pred),
+                        Some(other) => Some(intersect(&idom, pred, other)),
+                    };
+                }
+
+                if idom[id] != new {
+                    idom[id] = new;
+                    changed = true;
+                }
+            }
+        }
+
+        idom[root] = None;
+
+        let mut children = vec![Vec::new(); len];
+        for &id in order {
+            if let Some(parent) = idom[id] {
+                children[parent].push(id);
+            }
+        }
+
+        Self {
+            root,
+            idom,
+            children,
+        }
+    }
+
+    /// Immediate dominator of `id`, `None` for the root and unreachable blocks.
+    pub fn idom(&self, id: usize) -> Option<usize> {
+        self.idom[id]
+    }
+
+    pub fn children(&self, id: usize) -> &[usize] {
+        &self.children[id]
+    }
+
+    pub fn contains(&self, id: usize) -> bool {
+        id == self.root || self.idom[id].is_some()
+    }
+
+    pub fn dominates(&self, a: usize, b: usize) -> bool {
+        if !self.contains(b) {
+            return false;
+        }
+
+        let mut id = Some(b);
+        while let Some(other) = id {
+            if other == a {
+                return true;
+            }
+            id = self.idom[other];
+        }
+
+        false
+    }
+
+    /// Dominance frontier of every block, only meaningful for dominators.
+    pub fn frontiers(&self, cfg: &Cfg) -> Vec<Vec<usize>> {
+        let mut frontiers = vec![Vec::new(); cfg.blocks.len()];
+
+        for (id, block) in cfg.blocks.iter().enumerate() {
+            if block.preds.len() < 2 || !self.contains(id) {
+                continue;
+            }
+
+            for &pred in &block.preds {
+                let mut runner = pred;
+                while self.contains(runner) && Some(runner) != self.idom[id] {
+                    if !frontiers[runner].contains(&id) {
+                        frontiers[runner].push(id);
+                    }
+
+                    match self.idom[runner] {
+                        Some(parent) => runner = parent,
+                        None => break,
+                    }
+                }
+            }
+        }
+
+        frontiers
+    }
+
+    /// Blocks of the tree, parents before children.
+    pub fn preorder(&self) -> Vec<usize> {
+        let mut order = Vec::new();
+        let mut stack = vec![self.root];
+        while let Some(id) = stack.pop() {
+            order.push(id);
+            stack.extend(self.children[id].iter().rev());
+        }
+
+        order
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::cfg::tests::{function, LOOP};
+
+    #[test]
+    fn dominators() {
+        let cfg = Cfg::new(&function(LOOP).code);
+        let dom = DomTree::new(&cfg);
+
+        let idoms: Vec<Option<usize>> = (0..cfg.blocks.len()).map(|id| dom.idom(id)).collect();
+        assert_eq!(
+            idoms,
+            vec![
+                None,
+                Some(0),
+                Some(1),
+                Some(2),
+                Some(3),
+                Some(3),
+                Some(3),
+                Some(2),
+                Some(7)
+            ]
+        );
+
+        assert_eq!(dom.children(3), &[4, 5, 6]);
+        assert!(dom.dominates(2, 6));
+        assert!(dom.dominates(4, 4));
+        assert!(!dom.dominates(4, 6));
+        let frontiers = dom.frontiers(&cfg);
+        assert_eq!(frontiers[2], vec![2]);
+        assert_eq!(frontiers[4], vec![6]);
+        assert_eq!(frontiers[6], vec![2]);
+        assert!(frontiers[7].is_empty());
+        assert_eq!(dom.preorder(), vec![0, 1, 2, 3, 4, 5, 6, 7, 8]);
+    }
+
+    #[test]
+    fn post_dominators() {
+        let cfg = Cfg::new(&function(LOOP).code);
+        let post = DomTree::post(&cfg);
+
+        assert_eq!(post.root, cfg.exit);
+        assert_eq!(post.idom(3), Some(6));
+        assert_eq!(post.idom(6), Some(2));
+        assert_eq!(post.idom(2), Some(7));
+        assert!(post.dominates(2, 0));
+        assert!(!post.dominates(4, 3));
+    }
+
+    #[test]
+    fn unreachable_blocks() {
+        let cfg = Cfg::new(&function("fn f()\n\tjmp L1\n\tret\nL1\tret\n").code);
+        let dom = DomTree::new(&cfg);
+
+        assert!(!dom.contains(2));
+        assert_eq!(dom.idom(3), Some(1));
+        assert!(!dom.dominates(0, 2));
+    }
+}
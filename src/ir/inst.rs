@@ -0,0 +1,333 @@
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+
+use crate::ast::{Cons, Ident, Temp, Visitor};
+use crate::lex::Token;
+use crate::sym::Type;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operand {
+    Ident(Ident),
+    Temp(Temp),
+    Cons(Cons),
+}
+
+/// Storage named by an operand: identifiers by their offset, temporaries by their id.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Var {
+    Ident(usize),
+    Temp(usize),
+}
+
+impl Operand {
+    pub fn get_tp(&self) -> Type {
+        match self {
+            Self::Ident(ident) => ident.tp.clone(),
+            Self::Temp(temp) => temp.tp.clone(),
+            Self::Cons(cons) => cons.tp.clone(),
+        }
+    }
+
+    pub fn get_var(&self) -> Option<Var> {
+        match self {
+            Self::Ident(ident) => Some(Var::Ident(ident.offset)),
+            Self::Temp(temp) => Some(Var::Temp(temp.id)),
+            Self::Cons(_) => None,
+        }
+    }
+}
+
+impl Display for Operand {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::Ident(ident) => write!(f, "{}", ident),
+            Self::Temp(temp) => write!(f, "{}", temp),
+            Self::Cons(cons) => write!(f, "{}", cons),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Opcode {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eq,
+    Ne,
+}
+
+impl Opcode {
+    pub fn is_rel(&self) -> bool {
+        !matches!(self, Self::Add | Self::Sub | Self::Mul | Self::Div)
+    }
+
+    pub fn is_commutative(&self) -> bool {
+        matches!(self, Self::Add | Self::Mul | Self::Eq | Self::Ne)
+    }
+
+    pub fn get_token(&self) -> Token {
+        match self {
+            Self::Add => Token::Plus,
+            Self::Sub => Token::Minus,
+            Self::Mul => Token::Asterisk,
+            Self::Div => Token::Divide,
+            Self::Lt => Token::LessThan,
+            Self::Le => Token::LessEqual,
+            Self::Gt => Token::GreaterThan,
+            Self::Ge => Token::GreaterEqual,
+            Self::Eq => Token::Equal,
+            Self::Ne => Token::NotEqual,
+        }
+    }
+}
+
+impl Display for Opcode {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let out = match self {
+            Self::Add => "add",
+            Self::Sub => "sub",
+            Self::Mul => "mul",
+            Self::Div => "div",
+            Self::Lt => "lt",
+            Self::Le => "le",
+            Self::Gt => "gt",
+            Self::Ge => "ge",
+            Self::Eq => "eq",
+            Self::Ne => "ne",
+        };
+
+        write!(f, "{}", out)
+    }
+}
+
+impl FromStr for Opcode {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "add" => Ok(Self::Add),
+            "sub" => Ok(Self::Sub),
+            "mul" => Ok(Self::Mul),
+            "div" => Ok(Self::Div),
+            "lt" => Ok(Self::Lt),
+            "le" => Ok(Self::Le),
+            "gt" => Ok(Self::Gt),
+            "ge" => Ok(Self::Ge),
+            "eq" => Ok(Self::Eq),
+            "ne" => Ok(Self::Ne),
+            _ => Err(()),
+        }
+    }
+}
+
+/// A single three-address instruction, as emitted by `Visitor`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Inst {
+    Label(usize),
+    Binary {
+        op: Opcode,
+        dst: Operand,
+        lhs: Operand,
+        rhs: Operand,
+    },
+    Inv {
+        dst: Operand,
+        src: Operand,
+    },
+    Not {
+        dst: Operand,
+        src: Operand,
+    },
+    Mov {
+        dst: Operand,
+        src: Operand,
+    },
+    /**
+     * Reads the scalar of `array` at position `index`, nested arrays hold
+     * theirs row after row. The index counts scalars, not bytes: the code is
+     * the same for every target, and each backend multiplies it by the
+     * stride of the scalar in its own `Layout`.
+     */
+    Idx {
+        dst: Operand,
+        index: Operand,
+        array: Operand,
+    },
+    /// Writes the scalar of `array` at position `index`, counted as in `idx`.
+    Sto {
+        array: Operand,
+        index: Operand,
+        src: Operand,
+    },
+    /// Fails with an out of bounds error unless `0 <= index < len`, checks
+    /// the index of one dimension of a nested array before they're combined.
+    Chk {
+        index: Operand,
+        len: usize,
+    },
+    Jmp {
+        label: usize,
+    },
+    JmpT {
+        label: usize,
+        test: Operand,
+    },
+    JmpF {
+        label: usize,
+        test: Operand,
+    },
+    Param {
+        src: Operand,
+    },
+    Call {
+        dst: Option<Operand>,
+        func: String,
+        nargs: usize,
+    },
+    Ret {
+        src: Option<Operand>,
+    },
+    /// SSA join, one argument per predecessor block of the `Cfg` it lives in.
+    Phi {
+        dst: Operand,
+        args: Vec<(usize, Operand)>,
+    },
+}
+
+impl Inst {
+    pub fn get_opcode(&self) -> String {
+        match self {
+            Self::Label(_) => "label".to_owned(),
+            Self::Binary { op, .. } => op.to_string(),
+            Self::Inv { .. } => "inv".to_owned(),
+            Self::Not { .. } => "not".to_owned(),
+            Self::Mov { .. } => "mov".to_owned(),
+            Self::Idx { .. } => "idx".to_owned(),
+            Self::Sto { .. } => "sto".to_owned(),
+            Self::Chk { .. } => "chk".to_owned(),
+            Self::Jmp { .. } => "jmp".to_owned(),
+            Self::JmpT { .. } => "jmpt".to_owned(),
+            Self::JmpF { .. } => "jmpf".to_owned(),
+            Self::Param { .. } => "param".to_owned(),
+            Self::Call { .. } => "call".to_owned(),
+            Self::Ret { .. } => "ret".to_owned(),
+            Self::Phi { .. } => "phi".to_owned(),
+        }
+    }
+
+    /// Operand written by the instruction, `sto` only updates its array.
+    pub fn get_def(&self) -> Option<&Operand> {
+        match self {
+            Self::Binary { dst, .. }
+            | Self::Inv { dst, .. }
+            | Self::Not { dst, .. }
+            | Self::Mov { dst, .. }
+            | Self::Idx { dst, .. }
+            | Self::Phi { dst, .. } => Some(dst),
+            Self::Call { dst, .. } => dst.as_ref(),
+            _ => None,
+        }
+    }
+
+    pub fn get_def_mut(&mut self) -> Option<&mut Operand> {
+        match self {
+            Self::Binary { dst, .. }
+            | Self::Inv { dst, .. }
+            | Self::Not { dst, .. }
+            | Self::Mov { dst, .. }
+            | Self::Idx { dst, .. }
+            | Self::Phi { dst, .. } => Some(dst),
+            Self::Call { dst, .. } => dst.as_mut(),
+            _ => None,
+        }
+    }
+
+    /// Operands read by the instruction, constants included.
+    pub fn get_uses(&self) -> Vec<&Operand> {
+        match self {
+            Self::Binary { lhs, rhs, .. } => vec![lhs, rhs],
+            Self::Inv { src, .. } | Self::Not { src, .. } | Self::Mov { src, .. } => vec![src],
+            Self::Idx { index, array, .. } => vec![index, array],
+            Self::Sto { array, index, src } => vec![array, index, src],
+            Self::Chk { index, .. } => vec![index],
+            Self::JmpT { test, .. } | Self::JmpF { test, .. } => vec![test],
+            Self::Param { src } => vec![src],
+            Self::Ret { src } => src.iter().collect(),
+            Self::Phi { args, .. } => args.iter().map(|(_, arg)| arg).collect(),
+            Self::Label(_) | Self::Jmp { .. } | Self::Call { .. } => vec![],
+        }
+    }
+
+    pub fn get_uses_mut(&mut self) -> Vec<&mut Operand> {
+        match self {
+            Self::Binary { lhs, rhs, .. } => vec![lhs, rhs],
+            Self::Inv { src, .. } | Self::Not { src, .. } | Self::Mov { src, .. } => vec![src],
+            Self::Idx { index, array, .. } => vec![index, array],
+            Self::Sto { array, index, src } => vec![array, index, src],
+            Self::Chk { index, .. } => vec![index],
+            Self::JmpT { test, .. } | Self::JmpF { test, .. } => vec![test],
+            Self::Param { src } => vec![src],
+            Self::Ret { src } => src.iter_mut().collect(),
+            Self::Phi { args, .. } => args.iter_mut().map(|(_, arg)| arg).collect(),
+            Self::Label(_) | Self::Jmp { .. } | Self::Call { .. } => vec![],
+        }
+    }
+
+    /// Whether the instruction ends a basic block.
+    pub fn is_terminator(&self) -> bool {
+        matches!(
+            self,
+            Self::Jmp { .. } | Self::JmpT { .. } | Self::JmpF { .. } | Self::Ret { .. }
+        )
+    }
+
+    pub fn emit(&self, visitor: &mut Visitor) {
+        match self {
+            Self::Label(label) => visitor.emit_label(*label),
+            inst => visitor.emit_inst(&inst.to_string()),
+        }
+    }
+}
+
+impl Display for Inst {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::Label(label) => write!(f, "L{}", label),
+            Self::Binary { op, dst, lhs, rhs } => write!(f, "{} {} {} {}", op, dst, lhs, rhs),
+            Self::Inv { dst, src } => write!(f, "inv {} {}", dst, src),
+            Self::Not { dst, src } => write!(f, "not {} {}", dst, src),
+            Self::Mov { dst, src } => write!(f, "mov {} {}", dst, src),
+            Self::Idx { dst, index, array } => write!(f, "idx {} {} {}", dst, index, array),
+            Self::Sto { array, index, src } => write!(f, "sto {} {} {}", array, index, src),
+            Self::Chk { index, len } => write!(f, "chk {} {}", index, len),
+            Self::Jmp { label } => write!(f, "jmp L{}", label),
+            Self::JmpT { label, test } => write!(f, "jmpt L{} {}", label, test),
+            Self::JmpF { label, test } => write!(f, "jmpf L{} {}", label, test),
+            Self::Param { src } => write!(f, "param {}", src),
+            Self::Call {
+                dst: Some(dst),
+                func,
+                nargs,
+            } => write!(f, "call {} {} {}", dst, func, nargs),
+            Self::Call {
+                dst: None,
+                func,
+                nargs,
+            } => write!(f, "call {} {}", func, nargs),
+            Self::Ret { src: Some(src) } => write!(f, "ret {}", src),
+            Self::Ret { src: None } => write!(f, "ret"),
+            Self::Phi { dst, args } => {
+                let args: Vec<String> = args
+                    .iter()
+                    .map(|(pred, arg)| format!("B{}:{}", pred, arg))
+                    .collect();
+                write!(f, "phi {} {}", dst, args.join(" "))
+            }
+        }
+    }
+}
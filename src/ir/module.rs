@@ -0,0 +1,42 @@
+use crate::ast::{Ident, Visitor};
+use crate::ir::Inst;
+use crate::sym::Type;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Function {
+    pub name: String,
+    pub params: Vec<Ident>,
+    pub locals: Vec<Ident>,
+    pub ret: Option<Type>,
+    pub code: Vec<Inst>,
+}
+
+impl Function {
+    pub fn emit(&self, visitor: &mut Visitor) {
+        visitor.emit_func(&self.name, &self.params, &self.ret);
+        for local in &self.locals {
+            visitor.emit_var(local);
+        }
+
+        for inst in &self.code {
+            inst.emit(visitor);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Module {
+    pub funcs: Vec<Function>,
+}
+
+impl Module {
+    pub fn get(&self, name: &str) -> Option<&Function> {
+        self.funcs.iter().find(|func| func.name == name)
+    }
+
+    pub fn emit(&self, visitor: &mut Visitor) {
+        for func in &self.funcs {
+            func.emit(visitor);
+        }
+    }
+}
@@ -0,0 +1,704 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::ast::{reserve_label_id, reserve_temp_id, Cons, Ident, Temp};
+use crate::error::IrError;
+use crate::ir::{Function, Inst, Module, Opcode, Operand};
+use crate::lex::Token;
+use crate::sym::{Env, Type};
+
+pub type IrResult<T> = Result<T, IrError>;
+
+type Signature = (Vec<Ident>, Option<Type>);
+
+/**
+ * Parser for the textual three-address code written by `Visitor`:
+ *  Module  = { Func }*
+ *  Func    = fn Name ( [ Name: Type { , Name: Type }* ] ) [ -> Type ] \n { Line }*
+ *  Line    = { Label }* [ \t Inst ] | \t var Name: Type
+ *  Label   = L<number>
+ *  Inst    = Opcode Operand { Operand }*
+ *
+ * Identifiers are resolved through the `Env` (function parameters and `var`
+ * declarations are put into a new scope), temporaries take the type of the
+ * instruction that defines them. Parsed labels and temporaries are reserved,
+ * so that the ones created afterwards don't clash with them.
+ */
+#[derive(Debug)]
+pub struct Parser {
+    env: Env,
+    sigs: HashMap<String, Signature>,
+    temps: HashMap<usize, Type>,
+    labels: HashSet<usize>,
+    jumps: Vec<(usize, usize)>,
+    offset: usize,
+    line: usize,
+}
+
+pub fn parse(input: &str, env: &Env) -> IrResult<Vec<Inst>> {
+    Parser::new(env).parse(input)
+}
+
+pub fn parse_module(input: &str) -> IrResult<Module> {
+    Parser::new(&Env::new()).parse_module(input)
+}
+
+impl Parser {
+    pub fn new(env: &Env) -> Self {
+        Self {
+            env: env.clone(),
+            sigs: HashMap::new(),
+            temps: HashMap::new(),
+            labels: HashSet::new(),
+            jumps: Vec::new(),
+            offset: 0,
+            line: 0,
+        }
+    }
+
+    /// Parses a bare sequence of instructions, without function headers.
+    pub fn parse(&mut self, input: &str) -> IrResult<Vec<Inst>> {
+        let mut insts = Vec::new();
+
+        for (nline, line) in input.lines().enumerate() {
+            self.line = nline + 1;
+            self.parse_line(line, &mut insts, &mut Vec::new())?;
+        }
+
+        self.check_jumps()?;
+        Ok(insts)
+    }
+
+    pub fn parse_module(&mut self, input: &str) -> IrResult<Module> {
+        for (nline, line) in input.lines().enumerate() {
+            self.line = nline + 1;
+            if line.starts_with("fn ") {
+                let (name, params, ret) = self.header(line)?;
+                if self.sigs.insert(name.clone(), (params, ret)).is_some() {
+                    return Err(self.error(&format!("function `{}` defined twice", name)));
+                }
+            }
+        }
+
+        let mut module = Module::default();
+        for (nline, line) in input.lines().enumerate() {
+            self.line = nline + 1;
+
+            if line.starts_with("fn ") {
+                self.finish(&mut module)?;
+
+                let (name, _, ret) = self.header(line)?;
+                self.env.push();
+                self.offset = 0;
+
+                let params = self.sigs[&name].0.clone();
+                let params = params
+                    .iter()
+                    .map(|param| self.declare(&param.id, &param.tp))
+                    .collect();
+
+                module.funcs.push(Function {
+                    name,
+                    params,
+                    locals: Vec::new(),
+                    ret,
+                    code: Vec::new(),
+                });
+                continue;
+            }
+
+            match module.funcs.last_mut() {
+                Some(func) => self.parse_line(line, &mut func.code, &mut func.locals)?,
+                None if line.trim().is_empty() => {}
+                None => return Err(self.error("expected function header")),
+            }
+        }
+
+        self.finish(&mut module)?;
+        Ok(module)
+    }
+
+    fn finish(&mut self, module: &mut Module) -> IrResult<()> {
+        if module.funcs.is_empty() {
+            return Ok(());
+        }
+
+        self.check_jumps()?;
+        self.env.pop();
+        self.temps.clear();
+        self.labels.clear();
+        Ok(())
+    }
+
+    fn check_jumps(&mut self) -> IrResult<()> {
+        for (label, line) in self.jumps.drain(..) {
+            if !self.labels.contains(&label) {
+                return Err(IrError::new(line, &format!("undefined label L{}", label)));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn parse_line(
+        &mut self,
+        line: &str,
+        insts: &mut Vec<Inst>,
+        locals: &mut Vec<Ident>,
+    ) -> IrResult<()> {
+        let line = match line.find("//") {
+            Some(pos) => &line[..pos],
+            None => line,
+        };
+
+        let rest = line.trim_start();
+        let labels = &line[..line.len() - rest.len()];
+        let (labels, rest) = if labels.is_empty() {
+            match rest.find(char::is_whitespace) {
+                Some(pos) => (&rest[..pos], &rest[pos..]),
+                None => (rest, ""),
+            }
+        } else {
+            ("", rest)
+        };
+
+        for label in self.labels(labels)? {
+            if !self.labels.insert(label) {
+                return Err(self.error(&format!("label L{} defined twice", label)));
+            }
+
+            reserve_label_id(label);
+            insts.push(Inst::Label(label));
+        }
+
+        let words: Vec<&str> = rest.split_whitespace().collect();
+        match words.first() {
+            None => {}
+            Some(&"var") => {
+                let (name, tp) = self.decl(rest.trim_start()[3..].trim())?;
+                let local = self.declare(&name, &tp);
+                locals.push(local);
+            }
+            Some(_) => insts.push(self.inst(&words)?),
+        }
+
+        Ok(())
+    }
+
+    fn error(&self, msg: &str) -> IrError {
+        IrError::new(self.line, msg)
+    }
+
+    fn declare(&mut self, name: &str, tp: &Type) -> Ident {
+        let ident = Ident {
+            id: name.to_owned(),
+            tp: tp.clone(),
+            offset: self.offset,
+        };
+
+        self.offset += tp.get_width();
+        self.env.put(name, &ident);
+        ident
+    }
+
+    fn decl(&self, decl: &str) -> IrResult<(String, Type)> {
+        let mut parts = decl.splitn(2, ':');
+        let name = parts.next().unwrap_or("").trim();
+        let tp = parts.next().unwrap_or("").trim();
+
+        if !name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+            return Err(self.error(&format!("malformed declaration `{}`", decl)));
+        }
+
+        match tp.parse() {
+            Ok(tp) => Ok((name.to_owned(), tp)),
+            Err(_) => Err(self.error(&format!("unknown type `{}`", tp))),
+        }
+    }
+
+    fn header(&self, line: &str) -> IrResult<(String, Vec<Ident>, Option<Type>)> {
+        let malformed = || self.error(&format!("malformed function header `{}`", line));
+
+        let line = &line[3..];
+        let open = line.find('(').ok_or_else(malformed)?;
+        let close = line.rfind(')').ok_or_else(malformed)?;
+        let name = line[..open].trim();
+        if name.is_empty() || close < open {
+            return Err(malformed());
+        }
+
+        let mut params = Vec::new();
+        for decl in line[open + 1..close].split(',') {
+            if !decl.trim().is_empty() {
+                let (id, tp) = self.decl(decl)?;
+                params.push(Ident { id, tp, offset: 0 });
+            }
+        }
+
+        let ret = match line[close + 1..].trim() {
+            "" => None,
+            ret => match ret.strip_prefix("->").map(|tp| tp.trim().parse()) {
+                Some(Ok(tp)) => Some(tp),
+                _ => return Err(malformed()),
+            },
+        };
+
+        Ok((name.to_owned(), params, ret))
+    }
+
+    fn labels(&self, word: &str) -> IrResult<Vec<usize>> {
+        if word.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        if !word.starts_with('L') {
+            return Err(self.error(&format!("expected label, got `{}`", word)));
+        }
+
+        word[1..]
+            .split('L')
+            .map(|id| {
+                id.parse()
+                    .map_err(|_| self.error(&format!("malformed label `{}`", word)))
+            })
+            .collect()
+    }
+
+    fn label(&mut self, word: &str) -> IrResult<usize> {
+        let label = match word.strip_prefix('L').map(str::parse) {
+            Some(Ok(label)) => label,
+            _ => return Err(self.error(&format!("malformed label `{}`", word))),
+        };
+
+        self.jumps.push((label, self.line));
+        Ok(label)
+    }
+
+    fn inst(&mut self, words: &[&str]) -> IrResult<Inst> {
+        let (code, args) = (words[0], &words[1..]);
+
+        let arity = match code {
+            "idx" | "sto" => 3..=3,
+            "inv" | "not" | "mov" | "jmpt" | "jmpf" | "chk" => 2..=2,
+            "jmp" | "param" => 1..=1,
+            "call" => 2..=3,
+            "ret" => 0..=1,
+            op if op.parse::<Opcode>().is_ok() => 3..=3,
+            _ => return Err(self.error(&format!("unknown instruction `{}`", code))),
+        };
+
+        if !arity.contains(&args.len()) {
+            return Err(self.error(&format!(
+                "`{}` expects {} operands, got {}",
+                code,
+                arity.end(),
+                args.len()
+            )));
+        }
+
+        let inst = match code {
+            "jmp" => Inst::Jmp {
+                label: self.label(args[0])?,
+            },
+
+            "jmpt" => Inst::JmpT {
+                label: self.label(args[0])?,
+                test: self.operand(args[1])?,
+            },
+
+            "jmpf" => Inst::JmpF {
+                label: self.label(args[0])?,
+                test: self.operand(args[1])?,
+            },
+
+            "inv" => {
+                let src = self.operand(args[1])?;
+                let tp = Type::Int64
+                    .upcast(&src.get_tp())
+                    .ok_or_else(|| self.error(&format!("cannot negate `{}`", src)))?;
+
+                Inst::Inv {
+                    dst: self.place(args[0], tp)?,
+                    src,
+                }
+            }
+
+            "not" => {
+                let src = self.operand(args[1])?;
+                if src.get_tp() != Type::Bool {
+                    return Err(self.error(&format!("`{}` is not a bool", src)));
+                }
+
+                Inst::Not {
+                    dst: self.place(args[0], Type::Bool)?,
+                    src,
+                }
+            }
+
+            "mov" => {
+                let src = self.operand(args[1])?;
+                Inst::Mov {
+                    dst: self.place(args[0], src.get_tp())?,
+                    src,
+                }
+            }
+
+            "idx" => {
+                let index = self.operand(args[1])?;
+                let array = self.operand(args[2])?;
+                Inst::Idx {
+                    dst: self.place(args[0], self.element(&array)?)?,
+                    index,
+                    array,
+                }
+            }
+
+            "sto" => {
+                let array = self.operand(args[0])?;
+                self.element(&array)?;
+
+                Inst::Sto {
+                    array,
+                    index: self.operand(args[1])?,
+                    src: self.operand(args[2])?,
+                }
+            }
+
+            "chk" => Inst::Chk {
+                index: self.operand(args[0])?,
+                len: args[1]
+                    .parse()
+                    .map_err(|_| self.error(&format!("invalid length `{}`", args[1])))?,
+            },
+
+            "param" => Inst::Param {
+                src: self.operand(args[0])?,
+            },
+
+            "call" => {
+                let (func, nargs) = (args[args.len() - 2], args[args.len() - 1]);
+                let nargs = nargs
+                    .parse()
+                    .map_err(|_| self.error(&format!("invalid argument count `{}`", nargs)))?;
+
+                let (params, ret) = self
+                    .sigs
+                    .get(func)
+                    .ok_or_else(|| self.error(&format!("undefined function `{}`", func)))?;
+
+                if params.len() != nargs {
+                    return Err(self.error(&format!(
+                        "function `{}` takes {} arguments, got {}",
+                        func,
+                        params.len(),
+                        nargs
+                    )));
+                }
+
+                let dst = match (args.len(), ret.clone()) {
+                    (2, _) => None,
+                    (_, Some(tp)) => Some(self.place(args[0], tp)?),
+                    (_, None) => {
+                        return Err(self.error(&format!("function `{}` returns nothing", func)))
+                    }
+                };
+
+                Inst::Call {
+                    dst,
+                    func: func.to_owned(),
+                    nargs,
+                }
+            }
+
+            "ret" => Inst::Ret {
+                src: match args.first() {
+                    Some(arg) => Some(self.operand(arg)?),
+                    None => None,
+                },
+            },
+
+            op => {
+                let op = op.parse::<Opcode>().unwrap();
+                let lhs = self.operand(args[1])?;
+                let rhs = self.operand(args[2])?;
+
+                let tp = match lhs.get_tp().upcast(&rhs.get_tp()) {
+                    Some(_) if op.is_rel() => Some(Type::Bool),
+                    None if op.is_rel() && lhs.get_tp() == rhs.get_tp() => match op {
+                        Opcode::Eq | Opcode::Ne => Some(Type::Bool),
+                        _ => None,
+                    },
+                    tp => tp,
+                };
+
+                let tp = tp
+                    .ok_or_else(|| self.error(&format!("cannot coerce `{}` and `{}`", lhs, rhs)))?;
+
+                Inst::Binary {
+                    op,
+                    dst: self.place(args[0], tp)?,
+                    lhs,
+                    rhs,
+                }
+            }
+        };
+
+        Ok(inst)
+    }
+
+    fn element(&self, array: &Operand) -> IrResult<Type> {
+        match (array, array.get_tp()) {
+            (Operand::Ident(_), tp @ Type::Array { .. }) => Ok(tp.get_scalar().clone()),
+            _ => Err(self.error(&format!("`{}` is not an array", array))),
+        }
+    }
+
+    fn temp_id(word: &str) -> Option<usize> {
+        word.strip_prefix("__t").and_then(|id| id.parse().ok())
+    }
+
+    fn place(&mut self, word: &str, tp: Type) -> IrResult<Operand> {
+        if let Some(id) = Self::temp_id(word) {
+            reserve_temp_id(id);
+            self.temps.insert(id, tp.clone());
+            return Ok(Operand::Temp(Temp { id, tp }));
+        }
+
+        match self.operand(word)? {
+            Operand::Cons(_) => Err(self.error(&format!("cannot assign to `{}`", word))),
+            place => Ok(place),
+        }
+    }
+
+    fn operand(&self, word: &str) -> IrResult<Operand> {
+        if let Some(id) = Self::temp_id(word) {
+            return match self.temps.get(&id) {
+                Some(tp) => Ok(Operand::Temp(Temp { id, tp: tp.clone() })),
+                None => Err(self.error(&format!("use of undefined temporary `{}`", word))),
+            };
+        }
+
+        let cons = |tok, tp| Ok(Operand::Cons(Cons { tok, tp }));
+
+        match word {
+            "true" | "false" => cons(Token::ReservedWord(word.to_owned()), Type::Bool),
+
+            word if word.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') => {
+                match self.env.get(word) {
+                    Some(ident) => Ok(Operand::Ident(ident.clone())),
+                    None => Err(self.error(&format!("undeclared identifier `{}`", word))),
+                }
+            }
+
+            // Numbers, with the suffix of their type unless `i32` or `f64`
+            word => {
+                let (num, tp) = [Type::Int32, Type::Int64, Type::Flt32, Type::Flt64]
+                    .iter()
+                    .find_map(|tp| Some((word.strip_suffix(&tp.to_string())?, Some(tp))))
+                    .unwrap_or((word, None));
+
+                if let Ok(num) = num.parse() {
+                    cons(Token::Integer(num), tp.unwrap_or(&Type::Int32).clone())
+                } else if let Ok(num) = num.parse() {
+                    cons(Token::Float(num), tp.unwrap_or(&Type::Flt64).clone())
+                } else {
+                    Err(self.error(&format!("invalid operand `{}`", word)))
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::node::tests::Buffer;
+    use crate::ast::{Arithm, Expr, Ident, Index, Unary};
+
+    fn ident(id: &str, tp: Type, offset: usize) -> Ident {
+        Ident {
+            id: id.to_owned(),
+            tp,
+            offset,
+        }
+    }
+
+    fn env() -> Env {
+        let mut env = Env::new();
+        env.push();
+        env.put("a", &ident("a", Type::Int32, 0));
+        env.put("b", &ident("b", Type::Flt64, 4));
+        env.put(
+            "v",
+            &ident(
+                "v",
+                Type::Array {
+                    of: Box::new(Type::Int64),
+                    size: 4,
+                },
+                12,
+            ),
+        );
+        env
+    }
+
+    #[test]
+    fn parse_insts() -> Result<(), IrError> {
+        let env = env();
+        let code = "L1\tadd __t0 a 2\n\tidx __t1 __t0 v\n\tmul __t2 __t1 b\nL2L3\tjmpt L1 true\n\tjmp L2\n\tchk __t0 4\n";
+        let insts = parse(code, &env)?;
+
+        assert_eq!(insts.len(), 9);
+        assert_eq!(insts[0], Inst::Label(1));
+        assert_eq!(insts[5], Inst::Label(3));
+        match &insts[3] {
+            Inst::Binary {
+                op: Opcode::Mul,
+                dst: Operand::Temp(temp),
+                ..
+            } => assert_eq!(temp.tp, Type::Flt64),
+            inst => panic!("unexpected {}", inst),
+        }
+        match &insts[2] {
+            Inst::Idx { dst, .. } => assert_eq!(dst.get_tp(), Type::Int64),
+            inst => panic!("unexpected {}", inst),
+        }
+
+        let buf = Buffer::default();
+        let mut visitor = buf.visitor();
+        insts.iter().for_each(|inst| inst.emit(&mut visitor));
+        assert_eq!(buf.contents(), code);
+
+        Ok(())
+    }
+
+    #[test]
+    fn parse_constants() -> Result<(), IrError> {
+        let env = env();
+        let code = "\tadd __t0 6i64 2\n\tadd __t1 6.0 1.5f32\n\tadd __t2 2f64 -1e300\n";
+        let insts = parse(code, &env)?;
+
+        let tps: Vec<_> = insts
+            .iter()
+            .flat_map(|inst| inst.get_uses())
+            .map(|operand| operand.get_tp())
+            .collect();
+        let expected = [Type::Int64, Type::Int32, Type::Flt64, Type::Flt32];
+        assert_eq!(tps[..4], expected);
+        assert_eq!(tps[4..], [Type::Flt64, Type::Flt64]);
+
+        let buf = Buffer::default();
+        let mut visitor = buf.visitor();
+        insts.iter().for_each(|inst| inst.emit(&mut visitor));
+        assert_eq!(buf.contents(), code);
+
+        Ok(())
+    }
+
+    #[test]
+    fn parse_errors() {
+        let env = env();
+        let line = |code| parse(code, &env).unwrap_err().line;
+
+        assert_eq!(line("\tadd __t0 a b\n\tmul __t1 __t2 a\n"), 2);
+        assert_eq!(line("\tadd __t0 a c\n"), 1);
+        assert_eq!(line("\tadd __t0 a\n"), 1);
+        assert_eq!(line("\tchk a -1\n"), 1);
+        assert_eq!(line("\tmove __t0 a\n"), 1);
+        assert_eq!(line("\tadd 3 a b\n"), 1);
+        assert_eq!(line("\tidx __t0 a a\n"), 1);
+        assert_eq!(line("L1\tjmp L1\n\n\tjmpt L4 a\n"), 3);
+        assert_eq!(line("L1\tjmp L1\nL1\tjmp L1\n"), 2);
+        assert_eq!(line("X1\tjmp L1\n"), 1);
+    }
+
+    #[test]
+    fn round_trip_visitor() -> Result<(), IrError> {
+        let env = env();
+        let var = |id| Expr::Ident(env.get(id).unwrap().clone());
+        let two = Expr::Cons(Cons {
+            tok: Token::Integer(2),
+            tp: Type::Int32,
+        });
+
+        let index = Expr::Index(Index::new(
+            env.get("v").unwrap(),
+            &[Arithm::new(&Token::Plus, &var("a"), &two)],
+        ));
+        let expr = Arithm::new(
+            &Token::Plus,
+            &Unary::new(&Token::Minus, &var("a")),
+            &Arithm::new(&Token::Asterisk, &var("b"), &two),
+        );
+
+        let buf = Buffer::default();
+        let mut visitor = buf.visitor();
+        index.reduce(&mut visitor);
+        let test = expr.reduce(&mut visitor);
+        visitor.emit_jump(&test.to_string(), 1, 0);
+        visitor.emit_label(1);
+
+        let code = buf.contents();
+        let insts = parse(&code, &env)?;
+        assert_eq!(insts.len(), 7);
+
+        let out = Buffer::default();
+        let mut visitor = out.visitor();
+        insts.iter().for_each(|inst| inst.emit(&mut visitor));
+        assert_eq!(out.contents(), code);
+
+        Ok(())
+    }
+
+    #[test]
+    fn parse_functions() -> Result<(), IrError> {
+        let code = "fn f(x: i32, v: [2]f64) -> f64\n\tvar y: f64\n\tidx y x v\n\tret y\n\
+                    fn main()\n\tparam 1\n\tparam 1\n\tcall __t0 f 2\n\tlt __t1 __t0 3\n\tret\n";
+        let module = parse_module(code)?;
+
+        let f = module.get("f").unwrap();
+        assert_eq!(f.params.len(), 2);
+        assert_eq!(f.params[1].offset, 4);
+        assert_eq!(f.locals[0].offset, 20);
+        assert_eq!(f.ret, Some(Type::Flt64));
+
+        let main = module.get("main").unwrap();
+        match &main.code[3] {
+            Inst::Binary { dst, .. } => assert_eq!(dst.get_tp(), Type::Bool),
+            inst => panic!("unexpected {}", inst),
+        }
+
+        let buf = Buffer::default();
+        module.emit(&mut buf.visitor());
+        assert_eq!(buf.contents(), code);
+
+        // Strings keep their size, and the locals after them their offsets
+        let code = "fn s(a: string(5))\n\tvar b: string(3)\n\tvar c: i32\n\tret\n";
+        let module = parse_module(code)?;
+        let s = module.get("s").unwrap();
+        assert_eq!(s.locals[0].offset, 5);
+        assert_eq!(s.locals[1].offset, 8);
+
+        let buf = Buffer::default();
+        module.emit(&mut buf.visitor());
+        assert_eq!(buf.contents(), code);
+
+        Ok(())
+    }
+
+    #[test]
+    fn parse_function_errors() {
+        let line = |code| parse_module(code).unwrap_err().line;
+
+        assert_eq!(line("\tret\n"), 1);
+        assert_eq!(line("fn f(\n"), 1);
+        assert_eq!(line("fn f() -> u8\n"), 1);
+        assert_eq!(line("fn f()\n\tret\nfn f()\n"), 3);
+        assert_eq!(line("fn f()\n\tvar x i32\n"), 2);
+        assert_eq!(line("fn f()\n\tvar s: string\n"), 2);
+        assert_eq!(line("fn f()\n\tcall g 0\n"), 2);
+        assert_eq!(line("fn f()\n\tcall __t0 f 0\n"), 2);
+        assert_eq!(line("fn f(a: i32)\n\tcall f 0\n"), 2);
+        assert_eq!(line("fn f(a: i32)\n\tret\nfn g()\n\tmov a 1\n"), 4);
+        assert_eq!(line("fn f()\nL1\tret\nfn g()\n\tjmp L1\n"), 4);
+    }
+}
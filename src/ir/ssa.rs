@@ -0,0 +1,387 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::ast::{new_label_id, new_temp_id, Temp};
+use crate::ir::{Cfg, DomTree, Inst, Operand, Var};
+use crate::sym::Type;
+
+/// Variables that take part in SSA, arrays are updated in place and are left alone.
+fn ssa_var(operand: &Operand) -> Option<Var> {
+    match operand.get_tp() {
+        Type::Array { .. } => None,
+        _ => operand.get_var(),
+    }
+}
+
+fn new_temp(tp: Type) -> Operand {
+    Operand::Temp(Temp {
+        id: new_temp_id(),
+        tp,
+    })
+}
+
+/**
+ * Converts the code of `cfg` into semi-pruned SSA form: phis are only placed
+ * (at the iterated dominance frontier of their definitions) for variables
+ * that are live across blocks, and every definition then gets a fresh `Temp`.
+ * Parameters and locals keep their `Ident` as the value they have on entry.
+ */
+pub fn to_ssa(cfg: &mut Cfg) {
+    let dom = DomTree::new(cfg);
+    let frontiers = dom.frontiers(cfg);
+
+    let mut globals = HashSet::new();
+    let mut defsites: HashMap<Var, (Operand, Vec<usize>)> = HashMap::new();
+
+    for id in dom.preorder() {
+        let mut killed = HashSet::new();
+        for inst in cfg.blocks[id].body() {
+            for var in inst.get_uses().into_iter().filter_map(ssa_var) {
+                if !killed.contains(&var) {
+                    globals.insert(var);
+                }
+            }
+
+            if let Some(def) = inst.get_def() {
+                if let Some(var) = ssa_var(def) {
+                    killed.insert(var);
+                    let (_, sites) = defsites.entry(var).or_insert((def.clone(), vec![]));
+                    sites.push(id);
+                }
+            }
+        }
+    }
+
+    let mut vars: Vec<&Var> = defsites
+        .keys()
+        .filter(|var| globals.contains(var))
+        .collect();
+    vars.sort();
+
+    for var in vars {
+        let (operand, sites) = &defsites[var];
+        let mut placed = HashSet::new();
+        let mut work = sites.clone();
+
+        while let Some(id) = work.pop() {
+            for &front in &frontiers[id] {
+                if !placed.insert(front) {
+                    continue;
+                }
+
+                let block = &mut cfg.blocks[front];
+                let args = block
+                    .preds
+                    .iter()
+                    .map(|&pred| (pred, operand.clone()))
+                    .collect();
+
+                let at = block.labels().count();
+                block.insts.insert(
+                    at,
+                    Inst::Phi {
+                        dst: operand.clone(),
+                        args,
+                    },
+                );
+
+                work.push(front);
+            }
+        }
+    }
+
+    rename(cfg, &dom, dom.root, &mut HashMap::new());
+}
+
+fn rename(cfg: &mut Cfg, dom: &DomTree, id: usize, stacks: &mut HashMap<Var, Vec<Operand>>) {
+    let current = |stacks: &HashMap<Var, Vec<Operand>>, operand: &mut Operand| {
+        if let Some(var) = ssa_var(operand) {
+            if let Some(top) = stacks.get(&var).and_then(|stack| stack.last()) {
+                *operand = top.clone();
+            }
+        }
+    };
+
+    let mut pushed = Vec::new();
+    let labels = cfg.blocks[id].labels().count();
+
+    for inst in &mut cfg.blocks[id].insts[labels..] {
+        if !matches!(inst, Inst::Phi { .. }) {
+            for operand in inst.get_uses_mut() {
+                current(stacks, operand);
+            }
+        }
+
+        if let Some(def) = inst.get_def_mut() {
+            if let Some(var) = ssa_var(def) {
+                *def = new_temp(def.get_tp());
+                stacks.entry(var).or_default().push(def.clone());
+                pushed.push(var);
+            }
+        }
+    }
+
+    for succ in cfg.blocks[id].succs.clone() {
+        for inst in &mut cfg.blocks[succ].insts {
+            if let Inst::Phi { args, .. } = inst {
+                for (pred, arg) in args {
+                    if *pred == id {
+                        current(stacks, arg);
+                    }
+                }
+            }
+        }
+    }
+
+    for &child in dom.children(id) {
+        rename(cfg, dom, child, stacks);
+    }
+
+    for var in pushed {
+        stacks.get_mut(&var).unwrap().pop();
+    }
+}
+
+/**
+ * Translates `cfg` out of SSA form, replacing phis with copies on the
+ * incoming edges. Critical edges are split through a new labeled block placed
+ * at the end of the function, and every edge's copies are sequentialized.
+ */
+pub fn from_ssa(cfg: &mut Cfg) {
+    let copies = |cfg: &Cfg, pred: usize, succ: usize| {
+        let mut copies = Vec::new();
+        for inst in &cfg.blocks[succ].insts {
+            if let Inst::Phi { dst, args } = inst {
+                for (other, arg) in args {
+                    if *other == pred {
+                        copies.push((dst.clone(), arg.clone()));
+                    }
+                }
+            }
+        }
+
+        sequentialize(copies)
+    };
+
+    let mut code = Vec::new();
+    let mut split = Vec::new();
+
+    for (id, block) in cfg.blocks.iter().enumerate() {
+        let mut insts: Vec<Inst> = block
+            .insts
+            .iter()
+            .filter(|inst| !matches!(inst, Inst::Phi { .. }))
+            .cloned()
+            .collect();
+
+        match insts.last_mut() {
+            Some(Inst::JmpT { label, .. }) | Some(Inst::JmpF { label, .. }) => {
+                let target = cfg.block_of(*label).expect("Undefined label");
+                let moves = copies(cfg, id, target);
+                if !moves.is_empty() {
+                    let new = new_label_id();
+                    split.push(Inst::Label(new));
+                    split.extend(moves);
+                    split.push(Inst::Jmp { label: *label });
+                    *label = new;
+                }
+
+                code.extend(insts);
+                code.extend(copies(cfg, id, id + 1));
+            }
+
+            Some(Inst::Jmp { label }) => {
+                let target = cfg.block_of(*label).expect("Undefined label");
+                let jump = insts.pop().unwrap();
+                code.extend(insts);
+                code.extend(copies(cfg, id, target));
+                code.push(jump);
+            }
+
+            Some(Inst::Ret { .. }) => code.extend(insts),
+
+            _ if id == cfg.exit => code.extend(insts),
+
+            _ => {
+                code.extend(insts);
+                code.extend(copies(cfg, id, id + 1));
+            }
+        }
+    }
+
+    if !split.is_empty() {
+        if !code.last().is_some_and(Inst::is_terminator) {
+            code.push(Inst::Ret { src: None });
+        }
+
+        code.extend(split);
+    }
+
+    *cfg = Cfg::new(&code);
+}
+
+/// Orders a parallel copy `dst <- src` into `mov`s, breaking cycles with new temporaries.
+pub fn sequentialize(copies: Vec<(Operand, Operand)>) -> Vec<Inst> {
+    let mut pending: Vec<(Operand, Operand)> =
+        copies.into_iter().filter(|(dst, src)| dst != src).collect();
+    let mut moves = Vec::new();
+
+    while !pending.is_empty() {
+        let ready = pending
+            .iter()
+            .position(|(dst, _)| !pending.iter().any(|(_, src)| src == dst));
+
+        match ready {
+            Some(pos) => {
+                let (dst, src) = pending.remove(pos);
+                moves.push(Inst::Mov { dst, src });
+            }
+
+            None => {
+                let dst = pending[0].0.clone();
+                let temp = new_temp(dst.get_tp());
+                moves.push(Inst::Mov {
+                    dst: temp.clone(),
+                    src: dst.clone(),
+                });
+
+                for (_, src) in &mut pending {
+                    if *src == dst {
+                        *src = temp.clone();
+                    }
+                }
+            }
+        }
+    }
+
+    moves
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::Ident;
+    use crate::interp::interpreter::tests::{fact_program, loops_program};
+    use crate::interp::{Interpreter, RunResult, Value};
+    use crate::ir::cfg::tests::{function, LOOP};
+    use crate::ir::Module;
+    use crate::vm::machine::tests::compile;
+    use crate::vm::Machine;
+
+    fn round_trip(module: &mut Module) {
+        for func in &mut module.funcs {
+            let mut cfg = Cfg::new(&func.code);
+            to_ssa(&mut cfg);
+            from_ssa(&mut cfg);
+            func.code = cfg.to_code();
+        }
+    }
+
+    #[test]
+    fn single_assignment() {
+        let func = function(LOOP);
+        let mut cfg = Cfg::new(&func.code);
+        to_ssa(&mut cfg);
+
+        let mut defs = HashSet::new();
+        for inst in cfg.blocks.iter().flat_map(|block| &block.insts) {
+            if let Some(def) = inst.get_def() {
+                assert!(defs.insert(def.get_var()), "{} defined twice", def);
+            }
+        }
+
+        let phis = |id: usize| {
+            cfg.blocks[id]
+                .body()
+                .iter()
+                .take_while(|inst| matches!(inst, Inst::Phi { .. }))
+                .count()
+        };
+
+        assert_eq!(phis(2), 2);
+        assert_eq!(phis(6), 1);
+        assert_eq!(phis(3), 0);
+
+        match &cfg.blocks[2].body()[0] {
+            Inst::Phi { args, .. } => {
+                let preds: Vec<usize> = args.iter().map(|(pred, _)| *pred).collect();
+                assert_eq!(preds, vec![1, 6]);
+            }
+            inst => panic!("Expected phi, got {}", inst),
+        }
+    }
+
+    #[test]
+    fn round_trip_loop() -> RunResult<()> {
+        let mut module = Module {
+            funcs: vec![function(LOOP)],
+        };
+
+        let expected = Machine::new(&module).call("f", &[Value::Int32(10)])?;
+        round_trip(&mut module);
+
+        let code = &module.funcs[0].code;
+        assert!(code.iter().all(|inst| !matches!(inst, Inst::Phi { .. })));
+        assert_eq!(expected, Some(Value::Int32(-25)));
+        assert_eq!(
+            Machine::new(&module).call("f", &[Value::Int32(10)])?,
+            expected
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn round_trip_programs() -> RunResult<()> {
+        for program in &[fact_program(), loops_program()] {
+            let mut module = compile(program);
+            round_trip(&mut module);
+            assert_eq!(
+                Machine::new(&module).run()?,
+                Interpreter::new(program).run()?
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn parallel_copies() {
+        let var = |id: &str, offset| {
+            Operand::Ident(Ident {
+                id: id.to_owned(),
+                tp: Type::Int32,
+                offset,
+            })
+        };
+
+        let (a, b, c) = (var("a", 0), var("b", 4), var("c", 8));
+
+        let chain = sequentialize(vec![(a.clone(), b.clone()), (b.clone(), c.clone())]);
+        assert_eq!(
+            chain,
+            vec![
+                Inst::Mov {
+                    dst: a.clone(),
+                    src: b.clone()
+                },
+                Inst::Mov {
+                    dst: b.clone(),
+                    src: c
+                }
+            ]
+        );
+
+        let swap = sequentialize(vec![(a.clone(), b.clone()), (b.clone(), a.clone())]);
+        assert_eq!(swap.len(), 3);
+        match (&swap[0], &swap[2]) {
+            (Inst::Mov { dst: temp, src }, Inst::Mov { dst, src: last }) => {
+                assert_eq!(src, &a);
+                assert_eq!(dst, &b);
+                assert_eq!(last, temp);
+            }
+            _ => panic!("Expected moves"),
+        }
+
+        assert!(sequentialize(vec![(a.clone(), a)]).is_empty());
+    }
+}
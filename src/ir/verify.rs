@@ -0,0 +1,157 @@
+use std::collections::HashSet;
+
+use crate::error::IrError;
+use crate::ir::{Cfg, Inst, Operand, Var};
+
+/**
+ * Checks the invariants that every pass has to keep on `cfg`: `entry` and
+ * `exit` wrap the code and stay empty, labels are defined once and only lead
+ * their block, jumps only end it and go to defined labels, the edges match the
+ * instructions, and every temporary that is read is defined somewhere. Phis
+ * are only allowed in SSA form, right after the labels, with one argument per
+ * predecessor at most, and then every temporary must be defined once. Errors
+ * point at the instruction, counting from 1 over the linearized code.
+ */
+pub fn verify(cfg: &Cfg, ssa: bool) -> Result<(), IrError> {
+    if cfg.entry != 0 || cfg.exit + 1 != cfg.blocks.len() {
+        return Err(IrError::new(0, "entry and exit don't wrap the code"));
+    }
+
+    if !cfg.blocks[cfg.entry].insts.is_empty() || !cfg.blocks[cfg.exit].insts.is_empty() {
+        return Err(IrError::new(0, "entry and exit must be empty"));
+    }
+
+    let mut labels = HashSet::new();
+    let mut line = 0;
+    for block in &cfg.blocks {
+        for inst in &block.insts {
+            line += 1;
+            if let Inst::Label(label) = inst {
+                if !labels.insert(*label) {
+                    return Err(IrError::new(
+                        line,
+                        &format!("label defined twice: {}", inst),
+                    ));
+                }
+            }
+        }
+    }
+
+    let mut line = 0;
+    for block in &cfg.blocks {
+        for inst in &block.insts {
+            line += 1;
+            match inst {
+                Inst::Jmp { label } | Inst::JmpT { label, .. } | Inst::JmpF { label, .. }
+                    if !labels.contains(label) =>
+                {
+                    return Err(IrError::new(
+                        line,
+                        &format!("jump to undefined label: {}", inst),
+                    ))
+                }
+                _ => {}
+            }
+        }
+    }
+
+    let mut linked = cfg.clone();
+    linked.link();
+    if linked != *cfg {
+        return Err(IrError::new(0, "edges don't match the instructions"));
+    }
+
+    let mut defs = HashSet::new();
+    let mut uses = Vec::new();
+    let mut line = 0;
+
+    for block in &cfg.blocks {
+        let leading = block.labels().count();
+        let mut phis = true;
+
+        for (index, inst) in block.insts.iter().enumerate() {
+            line += 1;
+            let fail = |msg: &str| Err(IrError::new(line, &format!("{}: {}", msg, inst)));
+
+            match inst {
+                Inst::Label(_) if index >= leading => return fail("label inside a block"),
+                Inst::Phi { .. } if !ssa => return fail("phi outside of SSA form"),
+                Inst::Phi { .. } if !phis => return fail("phi after other instructions"),
+                Inst::Phi { args, .. } => {
+                    let mut preds = HashSet::new();
+                    for (pred, _) in args {
+                        if !block.preds.contains(pred) || !preds.insert(*pred) {
+                            return fail("phi argument from a block that isn't a predecessor");
+                        }
+                    }
+                }
+                inst if inst.is_terminator() && index + 1 != block.insts.len() => {
+                    return fail("jump inside a block")
+                }
+                _ => {}
+            }
+
+            if index >= leading && !matches!(inst, Inst::Phi { .. }) {
+                phis = false;
+            }
+
+            if let Some(Operand::Temp(temp)) = inst.get_def() {
+                if !defs.insert(Var::Temp(temp.id)) && ssa {
+                    return fail("temporary defined twice in SSA form");
+                }
+            }
+
+            for operand in inst.get_uses() {
+                if let Operand::Temp(temp) = operand {
+                    uses.push((line, Var::Temp(temp.id), inst));
+                }
+            }
+        }
+    }
+
+    for (line, var, inst) in uses {
+        if !defs.contains(&var) {
+            return Err(IrError::new(
+                line,
+                &format!("temporary never defined: {}", inst),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::cfg::tests::{function, LOOP};
+    use crate::ir::{from_ssa, to_ssa};
+
+    #[test]
+    fn invariants() {
+        let func = function(LOOP);
+        let mut cfg = Cfg::new(&func.code);
+        assert!(verify(&cfg, false).is_ok());
+
+        to_ssa(&mut cfg);
+        assert!(verify(&cfg, true).is_ok());
+        assert!(verify(&cfg, false).is_err());
+
+        from_ssa(&mut cfg);
+        assert!(verify(&cfg, false).is_ok());
+
+        // Dropping the loop header leaves the back edge dangling
+        let mut broken = cfg.clone();
+        broken.blocks[2].insts.remove(0);
+        let error = verify(&broken, false).unwrap_err();
+        assert!(error.msg.starts_with("jump to undefined label"));
+
+        // Edges have to follow the code
+        let mut broken = cfg;
+        broken.blocks[3].insts.pop();
+        assert_eq!(
+            verify(&broken, false).unwrap_err().msg,
+            "edges don't match the instructions"
+        );
+    }
+}
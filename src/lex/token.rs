@@ -68,10 +68,7 @@ pub enum Token {
 
 impl Token {
     pub fn is_whitespace(&self) -> bool {
-        match self {
-            Token::Tab | Token::Space | Token::Newline => true,
-            _ => false,
-        }
+        matches!(self, Token::Tab | Token::Space | Token::Newline)
     }
 }
 
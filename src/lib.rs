@@ -1,5 +1,11 @@
+pub mod analysis;
 pub mod ast;
+pub mod codegen;
 pub mod error;
+pub mod interp;
+pub mod ir;
 pub mod lex;
+pub mod opt;
 pub mod sym;
 pub mod syn;
+pub mod vm;
@@ -0,0 +1,19 @@
+pub mod copies;
+pub mod dce;
+pub mod gvn;
+pub mod inline;
+pub mod loops;
+pub mod lvn;
+pub mod manager;
+pub mod peephole;
+pub mod sccp;
+
+pub use copies::*;
+pub use dce::*;
+pub use gvn::*;
+pub use inline::*;
+pub use loops::*;
+pub use lvn::*;
+pub use manager::*;
+pub use peephole::*;
+pub use sccp::*;
@@ -0,0 +1,149 @@
+use crate::analysis::{Dataflow, Liveness};
+use crate::ir::{Cfg, Inst, Operand};
+
+/// Finds a `mov dst src` of `block` whose `src` can be written as `dst` right
+/// away, returns the position of the definition and of the `mov`.
+fn candidate(cfg: &Cfg, live: &Dataflow<Liveness>, block: usize) -> Option<(usize, usize)> {
+    let insts = &cfg.blocks[block].insts;
+    let points = live.points(block);
+
+    for (pos, inst) in insts.iter().enumerate() {
+        let (dst, src) = match inst {
+            Inst::Mov {
+                dst,
+                src: src @ Operand::Temp(_),
+            } if dst.get_tp() == src.get_tp() => (dst, src),
+            _ => continue,
+        };
+
+        let var = src.get_var().unwrap();
+        if points[pos + 1].contains(&var) {
+            continue;
+        }
+
+        let def = match insts[..pos]
+            .iter()
+            .rposition(|inst| inst.get_def() == Some(src))
+        {
+            Some(def) => def,
+            None => continue,
+        };
+
+        // Nothing in between may see either value
+        let touched = insts[def + 1..pos].iter().any(|inst| {
+            inst.get_def()
+                .is_some_and(|other| other == dst || other == src)
+                || inst
+                    .get_uses()
+                    .into_iter()
+                    .any(|other| other == dst || other == src)
+        });
+
+        if !touched && !matches!(insts[def], Inst::Phi { .. }) {
+            return Some((def, pos));
+        }
+    }
+
+    None
+}
+
+/**
+ * Coalesces copies out of temporaries that die with them: in `add t a b`
+ * followed by `mov x t`, where nothing else reads `t` and nothing in between
+ * touches `x` or `t`, the `add` writes `x` directly and the `mov` goes away.
+ * Cleans up after the copies that `from_ssa` leaves on loop back edges.
+ * Returns whether the code changed.
+ */
+pub fn coalesce(cfg: &mut Cfg) -> bool {
+    let mut changed = false;
+
+    loop {
+        let found = {
+            let live = Dataflow::new(cfg, Liveness);
+            (0..cfg.blocks.len())
+                .find_map(|block| candidate(cfg, &live, block).map(|pair| (block, pair)))
+        };
+
+        let (block, (def, pos)) = match found {
+            Some(found) => found,
+            None => return changed,
+        };
+
+        let insts = &mut cfg.blocks[block].insts;
+        if let Inst::Mov { dst, .. } = insts.remove(pos) {
+            *insts[def].get_def_mut().unwrap() = dst;
+        }
+
+        changed = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interp::interpreter::tests::loops_program;
+    use crate::interp::{Interpreter, RunResult};
+    use crate::ir::cfg::tests::function;
+    use crate::ir::{from_ssa, to_ssa};
+    use crate::vm::machine::tests::compile;
+    use crate::vm::Machine;
+
+    #[test]
+    fn coalesce_copies() {
+        let func = function(
+            "fn f(a: i32) -> i32\n\tvar x: i32\n\tvar y: i32\n\
+             \tadd __t0 a 1\n\tmul __t1 a 2\n\tmov x __t0\n\tmov y __t1\n\
+             \tadd __t2 x 1\n\tmov a __t2\n\tadd __t3 __t2 1\n\
+             \tadd __t4 y 1\n\tmov __t5 y\n\tmov y __t4\n\
+             \tadd __t6 __t5 y\n\tadd __t7 __t6 a\n\tadd __t8 __t7 __t3\n\tret __t8\n",
+        );
+
+        let mut cfg = Cfg::new(&func.code);
+        assert!(coalesce(&mut cfg));
+
+        let code: Vec<String> = cfg.to_code().iter().map(Inst::to_string).collect();
+        assert_eq!(
+            code,
+            vec![
+                "add x a 1",
+                "mul y a 2",
+                "add __t2 x 1",
+                "mov a __t2",
+                "add __t3 __t2 1",
+                "add __t4 y 1",
+                "mov __t5 y",
+                "mov y __t4",
+                "add __t6 __t5 y",
+                "add __t7 __t6 a",
+                "add __t8 __t7 __t3",
+                "ret __t8"
+            ]
+        );
+    }
+
+    #[test]
+    fn out_of_ssa() -> RunResult<()> {
+        let program = loops_program();
+        let mut module = compile(&program);
+        let mut removed = 0;
+
+        for func in &mut module.funcs {
+            let mut cfg = Cfg::new(&func.code);
+            to_ssa(&mut cfg);
+            from_ssa(&mut cfg);
+
+            let len = cfg.to_code().len();
+            coalesce(&mut cfg);
+            func.code = cfg.to_code();
+            removed += len - func.code.len();
+        }
+
+        assert!(removed > 0);
+        assert_eq!(
+            Machine::new(&module).run()?,
+            Interpreter::new(&program).run()?
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,344 @@
+use std::collections::HashSet;
+
+use crate::analysis::{Dataflow, Liveness};
+use crate::interp::Value;
+use crate::ir::{Cfg, Inst, Opcode, Operand, Var};
+use crate::sym::Type;
+
+/// Whether executing `inst` can only write its destination: no call, store or
+/// jump, and no division by zero or out of bounds load either.
+pub(crate) fn is_pure(inst: &Inst) -> bool {
+    match inst {
+        Inst::Binary {
+            op: Opcode::Div,
+            rhs,
+            ..
+        } => match rhs {
+            Operand::Cons(cons) => !matches!(
+                Value::from_cons(cons),
+                Value::Int32(0) | Value::Int64(0) | Value::Char('\0')
+            ),
+            _ => matches!(rhs.get_tp(), Type::Flt32 | Type::Flt64),
+        },
+
+        Inst::Idx { index, array, .. } => match array.get_tp() {
+            tp @ Type::Array { .. } => in_bounds(index, tp.get_len()),
+            _ => false,
+        },
+
+        Inst::Binary { .. } | Inst::Inv { .. } | Inst::Not { .. } | Inst::Mov { .. } => true,
+        Inst::Phi { .. } => true,
+        _ => false,
+    }
+}
+
+/// Whether `index` is a constant in `0..len`.
+fn in_bounds(index: &Operand, len: usize) -> bool {
+    match index {
+        Operand::Cons(cons) => Value::from_cons(cons)
+            .as_index()
+            .is_ok_and(|index| index >= 0 && (index as usize) < len),
+        _ => false,
+    }
+}
+
+/**
+ * Dead code elimination based on liveness: pure instructions and stores at
+ * constant indices in bounds to arrays whose result is never read again are
+ * removed, and so are the destinations of calls and the `chk`s of constants
+ * in bounds. Runs until nothing changes, so temporaries that only feed dead
+ * code go away too. Returns whether the code changed.
+ */
+pub fn dce(cfg: &mut Cfg) -> bool {
+    let mut changed = false;
+
+    loop {
+        let live = Dataflow::new(cfg, Liveness);
+        let mut blocks = Vec::with_capacity(cfg.blocks.len());
+        let mut removed = false;
+
+        for (id, block) in cfg.blocks.iter().enumerate() {
+            let points = live.points(id);
+            let mut insts = Vec::with_capacity(block.insts.len());
+
+            for (index, inst) in block.insts.iter().enumerate() {
+                let after = &points[index + 1];
+                let dead =
+                    |operand: &Operand| operand.get_var().is_some_and(|var| !after.contains(&var));
+
+                match inst {
+                    Inst::Call {
+                        dst: Some(dst),
+                        func,
+                        nargs,
+                    } if dead(dst) => {
+                        insts.push(Inst::Call {
+                            dst: None,
+                            func: func.clone(),
+                            nargs: *nargs,
+                        });
+                        removed = true;
+                    }
+
+                    // A store out of bounds still fails, even if nothing reads it
+                    Inst::Sto { array, index, .. }
+                        if dead(array) && in_bounds(index, array.get_tp().get_len()) =>
+                    {
+                        removed = true
+                    }
+
+                    Inst::Chk { index, len } if in_bounds(index, *len) => removed = true,
+
+                    inst if is_pure(inst) && inst.get_def().is_some_and(dead) => removed = true,
+
+                    inst => insts.push(inst.clone()),
+                }
+            }
+
+            blocks.push(insts);
+        }
+
+        if !removed {
+            return changed;
+        }
+
+        for (block, insts) in cfg.blocks.iter_mut().zip(blocks) {
+            block.insts = insts;
+        }
+
+        changed = true;
+    }
+}
+
+/// Drops the blocks that can't be reached from `cfg.entry`. Returns whether
+/// any block was removed.
+pub fn remove_unreachable(cfg: &mut Cfg) -> bool {
+    let mut reachable = vec![false; cfg.blocks.len()];
+    for id in cfg.reverse_postorder() {
+        reachable[id] = true;
+    }
+    reachable[cfg.exit] = true;
+
+    if reachable.iter().all(|&reachable| reachable) {
+        return false;
+    }
+
+    let mut index = vec![None; cfg.blocks.len()];
+    let mut blocks = Vec::new();
+    for (id, block) in std::mem::take(&mut cfg.blocks).into_iter().enumerate() {
+        if reachable[id] {
+            index[id] = Some(blocks.len());
+            blocks.push(block);
+        }
+    }
+
+    for inst in blocks.iter_mut().flat_map(|block| &mut block.insts) {
+        if let Inst::Phi { args, .. } = inst {
+            *args = args
+                .drain(..)
+                .filter_map(|(pred, arg)| index[pred].map(|pred| (pred, arg)))
+                .collect();
+        }
+    }
+
+    cfg.blocks = blocks;
+    cfg.entry = index[cfg.entry].unwrap();
+    cfg.exit = index[cfg.exit].unwrap();
+    cfg.link();
+    true
+}
+
+/**
+ * Removes the `sto`s that are overwritten inside their block before the array
+ * is read: same array and same index, with the index left unchanged in
+ * between. Returns whether the code changed.
+ */
+pub fn dse(cfg: &mut Cfg) -> bool {
+    let mut changed = false;
+
+    for block in &mut cfg.blocks {
+        // Stores further down the block, by array and index
+        let mut later: HashSet<(Var, String)> = HashSet::new();
+        let mut insts = Vec::with_capacity(block.insts.len());
+
+        for inst in block.insts.drain(..).rev() {
+            if let Some(var) = inst.get_def().and_then(Operand::get_var) {
+                later.retain(|(array, _)| *array != var);
+                let name = var_name(var);
+                later.retain(|(_, index)| *index != name);
+            }
+
+            match &inst {
+                Inst::Sto { array, index, .. } => {
+                    let key = (array.get_var().unwrap(), operand_name(index));
+                    if later.contains(&key) {
+                        changed = true;
+                        continue;
+                    }
+
+                    later.insert(key);
+                }
+
+                inst => {
+                    for var in inst.get_uses().into_iter().filter_map(Operand::get_var) {
+                        later.retain(|(array, _)| *array != var);
+                    }
+                }
+            }
+
+            insts.push(inst);
+        }
+
+        insts.reverse();
+        block.insts = insts;
+    }
+
+    changed
+}
+
+fn var_name(var: Var) -> String {
+    match var {
+        Var::Ident(offset) => format!("v{}", offset),
+        Var::Temp(id) => format!("t{}", id),
+    }
+}
+
+fn operand_name(operand: &Operand) -> String {
+    match operand {
+        Operand::Cons(cons) => format!("{}:{}", cons.tok, cons.tp),
+        operand => var_name(operand.get_var().unwrap()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::RuntimeError;
+    use crate::interp::interpreter::tests::{fact_program, loops_program};
+    use crate::interp::{Interpreter, RunResult};
+    use crate::ir::cfg::tests::function;
+    use crate::ir::{from_ssa, parse_module, to_ssa, Module};
+    use crate::opt::sccp;
+    use crate::vm::machine::tests::compile;
+    use crate::vm::Machine;
+
+    fn code(cfg: &Cfg) -> Vec<String> {
+        cfg.to_code().iter().map(Inst::to_string).collect()
+    }
+
+    #[test]
+    fn dead_code() {
+        let func = function(
+            "fn f(a: i32, b: i32) -> i32\n\tvar x: i32\n\
+             \tadd __t0 a b\n\tmul __t1 __t0 2\n\tmov x __t1\n\
+             \tparam a\n\tparam b\n\tcall __t2 f 2\n\tdiv __t3 a b\n\tdiv __t4 a 2\n\
+             \tmov x 5\n\tret x\n",
+        );
+
+        let mut cfg = Cfg::new(&func.code);
+        assert!(dce(&mut cfg));
+        assert!(!dce(&mut cfg));
+        assert_eq!(
+            code(&cfg),
+            vec![
+                "param a",
+                "param b",
+                "call f 2",
+                "div __t3 a b",
+                "mov x 5",
+                "ret x"
+            ]
+        );
+    }
+
+    #[test]
+    fn unreachable_blocks() {
+        let func = function(
+            "fn f() -> i32\n\tvar x: i32\n\tjmp L1\n\tmov x 1\n\tret x\nL1\tret x\nL2\tret 2\n",
+        );
+
+        let mut cfg = Cfg::new(&func.code);
+        let blocks = cfg.blocks.len();
+        assert!(remove_unreachable(&mut cfg));
+        assert_eq!(cfg.blocks.len(), blocks - 2);
+        assert_eq!(code(&cfg), vec!["jmp L1", "L1", "ret x"]);
+        assert_eq!(cfg.blocks[cfg.exit].preds, vec![2]);
+        assert!(!remove_unreachable(&mut cfg));
+    }
+
+    #[test]
+    fn dead_stores() {
+        let func = function(
+            "fn f(i: i32) -> i32\n\tvar v: [4]i32\n\
+             \tsto v 0 1\n\tsto v i 2\n\tsto v 0 3\n\
+             \tsto v i 4\n\tidx __t0 i v\n\tsto v i 5\n\
+             \tsto v 1 6\n\tadd i i 1\n\tsto v 1 7\n\
+             \tidx __t1 0 v\n\tsto v 2 8\n\tadd __t2 __t0 __t1\n\tret __t2\n",
+        );
+
+        let mut cfg = Cfg::new(&func.code);
+        assert!(dse(&mut cfg));
+        assert_eq!(
+            code(&cfg),
+            vec![
+                "sto v 0 3",
+                "sto v i 4",
+                "idx __t0 i v",
+                "sto v i 5",
+                "add i i 1",
+                "sto v 1 7",
+                "idx __t1 0 v",
+                "sto v 2 8",
+                "add __t2 __t0 __t1",
+                "ret __t2"
+            ]
+        );
+
+        // Nothing reads v after the last load, that's for liveness to find
+        assert!(dce(&mut cfg));
+        assert!(!code(&cfg).contains(&"sto v 2 8".to_owned()));
+    }
+
+    #[test]
+    fn dead_stores_out_of_bounds() {
+        // v is never read, but only the store in bounds can go
+        let mut module = parse_module(
+            "fn main() -> i32\n\tvar v: [4]i32\n\tvar i: i32\n\
+             \tsto v i 5\n\tsto v 4 3\n\tsto v 1 2\n\tret 0\n",
+        )
+        .unwrap();
+
+        let mut cfg = Cfg::new(&module.funcs[0].code);
+        assert!(dce(&mut cfg));
+        assert_eq!(code(&cfg), vec!["sto v i 5", "sto v 4 3", "ret 0"]);
+
+        module.funcs[0].code = cfg.to_code();
+        assert_eq!(
+            Machine::new(&module).run(),
+            Err(RuntimeError::IndexOutOfBounds { index: 4, len: 4 })
+        );
+    }
+
+    #[test]
+    fn optimized_programs() -> RunResult<()> {
+        for program in &[fact_program(), loops_program()] {
+            let mut module: Module = compile(program);
+            for func in &mut module.funcs {
+                let mut cfg = Cfg::new(&func.code);
+                to_ssa(&mut cfg);
+                sccp(&mut cfg);
+                dce(&mut cfg);
+                remove_unreachable(&mut cfg);
+                from_ssa(&mut cfg);
+                func.code = cfg.to_code();
+            }
+
+            assert_eq!(
+                Machine::new(&module).run()?,
+                Interpreter::new(program).run()?
+            );
+        }
+
+        Ok(())
+    }
+}
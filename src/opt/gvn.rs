@@ -0,0 +1,199 @@
+use std::collections::HashMap;
+
+use crate::ir::{Cfg, DomTree, Inst, Operand, Var};
+use crate::opt::lvn::expr_key;
+
+#[derive(Debug, Default)]
+struct Gvn {
+    /// Operand that replaces each removed temporary.
+    leaders: HashMap<Var, Operand>,
+    /// Expressions available in the dominator tree path being walked.
+    table: HashMap<String, Operand>,
+}
+
+impl Gvn {
+    fn leader(&self, operand: &Operand) -> Operand {
+        let mut operand = operand.clone();
+        while let Some(leader) = operand.get_var().and_then(|var| self.leaders.get(&var)) {
+            operand = leader.clone();
+        }
+
+        operand
+    }
+
+    fn name(operand: &Operand) -> String {
+        match operand {
+            Operand::Ident(ident) => format!("v{}", ident.offset),
+            Operand::Temp(temp) => format!("t{}", temp.id),
+            Operand::Cons(cons) => format!("{}:{}", cons.tok, cons.tp),
+        }
+    }
+
+    /// Finds the operand that already holds the value of `inst`, if any.
+    fn redundant(
+        &mut self,
+        block: usize,
+        inst: &Inst,
+        loads: &mut Vec<(String, Operand)>,
+    ) -> Option<Operand> {
+        let dst = match inst.get_def() {
+            Some(dst @ Operand::Temp(_)) => dst,
+            _ => return None,
+        };
+
+        let key = match inst {
+            Inst::Phi { args, .. } => {
+                let mut values = args.iter().map(|(_, arg)| arg).filter(|arg| *arg != dst);
+                let first = values.next()?;
+                if values.all(|arg| arg == first) {
+                    return Some(first.clone());
+                }
+
+                let args: Vec<String> = args
+                    .iter()
+                    .map(|(pred, arg)| format!("{}={}", pred, Self::name(arg)))
+                    .collect();
+                format!("phi B{} {} : {}", block, args.join(" "), dst.get_tp())
+            }
+
+            Inst::Mov { src, .. } if src.get_tp() == dst.get_tp() => return Some(src.clone()),
+
+            Inst::Idx { .. } => {
+                let args = inst.get_uses().into_iter().map(Self::name).collect();
+                let key = expr_key(inst, args)?;
+                match loads.iter().find(|(other, _)| *other == key) {
+                    Some((_, holder)) => return Some(holder.clone()),
+                    None => {
+                        loads.push((key, dst.clone()));
+                        return None;
+                    }
+                }
+            }
+
+            inst => {
+                let args = inst.get_uses().into_iter().map(Self::name).collect();
+                expr_key(inst, args)?
+            }
+        };
+
+        match self.table.get(&key) {
+            Some(holder) => Some(holder.clone()),
+            None => {
+                self.table.insert(key, dst.clone());
+                None
+            }
+        }
+    }
+
+    fn walk(&mut self, cfg: &mut Cfg, dom: &DomTree, block: usize) -> bool {
+        let scope: Vec<String> = self.table.keys().cloned().collect();
+        let mut loads: Vec<(String, Operand)> = Vec::new();
+        let mut changed = false;
+        let mut insts = Vec::new();
+
+        for mut inst in std::mem::take(&mut cfg.blocks[block].insts) {
+            for operand in inst.get_uses_mut() {
+                *operand = self.leader(operand);
+            }
+
+            if let Inst::Sto { array, .. } = &inst {
+                let name = Self::name(array);
+                loads.retain(|(key, _)| !key.split(' ').any(|arg| arg == name));
+            }
+
+            match self.redundant(block, &inst, &mut loads) {
+                Some(leader) => {
+                    let var = inst.get_def().and_then(Operand::get_var).unwrap();
+                    self.leaders.insert(var, leader);
+                    changed = true;
+                }
+                None => insts.push(inst),
+            }
+        }
+
+        cfg.blocks[block].insts = insts;
+
+        for &child in dom.children(block) {
+            changed |= self.walk(cfg, dom, child);
+        }
+
+        self.table.retain(|key, _| scope.contains(key));
+        changed
+    }
+}
+
+/**
+ * Dominator-based global value numbering over a `Cfg` in SSA form: an
+ * expression already computed in a dominating block (or earlier in the same
+ * one) is removed, and its uses read the first result instead. Copies and
+ * phis whose arguments all agree are folded the same way. Loads with `idx`
+ * are only reused inside their block, until their array is stored to.
+ * Returns whether the code changed.
+ */
+pub fn gvn(cfg: &mut Cfg) -> bool {
+    let dom = DomTree::new(cfg);
+    let mut gvn = Gvn::default();
+    let changed = gvn.walk(cfg, &dom, dom.root);
+
+    for inst in cfg.blocks.iter_mut().flat_map(|block| &mut block.insts) {
+        for operand in inst.get_uses_mut() {
+            *operand = gvn.leader(operand);
+        }
+    }
+
+    changed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interp::{RunResult, Value};
+    use crate::ir::cfg::tests::function;
+    use crate::ir::{from_ssa, to_ssa, Module};
+    use crate::vm::Machine;
+
+    #[test]
+    fn global_numbering() -> RunResult<()> {
+        // a + b is computed in the entry block, so every later (commuted) copy
+        // of it is redundant, and so is the phi that joins y
+        let code = "fn f(a: i32, b: i32, c: bool) -> i32\n\tvar x: i32\n\tvar y: i32\n\
+            \tadd x a b\n\tjmpf L1 c\n\
+            \tadd y b a\n\tsub __t0 a b\n\tjmp L2\n\
+            L1\tadd y a b\n\tsub __t1 a b\n\
+            L2\tmul __t2 x y\n\tadd __t3 a b\n\tadd __t4 __t2 __t3\n\tret __t4\n";
+
+        let mut func = function(code);
+        let mut cfg = Cfg::new(&func.code);
+        to_ssa(&mut cfg);
+        assert!(gvn(&mut cfg));
+        assert!(!cfg
+            .blocks
+            .iter()
+            .flat_map(|block| &block.insts)
+            .any(|inst| matches!(inst, Inst::Phi { .. })));
+
+        from_ssa(&mut cfg);
+        func.code = cfg.to_code();
+
+        let count = |opcode: &str| {
+            func.code
+                .iter()
+                .filter(|inst| inst.get_opcode() == opcode)
+                .count()
+        };
+
+        // sub a b lives in sibling blocks, neither dominates the other
+        assert_eq!(count("add"), 2);
+        assert_eq!(count("sub"), 2);
+        assert_eq!(count("mul"), 1);
+
+        let module = Module { funcs: vec![func] };
+        let args = [Value::Int32(2), Value::Int32(3), Value::Bool(true)];
+        assert_eq!(
+            Machine::new(&module).call("f", &args)?,
+            Some(Value::Int32(30))
+        );
+
+        Ok(())
+    }
+}
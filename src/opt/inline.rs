@@ -0,0 +1,421 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::ast::{new_label_id, new_temp_id, Temp};
+use crate::interp::Value;
+use crate::ir::{Cfg, Function, Inst, Module, Operand};
+use crate::sym::Type;
+
+/// Largest callee, in instructions (labels don't count), that `inline` expands
+/// by default.
+pub const INLINE_THRESHOLD: usize = 24;
+
+fn new_temp(tp: Type) -> Operand {
+    Operand::Temp(Temp {
+        id: new_temp_id(),
+        tp,
+    })
+}
+
+fn size(func: &Function) -> usize {
+    func.code
+        .iter()
+        .filter(|inst| !matches!(inst, Inst::Label(_)))
+        .count()
+}
+
+/// Functions that can end up calling themselves.
+fn recursive(module: &Module) -> HashSet<String> {
+    let graph: HashMap<&str, Vec<&str>> = module
+        .funcs
+        .iter()
+        .map(|func| {
+            let callees = func
+                .code
+                .iter()
+                .filter_map(|inst| match inst {
+                    Inst::Call { func, .. } => Some(func.as_str()),
+                    _ => None,
+                })
+                .collect();
+
+            (func.name.as_str(), callees)
+        })
+        .collect();
+
+    let mut recursive = HashSet::new();
+    for &name in graph.keys() {
+        let mut seen = HashSet::new();
+        let mut work = graph[name].clone();
+
+        while let Some(callee) = work.pop() {
+            if callee == name {
+                recursive.insert(name.to_owned());
+                break;
+            }
+
+            if seen.insert(callee) {
+                work.extend(graph.get(callee).into_iter().flatten());
+            }
+        }
+    }
+
+    recursive
+}
+
+/// Code of `callee` that can run, in its order: the blocks reachable from its
+/// entry, without the `ret` every function ends with when it can't be reached.
+fn reachable(callee: &Function) -> Vec<Inst> {
+    let cfg = Cfg::new(&callee.code);
+    let reachable: HashSet<usize> = cfg.reverse_postorder().into_iter().collect();
+
+    cfg.blocks
+        .iter()
+        .enumerate()
+        .filter(|(id, _)| reachable.contains(id))
+        .flat_map(|(_, block)| block.insts.iter().cloned())
+        .collect()
+}
+
+/// Whether the body of `callee` can be copied into its callers: small enough,
+/// no arrays (they can't move out of the frame), and no way of reaching a
+/// bare `ret` or the end without the value it has to return.
+fn inlinable(callee: &Function, threshold: usize) -> bool {
+    let scalar =
+        |tp: &Type| !matches!(tp, Type::Array { .. }) && Value::zero(tp).to_cons().is_some();
+
+    let returns = callee.ret.is_none() || {
+        let cfg = Cfg::new(&callee.code);
+        let reachable = cfg.reverse_postorder();
+        cfg.blocks[cfg.exit]
+            .preds
+            .iter()
+            .filter(|pred| reachable.contains(pred))
+            .all(|pred| {
+                matches!(
+                    cfg.blocks[*pred].insts.last(),
+                    Some(Inst::Ret { src: Some(_) })
+                )
+            })
+    };
+
+    size(callee) <= threshold
+        && returns
+        && callee
+            .params
+            .iter()
+            .chain(&callee.locals)
+            .all(|ident| scalar(&ident.tp))
+}
+
+/**
+ * Copy of the body of `callee` to put in place of a call: parameters and
+ * locals become fresh temporaries (locals start at zero, as in a new frame),
+ * and so do the callee's own temporaries and labels, leaving out the code
+ * that can't be reached. Every `ret` moves its
+ * value into a temporary of the return type and jumps past the copy, where
+ * the value is moved into `dst`.
+ */
+fn expand(callee: &Function, args: Vec<Operand>, dst: Option<Operand>) -> Vec<Inst> {
+    let mut code = Vec::new();
+    let mut vars = HashMap::new();
+
+    for (param, arg) in callee.params.iter().zip(args) {
+        let temp = new_temp(param.tp.clone());
+        code.push(Inst::Mov {
+            dst: temp.clone(),
+            src: arg,
+        });
+        vars.insert(param.offset, temp);
+    }
+
+    for local in &callee.locals {
+        let temp = new_temp(local.tp.clone());
+        let zero = Value::zero(&local.tp).to_cons().unwrap();
+        code.push(Inst::Mov {
+            dst: temp.clone(),
+            src: Operand::Cons(zero),
+        });
+        vars.insert(local.offset, temp);
+    }
+
+    let end = new_label_id();
+    let result = callee.ret.clone().map(new_temp);
+    let mut temps = HashMap::new();
+    let mut labels = HashMap::new();
+
+    let mut rename = |operand: &mut Operand| match operand {
+        Operand::Ident(ident) => {
+            if let Some(var) = vars.get(&ident.offset) {
+                *operand = var.clone();
+            }
+        }
+
+        Operand::Temp(temp) => {
+            *operand = temps
+                .entry(temp.id)
+                .or_insert_with(|| new_temp(temp.tp.clone()))
+                .clone()
+        }
+
+        Operand::Cons(_) => {}
+    };
+
+    for mut inst in reachable(callee) {
+        for operand in inst.get_uses_mut() {
+            rename(operand);
+        }
+
+        if let Some(def) = inst.get_def_mut() {
+            rename(def);
+        }
+
+        match &mut inst {
+            Inst::Label(label)
+            | Inst::Jmp { label }
+            | Inst::JmpT { label, .. }
+            | Inst::JmpF { label, .. } => {
+                *label = *labels.entry(*label).or_insert_with(new_label_id);
+            }
+            _ => {}
+        }
+
+        match inst {
+            Inst::Ret { src } => {
+                if let (Some(src), Some(result)) = (src, &result) {
+                    code.push(Inst::Mov {
+                        dst: result.clone(),
+                        src,
+                    });
+                }
+
+                code.push(Inst::Jmp { label: end });
+            }
+
+            inst => code.push(inst),
+        }
+    }
+
+    code.push(Inst::Label(end));
+    if let (Some(dst), Some(result)) = (dst, result) {
+        code.push(Inst::Mov { dst, src: result });
+    }
+
+    code
+}
+
+/// Arguments of the call at `pos`, if they are all passed right before it.
+fn args(code: &[Inst], pos: usize, nargs: usize) -> Option<Vec<Operand>> {
+    let start = pos.checked_sub(nargs)?;
+    code[start..pos]
+        .iter()
+        .map(|inst| match inst {
+            Inst::Param { src } => Some(src.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+/**
+ * Replaces calls to functions of at most `threshold` instructions with a copy
+ * of their body. Functions that can call themselves, directly or through
+ * others, are never inlined. Runs until nothing changes, so calls brought in
+ * by inlining get their turn too. Returns whether the module changed.
+ */
+pub fn inline(module: &mut Module, threshold: usize) -> bool {
+    let recursive = recursive(module);
+    let mut changed = false;
+
+    loop {
+        let mut step = false;
+
+        for id in 0..module.funcs.len() {
+            let mut pos = 0;
+            while pos < module.funcs[id].code.len() {
+                let code = &module.funcs[id].code;
+                let (callee, nargs, dst) = match &code[pos] {
+                    Inst::Call { dst, func, nargs } => (func, *nargs, dst.clone()),
+                    _ => {
+                        pos += 1;
+                        continue;
+                    }
+                };
+
+                let callee = match module.get(callee) {
+                    Some(callee)
+                        if !recursive.contains(&callee.name)
+                            && callee.params.len() == nargs
+                            && (dst.is_none() || callee.ret.is_some())
+                            && inlinable(callee, threshold) =>
+                    {
+                        callee.clone()
+                    }
+                    _ => {
+                        pos += 1;
+                        continue;
+                    }
+                };
+
+                let args = match args(code, pos, nargs) {
+                    Some(args) => args,
+                    None => {
+                        pos += 1;
+                        continue;
+                    }
+                };
+
+                let body = expand(&callee, args, dst);
+                let start = pos - nargs;
+                pos = start + body.len();
+                module.funcs[id].code.splice(start..=start + nargs, body);
+                step = true;
+            }
+        }
+
+        if !step {
+            return changed;
+        }
+
+        changed = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::{Call, Expr, Func, Program, Stmt};
+    use crate::error::RuntimeError;
+    use crate::interp::interpreter::tests::{
+        fact_program, ident, int, loops_program, main, rel, var,
+    };
+    use crate::interp::{Interpreter, RunResult};
+    use crate::ir::parse_module;
+    use crate::lex::Token;
+    use crate::opt::{OptLevel, PassManager};
+    use crate::vm::machine::tests::compile;
+    use crate::vm::Machine;
+
+    fn calls(module: &Module, name: &str) -> usize {
+        module
+            .get(name)
+            .unwrap()
+            .code
+            .iter()
+            .filter(|inst| matches!(inst, Inst::Call { .. }))
+            .count()
+    }
+
+    #[test]
+    fn inline_helpers() -> RunResult<()> {
+        // sum calls sq from inside its loop, and main calls both
+        let mut module = parse_module(
+            "fn sq(x: i32) -> i32\n\tmul __t0 x x\n\tret __t0\n\
+             fn sum(n: i32) -> i32\n\tvar i: i32\n\tvar s: i32\n\
+             L1\tlt __t1 i n\n\tjmpf L2 __t1\n\
+             \tparam i\n\tcall __t2 sq 1\n\tadd s s __t2\n\tadd i i 1\n\tjmp L1\n\
+             L2\tret s\n\
+             fn main() -> i32\n\tparam 4\n\tcall __t3 sum 1\n\
+             \tparam __t3\n\tcall __t4 sq 1\n\tret __t4\n",
+        )
+        .unwrap();
+
+        let expected = Machine::new(&module).run()?;
+        assert_eq!(expected, Some(Value::Int32(196)));
+
+        assert!(inline(&mut module, INLINE_THRESHOLD));
+        assert_eq!(calls(&module, "sum"), 0);
+        assert_eq!(calls(&module, "main"), 0);
+        assert_eq!(Machine::new(&module).run()?, expected);
+
+        let mut module = parse_module(
+            "fn sq(x: i32) -> i32\n\tmul __t0 x x\n\tret __t0\n\
+             fn main() -> i32\n\tparam 3\n\tcall __t1 sq 1\n\tret __t1\n",
+        )
+        .unwrap();
+
+        assert!(!inline(&mut module, 1));
+        assert_eq!(calls(&module, "main"), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn recursion_guard() {
+        let mut module = compile(&fact_program());
+        let before = module.clone();
+
+        assert!(!inline(&mut module, usize::MAX));
+        assert_eq!(module, before);
+    }
+
+    #[test]
+    fn inlined_programs() -> RunResult<()> {
+        for program in &[fact_program(), loops_program()] {
+            let mut module = compile(program);
+            inline(&mut module, INLINE_THRESHOLD);
+            assert_eq!(
+                Machine::new(&module).run()?,
+                Interpreter::new(program).run()?
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn missing_returns() -> RunResult<()> {
+        // The `ret` sign ends with, as every function, can't be reached
+        let x = ident("x", Type::Int32, 0);
+        let program = Program {
+            funcs: vec![
+                Func {
+                    name: "sign".to_owned(),
+                    params: vec![x.clone()],
+                    locals: vec![],
+                    ret: Some(Type::Int32),
+                    body: Stmt::Seq(vec![
+                        Stmt::If {
+                            cond: rel(Token::GreaterThan, var(&x), int(0)),
+                            then: Box::new(Stmt::Return(Some(int(1)))),
+                            otherwise: None,
+                        },
+                        Stmt::Return(Some(int(0))),
+                    ]),
+                },
+                main(
+                    vec![],
+                    vec![Stmt::Return(Some(Expr::Call(Call {
+                        func: "sign".to_owned(),
+                        tp: Some(Type::Int32),
+                        args: vec![int(5)],
+                    })))],
+                ),
+            ],
+        };
+
+        let mut module = compile(&program);
+        assert!(inline(&mut module, INLINE_THRESHOLD));
+        assert_eq!(calls(&module, "main"), 0);
+
+        let mut module = compile(&program);
+        PassManager::new(OptLevel::O2).run(&mut module).unwrap();
+        assert_eq!(
+            Machine::new(&module).run()?,
+            Interpreter::new(&program).run()?
+        );
+
+        // Here it can, so f stays a call that fails
+        let mut module = parse_module(
+            "fn f(x: i32) -> i32\n\tlt __t0 x 0\n\tjmpf L1 __t0\n\tret x\nL1\tret\n\
+             fn main() -> i32\n\tparam 1\n\tcall __t1 f 1\n\tret __t1\n",
+        )
+        .unwrap();
+
+        assert!(!inline(&mut module, INLINE_THRESHOLD));
+        assert_eq!(
+            Machine::new(&module).run(),
+            Err(RuntimeError::MissingReturn("f".to_owned()))
+        );
+
+        Ok(())
+    }
+}
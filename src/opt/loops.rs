@@ -0,0 +1,646 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use crate::ast::{new_label_id, new_temp_id, Cons, Temp};
+use crate::interp::Value;
+use crate::ir::{Block, Cfg, DomTree, Inst, Opcode, Operand, Var};
+use crate::lex::Token;
+use crate::opt::dce::is_pure;
+use crate::sym::Type;
+
+/// Natural loop of the back edges `latch -> header`, loops sharing their
+/// header are merged into one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Loop {
+    pub header: usize,
+    pub latches: Vec<usize>,
+    pub blocks: BTreeSet<usize>,
+}
+
+impl Loop {
+    pub fn contains(&self, block: usize) -> bool {
+        self.blocks.contains(&block)
+    }
+
+    /// Predecessors of the header from outside the loop.
+    pub fn entries<'a>(&'a self, cfg: &'a Cfg) -> impl Iterator<Item = usize> + 'a {
+        cfg.blocks[self.header]
+            .preds
+            .iter()
+            .cloned()
+            .filter(move |&pred| !self.contains(pred))
+    }
+
+    /// The only way into the loop, a block that does nothing but lead to the
+    /// header. `cfg.entry` doesn't count, as it must stay empty.
+    pub fn preheader(&self, cfg: &Cfg) -> Option<usize> {
+        let mut entries = self.entries(cfg);
+        match (entries.next(), entries.next()) {
+            (Some(pred), None) if pred != cfg.entry && cfg.blocks[pred].succs == [self.header] => {
+                Some(pred)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Natural loops of `cfg`, found from the edges whose target dominates their
+/// source. Inner loops come before the loops that contain them.
+pub fn find_loops(cfg: &Cfg, dom: &DomTree) -> Vec<Loop> {
+    let mut loops: Vec<Loop> = Vec::new();
+
+    for (latch, block) in cfg.blocks.iter().enumerate() {
+        for &header in &block.succs {
+            if !dom.dominates(header, latch) {
+                continue;
+            }
+
+            let pos = match loops.iter().position(|other| other.header == header) {
+                Some(pos) => pos,
+                None => {
+                    loops.push(Loop {
+                        header,
+                        latches: Vec::new(),
+                        blocks: Some(header).into_iter().collect(),
+                    });
+                    loops.len() - 1
+                }
+            };
+
+            let found = &mut loops[pos];
+            found.latches.push(latch);
+
+            let mut work = vec![latch];
+            while let Some(id) = work.pop() {
+                if dom.contains(id) && found.blocks.insert(id) {
+                    work.extend(&cfg.blocks[id].preds);
+                }
+            }
+        }
+    }
+
+    loops.sort_by_key(|found| found.blocks.len());
+    loops
+}
+
+/**
+ * Places a new block right before the header of `found` and sends every entry
+ * of the loop through it. Gives up if a block of the loop falls into the
+ * header, or if the header has phis and more than one entry. Blocks from the
+ * header on move one place down, the preheader takes the old index of the
+ * header.
+ */
+fn insert_preheader(cfg: &mut Cfg, found: &Loop) -> Option<usize> {
+    let header = found.header;
+    let entries: Vec<usize> = found.entries(cfg).collect();
+    let phis = cfg.blocks[header]
+        .body()
+        .iter()
+        .any(|inst| matches!(inst, Inst::Phi { .. }));
+
+    if found.contains(header - 1) || (phis && entries.len() > 1) {
+        return None;
+    }
+
+    let labels: Vec<usize> = cfg.blocks[header].labels().collect();
+    let label = new_label_id();
+
+    for &pred in &entries {
+        match cfg.blocks[pred].insts.last_mut() {
+            Some(Inst::Jmp { label: target })
+            | Some(Inst::JmpT { label: target, .. })
+            | Some(Inst::JmpF { label: target, .. })
+                if labels.contains(target) =>
+            {
+                *target = label
+            }
+            _ => {}
+        }
+    }
+
+    let shift = |id: usize| if id >= header { id + 1 } else { id };
+    for (id, block) in cfg.blocks.iter_mut().enumerate() {
+        for inst in &mut block.insts {
+            if let Inst::Phi { args, .. } = inst {
+                for (pred, _) in args {
+                    *pred = match id == header && entries.contains(pred) {
+                        true => header,
+                        false => shift(*pred),
+                    };
+                }
+            }
+        }
+    }
+
+    cfg.blocks.insert(
+        header,
+        Block {
+            insts: vec![Inst::Label(label)],
+            ..Block::default()
+        },
+    );
+
+    cfg.exit = shift(cfg.exit);
+    cfg.link();
+    Some(header)
+}
+
+/// Gives a preheader to every loop that can take one. Returns whether any
+/// block was added.
+pub fn insert_preheaders(cfg: &mut Cfg) -> bool {
+    let mut changed = false;
+
+    loop {
+        let dom = DomTree::new(cfg);
+        let missing: Vec<Loop> = find_loops(cfg, &dom)
+            .into_iter()
+            .filter(|found| found.preheader(cfg).is_none())
+            .collect();
+
+        let inserted = missing
+            .iter()
+            .any(|found| insert_preheader(cfg, found).is_some());
+
+        if !inserted {
+            return changed;
+        }
+
+        changed = true;
+    }
+}
+
+fn new_temp(tp: Type) -> Operand {
+    Operand::Temp(Temp {
+        id: new_temp_id(),
+        tp,
+    })
+}
+
+/// Inserts `insts` at the end of `block`, before its jump if it has one.
+fn append(block: &mut Block, insts: Vec<Inst>) {
+    let at = match block.insts.last() {
+        Some(last) if last.is_terminator() => block.insts.len() - 1,
+        _ => block.insts.len(),
+    };
+
+    block.insts.splice(at..at, insts);
+}
+
+fn defs(cfg: &Cfg) -> HashMap<Var, usize> {
+    let mut defs = HashMap::new();
+    for (id, block) in cfg.blocks.iter().enumerate() {
+        for inst in &block.insts {
+            if let Some(var) = inst.get_def().and_then(Operand::get_var) {
+                defs.insert(var, id);
+            }
+        }
+    }
+
+    defs
+}
+
+/// Moves the loop invariant computations of `found` into its preheader `pre`.
+fn hoist(cfg: &mut Cfg, found: &Loop, pre: usize) -> bool {
+    let mut defs = defs(cfg);
+    let stored: HashSet<Var> = found
+        .blocks
+        .iter()
+        .flat_map(|&id| &cfg.blocks[id].insts)
+        .filter_map(|inst| match inst {
+            Inst::Sto { array, .. } => array.get_var(),
+            _ => None,
+        })
+        .collect();
+
+    let order: Vec<usize> = cfg
+        .reverse_postorder()
+        .into_iter()
+        .filter(|&id| found.contains(id))
+        .collect();
+
+    let mut hoisted = Vec::new();
+    loop {
+        let mut moved = false;
+
+        for &id in &order {
+            let mut index = 0;
+            while index < cfg.blocks[id].insts.len() {
+                let inst = &cfg.blocks[id].insts[index];
+                let invariant = |operand: &Operand| match operand {
+                    Operand::Cons(_) => true,
+                    Operand::Ident(ident) => !stored.contains(&Var::Ident(ident.offset)),
+                    Operand::Temp(temp) => defs
+                        .get(&Var::Temp(temp.id))
+                        .is_some_and(|&block| !found.contains(block)),
+                };
+
+                // Loads may fail, so they only leave the header, which runs
+                // whenever the preheader does
+                let movable = match inst {
+                    Inst::Phi { .. } => false,
+                    Inst::Idx { .. } => id == found.header,
+                    inst => is_pure(inst),
+                };
+
+                match inst.get_def() {
+                    Some(Operand::Temp(temp))
+                        if movable && inst.get_uses().into_iter().all(invariant) =>
+                    {
+                        defs.insert(Var::Temp(temp.id), pre);
+                        hoisted.push(cfg.blocks[id].insts.remove(index));
+                        moved = true;
+                    }
+                    _ => index += 1,
+                }
+            }
+        }
+
+        if !moved {
+            break;
+        }
+    }
+
+    let changed = !hoisted.is_empty();
+    append(&mut cfg.blocks[pre], hoisted);
+    changed
+}
+
+/**
+ * Loop invariant code motion over a `Cfg` in SSA form: computations whose
+ * operands don't change inside a loop are moved into its preheader, inner
+ * loops first so that they can keep moving out. Returns whether the code
+ * changed.
+ */
+pub fn licm(cfg: &mut Cfg) -> bool {
+    let mut changed = insert_preheaders(cfg);
+    let dom = DomTree::new(cfg);
+
+    for found in find_loops(cfg, &dom) {
+        if let Some(pre) = found.preheader(cfg) {
+            changed |= hoist(cfg, &found, pre);
+        }
+    }
+
+    changed
+}
+
+/// Basic induction variable: a phi of the loop header that starts at `init`
+/// and is stepped by a constant on every iteration, `next = var op step`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Induction {
+    pub var: Operand,
+    pub init: Operand,
+    pub next: Operand,
+    pub op: Opcode,
+    pub step: Cons,
+}
+
+fn is_integer(tp: &Type) -> bool {
+    matches!(tp, Type::Int32 | Type::Int64)
+}
+
+/// Basic induction variables of `found`, which must have a preheader and a
+/// single latch.
+pub fn find_inductions(cfg: &Cfg, found: &Loop) -> Vec<Induction> {
+    let (pre, latch) = match (found.preheader(cfg), found.latches.as_slice()) {
+        (Some(pre), [latch]) => (pre, *latch),
+        _ => return Vec::new(),
+    };
+
+    let update = |next: &Operand| {
+        found
+            .blocks
+            .iter()
+            .flat_map(|&id| &cfg.blocks[id].insts)
+            .find(|inst| inst.get_def() == Some(next))
+    };
+
+    let mut inductions = Vec::new();
+    for inst in cfg.blocks[found.header].body() {
+        let (var, args) = match inst {
+            Inst::Phi { dst, args } if is_integer(&dst.get_tp()) => (dst, args),
+            _ => continue,
+        };
+
+        let arg = |from: usize| {
+            args.iter()
+                .find(|(pred, _)| *pred == from)
+                .map(|(_, arg)| arg)
+        };
+
+        let (init, next) = match (args.len(), arg(pre), arg(latch)) {
+            (2, Some(init), Some(next)) => (init, next),
+            _ => continue,
+        };
+
+        if next.get_tp() != var.get_tp() {
+            continue;
+        }
+
+        let step = match update(next) {
+            Some(Inst::Binary {
+                op: op @ Opcode::Add,
+                lhs,
+                rhs: Operand::Cons(step),
+                ..
+            })
+            | Some(Inst::Binary {
+                op: op @ Opcode::Add,
+                lhs: Operand::Cons(step),
+                rhs: lhs,
+                ..
+            })
+            | Some(Inst::Binary {
+                op: op @ Opcode::Sub,
+                lhs,
+                rhs: Operand::Cons(step),
+                ..
+            }) if lhs == var && is_integer(&step.tp) => (*op, step.clone()),
+            _ => continue,
+        };
+
+        inductions.push(Induction {
+            var: var.clone(),
+            init: init.clone(),
+            next: next.clone(),
+            op: step.0,
+            step: step.1,
+        });
+    }
+
+    inductions
+}
+
+/// A `mul dst var k` (or `mul dst k var`) of the loop that can be derived
+/// from `induction`, along with `k`. The product must stay in the type of the
+/// variable so that it wraps around in the same way.
+fn derived<'a>(inst: &'a Inst, induction: &Induction) -> Option<(&'a Operand, &'a Cons)> {
+    let (dst, factor) = match inst {
+        Inst::Binary {
+            op: Opcode::Mul,
+            dst,
+            lhs,
+            rhs: Operand::Cons(factor),
+        }
+        | Inst::Binary {
+            op: Opcode::Mul,
+            dst,
+            lhs: Operand::Cons(factor),
+            rhs: lhs,
+        } if *lhs == induction.var => (dst, factor),
+        _ => return None,
+    };
+
+    let tp = induction.var.get_tp();
+    match (dst, tp.upcast(&factor.tp)) {
+        (Operand::Temp(temp), Some(upcast)) if temp.tp == tp && upcast == tp => Some((dst, factor)),
+        _ => None,
+    }
+}
+
+/// Replaces `mul dst var k` with a new induction variable `j` that starts at
+/// `init * k` and moves by `step * k` along with `var`.
+fn reduce(cfg: &mut Cfg, found: &Loop, induction: &Induction, at: (usize, usize)) -> bool {
+    let (block, index) = at;
+    let (dst, factor) = match derived(&cfg.blocks[block].insts[index], induction) {
+        Some((dst, factor)) => (dst.clone(), factor.clone()),
+        None => return false,
+    };
+
+    let tp = dst.get_tp();
+    let stride = Value::arithm(
+        &Token::Asterisk,
+        &Value::from_cons(&induction.step),
+        &Value::from_cons(&factor),
+    )
+    .and_then(|stride| stride.cast(&tp))
+    .ok()
+    .and_then(|stride| stride.to_cons());
+
+    let stride = match stride {
+        Some(stride) => stride,
+        None => return false,
+    };
+
+    let (pre, latch) = (found.preheader(cfg).unwrap(), found.latches[0]);
+    let (init, var, next) = (new_temp(tp.clone()), new_temp(tp.clone()), new_temp(tp));
+
+    cfg.blocks[block].insts[index] = Inst::Mov {
+        dst,
+        src: var.clone(),
+    };
+
+    append(
+        &mut cfg.blocks[pre],
+        vec![Inst::Binary {
+            op: Opcode::Mul,
+            dst: init.clone(),
+            lhs: induction.init.clone(),
+            rhs: Operand::Cons(factor),
+        }],
+    );
+
+    let header = &mut cfg.blocks[found.header];
+    let labels = header.labels().count();
+    header.insts.insert(
+        labels,
+        Inst::Phi {
+            dst: var.clone(),
+            args: vec![(pre, init), (latch, next.clone())],
+        },
+    );
+
+    for &id in &found.blocks {
+        let insts = &mut cfg.blocks[id].insts;
+        if let Some(pos) = insts
+            .iter()
+            .position(|inst| inst.get_def() == Some(&induction.next))
+        {
+            let step = Inst::Binary {
+                op: induction.op,
+                dst: next,
+                lhs: var,
+                rhs: Operand::Cons(stride),
+            };
+
+            insts.insert(pos + 1, step);
+            break;
+        }
+    }
+
+    true
+}
+
+/**
+ * Strength reduction over a `Cfg` in SSA form: multiplications of a basic
+ * induction variable by a constant, such as the offset `i * w` of an element
+ * whose `Type::get_stride` is `w`, become a new induction variable stepped by
+ * additions alongside the original one. Returns whether the code changed.
+ */
+pub fn strength_reduce(cfg: &mut Cfg) -> bool {
+    let mut changed = insert_preheaders(cfg);
+    let dom = DomTree::new(cfg);
+
+    for found in find_loops(cfg, &dom) {
+        loop {
+            let inductions = find_inductions(cfg, &found);
+            let candidate = found.blocks.iter().find_map(|&id| {
+                cfg.blocks[id]
+                    .insts
+                    .iter()
+                    .enumerate()
+                    .find_map(|(index, inst)| {
+                        inductions
+                            .iter()
+                            .find(|induction| derived(inst, induction).is_some())
+                            .map(|induction| (induction.clone(), (id, index)))
+                    })
+            });
+
+            match candidate {
+                Some((induction, at)) if reduce(cfg, &found, &induction, at) => changed = true,
+                _ => break,
+            }
+        }
+    }
+
+    changed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interp::interpreter::tests::{fact_program, loops_program};
+    use crate::interp::{Interpreter, RunResult};
+    use crate::ir::cfg::tests::{function, LOOP};
+    use crate::ir::{from_ssa, to_ssa, Function, Module};
+    use crate::vm::machine::tests::compile;
+    use crate::vm::Machine;
+
+    fn optimize(code: &str, pass: fn(&mut Cfg) -> bool) -> (Cfg, Function) {
+        let mut func = function(code);
+        let mut cfg = Cfg::new(&func.code);
+        to_ssa(&mut cfg);
+        assert!(pass(&mut cfg));
+
+        let optimized = cfg.clone();
+        from_ssa(&mut cfg);
+        func.code = cfg.to_code();
+        (optimized, func)
+    }
+
+    fn in_loops(cfg: &Cfg, opcode: &str) -> usize {
+        let dom = DomTree::new(cfg);
+        let blocks: BTreeSet<usize> = find_loops(cfg, &dom)
+            .into_iter()
+            .flat_map(|found| found.blocks)
+            .collect();
+
+        blocks
+            .iter()
+            .flat_map(|&id| &cfg.blocks[id].insts)
+            .filter(|inst| inst.get_opcode() == opcode)
+            .count()
+    }
+
+    #[test]
+    fn natural_loops() {
+        let func = function(LOOP);
+        let mut cfg = Cfg::new(&func.code);
+        let loops = find_loops(&cfg, &DomTree::new(&cfg));
+
+        assert_eq!(loops.len(), 1);
+        assert_eq!(loops[0].header, 2);
+        assert_eq!(loops[0].latches, vec![6]);
+        assert_eq!(loops[0].blocks, (2..=6).collect());
+        assert_eq!(loops[0].preheader(&cfg), Some(1));
+        assert!(!insert_preheaders(&mut cfg));
+
+        // Nothing comes before the loop, so it needs a preheader
+        let func = function(
+            "fn f(n: i32) -> i32\n\
+             L1\tgt __t0 n 0\n\tjmpf L2 __t0\n\tsub n n 1\n\tjmp L1\n\
+             L2\tret n\n",
+        );
+
+        let mut cfg = Cfg::new(&func.code);
+        assert!(insert_preheaders(&mut cfg));
+        let loops = find_loops(&cfg, &DomTree::new(&cfg));
+        assert_eq!(loops[0].header, 2);
+        assert_eq!(loops[0].preheader(&cfg), Some(1));
+        assert_eq!(cfg.blocks[cfg.exit].preds, vec![4]);
+    }
+
+    #[test]
+    fn hoist_invariants() -> RunResult<()> {
+        let (cfg, func) = optimize(
+            "fn f(n: i32, a: i32, b: i32) -> i32\n\tvar i: i32\n\tvar s: i32\n\
+             L1\tlt __t0 i n\n\tjmpf L2 __t0\n\
+             \tmul __t1 a b\n\tadd __t2 __t1 1\n\tadd s s __t2\n\tadd i i 1\n\tjmp L1\n\
+             L2\tret s\n",
+            licm,
+        );
+
+        assert_eq!(in_loops(&cfg, "mul"), 0);
+        assert_eq!(in_loops(&cfg, "add"), 2);
+
+        let args = [Value::Int32(5), Value::Int32(2), Value::Int32(3)];
+        let module = Module { funcs: vec![func] };
+        assert_eq!(
+            Machine::new(&module).call("f", &args)?,
+            Some(Value::Int32(35))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn reduce_offsets() -> RunResult<()> {
+        // The byte offset of every i32 element of the array
+        let (cfg, func) = optimize(
+            "fn f(n: i32) -> i32\n\tvar i: i32\n\tvar s: i32\n\
+             L1\tlt __t0 i n\n\tjmpf L2 __t0\n\
+             \tmul __t1 i 4\n\tadd s s __t1\n\tadd i i 1\n\tjmp L1\n\
+             L2\tret s\n",
+            strength_reduce,
+        );
+
+        let dom = DomTree::new(&cfg);
+        let found = &find_loops(&cfg, &dom)[0];
+        let inductions = find_inductions(&cfg, found);
+        assert_eq!(inductions.len(), 2);
+        assert!(inductions
+            .iter()
+            .any(|induction| induction.step.tok == Token::Integer(4)));
+        assert_eq!(in_loops(&cfg, "mul"), 0);
+
+        let module = Module { funcs: vec![func] };
+        assert_eq!(
+            Machine::new(&module).call("f", &[Value::Int32(5)])?,
+            Some(Value::Int32(40))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn optimized_programs() -> RunResult<()> {
+        for program in &[fact_program(), loops_program()] {
+            let mut module = compile(program);
+            for func in &mut module.funcs {
+                let mut cfg = Cfg::new(&func.code);
+                to_ssa(&mut cfg);
+                licm(&mut cfg);
+                strength_reduce(&mut cfg);
+                from_ssa(&mut cfg);
+                func.code = cfg.to_code();
+            }
+
+            assert_eq!(
+                Machine::new(&module).run()?,
+                Interpreter::new(program).run()?
+            );
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,187 @@
+use std::collections::HashMap;
+
+use crate::analysis::Expression;
+use crate::ir::{Cfg, Inst, Operand, Var};
+
+/// Hash key of the expression computed by `inst`, given the value (number or
+/// name) of each of its operands. Operands of commutative operators are sorted.
+pub(crate) fn expr_key(inst: &Inst, mut args: Vec<String>) -> Option<String> {
+    let expr = Expression::new(inst)?;
+    if let Inst::Binary { op, .. } = inst {
+        if op.is_commutative() {
+            args.sort();
+        }
+    }
+
+    let tp = inst.get_def()?.get_tp();
+    Some(format!("{} {} : {}", expr.op, args.join(" "), tp))
+}
+
+#[derive(Debug, Default)]
+struct Numbering {
+    next: usize,
+    vars: HashMap<Var, usize>,
+    consts: HashMap<String, usize>,
+    /// Value number of each expression, where it's held and the array it loads from.
+    exprs: HashMap<String, (usize, Operand, Option<Var>)>,
+}
+
+impl Numbering {
+    fn fresh(&mut self) -> usize {
+        self.next += 1;
+        self.next
+    }
+
+    fn number(&mut self, operand: &Operand) -> usize {
+        let num = match operand {
+            Operand::Cons(cons) => self.consts.get(&format!("{}:{}", cons.tok, cons.tp)),
+            operand => self.vars.get(&operand.get_var().unwrap()),
+        };
+
+        if let Some(&num) = num {
+            return num;
+        }
+
+        let num = self.fresh();
+        match operand {
+            Operand::Cons(cons) => self.consts.insert(format!("{}:{}", cons.tok, cons.tp), num),
+            operand => self.vars.insert(operand.get_var().unwrap(), num),
+        };
+
+        num
+    }
+
+    fn holds(&self, operand: &Operand, num: usize) -> bool {
+        operand
+            .get_var()
+            .is_some_and(|var| self.vars.get(&var) == Some(&num))
+    }
+
+    fn assign(&mut self, dst: &Operand, num: usize) {
+        if let Some(var) = dst.get_var() {
+            self.vars.insert(var, num);
+        }
+    }
+
+    /// Numbers `inst`, returning the `mov` that replaces it when its value is
+    /// already held somewhere.
+    fn visit(&mut self, inst: &Inst) -> Option<Inst> {
+        let args = inst
+            .get_uses()
+            .into_iter()
+            .map(|arg| self.number(arg).to_string())
+            .collect();
+
+        let key = expr_key(inst, args);
+        let dst = inst.get_def();
+
+        if let (Some(key), Some(dst)) = (&key, dst) {
+            if let Some((num, holder, _)) = self.exprs.get(key).cloned() {
+                if self.holds(&holder, num) {
+                    self.assign(dst, num);
+                    return Some(Inst::Mov {
+                        dst: dst.clone(),
+                        src: holder,
+                    });
+                }
+            }
+        }
+
+        match inst {
+            Inst::Mov { dst, src } if dst.get_tp() == src.get_tp() => {
+                let num = self.number(src);
+                self.assign(dst, num);
+            }
+
+            Inst::Sto { array, .. } => {
+                let var = array.get_var();
+                self.exprs.retain(|_, (_, _, array)| *array != var);
+            }
+
+            inst => {
+                if let Some(dst) = inst.get_def() {
+                    let num = self.fresh();
+                    self.assign(dst, num);
+
+                    if let Some(key) = key {
+                        let array = match inst {
+                            Inst::Idx { array, .. } => array.get_var(),
+                            _ => None,
+                        };
+
+                        self.exprs.insert(key, (num, dst.clone(), array));
+                    }
+                }
+            }
+        }
+
+        None
+    }
+}
+
+/**
+ * Local value numbering: inside each basic block, an expression whose value
+ * is still held by an earlier destination is replaced by a `mov` from it.
+ * Loads with `idx` are forgotten as soon as their array is stored to.
+ * Returns whether the code changed.
+ */
+pub fn lvn(cfg: &mut Cfg) -> bool {
+    let mut changed = false;
+
+    for block in &mut cfg.blocks {
+        let mut numbering = Numbering::default();
+        for inst in &mut block.insts {
+            if let Some(mov) = numbering.visit(inst) {
+                *inst = mov;
+                changed = true;
+            }
+        }
+    }
+
+    changed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interp::{RunResult, Value};
+    use crate::ir::cfg::tests::function;
+    use crate::ir::Module;
+    use crate::vm::Machine;
+
+    #[test]
+    fn local_numbering() -> RunResult<()> {
+        // a[i] + a[i], then a store to the array and a commuted product
+        let code = "fn f(i: i32, v: [4]i32) -> i32\n\
+            \tidx __t0 i v\n\tidx __t1 i v\n\tadd __t2 __t0 __t1\n\
+            \tsto v i 7\n\tidx __t3 i v\n\
+            \tmul __t4 __t2 __t3\n\tmul __t5 __t3 __t2\n\
+            \tmov i 0\n\tidx __t6 i v\n\
+            \tadd __t7 __t4 __t5\n\tadd __t8 __t7 __t6\n\tret __t8\n";
+
+        let mut func = function(code);
+        let mut cfg = Cfg::new(&func.code);
+        assert!(lvn(&mut cfg));
+        func.code = cfg.to_code();
+
+        let count = |opcode: &str| {
+            func.code
+                .iter()
+                .filter(|inst| inst.get_opcode() == opcode)
+                .count()
+        };
+
+        assert_eq!(count("idx"), 3);
+        assert_eq!(count("mul"), 1);
+        assert_eq!(count("mov"), 3);
+
+        let module = Module { funcs: vec![func] };
+        let v = Value::Array((1..=4).map(Value::Int32).collect());
+        assert_eq!(
+            Machine::new(&module).call("f", &[Value::Int32(1), v])?,
+            Some(Value::Int32(57))
+        );
+
+        Ok(())
+    }
+}
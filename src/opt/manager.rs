@@ -0,0 +1,356 @@
+use std::fs::{self, File};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use crate::ast::Visitor;
+use crate::error::PassError;
+use crate::ir::{from_ssa, to_ssa, verify, Cfg, Inst, Module};
+use crate::opt::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum OptLevel {
+    O0,
+    O1,
+    O2,
+}
+
+impl FromStr for OptLevel {
+    type Err = ();
+
+    /// Accepts `-O2`, `O2` and `2`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim_start_matches('-').trim_start_matches('O') {
+            "0" => Ok(Self::O0),
+            "1" => Ok(Self::O1),
+            "2" => Ok(Self::O2),
+            _ => Err(()),
+        }
+    }
+}
+
+/// A step of the pipeline, along with the form of the IR it works on.
+#[derive(Debug, Clone, Copy)]
+pub enum Pass {
+    /// The whole module, as linear code.
+    Module(fn(&mut Module) -> bool),
+    /// The linear code of every function.
+    Code(fn(&mut Vec<Inst>) -> bool),
+    /// The `Cfg` of every function, in or out of SSA form.
+    Cfg(fn(&mut Cfg) -> bool),
+    /// The `Cfg` of every function, only in SSA form.
+    Ssa(fn(&mut Cfg) -> bool),
+    ToSsa,
+    FromSsa,
+}
+
+/// IR of the module while the pipeline runs: functions are only split into
+/// blocks while `Cfg` passes need them.
+struct State<'a> {
+    module: &'a mut Module,
+    cfgs: Option<Vec<Cfg>>,
+    ssa: bool,
+}
+
+impl<'a> State<'a> {
+    fn cfgs(&mut self) -> &mut Vec<Cfg> {
+        let funcs = &self.module.funcs;
+        self.cfgs
+            .get_or_insert_with(|| funcs.iter().map(|func| Cfg::new(&func.code)).collect())
+    }
+
+    fn flush(&mut self) {
+        if let Some(cfgs) = self.cfgs.take() {
+            for (func, cfg) in self.module.funcs.iter_mut().zip(cfgs) {
+                func.code = cfg.to_code();
+            }
+        }
+    }
+
+    fn verify(&self, pass: &str) -> Result<(), PassError> {
+        for (id, func) in self.module.funcs.iter().enumerate() {
+            let result = match &self.cfgs {
+                Some(cfgs) => verify(&cfgs[id], self.ssa),
+                None => verify(&Cfg::new(&func.code), false),
+            };
+
+            result.map_err(|error| PassError::Invalid {
+                pass: pass.to_owned(),
+                func: func.name.clone(),
+                error,
+            })?;
+        }
+
+        Ok(())
+    }
+
+    fn dump(&self, path: PathBuf) -> Result<(), PassError> {
+        let mut module = self.module.clone();
+        if let Some(cfgs) = &self.cfgs {
+            for (func, cfg) in module.funcs.iter_mut().zip(cfgs) {
+                func.code = cfg.to_code();
+            }
+        }
+
+        let file = File::create(&path).map_err(|error| PassError::Dump(error.to_string()))?;
+        module.emit(&mut Visitor::new(Box::new(file)));
+        Ok(())
+    }
+}
+
+/**
+ * Runs passes over a `Module` in the order they were added. After every pass
+ * the IR can be checked with `verify`, and written to a directory as
+ * `NN-name.ir` (the input goes in `00-input.ir`), which makes it easy to find
+ * the pass that broke a program.
+ */
+#[derive(Debug, Clone, Default)]
+pub struct PassManager {
+    passes: Vec<(&'static str, Pass)>,
+    verify: bool,
+    dump: Option<PathBuf>,
+}
+
+impl PassManager {
+    /// Pipeline for `level`, with verification on.
+    pub fn new(level: OptLevel) -> Self {
+        let passes = match level {
+            OptLevel::O0 => vec![],
+
+            OptLevel::O1 => vec![
+                ("lvn", Pass::Cfg(lvn)),
+                ("dce", Pass::Cfg(dce)),
+                ("remove-unreachable", Pass::Cfg(remove_unreachable)),
+                ("peephole", Pass::Code(peephole)),
+            ],
+
+            OptLevel::O2 => vec![
+                (
+                    "inline",
+                    Pass::Module(|module| inline(module, INLINE_THRESHOLD)),
+                ),
+                ("ssa", Pass::ToSsa),
+                ("sccp", Pass::Ssa(sccp)),
+                ("remove-unreachable", Pass::Cfg(remove_unreachable)),
+                ("gvn", Pass::Ssa(gvn)),
+                ("licm", Pass::Ssa(licm)),
+                ("strength-reduce", Pass::Ssa(strength_reduce)),
+                ("dce", Pass::Cfg(dce)),
+                ("from-ssa", Pass::FromSsa),
+                ("peephole", Pass::Code(peephole)),
+                ("coalesce", Pass::Cfg(coalesce)),
+                ("lvn", Pass::Cfg(lvn)),
+                ("dse", Pass::Cfg(dse)),
+                ("dce", Pass::Cfg(dce)),
+                ("peephole", Pass::Code(peephole)),
+            ],
+        };
+
+        Self {
+            passes,
+            verify: true,
+            dump: None,
+        }
+    }
+
+    pub fn add(mut self, name: &'static str, pass: Pass) -> Self {
+        self.passes.push((name, pass));
+        self
+    }
+
+    pub fn verify(mut self, verify: bool) -> Self {
+        self.verify = verify;
+        self
+    }
+
+    /// Writes the IR to `dir` before the first pass and after every other.
+    pub fn dump_to(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.dump = Some(dir.into());
+        self
+    }
+
+    pub fn passes(&self) -> impl Iterator<Item = &str> + '_ {
+        self.passes.iter().map(|(name, _)| *name)
+    }
+
+    /// Runs the pipeline over `module`, returns whether any pass changed it.
+    pub fn run(&self, module: &mut Module) -> Result<bool, PassError> {
+        if let Some(dir) = &self.dump {
+            fs::create_dir_all(dir).map_err(|error| PassError::Dump(error.to_string()))?;
+        }
+
+        let mut state = State {
+            module,
+            cfgs: None,
+            ssa: false,
+        };
+
+        self.after(&state, 0, "input")?;
+
+        let mut changed = false;
+        for (index, &(name, pass)) in self.passes.iter().enumerate() {
+            let form = |msg: &str| PassError::Form {
+                pass: name.to_owned(),
+                msg: msg.to_owned(),
+            };
+
+            match pass {
+                Pass::Module(_) | Pass::Code(_) if state.ssa => {
+                    return Err(form("needs the code out of SSA form"))
+                }
+                Pass::Ssa(_) | Pass::FromSsa if !state.ssa => {
+                    return Err(form("needs the code in SSA form"))
+                }
+                Pass::ToSsa if state.ssa => return Err(form("found the code in SSA form")),
+
+                Pass::Module(run) => {
+                    state.flush();
+                    changed |= run(state.module);
+                }
+
+                Pass::Code(run) => {
+                    state.flush();
+                    for func in &mut state.module.funcs {
+                        changed |= run(&mut func.code);
+                    }
+                }
+
+                Pass::Cfg(run) | Pass::Ssa(run) => {
+                    for cfg in state.cfgs() {
+                        changed |= run(cfg);
+                    }
+                }
+
+                Pass::ToSsa => {
+                    state.cfgs().iter_mut().for_each(to_ssa);
+                    state.ssa = true;
+                }
+
+                Pass::FromSsa => {
+                    state.cfgs().iter_mut().for_each(from_ssa);
+                    state.ssa = false;
+                }
+            }
+
+            self.after(&state, index + 1, name)?;
+        }
+
+        if state.ssa {
+            return Err(PassError::Form {
+                pass: "from-ssa".to_owned(),
+                msg: "is missing at the end of the pipeline".to_owned(),
+            });
+        }
+
+        state.flush();
+        Ok(changed)
+    }
+
+    fn after(&self, state: &State, index: usize, name: &str) -> Result<(), PassError> {
+        if self.verify {
+            state.verify(name)?;
+        }
+
+        match &self.dump {
+            Some(dir) => state.dump(dir.join(format!("{:02}-{}.ir", index, name))),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interp::interpreter::tests::{fact_program, loops_program, matrix_program};
+    use crate::interp::{Interpreter, RunResult};
+    use crate::ir::cfg::tests::function;
+    use crate::vm::machine::tests::compile;
+    use crate::vm::Machine;
+
+    #[test]
+    fn parse_levels() {
+        assert_eq!("-O0".parse(), Ok(OptLevel::O0));
+        assert_eq!("O1".parse(), Ok(OptLevel::O1));
+        assert_eq!("2".parse(), Ok(OptLevel::O2));
+        assert_eq!("-O3".parse::<OptLevel>(), Err(()));
+    }
+
+    #[test]
+    fn optimization_levels() -> RunResult<()> {
+        for program in &[fact_program(), loops_program(), matrix_program()] {
+            let expected = Interpreter::new(program).run()?;
+            let mut steps = Vec::new();
+
+            for level in &[OptLevel::O0, OptLevel::O1, OptLevel::O2] {
+                let mut module = compile(program);
+                PassManager::new(*level).run(&mut module).unwrap();
+
+                let mut machine = Machine::new(&module);
+                assert_eq!(machine.run()?, expected);
+                steps.push(machine.stats().steps);
+            }
+
+            assert!(steps[1] <= steps[0]);
+        }
+
+        assert_eq!(PassManager::new(OptLevel::O0).passes().count(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn invariant_checks() {
+        fn drop_labels(cfg: &mut Cfg) -> bool {
+            for block in &mut cfg.blocks {
+                block.insts.retain(|inst| !matches!(inst, Inst::Label(_)));
+            }
+
+            true
+        }
+
+        let mut module = Module {
+            funcs: vec![function(crate::ir::cfg::tests::LOOP)],
+        };
+
+        let error = PassManager::new(OptLevel::O1)
+            .add("drop-labels", Pass::Cfg(drop_labels))
+            .run(&mut module.clone())
+            .unwrap_err();
+
+        match error {
+            PassError::Invalid { pass, func, .. } => {
+                assert_eq!((pass.as_str(), func.as_str()), ("drop-labels", "f"))
+            }
+            error => panic!("Unexpected error: {}", error),
+        }
+
+        let error = PassManager::new(OptLevel::O0)
+            .add("ssa", Pass::ToSsa)
+            .add("peephole", Pass::Code(peephole))
+            .run(&mut module)
+            .unwrap_err();
+        assert!(matches!(error, PassError::Form { .. }));
+    }
+
+    #[test]
+    fn dump_passes() {
+        let dir = std::env::temp_dir().join(format!("ez-lang-dump-{}", std::process::id()));
+        let manager = PassManager::new(OptLevel::O2).dump_to(&dir);
+
+        let mut module = compile(&loops_program());
+        manager.run(&mut module).unwrap();
+
+        let mut files: Vec<String> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        files.sort();
+
+        assert_eq!(files.len(), manager.passes().count() + 1);
+        assert_eq!(files[0], "00-input.ir");
+        assert_eq!(files[2], "02-ssa.ir");
+        assert!(fs::read_to_string(dir.join(&files[2]))
+            .unwrap()
+            .contains("phi"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
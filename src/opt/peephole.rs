@@ -0,0 +1,316 @@
+use std::collections::HashSet;
+
+use crate::ir::Inst;
+
+fn target(inst: &Inst) -> Option<usize> {
+    match inst {
+        Inst::Jmp { label } | Inst::JmpT { label, .. } | Inst::JmpF { label, .. } => Some(*label),
+        _ => None,
+    }
+}
+
+fn target_mut(inst: &mut Inst) -> Option<&mut usize> {
+    match inst {
+        Inst::Jmp { label } | Inst::JmpT { label, .. } | Inst::JmpF { label, .. } => Some(label),
+        _ => None,
+    }
+}
+
+fn is_unconditional(inst: &Inst) -> bool {
+    matches!(inst, Inst::Jmp { .. } | Inst::Ret { .. })
+}
+
+fn position(code: &[Inst], label: usize) -> Option<usize> {
+    code.iter().position(|inst| *inst == Inst::Label(label))
+}
+
+/// First instruction at or after `pos` that isn't a label.
+fn skip_labels(code: &[Inst], pos: usize) -> Option<(usize, &Inst)> {
+    code.iter()
+        .enumerate()
+        .skip(pos)
+        .find(|(_, inst)| !matches!(inst, Inst::Label(_)))
+}
+
+/// Whether `label` is among the labels right after `pos`.
+fn falls_into(code: &[Inst], pos: usize, label: usize) -> bool {
+    code[pos + 1..]
+        .iter()
+        .take_while(|inst| matches!(inst, Inst::Label(_)))
+        .any(|inst| *inst == Inst::Label(label))
+}
+
+/// Retargets jumps whose destination is just another `jmp`.
+fn thread_jumps(code: &mut [Inst]) -> bool {
+    let mut changed = false;
+
+    for pos in 0..code.len() {
+        let mut label = match target(&code[pos]) {
+            Some(label) => label,
+            None => continue,
+        };
+
+        let mut seen = HashSet::new();
+        seen.insert(label);
+        while let Some(Inst::Jmp { label: next }) = position(code, label)
+            .and_then(|at| skip_labels(code, at))
+            .map(|(_, inst)| inst)
+        {
+            if !seen.insert(*next) {
+                break;
+            }
+            label = *next;
+        }
+
+        let old = target_mut(&mut code[pos]).unwrap();
+        if *old != label {
+            *old = label;
+            changed = true;
+        }
+    }
+
+    changed
+}
+
+/// Turns `jmpt L1 x; jmp L2; L1` into `jmpf L2 x; L1`, and the other way around.
+fn invert_jumps(code: &mut Vec<Inst>) -> bool {
+    let mut changed = false;
+    let mut pos = 0;
+
+    while pos + 1 < code.len() {
+        let inverted = match (&code[pos], &code[pos + 1]) {
+            (Inst::JmpT { label, test }, Inst::Jmp { label: other })
+                if falls_into(code, pos + 1, *label) =>
+            {
+                Some(Inst::JmpF {
+                    label: *other,
+                    test: test.clone(),
+                })
+            }
+
+            (Inst::JmpF { label, test }, Inst::Jmp { label: other })
+                if falls_into(code, pos + 1, *label) =>
+            {
+                Some(Inst::JmpT {
+                    label: *other,
+                    test: test.clone(),
+                })
+            }
+
+            _ => None,
+        };
+
+        if let Some(inst) = inverted {
+            code[pos] = inst;
+            code.remove(pos + 1);
+            changed = true;
+        }
+
+        pos += 1;
+    }
+
+    changed
+}
+
+/// Removes jumps to the labels that follow them, and the code after an
+/// unconditional jump (or `ret`) that no label leads to.
+fn remove_jumps(code: &mut Vec<Inst>) -> bool {
+    let len = code.len();
+    let mut insts = Vec::with_capacity(len);
+    let mut dead = false;
+
+    for inst in code.drain(..) {
+        if let Inst::Label(_) = inst {
+            dead = false;
+        }
+
+        if !dead {
+            insts.push(inst);
+        }
+
+        dead |= insts.last().is_some_and(is_unconditional);
+    }
+
+    let mut pos = 0;
+    while pos < insts.len() {
+        match target(&insts[pos]) {
+            Some(label) if falls_into(&insts, pos, label) => {
+                insts.remove(pos);
+            }
+            _ => pos += 1,
+        }
+    }
+
+    *code = insts;
+    code.len() != len
+}
+
+fn remove_labels(code: &mut Vec<Inst>) -> bool {
+    let used: HashSet<usize> = code.iter().filter_map(target).collect();
+    let len = code.len();
+    code.retain(|inst| match inst {
+        Inst::Label(label) => used.contains(label),
+        _ => true,
+    });
+
+    code.len() != len
+}
+
+/**
+ * Merges straight-line blocks: a `jmp` to a block that nothing falls into,
+ * and that ends in `jmp` or `ret` itself, is replaced by the block.
+ */
+fn merge_blocks(code: &mut Vec<Inst>) -> bool {
+    for pos in 0..code.len() {
+        let label = match &code[pos] {
+            Inst::Jmp { label } => *label,
+            _ => continue,
+        };
+
+        let mut start = match position(code, label) {
+            Some(start) => start,
+            None => continue,
+        };
+
+        while start > 0 && matches!(code[start - 1], Inst::Label(_)) {
+            start -= 1;
+        }
+
+        if start == 0 || !is_unconditional(&code[start - 1]) {
+            continue;
+        }
+
+        let end = match code[start..].iter().position(is_unconditional) {
+            Some(len) => start + len,
+            None => continue,
+        };
+
+        if (start..=end).contains(&pos) {
+            continue;
+        }
+
+        let block: Vec<Inst> = code.drain(start..=end).collect();
+        let pos = if pos > end { pos - block.len() } else { pos };
+        code.splice(pos..=pos, block);
+        return true;
+    }
+
+    false
+}
+
+/**
+ * Jump optimizations over the linear code of a function: threads chains of
+ * jumps, inverts conditional jumps over unconditional ones, drops jumps to the
+ * next instruction, unreachable code and unused labels, and merges
+ * straight-line blocks. Runs until nothing changes, returns whether the code
+ * changed.
+ */
+pub fn peephole(code: &mut Vec<Inst>) -> bool {
+    let mut changed = false;
+
+    loop {
+        let mut step = thread_jumps(code);
+        step |= invert_jumps(code);
+        step |= remove_jumps(code);
+        step |= remove_labels(code);
+
+        // Blocks are only moved around once everything else is settled
+        if !step && !merge_blocks(code) {
+            return changed;
+        }
+
+        changed = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interp::interpreter::tests::{fact_program, loops_program};
+    use crate::interp::{Interpreter, RunResult, Value};
+    use crate::ir::cfg::tests::function;
+    use crate::ir::Module;
+    use crate::vm::machine::tests::compile;
+    use crate::vm::Machine;
+
+    fn optimize(code: &str) -> (Vec<String>, Module) {
+        let mut func = function(code);
+        assert!(peephole(&mut func.code));
+
+        let lines = func.code.iter().map(Inst::to_string).collect();
+        (lines, Module { funcs: vec![func] })
+    }
+
+    #[test]
+    fn jumps() -> RunResult<()> {
+        let (code, module) = optimize(
+            "fn f(x: i32, y: i32) -> i32\n\tvar a: i32\n\
+             L1\tlt __t0 x y\n\tjmpt L2 __t0\n\tjmp L3\n\
+             L2\tmov a 1\n\tjmp L4\n\tmov a 3\n\
+             L3\tmov a 2\n\tjmp L4\n\
+             L4\tjmp L5\n\
+             L5\tret a\n",
+        );
+
+        assert_eq!(
+            code,
+            vec![
+                "lt __t0 x y",
+                "jmpf L3 __t0",
+                "mov a 1",
+                "jmp L5",
+                "L3",
+                "mov a 2",
+                "L5",
+                "ret a"
+            ]
+        );
+
+        let mut machine = Machine::new(&module);
+        assert_eq!(
+            machine.call("f", &[Value::Int32(1), Value::Int32(2)])?,
+            Some(Value::Int32(1))
+        );
+        assert_eq!(
+            machine.call("f", &[Value::Int32(2), Value::Int32(1)])?,
+            Some(Value::Int32(2))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn straight_line_blocks() -> RunResult<()> {
+        let (code, module) = optimize(
+            "fn f() -> i32\n\tvar a: i32\n\
+             \tmov a 1\n\tjmp L2\n\
+             L1\tadd a a 1\n\tret a\n\
+             L2\tmul a a 3\n\tjmp L1\n",
+        );
+
+        assert_eq!(code, vec!["mov a 1", "mul a a 3", "add a a 1", "ret a"]);
+        assert_eq!(Machine::new(&module).call("f", &[])?, Some(Value::Int32(4)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn generated_programs() -> RunResult<()> {
+        for program in &[fact_program(), loops_program()] {
+            let mut module = compile(program);
+            let before: usize = module.funcs.iter().map(|func| func.code.len()).sum();
+            for func in &mut module.funcs {
+                peephole(&mut func.code);
+            }
+
+            let after: usize = module.funcs.iter().map(|func| func.code.len()).sum();
+            assert!(after < before);
+            assert_eq!(
+                Machine::new(&module).run()?,
+                Interpreter::new(program).run()?
+            );
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,353 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::analysis::Point;
+use crate::interp::{RunResult, Value};
+use crate::ir::{Cfg, Inst, Operand, Var};
+
+#[derive(Debug, Clone, PartialEq)]
+enum Fact {
+    Undefined,
+    Constant(Value),
+    Overdefined,
+}
+
+impl Fact {
+    fn meet(&self, other: &Self) -> Self {
+        match (self, other) {
+            (Self::Undefined, fact) | (fact, Self::Undefined) => fact.clone(),
+            (Self::Constant(lhs), Self::Constant(rhs)) if lhs == rhs => self.clone(),
+            _ => Self::Overdefined,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Sccp<'a> {
+    cfg: &'a Cfg,
+    labels: HashMap<usize, usize>,
+    uses: HashMap<Var, Vec<Point>>,
+    facts: HashMap<Var, Fact>,
+    edges: HashSet<(usize, usize)>,
+    visited: Vec<bool>,
+    flow: Vec<(usize, usize)>,
+    ssa: Vec<Point>,
+}
+
+impl<'a> Sccp<'a> {
+    fn new(cfg: &'a Cfg) -> Self {
+        let mut labels = HashMap::new();
+        let mut uses: HashMap<Var, Vec<Point>> = HashMap::new();
+
+        for (block, insts) in cfg.blocks.iter().map(|block| &block.insts).enumerate() {
+            for (index, inst) in insts.iter().enumerate() {
+                if let Inst::Label(label) = inst {
+                    labels.insert(*label, block);
+                }
+
+                for var in inst.get_uses().into_iter().filter_map(Operand::get_var) {
+                    uses.entry(var).or_default().push(Point::new(block, index));
+                }
+            }
+        }
+
+        Self {
+            cfg,
+            labels,
+            uses,
+            facts: HashMap::new(),
+            edges: HashSet::new(),
+            visited: vec![false; cfg.blocks.len()],
+            flow: Vec::new(),
+            ssa: Vec::new(),
+        }
+    }
+
+    fn run(&mut self) {
+        self.visited[self.cfg.entry] = true;
+        self.visit_block(self.cfg.entry);
+
+        loop {
+            if let Some((from, to)) = self.flow.pop() {
+                if !self.edges.insert((from, to)) {
+                    continue;
+                }
+
+                if self.visited[to] {
+                    let phis = self.cfg.blocks[to]
+                        .insts
+                        .iter()
+                        .enumerate()
+                        .filter(|(_, inst)| matches!(inst, Inst::Phi { .. }));
+
+                    for (index, _) in phis {
+                        self.visit(Point::new(to, index));
+                    }
+                } else {
+                    self.visited[to] = true;
+                    self.visit_block(to);
+                }
+            } else if let Some(at) = self.ssa.pop() {
+                if self.visited[at.block] {
+                    self.visit(at);
+                }
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn visit_block(&mut self, block: usize) {
+        let insts = &self.cfg.blocks[block].insts;
+        for index in 0..insts.len() {
+            self.visit(Point::new(block, index));
+        }
+
+        if block != self.cfg.exit && !insts.last().is_some_and(Inst::is_terminator) {
+            self.flow.push((block, block + 1));
+        }
+    }
+
+    fn fact(&self, operand: &Operand) -> Fact {
+        match operand {
+            Operand::Cons(cons) => Fact::Constant(Value::from_cons(cons)),
+            Operand::Temp(temp) => self
+                .facts
+                .get(&Var::Temp(temp.id))
+                .cloned()
+                .unwrap_or(Fact::Undefined),
+            Operand::Ident(_) => Fact::Overdefined,
+        }
+    }
+
+    fn update(&mut self, dst: &Operand, fact: Fact) {
+        let var = match dst {
+            Operand::Temp(temp) => Var::Temp(temp.id),
+            _ => return,
+        };
+
+        if self.facts.get(&var) != Some(&fact) {
+            self.facts.insert(var, fact);
+            self.ssa
+                .extend(self.uses.get(&var).into_iter().flatten().cloned());
+        }
+    }
+
+    fn eval(&self, inst: &Inst) -> Fact {
+        let mut values = Vec::new();
+        for operand in inst.get_uses() {
+            match self.fact(operand) {
+                Fact::Constant(value) => values.push(value),
+                fact => return fact,
+            }
+        }
+
+        let value: RunResult<Value> = match (inst, values.as_slice()) {
+            (Inst::Binary { op, .. }, [lhs, rhs]) if op.is_rel() => {
+                Value::compare(&op.get_token(), lhs, rhs).map(Value::Bool)
+            }
+            (Inst::Binary { op, .. }, [lhs, rhs]) => Value::arithm(&op.get_token(), lhs, rhs),
+            (Inst::Inv { .. }, [src]) => src.negate(),
+            (Inst::Not { .. }, [src]) => src.as_bool().map(|value| Value::Bool(!value)),
+            (Inst::Mov { .. }, [src]) => Ok(src.clone()),
+            _ => return Fact::Overdefined,
+        };
+
+        let dst = inst.get_def().unwrap();
+        match value.and_then(|value| value.cast(&dst.get_tp())) {
+            Ok(value) if value.to_cons().is_some() => Fact::Constant(value),
+            _ => Fact::Overdefined,
+        }
+    }
+
+    fn visit(&mut self, at: Point) {
+        let inst = &self.cfg.blocks[at.block].insts[at.index];
+        match inst {
+            Inst::Phi { dst, args } => {
+                let fact = args
+                    .iter()
+                    .filter(|(pred, _)| self.edges.contains(&(*pred, at.block)))
+                    .fold(Fact::Undefined, |fact, (_, arg)| fact.meet(&self.fact(arg)));
+
+                self.update(dst, fact);
+            }
+
+            Inst::Jmp { label } => self.flow.push((at.block, self.labels[label])),
+
+            Inst::JmpT { label, test } | Inst::JmpF { label, test } => {
+                let (target, next) = (self.labels[label], at.block + 1);
+                let on = matches!(inst, Inst::JmpT { .. });
+
+                match self.fact(test) {
+                    Fact::Undefined => {}
+                    Fact::Constant(Value::Bool(value)) if value == on => {
+                        self.flow.push((at.block, target))
+                    }
+                    Fact::Constant(Value::Bool(_)) => self.flow.push((at.block, next)),
+                    _ => self.flow.extend(vec![(at.block, target), (at.block, next)]),
+                }
+            }
+
+            Inst::Ret { .. } => self.flow.push((at.block, self.cfg.exit)),
+
+            inst => {
+                if let Some(dst) = inst.get_def() {
+                    let fact = self.eval(inst);
+                    self.update(dst, fact);
+                }
+            }
+        }
+    }
+
+    fn constant(&self, operand: &Operand) -> Option<Operand> {
+        match (operand, self.fact(operand)) {
+            (Operand::Temp(_), Fact::Constant(value)) => value.to_cons().map(Operand::Cons),
+            _ => None,
+        }
+    }
+
+    fn rewrite(&self, block: usize, insts: &[Inst]) -> Vec<Inst> {
+        let mut rewritten = Vec::new();
+
+        for inst in insts {
+            if inst.get_def().and_then(|dst| self.constant(dst)).is_some() {
+                continue;
+            }
+
+            let mut inst = inst.clone();
+            for operand in inst.get_uses_mut() {
+                if let Some(cons) = self.constant(operand) {
+                    *operand = cons;
+                }
+            }
+
+            let on = matches!(inst, Inst::JmpT { .. });
+            match &mut inst {
+                Inst::Phi { args, .. } => {
+                    args.retain(|(pred, _)| self.edges.contains(&(*pred, block)))
+                }
+
+                Inst::JmpT {
+                    label,
+                    test: Operand::Cons(cons),
+                }
+                | Inst::JmpF {
+                    label,
+                    test: Operand::Cons(cons),
+                } => {
+                    if Value::from_cons(cons) != Value::Bool(on) {
+                        continue;
+                    }
+
+                    inst = Inst::Jmp { label: *label };
+                }
+
+                _ => {}
+            }
+
+            rewritten.push(inst);
+        }
+
+        rewritten
+    }
+}
+
+/**
+ * Sparse conditional constant propagation (Wegman and Zadeck) over a `Cfg` in
+ * SSA form. Temporaries found to be constant are replaced by their value and
+ * their definitions dropped, branches on constant conditions become plain
+ * jumps, and blocks that can't be reached are emptied. Returns whether the
+ * code changed.
+ */
+pub fn sccp(cfg: &mut Cfg) -> bool {
+    let mut sccp = Sccp::new(cfg);
+    sccp.run();
+
+    let blocks: Vec<Vec<Inst>> = (0..cfg.blocks.len())
+        .map(|block| match sccp.visited[block] {
+            true => sccp.rewrite(block, &cfg.blocks[block].insts),
+            false => Vec::new(),
+        })
+        .collect();
+
+    let mut changed = false;
+    for (block, insts) in cfg.blocks.iter_mut().zip(blocks) {
+        changed |= block.insts != insts;
+        block.insts = insts;
+    }
+
+    cfg.link();
+    changed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interp::Value;
+    use crate::ir::cfg::tests::function;
+    use crate::ir::{from_ssa, to_ssa, Module};
+    use crate::vm::Machine;
+
+    fn optimize(code: &str) -> Module {
+        let mut func = function(code);
+        let mut cfg = Cfg::new(&func.code);
+        to_ssa(&mut cfg);
+        assert!(sccp(&mut cfg));
+        from_ssa(&mut cfg);
+        func.code = cfg.to_code();
+
+        Module { funcs: vec![func] }
+    }
+
+    fn count(module: &Module, opcode: &str) -> usize {
+        module.funcs[0]
+            .code
+            .iter()
+            .filter(|inst| inst.get_opcode() == opcode)
+            .count()
+    }
+
+    #[test]
+    fn propagate_through_branches() -> RunResult<()> {
+        // x is 3 on both sides of the branch, so y = x * 2 is known, and the
+        // test on it decides the second branch
+        let code = "fn f(c: bool) -> i32\n\tvar x: i32\n\tvar y: i32\n\
+            \tjmpf L1 c\n\tadd x 1 2\n\tjmp L2\n\
+            L1\tmov x 3\n\
+            L2\tmul y x 2\n\tgt __t0 y 5\n\tjmpt L3 __t0\n\tret 0\n\
+            L3\tdiv __t1 y 0\n\tret y\n";
+
+        let module = optimize(code);
+        assert_eq!(count(&module, "jmpt"), 0);
+        assert_eq!(count(&module, "mul"), 0);
+        assert_eq!(count(&module, "div"), 1);
+        assert_eq!(count(&module, "ret"), 1);
+        assert_eq!(
+            Machine::new(&module).call("f", &[Value::Bool(true)]),
+            Err(crate::error::RuntimeError::DivisionByZero)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn loops_stay_variable() -> RunResult<()> {
+        // k stays 1 across the loop, while i changes on every iteration
+        let code = "fn f(n: i32) -> i32\n\tvar i: i32\n\tvar k: i32\n\
+            \tmov k 1\n\
+            L1\tlt __t0 i n\n\tjmpf L2 __t0\n\tadd i i k\n\tjmp L1\n\
+            L2\tmul __t1 i k\n\tret __t1\n";
+
+        let module = optimize(code);
+        assert_eq!(count(&module, "mul"), 1);
+        assert!(module.funcs[0]
+            .code
+            .iter()
+            .any(|inst| inst.to_string().ends_with(" 1") && inst.get_opcode() == "add"));
+        assert_eq!(
+            Machine::new(&module).call("f", &[Value::Int32(7)])?,
+            Some(Value::Int32(7))
+        );
+
+        Ok(())
+    }
+}
@@ -1,6 +1,6 @@
-//pub mod env;
+pub mod env;
 pub mod table;
 pub mod types;
 
-pub use table::*;
+pub use env::*;
 pub use types::*;
@@ -1,5 +1,7 @@
 use std::collections::HashMap;
 
+use crate::ast::Ident;
+
 type SymbolTable = HashMap<String, Ident>;
 
 #[derive(Debug, Clone, Default)]
@@ -28,13 +30,13 @@ impl Env {
         self.stack.pop();
     }
 
-    pub fn put(&mut self, name: &str, id: &Expr) {
+    pub fn put(&mut self, name: &str, id: &Ident) {
         if let Some(last) = self.stack.last_mut() {
             last.insert(name.to_owned(), id.clone());
         }
     }
 
-    pub fn get(&self, name: &str) -> Option<&Expr> {
+    pub fn get(&self, name: &str) -> Option<&Ident> {
         for sym_table in self.stack.iter().rev() {
             if let Some(id) = sym_table.get(name) {
                 return Some(id);
@@ -66,7 +68,6 @@ mod tests {
 
     #[test]
     fn manage_with_ids() {
-        use crate::lex::token::Token;
         use crate::sym::types::Type;
 
         let mut env = Env::new();
@@ -75,25 +76,29 @@ mod tests {
         assert_eq!(env.len(), 2);
         env.put(
             "a",
-            &Expr::Identifier {
-                id: Token::Identifier("a".to_owned()),
+            &Ident {
+                id: "a".to_owned(),
                 tp: Type::Int32,
                 offset: 0,
             },
         );
         env.put(
             "b",
-            &Expr::Identifier {
-                id: Token::Identifier("b".to_owned()),
+            &Ident {
+                id: "b".to_owned(),
                 tp: Type::Int64,
                 offset: 1,
             },
         );
         env.push();
         assert_eq!(env.len(), 3);
+        assert_eq!(env.get("a").map(|id| id.offset), Some(0));
+        assert_eq!(env.get("b").map(|id| id.tp.clone()), Some(Type::Int64));
+        assert!(env.get("c").is_none());
         env.pop();
         env.pop();
         assert_eq!(env.len(), 1);
+        assert!(env.get("a").is_none());
         env.pop();
         assert!(env.is_empty());
     }
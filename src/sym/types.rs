@@ -1,4 +1,94 @@
 use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+
+/// Size and alignment of the values of a type in memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Layout {
+    pub size: usize,
+    /// A power of two.
+    pub align: usize,
+}
+
+impl Layout {
+    pub fn new(size: usize, align: usize) -> Self {
+        debug_assert!(align.is_power_of_two());
+        Self { size, align }
+    }
+
+    /// Distance between consecutive elements of an array: the size rounded up
+    /// to the alignment.
+    pub fn stride(&self) -> usize {
+        self.size.div_ceil(self.align) * self.align
+    }
+
+    /// `count` elements one after the other.
+    pub fn array(&self, count: usize) -> Self {
+        Self::new(self.stride() * count, self.align)
+    }
+
+    /**
+     * Fields one after the other, each at its alignment, as in a struct.
+     * Returns the layout of the whole, padded to its alignment, and the
+     * offset of every field.
+     */
+    pub fn record(fields: &[Layout]) -> (Self, Vec<usize>) {
+        let mut size = 0;
+        let mut align = 1;
+        let mut offsets = Vec::with_capacity(fields.len());
+
+        for field in fields {
+            let offset = Self::new(size, field.align).stride();
+            offsets.push(offset);
+            size = offset + field.size;
+            align = align.max(field.align);
+        }
+
+        (Self::new(size, align).array(1), offsets)
+    }
+
+    /// Room for any one of `variants` at a time, as the payload of an enum.
+    pub fn union(variants: &[Layout]) -> Self {
+        let size = variants.iter().map(|variant| variant.size).max();
+        let align = variants.iter().map(|variant| variant.align).max();
+        Self::new(size.unwrap_or(0), align.unwrap_or(1)).array(1)
+    }
+}
+
+/// What layouts depend on in a target.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DataLayout {
+    pub pointer_size: usize,
+    /// Scalars wider than this are only aligned to it.
+    pub max_align: usize,
+}
+
+impl DataLayout {
+    /// 64 bits targets: x86-64, RV64, and the VM.
+    pub const LP64: Self = Self {
+        pointer_size: 8,
+        max_align: 8,
+    };
+
+    /// 32 bits targets, where 8 bytes scalars are aligned to 4 as on i386.
+    pub const ILP32: Self = Self {
+        pointer_size: 4,
+        max_align: 4,
+    };
+
+    pub fn pointer(&self) -> Layout {
+        self.scalar(self.pointer_size)
+    }
+
+    pub fn scalar(&self, size: usize) -> Layout {
+        Layout::new(size, size.min(self.max_align))
+    }
+}
+
+impl Default for DataLayout {
+    fn default() -> Self {
+        Self::LP64
+    }
+}
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Type {
@@ -14,10 +104,10 @@ pub enum Type {
 
 impl Type {
     pub fn is_numeric(&self) -> bool {
-        match self {
-            Self::Int32 | Self::Int64 | Self::Flt32 | Self::Flt64 | Self::Char => true,
-            _ => false,
-        }
+        matches!(
+            self,
+            Self::Int32 | Self::Int64 | Self::Flt32 | Self::Flt64 | Self::Char
+        )
     }
 
     pub fn upcast(&self, other: &Self) -> Option<Self> {
@@ -31,18 +121,44 @@ impl Type {
         }
     }
 
+    pub fn layout(&self, target: &DataLayout) -> Layout {
+        match self {
+            Self::Int32 | Self::Flt32 => target.scalar(4),
+            Self::Int64 | Self::Flt64 => target.scalar(8),
+            Self::Bool | Self::Char => target.scalar(1),
+            Self::String(size) => Layout::new(*size, 1),
+            Self::Array { of, size } => of.layout(target).array(*size),
+        }
+    }
+
+    /// Size of the values of the type on the default target.
     pub fn get_width(&self) -> usize {
+        self.layout(&DataLayout::default()).size
+    }
+
+    /// Type of the scalars at the bottom of nested arrays, the type itself
+    /// for the others.
+    pub fn get_scalar(&self) -> &Self {
         match self {
-            Self::Int32 => 4,
-            Self::Int64 => 8,
-            Self::Flt32 => 4,
-            Self::Flt64 => 8,
-            Self::Bool => 1,
-            Self::Char => 1,
-            Self::String(size) => *size,
-            Self::Array { of, size } => of.get_width() * size,
+            Self::Array { of, .. } => of.get_scalar(),
+            tp => tp,
         }
     }
+
+    /// Number of scalars in the values of the type. Nested arrays hold them
+    /// row after row, and are indexed by their position in that order.
+    pub fn get_len(&self) -> usize {
+        match self {
+            Self::Array { of, size } => of.get_len() * size,
+            _ => 1,
+        }
+    }
+
+    /// Distance between consecutive values of the type in an array, on the
+    /// default target.
+    pub fn get_stride(&self) -> usize {
+        self.layout(&DataLayout::default()).stride()
+    }
 }
 
 impl Display for Type {
@@ -54,14 +170,44 @@ impl Display for Type {
             Self::Flt64 => "f64".to_owned(),
             Self::Bool => "bool".to_owned(),
             Self::Char => "char".to_owned(),
-            Self::String(_) => "string".to_owned(),
-            Self::Array { of, size } => format!("[{}]{}", size, of.to_string()),
+            Self::String(size) => format!("string({})", size),
+            Self::Array { of, size } => format!("[{}]{}", size, of),
         };
 
         write!(f, "{}", out)
     }
 }
 
+impl FromStr for Type {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "i32" => Ok(Self::Int32),
+            "i64" => Ok(Self::Int64),
+            "f32" => Ok(Self::Flt32),
+            "f64" => Ok(Self::Flt64),
+            "bool" => Ok(Self::Bool),
+            "char" => Ok(Self::Char),
+            string if string.starts_with("string(") => {
+                let size = string["string(".len()..].strip_suffix(')').ok_or(())?;
+                Ok(Self::String(size.parse().map_err(|_| ())?))
+            }
+            array => {
+                let array = array.strip_prefix('[').ok_or(())?;
+                let end = array.find(']').ok_or(())?;
+                let size = array[..end].parse().map_err(|_| ())?;
+                let of = array[end + 1..].parse()?;
+
+                Ok(Self::Array {
+                    of: Box::new(of),
+                    size,
+                })
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -87,5 +233,74 @@ mod tests {
             }
             .to_string()
         );
+        assert_eq!("string(12)".to_owned(), Type::String(12).to_string());
+    }
+
+    #[test]
+    fn from_str() {
+        assert_eq!(Ok(Type::Flt32), "f32".parse());
+        assert_eq!(
+            Ok(Type::Array {
+                of: Box::new(Type::Array {
+                    of: Box::new(Type::Char),
+                    size: 2
+                }),
+                size: 3
+            }),
+            "[3][2]char".parse()
+        );
+        assert_eq!(Err(()), "[3i32".parse::<Type>());
+        assert_eq!(Ok(Type::String(12)), "string(12)".parse());
+        assert_eq!(Err(()), "string".parse::<Type>());
+        assert_eq!(Err(()), "string(12".parse::<Type>());
+        assert_eq!(Err(()), "u8".parse::<Type>());
+    }
+
+    #[test]
+    fn scalars() {
+        let matrix: Type = "[3][2]char".parse().unwrap();
+        assert_eq!(&Type::Char, matrix.get_scalar());
+        assert_eq!(6, matrix.get_len());
+        assert_eq!(&Type::Int64, Type::Int64.get_scalar());
+        assert_eq!(1, Type::Int64.get_len());
+    }
+
+    #[test]
+    fn layouts() {
+        let lp64 = DataLayout::LP64;
+        let ilp32 = DataLayout::ILP32;
+        let matrix: Type = "[3][2]f64".parse().unwrap();
+
+        assert_eq!(Layout::new(48, 8), matrix.layout(&lp64));
+        assert_eq!(Layout::new(48, 4), matrix.layout(&ilp32));
+        assert_eq!(Layout::new(5, 1), Type::String(5).layout(&lp64));
+        assert_eq!(8, lp64.pointer().align);
+        assert_eq!(4, ilp32.pointer().size);
+        assert_eq!(16, Layout::new(13, 8).stride());
+        assert_eq!(48, Layout::new(13, 8).array(3).size);
+
+        // struct { c: char, x: i64, b: bool, y: i32 }
+        let fields: Vec<_> = ["char", "i64", "bool", "i32"]
+            .iter()
+            .map(|tp| tp.parse::<Type>().unwrap().layout(&lp64))
+            .collect();
+        let (record, offsets) = Layout::record(&fields);
+        assert_eq!(vec![0, 8, 16, 20], offsets);
+        assert_eq!(Layout::new(24, 8), record);
+
+        let packed: Vec<_> = ["char", "i64", "bool", "i32"]
+            .iter()
+            .map(|tp| tp.parse::<Type>().unwrap().layout(&ilp32))
+            .collect();
+        assert_eq!(
+            (Layout::new(20, 4), vec![0, 4, 12, 16]),
+            Layout::record(&packed)
+        );
+
+        assert_eq!(Layout::new(24, 8), Layout::union(&[record, fields[0]]));
+        assert_eq!(
+            Layout::new(8, 8),
+            Layout::union(&[Layout::new(5, 1), lp64.pointer()])
+        );
     }
 }
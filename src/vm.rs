@@ -0,0 +1,9 @@
+pub mod bytecode;
+pub mod jit;
+pub mod machine;
+pub mod riscv;
+
+pub use bytecode::*;
+pub use jit::*;
+pub use machine::*;
+pub use riscv::*;
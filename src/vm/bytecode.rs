@@ -0,0 +1,1029 @@
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::fs;
+use std::path::Path;
+
+use crate::ast::{Cons, Ident, Temp};
+use crate::error::BytecodeError;
+use crate::ir::{Function, Inst, Module, Opcode, Operand};
+use crate::lex::Token;
+use crate::sym::Type;
+
+/*
+ * Layout of an `.ezb` file, every integer little-endian:
+ *
+ *   header     "EZB\0", u16 version, u16 reserved
+ *   pool       u32 count, then entries: tag 0 (type, token) or tag 1 (string)
+ *   functions  u32 count, then name, return type, params, locals, code start
+ *              and instruction count for each
+ *   code       u32 length, then the instructions of every function
+ *
+ * Names and constants live in the pool and are referenced by index, so that
+ * identifiers and constants used over and over are stored once.
+ */
+pub const MAGIC: &[u8; 4] = b"EZB\0";
+pub const VERSION: u16 = 2;
+
+pub type BytecodeResult<T> = Result<T, BytecodeError>;
+
+const POOL_CONS: u8 = 0;
+const POOL_NAME: u8 = 1;
+
+const OP_LABEL: u8 = 0;
+const OP_INV: u8 = 11;
+const OP_NOT: u8 = 12;
+const OP_MOV: u8 = 13;
+const OP_IDX: u8 = 14;
+const OP_STO: u8 = 15;
+const OP_JMP: u8 = 16;
+const OP_JMPT: u8 = 17;
+const OP_JMPF: u8 = 18;
+const OP_PARAM: u8 = 19;
+const OP_CALL: u8 = 20;
+const OP_RET: u8 = 21;
+/// Since version 2.
+const OP_CHK: u8 = 22;
+
+const BINARY: [Opcode; 10] = [
+    Opcode::Add,
+    Opcode::Sub,
+    Opcode::Mul,
+    Opcode::Div,
+    Opcode::Lt,
+    Opcode::Le,
+    Opcode::Gt,
+    Opcode::Ge,
+    Opcode::Eq,
+    Opcode::Ne,
+];
+
+/// Nesting allowed for array types, so that hostile input can't exhaust the stack.
+const MAX_DEPTH: usize = 32;
+
+/// Encodes `module` as `.ezb` bytes. Phis can't be encoded, translate out of
+/// SSA first.
+pub fn encode(module: &Module) -> Vec<u8> {
+    let mut pool = Pool::default();
+    let mut table = Vec::new();
+    let mut code = Vec::new();
+
+    put_u32(&mut table, module.funcs.len());
+    for func in &module.funcs {
+        put_u32(&mut table, pool.name(&func.name));
+        match &func.ret {
+            Some(tp) => {
+                table.push(1);
+                put_type(&mut table, tp);
+            }
+            None => table.push(0),
+        }
+
+        for idents in &[&func.params, &func.locals] {
+            put_u32(&mut table, idents.len());
+            for ident in idents.iter() {
+                put_ident(&mut pool, &mut table, ident);
+            }
+        }
+
+        put_u32(&mut table, code.len());
+        put_u32(&mut table, func.code.len());
+        for inst in &func.code {
+            put_inst(&mut pool, &mut code, inst);
+        }
+    }
+
+    let mut out = MAGIC.to_vec();
+    out.extend_from_slice(&VERSION.to_le_bytes());
+    out.extend_from_slice(&0u16.to_le_bytes());
+    put_u32(&mut out, pool.count);
+    out.extend(pool.bytes);
+    out.extend(table);
+    put_u32(&mut out, code.len());
+    out.extend(code);
+    out
+}
+
+/// Decodes and verifies `.ezb` bytes, the module is safe to run by `Machine`.
+pub fn decode(bytes: &[u8]) -> BytecodeResult<Module> {
+    let mut reader = Reader {
+        bytes,
+        pos: 0,
+        pool: Vec::new(),
+    };
+    if reader.take(4)? != MAGIC {
+        return Err(reader.malformed_at(0, "not an .ezb file"));
+    }
+
+    let version = reader.u16()?;
+    if version == 0 || version > VERSION {
+        return Err(reader.malformed_at(4, &format!("unsupported version {}", version)));
+    }
+    reader.u16()?;
+
+    let count = reader.u32()?;
+    let mut pool = Vec::new();
+    for _ in 0..count {
+        let entry = match reader.u8()? {
+            POOL_CONS => {
+                let tp = reader.tp(0)?;
+                let tok = reader.token()?;
+                Entry::Cons(Cons { tok, tp })
+            }
+            POOL_NAME => Entry::Name(reader.string()?),
+            tag => return Err(reader.malformed(&format!("unknown pool entry {}", tag))),
+        };
+        pool.push(entry);
+    }
+    reader.pool = pool;
+
+    let count = reader.u32()?;
+    let mut funcs = Vec::new();
+    for _ in 0..count {
+        let name = reader.name()?;
+        let ret = match reader.u8()? {
+            0 => None,
+            1 => Some(reader.tp(0)?),
+            flag => return Err(reader.malformed(&format!("bad return flag {}", flag))),
+        };
+        let params = reader.idents()?;
+        let locals = reader.idents()?;
+        let start = reader.u32()?;
+        let len = reader.u32()?;
+        funcs.push((
+            Function {
+                name,
+                params,
+                locals,
+                ret,
+                code: Vec::new(),
+            },
+            start,
+            len,
+        ));
+    }
+
+    let size = reader.u32()?;
+    let base = reader.pos;
+    reader.take(size)?;
+    if reader.pos != bytes.len() {
+        return Err(reader.malformed("trailing bytes after the code section"));
+    }
+
+    let code = Reader {
+        bytes: &bytes[..base + size],
+        pos: base,
+        pool: reader.pool,
+    };
+    let mut module = Module::default();
+    for (mut func, start, len) in funcs {
+        let mut reader = Reader {
+            pos: base + start,
+            ..code.clone()
+        };
+        if start > size {
+            return Err(
+                reader.malformed_at(base, &format!("code of `{}` out of bounds", func.name))
+            );
+        }
+
+        for _ in 0..len {
+            func.code.push(reader.inst()?);
+        }
+        module.funcs.push(func);
+    }
+
+    verify(&module)?;
+    Ok(module)
+}
+
+pub fn save(module: &Module, path: &Path) -> BytecodeResult<()> {
+    fs::write(path, encode(module)).map_err(|err| BytecodeError::Io(err.to_string()))
+}
+
+pub fn load(path: &Path) -> BytecodeResult<Module> {
+    let bytes = fs::read(path).map_err(|err| BytecodeError::Io(err.to_string()))?;
+    decode(&bytes)
+}
+
+#[derive(Default)]
+struct Pool {
+    bytes: Vec<u8>,
+    count: usize,
+    entries: HashMap<Vec<u8>, usize>,
+}
+
+impl Pool {
+    fn entry(&mut self, entry: Vec<u8>) -> usize {
+        if let Some(index) = self.entries.get(&entry) {
+            return *index;
+        }
+
+        let index = self.count;
+        self.count += 1;
+        self.bytes.extend_from_slice(&entry);
+        self.entries.insert(entry, index);
+        index
+    }
+
+    fn name(&mut self, name: &str) -> usize {
+        let mut entry = vec![POOL_NAME];
+        put_str(&mut entry, name);
+        self.entry(entry)
+    }
+
+    fn cons(&mut self, cons: &Cons) -> usize {
+        let mut entry = vec![POOL_CONS];
+        put_type(&mut entry, &cons.tp);
+        match &cons.tok {
+            Token::Integer(num) => {
+                entry.push(0);
+                entry.extend_from_slice(&num.to_le_bytes());
+            }
+            Token::Float(num) => {
+                entry.push(1);
+                entry.extend_from_slice(&num.to_bits().to_le_bytes());
+            }
+            Token::ReservedWord(word) => {
+                entry.push(2);
+                put_str(&mut entry, word);
+            }
+            Token::String(string) => {
+                entry.push(3);
+                put_str(&mut entry, string);
+            }
+            tok => panic!("Bad constant {}", tok),
+        }
+        self.entry(entry)
+    }
+}
+
+fn put_u32(out: &mut Vec<u8>, value: usize) {
+    let value = u32::try_from(value).expect("Module too large for bytecode");
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_str(out: &mut Vec<u8>, string: &str) {
+    put_u32(out, string.len());
+    out.extend_from_slice(string.as_bytes());
+}
+
+fn put_type(out: &mut Vec<u8>, tp: &Type) {
+    match tp {
+        Type::Int32 => out.push(0),
+        Type::Int64 => out.push(1),
+        Type::Flt32 => out.push(2),
+        Type::Flt64 => out.push(3),
+        Type::Char => out.push(4),
+        Type::Bool => out.push(5),
+        Type::String(size) => {
+            out.push(6);
+            put_u32(out, *size);
+        }
+        Type::Array { of, size } => {
+            out.push(7);
+            put_u32(out, *size);
+            put_type(out, of);
+        }
+    }
+}
+
+fn put_ident(pool: &mut Pool, out: &mut Vec<u8>, ident: &Ident) {
+    put_u32(out, pool.name(&ident.id));
+    put_type(out, &ident.tp);
+    put_u32(out, ident.offset);
+}
+
+fn put_operand(pool: &mut Pool, out: &mut Vec<u8>, operand: &Operand) {
+    match operand {
+        Operand::Ident(ident) => {
+            out.push(0);
+            put_ident(pool, out, ident);
+        }
+        Operand::Temp(temp) => {
+            out.push(1);
+            put_u32(out, temp.id);
+            put_type(out, &temp.tp);
+        }
+        Operand::Cons(cons) => {
+            out.push(2);
+            put_u32(out, pool.cons(cons));
+        }
+    }
+}
+
+fn put_inst(pool: &mut Pool, out: &mut Vec<u8>, inst: &Inst) {
+    match inst {
+        Inst::Label(label) => {
+            out.push(OP_LABEL);
+            put_u32(out, *label);
+        }
+        Inst::Binary { op, dst, lhs, rhs } => {
+            out.push(BINARY.iter().position(|other| other == op).unwrap() as u8 + 1);
+            for operand in &[dst, lhs, rhs] {
+                put_operand(pool, out, operand);
+            }
+        }
+        Inst::Inv { dst, src } | Inst::Not { dst, src } | Inst::Mov { dst, src } => {
+            out.push(match inst {
+                Inst::Inv { .. } => OP_INV,
+                Inst::Not { .. } => OP_NOT,
+                _ => OP_MOV,
+            });
+            put_operand(pool, out, dst);
+            put_operand(pool, out, src);
+        }
+        Inst::Idx { dst, index, array } => {
+            out.push(OP_IDX);
+            for operand in &[dst, index, array] {
+                put_operand(pool, out, operand);
+            }
+        }
+        Inst::Sto { array, index, src } => {
+            out.push(OP_STO);
+            for operand in &[array, index, src] {
+                put_operand(pool, out, operand);
+            }
+        }
+        Inst::Chk { index, len } => {
+            out.push(OP_CHK);
+            put_operand(pool, out, index);
+            put_u32(out, *len);
+        }
+        Inst::Jmp { label } => {
+            out.push(OP_JMP);
+            put_u32(out, *label);
+        }
+        Inst::JmpT { label, test } | Inst::JmpF { label, test } => {
+            out.push(match inst {
+                Inst::JmpT { .. } => OP_JMPT,
+                _ => OP_JMPF,
+            });
+            put_u32(out, *label);
+            put_operand(pool, out, test);
+        }
+        Inst::Param { src } => {
+            out.push(OP_PARAM);
+            put_operand(pool, out, src);
+        }
+        Inst::Call { dst, func, nargs } => {
+            out.push(OP_CALL);
+            match dst {
+                Some(dst) => {
+                    out.push(1);
+                    put_operand(pool, out, dst);
+                }
+                None => out.push(0),
+            }
+            put_u32(out, pool.name(func));
+            put_u32(out, *nargs);
+        }
+        Inst::Ret { src } => {
+            out.push(OP_RET);
+            match src {
+                Some(src) => {
+                    out.push(1);
+                    put_operand(pool, out, src);
+                }
+                None => out.push(0),
+            }
+        }
+        Inst::Phi { .. } => panic!("Cannot encode phis, translate out of SSA first"),
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Entry {
+    Cons(Cons),
+    Name(String),
+}
+
+#[derive(Debug, Clone)]
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    pool: Vec<Entry>,
+}
+
+impl<'a> Reader<'a> {
+    fn malformed_at(&self, offset: usize, msg: &str) -> BytecodeError {
+        BytecodeError::Malformed {
+            offset,
+            msg: msg.to_owned(),
+        }
+    }
+
+    fn malformed(&self, msg: &str) -> BytecodeError {
+        self.malformed_at(self.pos, msg)
+    }
+
+    fn take(&mut self, len: usize) -> BytecodeResult<&'a [u8]> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|end| *end <= self.bytes.len())
+            .ok_or_else(|| self.malformed("unexpected end of input"))?;
+        let bytes = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> BytecodeResult<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> BytecodeResult<u16> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> BytecodeResult<usize> {
+        let mut buf = [0; 4];
+        buf.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(buf) as usize)
+    }
+
+    fn string(&mut self) -> BytecodeResult<String> {
+        let len = self.u32()?;
+        let start = self.pos;
+        let bytes = self.take(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| self.malformed_at(start, "invalid UTF-8"))
+    }
+
+    fn tp(&mut self, depth: usize) -> BytecodeResult<Type> {
+        let tp = match self.u8()? {
+            0 => Type::Int32,
+            1 => Type::Int64,
+            2 => Type::Flt32,
+            3 => Type::Flt64,
+            4 => Type::Char,
+            5 => Type::Bool,
+            6 => Type::String(self.u32()?),
+            7 if depth < MAX_DEPTH => {
+                let size = self.u32()?;
+                let of = Box::new(self.tp(depth + 1)?);
+                Type::Array { of, size }
+            }
+            7 => return Err(self.malformed("array type nested too deep")),
+            tag => return Err(self.malformed(&format!("unknown type {}", tag))),
+        };
+
+        Ok(tp)
+    }
+
+    fn token(&mut self) -> BytecodeResult<Token> {
+        let tok = match self.u8()? {
+            0 => Token::Integer(i32::from_le_bytes([
+                self.u8()?,
+                self.u8()?,
+                self.u8()?,
+                self.u8()?,
+            ])),
+            1 => {
+                let mut buf = [0; 8];
+                buf.copy_from_slice(self.take(8)?);
+                Token::Float(f64::from_bits(u64::from_le_bytes(buf)))
+            }
+            2 => Token::ReservedWord(self.string()?),
+            3 => Token::String(self.string()?),
+            tag => return Err(self.malformed(&format!("unknown constant {}", tag))),
+        };
+
+        Ok(tok)
+    }
+
+    fn entry(&mut self) -> BytecodeResult<&Entry> {
+        let start = self.pos;
+        let index = self.u32()?;
+        let pool = &self.pool;
+        pool.get(index).ok_or(BytecodeError::Malformed {
+            offset: start,
+            msg: format!("pool index {} out of bounds", index),
+        })
+    }
+
+    fn name(&mut self) -> BytecodeResult<String> {
+        let start = self.pos;
+        match self.entry()? {
+            Entry::Name(name) => Ok(name.clone()),
+            Entry::Cons(_) => Err(self.malformed_at(start, "expected a name in the pool")),
+        }
+    }
+
+    fn cons(&mut self) -> BytecodeResult<Cons> {
+        let start = self.pos;
+        match self.entry()? {
+            Entry::Cons(cons) => Ok(cons.clone()),
+            Entry::Name(_) => Err(self.malformed_at(start, "expected a constant in the pool")),
+        }
+    }
+
+    fn ident(&mut self) -> BytecodeResult<Ident> {
+        let id = self.name()?;
+        let tp = self.tp(0)?;
+        let offset = self.u32()?;
+        Ok(Ident { id, tp, offset })
+    }
+
+    fn idents(&mut self) -> BytecodeResult<Vec<Ident>> {
+        let count = self.u32()?;
+        (0..count).map(|_| self.ident()).collect()
+    }
+
+    fn operand(&mut self) -> BytecodeResult<Operand> {
+        let operand = match self.u8()? {
+            0 => Operand::Ident(self.ident()?),
+            1 => {
+                let id = self.u32()?;
+                let tp = self.tp(0)?;
+                Operand::Temp(Temp { id, tp })
+            }
+            2 => Operand::Cons(self.cons()?),
+            tag => return Err(self.malformed(&format!("unknown operand {}", tag))),
+        };
+
+        Ok(operand)
+    }
+
+    fn optional(&mut self) -> BytecodeResult<Option<Operand>> {
+        match self.u8()? {
+            0 => Ok(None),
+            1 => Ok(Some(self.operand()?)),
+            flag => Err(self.malformed(&format!("bad operand flag {}", flag))),
+        }
+    }
+
+    fn inst(&mut self) -> BytecodeResult<Inst> {
+        let start = self.pos;
+        let inst = match self.u8()? {
+            OP_LABEL => Inst::Label(self.u32()?),
+            op @ 1..=10 => Inst::Binary {
+                op: BINARY[op as usize - 1],
+                dst: self.operand()?,
+                lhs: self.operand()?,
+                rhs: self.operand()?,
+            },
+            OP_INV => Inst::Inv {
+                dst: self.operand()?,
+                src: self.operand()?,
+            },
+            OP_NOT => Inst::Not {
+                dst: self.operand()?,
+                src: self.operand()?,
+            },
+            OP_MOV => Inst::Mov {
+                dst: self.operand()?,
+                src: self.operand()?,
+            },
+            OP_IDX => Inst::Idx {
+                dst: self.operand()?,
+                index: self.operand()?,
+                array: self.operand()?,
+            },
+            OP_STO => Inst::Sto {
+                array: self.operand()?,
+                index: self.operand()?,
+                src: self.operand()?,
+            },
+            OP_CHK => Inst::Chk {
+                index: self.operand()?,
+                len: self.u32()?,
+            },
+            OP_JMP => Inst::Jmp { label: self.u32()? },
+            OP_JMPT => Inst::JmpT {
+                label: self.u32()?,
+                test: self.operand()?,
+            },
+            OP_JMPF => Inst::JmpF {
+                label: self.u32()?,
+                test: self.operand()?,
+            },
+            OP_PARAM => Inst::Param {
+                src: self.operand()?,
+            },
+            OP_CALL => Inst::Call {
+                dst: self.optional()?,
+                func: self.name()?,
+                nargs: self.u32()?,
+            },
+            OP_RET => Inst::Ret {
+                src: self.optional()?,
+            },
+            op => return Err(self.malformed_at(start, &format!("unknown opcode {}", op))),
+        };
+
+        Ok(inst)
+    }
+}
+
+/// Whether `Value::cast` turns values of type `from` into `to`.
+fn assignable(from: &Type, to: &Type) -> bool {
+    match (from, to) {
+        (Type::Bool, Type::Bool) | (Type::String(_), Type::String(_)) => true,
+        (
+            Type::Array { of, size },
+            Type::Array {
+                of: to_of,
+                size: to_size,
+            },
+        ) => size == to_size && assignable(of, to_of),
+        (from, to) => from.is_numeric() && to.is_numeric(),
+    }
+}
+
+fn is_index(tp: &Type) -> bool {
+    matches!(tp, Type::Int32 | Type::Int64 | Type::Char)
+}
+
+/**
+ * Checks what `Machine` takes for granted of the code it runs: jumps land on
+ * labels of their function, arguments are pushed right before the call that
+ * takes them, operands agree in type with each other and with their
+ * declarations, and constants hold tokens of their type.
+ */
+fn verify(module: &Module) -> BytecodeResult<()> {
+    let mut names = HashSet::new();
+    for func in &module.funcs {
+        if !names.insert(&func.name) {
+            return Err(invalid(func, 0, "defined twice"));
+        }
+        Checker::new(module, func).check()?;
+    }
+
+    Ok(())
+}
+
+fn invalid(func: &Function, inst: usize, msg: &str) -> BytecodeError {
+    BytecodeError::Invalid {
+        func: func.name.clone(),
+        inst,
+        msg: msg.to_owned(),
+    }
+}
+
+struct Checker<'a> {
+    module: &'a Module,
+    func: &'a Function,
+    /// Instruction being checked, counting from 1.
+    inst: usize,
+    idents: HashMap<usize, &'a Ident>,
+    temps: HashMap<usize, &'a Type>,
+}
+
+impl<'a> Checker<'a> {
+    fn new(module: &'a Module, func: &'a Function) -> Self {
+        Self {
+            module,
+            func,
+            inst: 0,
+            idents: HashMap::new(),
+            temps: HashMap::new(),
+        }
+    }
+
+    fn error(&self, msg: &str) -> BytecodeError {
+        invalid(self.func, self.inst, msg)
+    }
+
+    fn check(mut self) -> BytecodeResult<()> {
+        for ident in self.func.params.iter().chain(&self.func.locals) {
+            if self.idents.insert(ident.offset, ident).is_some() {
+                return Err(self.error(&format!("offset {} declared twice", ident.offset)));
+            }
+        }
+
+        let mut labels = HashSet::new();
+        for (index, inst) in self.func.code.iter().enumerate() {
+            if let Inst::Label(label) = inst {
+                if !labels.insert(*label) {
+                    self.inst = index + 1;
+                    return Err(self.error(&format!("label L{} defined twice", label)));
+                }
+            }
+        }
+
+        let mut pending: Vec<&Operand> = Vec::new();
+        for (index, inst) in self.func.code.iter().enumerate() {
+            self.inst = index + 1;
+            match inst {
+                Inst::Label(_)
+                | Inst::Jmp { .. }
+                | Inst::JmpT { .. }
+                | Inst::JmpF { .. }
+                | Inst::Ret { .. }
+                    if !pending.is_empty() =>
+                {
+                    return Err(self.error("arguments pushed but never passed"));
+                }
+                _ => {}
+            }
+
+            match inst {
+                Inst::Label(_) => {}
+                Inst::Binary { op, dst, lhs, rhs } => {
+                    let (lhs, rhs) = (self.operand(lhs)?, self.operand(rhs)?);
+                    let tp = match lhs.upcast(&rhs) {
+                        Some(_) if op.is_rel() => Type::Bool,
+                        Some(tp) => tp,
+                        None if matches!(op, Opcode::Eq | Opcode::Ne)
+                            && lhs == Type::Bool
+                            && rhs == Type::Bool =>
+                        {
+                            Type::Bool
+                        }
+                        None => return Err(self.error(&format!("`{}` of {} and {}", op, lhs, rhs))),
+                    };
+                    self.store(dst, &tp)?;
+                }
+                Inst::Inv { dst, src } => {
+                    let tp = self.operand(src)?;
+                    if !tp.is_numeric() {
+                        return Err(self.error(&format!("`inv` of {}", tp)));
+                    }
+                    self.store(dst, &tp)?;
+                }
+                Inst::Not { dst, src } => {
+                    let tp = self.operand(src)?;
+                    if tp != Type::Bool {
+                        return Err(self.error(&format!("`not` of {}", tp)));
+                    }
+                    self.store(dst, &tp)?;
+                }
+                Inst::Mov { dst, src } => {
+                    let tp = self.operand(src)?;
+                    self.store(dst, &tp)?;
+                }
+                Inst::Idx { dst, index, array } => {
+                    let of = self.array(index, array)?;
+                    self.store(dst, &of)?;
+                }
+                Inst::Sto { array, index, src } => {
+                    let of = self.array(index, array)?;
+                    let tp = self.operand(src)?;
+                    if !assignable(&tp, &of) {
+                        return Err(self.error(&format!("cannot store {} into {}", tp, of)));
+                    }
+                }
+                Inst::Chk { index, .. } => {
+                    let tp = self.operand(index)?;
+                    if !is_index(&tp) {
+                        return Err(self.error(&format!("index of type {}", tp)));
+                    }
+                }
+                Inst::Jmp { label } => self.label(&labels, *label)?,
+                Inst::JmpT { label, test } | Inst::JmpF { label, test } => {
+                    self.label(&labels, *label)?;
+                    let tp = self.operand(test)?;
+                    if tp != Type::Bool {
+                        return Err(self.error(&format!("jump on {}", tp)));
+                    }
+                }
+                Inst::Param { src } => {
+                    self.operand(src)?;
+                    pending.push(src);
+                }
+                Inst::Call { dst, func, nargs } => {
+                    let callee = self
+                        .module
+                        .get(func)
+                        .ok_or_else(|| self.error(&format!("call to undefined `{}`", func)))?;
+                    if callee.params.len() != *nargs {
+                        return Err(self.error(&format!(
+                            "`{}` takes {} arguments, called with {}",
+                            func,
+                            callee.params.len(),
+                            nargs
+                        )));
+                    }
+                    if pending.len() < *nargs {
+                        return Err(self.error(&format!(
+                            "{} arguments pushed for `{}`",
+                            pending.len(),
+                            func
+                        )));
+                    }
+
+                    let args = pending.split_off(pending.len() - nargs);
+                    for (arg, param) in args.iter().zip(&callee.params) {
+                        let tp = arg.get_tp();
+                        if !assignable(&tp, &param.tp) {
+                            return Err(self.error(&format!(
+                                "cannot pass {} as `{}` of type {}",
+                                tp, param.id, param.tp
+                            )));
+                        }
+                    }
+
+                    match (dst, &callee.ret) {
+                        (Some(dst), Some(ret)) => self.store(dst, ret)?,
+                        (Some(_), None) => {
+                            return Err(self.error(&format!("`{}` returns no value", func)))
+                        }
+                        (None, _) => {}
+                    }
+                }
+                Inst::Ret { src: Some(src) } => {
+                    let tp = self.operand(src)?;
+                    match &self.func.ret {
+                        Some(ret) if assignable(&tp, ret) => {}
+                        Some(ret) => {
+                            return Err(self.error(&format!("cannot return {} as {}", tp, ret)))
+                        }
+                        None => return Err(self.error("value returned from a procedure")),
+                    }
+                }
+                Inst::Ret { src: None } => {}
+                Inst::Phi { .. } => return Err(self.error("phis can't be run")),
+            }
+        }
+
+        if !pending.is_empty() {
+            return Err(self.error("arguments pushed but never passed"));
+        }
+
+        Ok(())
+    }
+
+    /// Type of `operand`, after checking it against the declarations.
+    fn operand(&mut self, operand: &'a Operand) -> BytecodeResult<Type> {
+        match operand {
+            Operand::Ident(ident) => match self.idents.get(&ident.offset) {
+                Some(decl) if *decl == ident => {}
+                _ => return Err(self.error(&format!("undeclared `{}`", ident.id))),
+            },
+            Operand::Temp(temp) => {
+                let tp = *self.temps.entry(temp.id).or_insert(&temp.tp);
+                if *tp != temp.tp {
+                    return Err(self.error(&format!("{} used as {} and {}", temp, tp, temp.tp)));
+                }
+            }
+            Operand::Cons(cons) => {
+                let ok = match &cons.tok {
+                    Token::Integer(_) | Token::Float(_) => cons.tp.is_numeric(),
+                    Token::ReservedWord(word) => {
+                        cons.tp == Type::Bool && (word == "true" || word == "false")
+                    }
+                    Token::String(_) => matches!(cons.tp, Type::Char | Type::String(_)),
+                    _ => false,
+                };
+                if !ok {
+                    return Err(self.error(&format!("bad constant {} of type {}", cons, cons.tp)));
+                }
+            }
+        }
+
+        Ok(operand.get_tp())
+    }
+
+    fn store(&mut self, dst: &'a Operand, tp: &Type) -> BytecodeResult<()> {
+        if let Operand::Cons(cons) = dst {
+            return Err(self.error(&format!("assignment to constant {}", cons)));
+        }
+
+        let dst_tp = self.operand(dst)?;
+        if !assignable(tp, &dst_tp) {
+            return Err(self.error(&format!("cannot assign {} to {}", tp, dst_tp)));
+        }
+
+        Ok(())
+    }
+
+    /// Element type of `array`, after checking that `index` can index it.
+    fn array(&mut self, index: &'a Operand, array: &'a Operand) -> BytecodeResult<Type> {
+        let tp = self.operand(index)?;
+        if !is_index(&tp) {
+            return Err(self.error(&format!("index of type {}", tp)));
+        }
+
+        match (array, self.operand(array)?) {
+            (Operand::Ident(_), tp @ Type::Array { .. }) => Ok(tp.get_scalar().clone()),
+            (_, tp) => Err(self.error(&format!("indexing into {}", tp))),
+        }
+    }
+
+    fn label(&self, labels: &HashSet<usize>, label: usize) -> BytecodeResult<()> {
+        if labels.contains(&label) {
+            Ok(())
+        } else {
+            Err(self.error(&format!("jump to undefined label L{}", label)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::RuntimeError;
+    use crate::interp::interpreter::tests::fact_program;
+    use crate::ir::parse_module;
+    use crate::vm::machine::tests::{compile, for_each_program};
+    use crate::vm::Machine;
+
+    #[test]
+    fn roundtrip() {
+        for_each_program(|_, module, expected| {
+            let bytes = encode(module);
+            assert_eq!(&bytes[..4], MAGIC);
+            let decoded = decode(&bytes).unwrap();
+            assert_eq!(&decoded, module);
+            assert_eq!(encode(&decoded), bytes);
+            assert_eq!(Machine::new(&decoded).run(), Ok(expected));
+        });
+    }
+
+    #[test]
+    fn index_checks() {
+        // `chk` is new in version 2, and still fails once decoded
+        let module = parse_module(
+            "fn main() -> i32\n\tvar m: [3][4]i32\n\tvar i: i32\n\tvar j: i32\n\
+             \tmov i 0\n\tmov j 5\n\tchk i 3\n\tchk j 4\n\
+             \tmul __t0 i 4\n\tadd __t1 __t0 j\n\tidx __t2 __t1 m\n\tret __t2\n",
+        )
+        .unwrap();
+
+        let decoded = decode(&encode(&module)).unwrap();
+        assert_eq!(decoded, module);
+        assert_eq!(
+            Machine::new(&decoded).run(),
+            Err(RuntimeError::IndexOutOfBounds { index: 5, len: 4 })
+        );
+
+        let mut float = module;
+        float.funcs[0].code[2] = Inst::Chk {
+            index: Operand::Cons(Cons {
+                tok: Token::Float(1.0),
+                tp: Type::Flt64,
+            }),
+            len: 3,
+        };
+        rejects(&float, "index of type f64");
+    }
+
+    #[test]
+    fn malformed_input() {
+        let bytes = encode(&compile(&fact_program()));
+        let offset = |result: BytecodeResult<Module>| match result {
+            Err(BytecodeError::Malformed { offset, msg }) => (offset, msg),
+            other => panic!("expected malformed input, got {:?}", other),
+        };
+
+        let mut magic = bytes.clone();
+        magic[0] = b'X';
+        assert_eq!(offset(decode(&magic)).0, 0);
+
+        let mut version = bytes.clone();
+        version[4] = 3;
+        assert_eq!(offset(decode(&version)).0, 4);
+
+        let (_, msg) = offset(decode(&bytes[..bytes.len() - 1]));
+        assert_eq!(msg, "unexpected end of input");
+
+        let mut trailing = bytes;
+        trailing.push(0);
+        assert_eq!(offset(decode(&trailing)).0, trailing.len() - 1);
+    }
+
+    fn rejects(module: &Module, needle: &str) {
+        match decode(&encode(module)) {
+            Err(err @ BytecodeError::Invalid { .. }) => {
+                assert!(err.to_string().contains(needle), "{}", err)
+            }
+            other => panic!("expected {}, got {:?}", needle, other),
+        }
+    }
+
+    #[test]
+    fn verifier() {
+        let module = compile(&fact_program());
+        let fact = module.funcs.iter().position(|f| f.name == "fact").unwrap();
+        let find = |f: &dyn Fn(&Inst) -> bool| module.funcs[fact].code.iter().position(f).unwrap();
+
+        let mut label = module.clone();
+        let jump = find(&|inst| matches!(inst, Inst::JmpF { .. }));
+        if let Inst::JmpF { label, .. } = &mut label.funcs[fact].code[jump] {
+            *label = 999;
+        }
+        rejects(&label, "undefined label L999");
+
+        let mut arity = module.clone();
+        let call = find(&|inst| matches!(inst, Inst::Call { .. }));
+        if let Inst::Call { nargs, .. } = &mut arity.funcs[fact].code[call] {
+            *nargs = 2;
+        }
+        rejects(&arity, "takes 1 arguments, called with 2");
+
+        let mut unbalanced = module.clone();
+        let param = find(&|inst| matches!(inst, Inst::Param { .. }));
+        let extra = unbalanced.funcs[fact].code[param].clone();
+        unbalanced.funcs[fact].code.insert(param, extra);
+        rejects(&unbalanced, "never passed");
+
+        let mut test = module.clone();
+        if let Inst::JmpF { test, .. } = &mut test.funcs[fact].code[jump] {
+            *test = Operand::Cons(Cons {
+                tok: Token::Integer(1),
+                tp: Type::Int32,
+            });
+        }
+        rejects(&test, "jump on i32");
+    }
+}
@@ -0,0 +1,1013 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::mem;
+
+use cranelift_codegen::ir::condcodes::{FloatCC, IntCC};
+use cranelift_codegen::ir::{
+    types, AbiParam, Block, InstBuilder, MemFlags, Signature, StackSlot, StackSlotData,
+    StackSlotKind,
+};
+use cranelift_codegen::settings::{self, Configurable};
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext, Variable};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{default_libcall_names, FuncId, Linkage, Module as _};
+
+use crate::codegen::CodegenResult;
+use crate::error::{CodegenError, RuntimeError};
+use crate::interp::{RunResult, Value};
+use crate::ir::{Function, Inst, Module, Opcode, Operand, Var};
+use crate::sym::{DataLayout, Type};
+
+/// Frames allowed on the native stack, as in `Machine`.
+const MAX_DEPTH: u32 = 4096;
+
+/// Shared by the compiled functions of a call, they report runtime errors
+/// through it and unwind by returning zeroes.
+#[repr(C)]
+#[derive(Debug, Default)]
+struct Status {
+    code: u32,
+    depth: u32,
+    /// Function that finished without returning a value.
+    func: u32,
+    index: i64,
+    len: i64,
+}
+
+const OK: u32 = 0;
+const DIVISION_BY_ZERO: u32 = 1;
+const OUT_OF_BOUNDS: u32 = 2;
+const MISSING_RETURN: u32 = 3;
+const STACK_OVERFLOW: u32 = 4;
+
+fn offset(field: usize) -> i32 {
+    field as i32
+}
+
+/// Cranelift type of scalars of type `tp`, as wide as `Type::get_width`.
+fn clif_type(tp: &Type) -> CodegenResult<types::Type> {
+    let width = tp.get_width() as u16;
+    let clif = match tp {
+        Type::Flt32 | Type::Flt64 if width == 4 => Some(types::F32),
+        Type::Flt32 | Type::Flt64 => Some(types::F64),
+        Type::Int32 | Type::Int64 | Type::Char | Type::Bool => {
+            types::Type::int_with_byte_size(width)
+        }
+        _ => None,
+    };
+
+    clif.ok_or_else(|| CodegenError::new(&format!("no Cranelift scalar type for {}", tp)))
+}
+
+fn is_float(tp: &Type) -> bool {
+    matches!(tp, Type::Flt32 | Type::Flt64)
+}
+
+fn signature(jit: &JITModule, func: &Function) -> CodegenResult<Signature> {
+    let mut sig = jit.make_signature();
+    for param in &func.params {
+        sig.params.push(AbiParam::new(clif_type(&param.tp)?));
+    }
+    sig.params
+        .push(AbiParam::new(jit.target_config().pointer_type()));
+    if let Some(ret) = &func.ret {
+        sig.returns.push(AbiParam::new(clif_type(ret)?));
+    }
+
+    Ok(sig)
+}
+
+fn module_error(err: cranelift_module::ModuleError) -> CodegenError {
+    CodegenError::new(&format!("{:?}", err))
+}
+
+#[derive(Debug)]
+struct Compiled {
+    /// Calls the function with arguments and result in 8-byte slots.
+    entry: *const u8,
+    params: Vec<Type>,
+    ret: Option<Type>,
+}
+
+type Entry = extern "C" fn(*const u64, *mut u64, *mut Status);
+
+/**
+ * Functions of a `Module` compiled to native code in-process. Every function
+ * takes a hidden `Status` pointer, so that division by zero, out of bounds
+ * indexing and the like fail the call with the same `RuntimeError` as the VM.
+ */
+pub struct Jit {
+    module: Option<JITModule>,
+    names: Vec<String>,
+    funcs: HashMap<String, Compiled>,
+}
+
+impl Jit {
+    pub fn new(module: &Module) -> CodegenResult<Self> {
+        let mut flags = settings::builder();
+        flags
+            .set("opt_level", "speed")
+            .map_err(|err| CodegenError::new(&err.to_string()))?;
+        let isa = cranelift_native::builder()
+            .map_err(CodegenError::new)?
+            .finish(settings::Flags::new(flags))
+            .map_err(|err| CodegenError::new(&err.to_string()))?;
+        let mut jit = JITModule::new(JITBuilder::with_isa(isa, default_libcall_names()));
+
+        let mut ids = HashMap::new();
+        for func in &module.funcs {
+            let sig = signature(&jit, func)?;
+            let id = jit
+                .declare_function(&format!("ez_{}", func.name), Linkage::Local, &sig)
+                .map_err(module_error)?;
+            ids.insert(func.name.as_str(), (id, func));
+        }
+
+        let mut ctx = jit.make_context();
+        let mut fn_ctx = FunctionBuilderContext::new();
+        let mut entries = Vec::new();
+        for (index, func) in module.funcs.iter().enumerate() {
+            let (id, _) = ids[func.name.as_str()];
+            ctx.func.signature = signature(&jit, func)?;
+            let builder = FunctionBuilder::new(&mut ctx.func, &mut fn_ctx);
+            Lowering::new(&mut jit, &ids, func, index, builder)?.lower()?;
+            jit.define_function(id, &mut ctx).map_err(module_error)?;
+            jit.clear_context(&mut ctx);
+
+            let entry = Self::entry(&mut jit, &mut ctx, &mut fn_ctx, func, id)?;
+            entries.push(entry);
+        }
+
+        jit.finalize_definitions().map_err(module_error)?;
+        let funcs = module
+            .funcs
+            .iter()
+            .zip(entries)
+            .map(|(func, entry)| {
+                let compiled = Compiled {
+                    entry: jit.get_finalized_function(entry),
+                    params: func.params.iter().map(|param| param.tp.clone()).collect(),
+                    ret: func.ret.clone(),
+                };
+                (func.name.clone(), compiled)
+            })
+            .collect();
+
+        Ok(Self {
+            module: Some(jit),
+            names: module.funcs.iter().map(|func| func.name.clone()).collect(),
+            funcs,
+        })
+    }
+
+    /// Compiles the `Entry` of `func`: loads the arguments from their slots,
+    /// calls it and stores the result.
+    fn entry(
+        jit: &mut JITModule,
+        ctx: &mut cranelift_codegen::Context,
+        fn_ctx: &mut FunctionBuilderContext,
+        func: &Function,
+        callee: FuncId,
+    ) -> CodegenResult<FuncId> {
+        let ptr = jit.target_config().pointer_type();
+        let mut sig = jit.make_signature();
+        sig.params.extend(vec![AbiParam::new(ptr); 3]);
+        let id = jit
+            .declare_function(&format!("ez_entry_{}", func.name), Linkage::Local, &sig)
+            .map_err(module_error)?;
+
+        ctx.func.signature = sig;
+        let mut builder = FunctionBuilder::new(&mut ctx.func, fn_ctx);
+        let block = builder.create_block();
+        builder.append_block_params_for_function_params(block);
+        builder.switch_to_block(block);
+        builder.seal_block(block);
+
+        let (args, ret, status) = match builder.block_params(block) {
+            [args, ret, status] => (*args, *ret, *status),
+            _ => unreachable!(),
+        };
+        let mut values = Vec::new();
+        for (i, param) in func.params.iter().enumerate() {
+            let tp = clif_type(&param.tp)?;
+            values.push(
+                builder
+                    .ins()
+                    .load(tp, MemFlags::trusted(), args, 8 * i as i32),
+            );
+        }
+        values.push(status);
+
+        let callee = jit.declare_func_in_func(callee, builder.func);
+        let call = builder.ins().call(callee, &values);
+        if func.ret.is_some() {
+            let result = builder.inst_results(call)[0];
+            builder.ins().store(MemFlags::trusted(), result, ret, 0);
+        }
+        builder.ins().return_(&[]);
+        builder.finalize();
+
+        jit.define_function(id, ctx).map_err(module_error)?;
+        jit.clear_context(ctx);
+        Ok(id)
+    }
+
+    pub fn get(&self, name: &str) -> Option<JitFunction<'_>> {
+        self.funcs
+            .get_key_value(name)
+            .map(|(name, compiled)| JitFunction {
+                jit: self,
+                name,
+                compiled,
+            })
+    }
+
+    pub fn run(&self) -> RunResult<Option<Value>> {
+        self.call("main", &[])
+    }
+
+    pub fn call(&self, name: &str, args: &[Value]) -> RunResult<Option<Value>> {
+        self.get(name)
+            .ok_or_else(|| RuntimeError::UndefinedFunction(name.to_owned()))?
+            .call(args)
+    }
+}
+
+impl Drop for Jit {
+    fn drop(&mut self) {
+        if let Some(jit) = self.module.take() {
+            // No `JitFunction` outlives `self`, so the code is unreachable
+            unsafe { jit.free_memory() }
+        }
+    }
+}
+
+impl fmt::Debug for Jit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Jit").field("funcs", &self.names).finish()
+    }
+}
+
+/// A compiled function, callable for as long as its `Jit` lives.
+#[derive(Debug, Clone, Copy)]
+pub struct JitFunction<'a> {
+    jit: &'a Jit,
+    name: &'a str,
+    compiled: &'a Compiled,
+}
+
+impl<'a> JitFunction<'a> {
+    pub fn params(&self) -> &'a [Type] {
+        &self.compiled.params
+    }
+
+    pub fn ret(&self) -> Option<&'a Type> {
+        self.compiled.ret.as_ref()
+    }
+
+    /// Calls the function, `args` are cast to the parameter types as the VM
+    /// does when passing them.
+    pub fn call(&self, args: &[Value]) -> RunResult<Option<Value>> {
+        let params = &self.compiled.params;
+        if args.len() != params.len() {
+            return Err(RuntimeError::ArityMismatch {
+                func: self.name.to_owned(),
+                expected: params.len(),
+                got: args.len(),
+            });
+        }
+
+        let slots = args
+            .iter()
+            .zip(params)
+            .map(|(arg, tp)| arg.cast(tp).map(|value| to_slot(&value)))
+            .collect::<RunResult<Vec<_>>>()?;
+        let mut ret = 0;
+        let mut status = Status::default();
+
+        // The entry was compiled for exactly these slots
+        let entry: Entry = unsafe { mem::transmute(self.compiled.entry) };
+        entry(slots.as_ptr(), &mut ret, &mut status);
+
+        match status.code {
+            OK => Ok(self.compiled.ret.as_ref().map(|tp| from_slot(ret, tp))),
+            DIVISION_BY_ZERO => Err(RuntimeError::DivisionByZero),
+            OUT_OF_BOUNDS => Err(RuntimeError::IndexOutOfBounds {
+                index: status.index,
+                len: status.len as usize,
+            }),
+            MISSING_RETURN => Err(RuntimeError::MissingReturn(
+                self.jit.names[status.func as usize].clone(),
+            )),
+            STACK_OVERFLOW => Err(RuntimeError::StackOverflow),
+            code => panic!("Bad status {}", code),
+        }
+    }
+}
+
+fn to_slot(value: &Value) -> u64 {
+    match value {
+        Value::Int32(num) => *num as u32 as u64,
+        Value::Int64(num) => *num as u64,
+        Value::Flt32(num) => num.to_bits() as u64,
+        Value::Flt64(num) => num.to_bits(),
+        Value::Char(chr) => *chr as u8 as u64,
+        Value::Bool(value) => *value as u64,
+        value => panic!("Cannot pass {} to compiled code", value),
+    }
+}
+
+fn from_slot(slot: u64, tp: &Type) -> Value {
+    match tp {
+        Type::Int32 => Value::Int32(slot as u32 as i32),
+        Type::Int64 => Value::Int64(slot as i64),
+        Type::Flt32 => Value::Flt32(f32::from_bits(slot as u32)),
+        Type::Flt64 => Value::Flt64(f64::from_bits(slot)),
+        Type::Char => Value::Char(slot as u8 as char),
+        Type::Bool => Value::Bool(slot as u8 != 0),
+        tp => panic!("Cannot return {} from compiled code", tp),
+    }
+}
+
+type Clif = cranelift_codegen::ir::Value;
+
+struct Lowering<'a, 'b> {
+    jit: &'a mut JITModule,
+    ids: &'a HashMap<&'a str, (FuncId, &'a Function)>,
+    func: &'a Function,
+    index: usize,
+    builder: FunctionBuilder<'b>,
+    vars: HashMap<Var, Variable>,
+    arrays: HashMap<usize, StackSlot>,
+    labels: HashMap<usize, Block>,
+    status: Clif,
+    /// Returns zeroes once a callee failed.
+    unwind: Block,
+    args: Vec<(Clif, Type)>,
+}
+
+impl<'a, 'b> Lowering<'a, 'b> {
+    fn new(
+        jit: &'a mut JITModule,
+        ids: &'a HashMap<&'a str, (FuncId, &'a Function)>,
+        func: &'a Function,
+        index: usize,
+        mut builder: FunctionBuilder<'b>,
+    ) -> CodegenResult<Self> {
+        let entry = builder.create_block();
+        builder.append_block_params_for_function_params(entry);
+        builder.switch_to_block(entry);
+        builder.seal_block(entry);
+        let params = builder.block_params(entry).to_vec();
+        let unwind = builder.create_block();
+
+        let mut lowering = Self {
+            jit,
+            ids,
+            func,
+            index,
+            builder,
+            vars: HashMap::new(),
+            arrays: HashMap::new(),
+            labels: HashMap::new(),
+            status: *params.last().unwrap(),
+            unwind,
+            args: Vec::new(),
+        };
+
+        for (param, value) in func.params.iter().zip(&params) {
+            let var = lowering.declare(Var::Ident(param.offset), &param.tp)?;
+            lowering.builder.def_var(var, *value);
+        }
+        for local in &func.locals {
+            if let Type::Array { .. } = local.tp {
+                lowering.array(local.offset, &local.tp);
+            } else {
+                let var = lowering.declare(Var::Ident(local.offset), &local.tp)?;
+                let zero = lowering.zero(&local.tp)?;
+                lowering.builder.def_var(var, zero);
+            }
+        }
+        for inst in &func.code {
+            for operand in operands(inst) {
+                if let Operand::Temp(temp) = operand {
+                    if !lowering.vars.contains_key(&Var::Temp(temp.id)) {
+                        let var = lowering.declare(Var::Temp(temp.id), &temp.tp)?;
+                        let zero = lowering.zero(&temp.tp)?;
+                        lowering.builder.def_var(var, zero);
+                    }
+                }
+            }
+        }
+
+        Ok(lowering)
+    }
+
+    fn declare(&mut self, key: Var, tp: &Type) -> CodegenResult<Variable> {
+        let var = Variable::from_u32(self.vars.len() as u32);
+        self.builder.declare_var(var, clif_type(tp)?);
+        self.vars.insert(key, var);
+        Ok(var)
+    }
+
+    /// Allocates a zeroed stack slot for the array at `offset`.
+    fn array(&mut self, offset: usize, tp: &Type) {
+        let layout = tp.layout(&DataLayout::default());
+        let size = layout.size as u32;
+        let slot = self.builder.create_sized_stack_slot(StackSlotData::new(
+            StackSlotKind::ExplicitSlot,
+            size,
+            layout.align.trailing_zeros() as u8,
+        ));
+        let ptr = self.jit.target_config().pointer_type();
+        let addr = self.builder.ins().stack_addr(ptr, slot, 0);
+        let zero = self.builder.ins().iconst(types::I8, 0);
+        let size = self.builder.ins().iconst(ptr, size as i64);
+        let config = self.jit.target_config();
+        self.builder.call_memset(config, addr, zero, size);
+        self.arrays.insert(offset, slot);
+    }
+
+    fn zero(&mut self, tp: &Type) -> CodegenResult<Clif> {
+        let clif = clif_type(tp)?;
+        Ok(match clif {
+            types::F32 => self.builder.ins().f32const(0.0),
+            types::F64 => self.builder.ins().f64const(0.0),
+            _ => self.builder.ins().iconst(clif, 0),
+        })
+    }
+
+    fn error(&self, msg: &str) -> CodegenError {
+        CodegenError::new(&format!("{} in {}", msg, self.func.name))
+    }
+
+    fn lower(mut self) -> CodegenResult<()> {
+        self.enter();
+        for inst in &self.func.code {
+            self.inst(inst)?;
+        }
+
+        // Running off the end is a bare `ret`
+        self.inst(&Inst::Ret { src: None })?;
+        self.builder.switch_to_block(self.unwind);
+        self.bail()?;
+
+        self.builder.seal_all_blocks();
+        self.builder.finalize();
+        Ok(())
+    }
+
+    fn enter(&mut self) {
+        let depth = self.load_status(types::I32, mem::offset_of!(Status, depth));
+        let full =
+            self.builder
+                .ins()
+                .icmp_imm(IntCC::UnsignedGreaterThanOrEqual, depth, MAX_DEPTH as i64);
+        self.fail_if(full, STACK_OVERFLOW, &[]);
+        let depth = self.builder.ins().iadd_imm(depth, 1);
+        self.store_status(depth, mem::offset_of!(Status, depth));
+    }
+
+    fn load_status(&mut self, tp: types::Type, field: usize) -> Clif {
+        self.builder
+            .ins()
+            .load(tp, MemFlags::trusted(), self.status, offset(field))
+    }
+
+    fn store_status(&mut self, value: Clif, field: usize) {
+        self.builder
+            .ins()
+            .store(MemFlags::trusted(), value, self.status, offset(field));
+    }
+
+    /// Returns zero, callers find out from the status.
+    fn bail(&mut self) -> CodegenResult<()> {
+        match &self.func.ret {
+            Some(tp) => {
+                let zero = self.zero(tp)?;
+                self.builder.ins().return_(&[zero]);
+            }
+            None => {
+                self.builder.ins().return_(&[]);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Fails with `code` when `cond` holds, after saving `fields` to the status.
+    fn fail_if(&mut self, cond: Clif, code: u32, fields: &[(Clif, usize)]) {
+        let fail = self.builder.create_block();
+        let next = self.builder.create_block();
+        self.builder.ins().brif(cond, fail, &[], next, &[]);
+
+        self.builder.switch_to_block(fail);
+        let code = self.builder.ins().iconst(types::I32, code as i64);
+        self.store_status(code, mem::offset_of!(Status, code));
+        for (value, field) in fields {
+            self.store_status(*value, *field);
+        }
+        // Zeroes can't fail to lower, the return type was checked by `signature`
+        self.bail().unwrap();
+
+        self.builder.switch_to_block(next);
+    }
+
+    /// Switches to a fresh block for the code following a jump or return.
+    fn dead(&mut self) {
+        let block = self.builder.create_block();
+        self.builder.switch_to_block(block);
+    }
+
+    fn label(&mut self, label: usize) -> Block {
+        let builder = &mut self.builder;
+        *self
+            .labels
+            .entry(label)
+            .or_insert_with(|| builder.create_block())
+    }
+
+    fn load(&mut self, operand: &Operand) -> CodegenResult<Clif> {
+        match operand {
+            Operand::Ident(_) | Operand::Temp(_) => {
+                let var = operand
+                    .get_var()
+                    .and_then(|var| self.vars.get(&var))
+                    .ok_or_else(|| self.error(&format!("no scalar {}", operand)))?;
+                Ok(self.builder.use_var(*var))
+            }
+            Operand::Cons(cons) => {
+                let ins = self.builder.ins();
+                Ok(match Value::from_cons(cons) {
+                    Value::Int32(num) => ins.iconst(types::I32, num as i64),
+                    Value::Int64(num) => ins.iconst(types::I64, num),
+                    Value::Flt32(num) => ins.f32const(num),
+                    Value::Flt64(num) => ins.f64const(num),
+                    Value::Char(chr) => ins.iconst(types::I8, chr as u8 as i64),
+                    Value::Bool(value) => ins.iconst(types::I8, value as i64),
+                    value => return Err(self.error(&format!("constant {}", value))),
+                })
+            }
+        }
+    }
+
+    /// `value` of type `from` converted to `to`, like `Value::cast`.
+    fn cast(&mut self, value: Clif, from: &Type, to: &Type) -> CodegenResult<Clif> {
+        if from == to {
+            return Ok(value);
+        }
+        if !from.is_numeric() || !to.is_numeric() {
+            return Err(self.error(&format!("cast from {} to {}", from, to)));
+        }
+
+        // Through `i64` or `f64`, as the VM does
+        let ins = self.builder.ins();
+        let wide = match from {
+            Type::Int32 => ins.sextend(types::I64, value),
+            Type::Char => ins.uextend(types::I64, value),
+            Type::Flt32 => ins.fpromote(types::F64, value),
+            _ => value,
+        };
+
+        let ins = self.builder.ins();
+        let value = match (is_float(from), to) {
+            (false, Type::Flt32) => {
+                let wide = ins.fcvt_from_sint(types::F64, wide);
+                self.builder.ins().fdemote(types::F32, wide)
+            }
+            (false, Type::Flt64) => ins.fcvt_from_sint(types::F64, wide),
+            (true, Type::Flt32) => ins.fdemote(types::F32, wide),
+            (true, Type::Flt64) => wide,
+            (float, to) => {
+                let wide = if float {
+                    ins.fcvt_to_sint_sat(types::I64, wide)
+                } else {
+                    wide
+                };
+                match to {
+                    Type::Int64 => wide,
+                    _ => self.builder.ins().ireduce(clif_type(to)?, wide),
+                }
+            }
+        };
+
+        Ok(value)
+    }
+
+    fn store(&mut self, dst: &Operand, value: Clif, tp: &Type) -> CodegenResult<()> {
+        let value = self.cast(value, tp, &dst.get_tp())?;
+        let var = dst
+            .get_var()
+            .and_then(|var| self.vars.get(&var))
+            .ok_or_else(|| self.error(&format!("cannot assign to {}", dst)))?;
+        self.builder.def_var(*var, value);
+        Ok(())
+    }
+
+    /// Loads `operand` converted to `tp`.
+    fn load_as(&mut self, operand: &Operand, tp: &Type) -> CodegenResult<Clif> {
+        let value = self.load(operand)?;
+        self.cast(value, &operand.get_tp(), tp)
+    }
+
+    fn binary(&mut self, op: Opcode, lhs: &Operand, rhs: &Operand) -> CodegenResult<(Clif, Type)> {
+        let (ltp, rtp) = (lhs.get_tp(), rhs.get_tp());
+        let tp = match ltp.upcast(&rtp) {
+            Some(tp) => tp,
+            None if ltp == Type::Bool && rtp == Type::Bool => {
+                let (lhs, rhs) = (self.load(lhs)?, self.load(rhs)?);
+                let value = match op {
+                    Opcode::Eq => self.builder.ins().icmp(IntCC::Equal, lhs, rhs),
+                    Opcode::Ne => self.builder.ins().icmp(IntCC::NotEqual, lhs, rhs),
+                    _ => return Err(self.error(&format!("`{}` of booleans", op))),
+                };
+                return Ok((value, Type::Bool));
+            }
+            None => return Err(self.error(&format!("`{}` of {} and {}", op, ltp, rtp))),
+        };
+
+        if is_float(&tp) {
+            let (lhs, rhs) = (
+                self.load_as(lhs, &Type::Flt64)?,
+                self.load_as(rhs, &Type::Flt64)?,
+            );
+            let ins = self.builder.ins();
+            let value = match op {
+                Opcode::Add => ins.fadd(lhs, rhs),
+                Opcode::Sub => ins.fsub(lhs, rhs),
+                Opcode::Mul => ins.fmul(lhs, rhs),
+                Opcode::Div => ins.fdiv(lhs, rhs),
+                Opcode::Lt => return Ok((ins.fcmp(FloatCC::LessThan, lhs, rhs), Type::Bool)),
+                Opcode::Le => {
+                    return Ok((ins.fcmp(FloatCC::LessThanOrEqual, lhs, rhs), Type::Bool))
+                }
+                Opcode::Gt => return Ok((ins.fcmp(FloatCC::GreaterThan, lhs, rhs), Type::Bool)),
+                Opcode::Ge => {
+                    return Ok((ins.fcmp(FloatCC::GreaterThanOrEqual, lhs, rhs), Type::Bool))
+                }
+                Opcode::Eq => return Ok((ins.fcmp(FloatCC::Equal, lhs, rhs), Type::Bool)),
+                Opcode::Ne => return Ok((ins.fcmp(FloatCC::NotEqual, lhs, rhs), Type::Bool)),
+            };
+            let value = self.cast(value, &Type::Flt64, &tp)?;
+            return Ok((value, tp));
+        }
+
+        let (lhs, rhs) = (
+            self.load_as(lhs, &Type::Int64)?,
+            self.load_as(rhs, &Type::Int64)?,
+        );
+        let cc = match op {
+            Opcode::Lt => Some(IntCC::SignedLessThan),
+            Opcode::Le => Some(IntCC::SignedLessThanOrEqual),
+            Opcode::Gt => Some(IntCC::SignedGreaterThan),
+            Opcode::Ge => Some(IntCC::SignedGreaterThanOrEqual),
+            Opcode::Eq => Some(IntCC::Equal),
+            Opcode::Ne => Some(IntCC::NotEqual),
+            _ => None,
+        };
+        if let Some(cc) = cc {
+            return Ok((self.builder.ins().icmp(cc, lhs, rhs), Type::Bool));
+        }
+
+        let value = match op {
+            Opcode::Add => self.builder.ins().iadd(lhs, rhs),
+            Opcode::Sub => self.builder.ins().isub(lhs, rhs),
+            Opcode::Mul => self.builder.ins().imul(lhs, rhs),
+            _ => {
+                let zero = self.builder.ins().icmp_imm(IntCC::Equal, rhs, 0);
+                self.fail_if(zero, DIVISION_BY_ZERO, &[]);
+
+                // `sdiv` traps on `i64::MIN / -1`, which wraps in the VM
+                let minus_one = self.builder.ins().icmp_imm(IntCC::Equal, rhs, -1);
+                let one = self.builder.ins().iconst(types::I64, 1);
+                let divisor = self.builder.ins().select(minus_one, one, rhs);
+                let quotient = self.builder.ins().sdiv(lhs, divisor);
+                let negated = self.builder.ins().ineg(lhs);
+                self.builder.ins().select(minus_one, negated, quotient)
+            }
+        };
+        let value = self.cast(value, &Type::Int64, &tp)?;
+        Ok((value, tp))
+    }
+
+    /// Address of the element of `array` at `index`, after checking bounds.
+    /// `index` as an `i64`, failing unless `0 <= index < len`.
+    fn check(&mut self, index: &Operand, len: usize) -> CodegenResult<Clif> {
+        let index = self.load_as(index, &Type::Int64)?;
+        let out = self
+            .builder
+            .ins()
+            .icmp_imm(IntCC::UnsignedGreaterThanOrEqual, index, len as i64);
+        let len = self.builder.ins().iconst(types::I64, len as i64);
+        self.fail_if(
+            out,
+            OUT_OF_BOUNDS,
+            &[
+                (index, mem::offset_of!(Status, index)),
+                (len, mem::offset_of!(Status, len)),
+            ],
+        );
+
+        Ok(index)
+    }
+
+    fn element(&mut self, array: &Operand, index: &Operand) -> CodegenResult<(Clif, Type)> {
+        let (slot, of, size) = match (array, array.get_tp()) {
+            (Operand::Ident(ident), tp @ Type::Array { .. }) => (
+                self.arrays[&ident.offset],
+                tp.get_scalar().clone(),
+                tp.get_len(),
+            ),
+            _ => return Err(self.error(&format!("cannot index into {}", array))),
+        };
+
+        let index = self.check(index, size)?;
+        let ptr = self.jit.target_config().pointer_type();
+        let base = self.builder.ins().stack_addr(ptr, slot, 0);
+        let offset = self.builder.ins().imul_imm(index, of.get_stride() as i64);
+        let offset = if ptr == types::I64 {
+            offset
+        } else {
+            self.builder.ins().ireduce(ptr, offset)
+        };
+        Ok((self.builder.ins().iadd(base, offset), of))
+    }
+
+    fn check_args(&self) -> CodegenResult<()> {
+        if self.args.is_empty() {
+            Ok(())
+        } else {
+            Err(self.error("arguments split from their call"))
+        }
+    }
+
+    fn inst(&mut self, inst: &Inst) -> CodegenResult<()> {
+        match inst {
+            Inst::Label(_) | Inst::Jmp { .. } | Inst::JmpT { .. } | Inst::JmpF { .. } => {
+                self.check_args()?
+            }
+            _ => {}
+        }
+
+        match inst {
+            Inst::Label(label) => {
+                let block = self.label(*label);
+                self.builder.ins().jump(block, &[]);
+                self.builder.switch_to_block(block);
+            }
+            Inst::Binary { op, dst, lhs, rhs } => {
+                let (value, tp) = self.binary(*op, lhs, rhs)?;
+                self.store(dst, value, &tp)?;
+            }
+            Inst::Inv { dst, src } => {
+                let tp = Type::Int64
+                    .upcast(&src.get_tp())
+                    .ok_or_else(|| self.error(&format!("`inv` of {}", src)))?;
+                let value = if is_float(&tp) {
+                    let src = self.load_as(src, &Type::Flt64)?;
+                    let zero = self.builder.ins().f64const(0.0);
+                    let value = self.builder.ins().fsub(zero, src);
+                    self.cast(value, &Type::Flt64, &tp)?
+                } else {
+                    let src = self.load_as(src, &Type::Int64)?;
+                    self.builder.ins().ineg(src)
+                };
+                self.store(dst, value, &tp)?;
+            }
+            Inst::Not { dst, src } => {
+                let src = self.load(src)?;
+                let value = self.builder.ins().bxor_imm(src, 1);
+                self.store(dst, value, &Type::Bool)?;
+            }
+            Inst::Mov { dst, src } => {
+                let value = self.load(src)?;
+                self.store(dst, value, &src.get_tp())?;
+            }
+            Inst::Idx { dst, index, array } => {
+                let (addr, tp) = self.element(array, index)?;
+                let value = self
+                    .builder
+                    .ins()
+                    .load(clif_type(&tp)?, MemFlags::trusted(), addr, 0);
+                self.store(dst, value, &tp)?;
+            }
+            Inst::Sto { array, index, src } => {
+                let (addr, tp) = self.element(array, index)?;
+                let value = self.load_as(src, &tp)?;
+                self.builder
+                    .ins()
+                    .store(MemFlags::trusted(), value, addr, 0);
+            }
+            Inst::Chk { index, len } => {
+                self.check(index, *len)?;
+            }
+            Inst::Jmp { label } => {
+                let block = self.label(*label);
+                self.builder.ins().jump(block, &[]);
+                self.dead();
+            }
+            Inst::JmpT { label, test } | Inst::JmpF { label, test } => {
+                let block = self.label(*label);
+                let next = self.builder.create_block();
+                let test = self.load(test)?;
+                if let Inst::JmpT { .. } = inst {
+                    self.builder.ins().brif(test, block, &[], next, &[]);
+                } else {
+                    self.builder.ins().brif(test, next, &[], block, &[]);
+                }
+                self.builder.switch_to_block(next);
+            }
+            Inst::Param { src } => {
+                let value = self.load(src)?;
+                self.args.push((value, src.get_tp()));
+            }
+            Inst::Call { dst, func, nargs } => self.call(dst.as_ref(), func, *nargs)?,
+            Inst::Ret { src } => {
+                self.check_args()?;
+                match (src, &self.func.ret) {
+                    (Some(src), Some(tp)) => {
+                        let value = self.load_as(src, tp)?;
+                        self.leave();
+                        self.builder.ins().return_(&[value]);
+                    }
+                    (None, Some(_)) => {
+                        let code = self.builder.ins().iconst(types::I32, MISSING_RETURN as i64);
+                        let func = self.builder.ins().iconst(types::I32, self.index as i64);
+                        self.store_status(code, mem::offset_of!(Status, code));
+                        self.store_status(func, mem::offset_of!(Status, func));
+                        self.bail()?;
+                    }
+                    (None, None) => {
+                        self.leave();
+                        self.builder.ins().return_(&[]);
+                    }
+                    (Some(_), None) => return Err(self.error("value returned from a procedure")),
+                }
+                self.dead();
+            }
+            Inst::Phi { .. } => return Err(self.error("phi left, translate out of SSA first")),
+        }
+
+        Ok(())
+    }
+
+    fn leave(&mut self) {
+        let depth = self.load_status(types::I32, mem::offset_of!(Status, depth));
+        let depth = self.builder.ins().iadd_imm(depth, -1);
+        self.store_status(depth, mem::offset_of!(Status, depth));
+    }
+
+    fn call(&mut self, dst: Option<&Operand>, name: &str, nargs: usize) -> CodegenResult<()> {
+        let (id, callee) = *self
+            .ids
+            .get(name)
+            .ok_or_else(|| self.error(&format!("call to undefined {}", name)))?;
+        if callee.params.len() != nargs || self.args.len() < nargs {
+            return Err(self.error(&format!("bad call to {}", name)));
+        }
+
+        let args = self.args.split_off(self.args.len() - nargs);
+        let mut values = Vec::new();
+        for ((value, tp), param) in args.into_iter().zip(&callee.params) {
+            values.push(self.cast(value, &tp, &param.tp)?);
+        }
+        values.push(self.status);
+
+        let callee_ref = self.jit.declare_func_in_func(id, self.builder.func);
+        let call = self.builder.ins().call(callee_ref, &values);
+        let result = self.builder.inst_results(call).first().copied();
+
+        let code = self.load_status(types::I32, mem::offset_of!(Status, code));
+        let next = self.builder.create_block();
+        self.builder.ins().brif(code, self.unwind, &[], next, &[]);
+        self.builder.switch_to_block(next);
+
+        match (dst, result, &callee.ret) {
+            (Some(dst), Some(value), Some(tp)) => self.store(dst, value, tp),
+            (Some(_), _, _) => Err(self.error(&format!("{} returns no value", name))),
+            (None, _, _) => Ok(()),
+        }
+    }
+}
+
+fn operands(inst: &Inst) -> Vec<&Operand> {
+    match inst {
+        Inst::Binary { dst, lhs, rhs, .. } => vec![dst, lhs, rhs],
+        Inst::Inv { dst, src } | Inst::Not { dst, src } | Inst::Mov { dst, src } => vec![dst, src],
+        Inst::Idx { dst, index, array } => vec![dst, index, array],
+        Inst::Sto { array, index, src } => vec![array, index, src],
+        Inst::Chk { index, .. } => vec![index],
+        Inst::JmpT { test, .. } | Inst::JmpF { test, .. } => vec![test],
+        Inst::Param { src } => vec![src],
+        Inst::Call { dst, .. } => dst.iter().collect(),
+        Inst::Ret { src } => src.iter().collect(),
+        Inst::Phi { dst, args } => {
+            let mut operands = vec![dst];
+            operands.extend(args.iter().map(|(_, arg)| arg));
+            operands
+        }
+        Inst::Label(_) | Inst::Jmp { .. } => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::parse_module;
+    use crate::vm::machine::tests::for_each_program;
+    use crate::vm::Machine;
+
+    #[test]
+    fn compiled_programs() {
+        for_each_program(|_, module, expected| {
+            assert_eq!(Jit::new(module).unwrap().run(), Ok(expected));
+        });
+    }
+
+    #[test]
+    fn casts_and_checks() {
+        // Floats saturate into i64 before they're truncated, and each index of
+        // a nested array is checked against its own dimension
+        let module = parse_module(
+            "fn trunc(x: f64) -> i32\n\tvar y: i32\n\tmov y x\n\tret y\n\
+             fn get(i: i32, j: i32) -> i32\n\tvar m: [3][4]i32\n\
+             \tchk i 3\n\tchk j 4\n\tmul __t0 i 4\n\tadd __t1 __t0 j\n\
+             \tidx __t2 __t1 m\n\tret __t2\n",
+        )
+        .unwrap();
+        let jit = Jit::new(&module).unwrap();
+        let machine = |name: &str, args: &[Value]| Machine::new(&module).call(name, args);
+
+        for num in &[1e19, -1e19, 1e10, -3.7, f64::NAN] {
+            let args = [Value::Flt64(*num)];
+            assert_eq!(jit.call("trunc", &args), machine("trunc", &args));
+        }
+        for (i, j) in &[(2, 3), (0, 5), (3, 0)] {
+            let args = [Value::Int32(*i), Value::Int32(*j)];
+            assert_eq!(jit.call("get", &args), machine("get", &args));
+        }
+        assert_eq!(
+            jit.call("get", &[Value::Int32(0), Value::Int32(5)]),
+            Err(RuntimeError::IndexOutOfBounds { index: 5, len: 4 })
+        );
+    }
+
+    #[test]
+    fn machine_semantics() {
+        let module = parse_module(
+            "fn half(x: f32, n: i64) -> f64\n\tdiv __t0 x n\n\tret __t0\n\
+             fn main() -> i32\n\tvar a: [3]char\n\tvar big: i32\n\
+             \tmov big 2147483647\n\tadd big big 2\n\
+             \tsto a 2 300\n\tidx __t1 2 a\n\
+             \tparam 5.5\n\tparam 2\n\tcall __t2 half 2\n\
+             \tinv __t3 __t2\n\tmul __t4 __t3 10\n\
+             \tadd __t5 big __t1\n\tadd __t6 __t5 __t4\n\tret __t6\n\
+             fn div(a: i64, b: i64) -> i64\n\tdiv __t0 a b\n\tret __t0\n\
+             fn get(i: i32) -> f32\n\tvar v: [2]f32\n\tsto v 1 2.5\n\tidx __t0 i v\n\tret __t0\n\
+             fn none() -> i32\n\tret\n\
+             fn deep(n: i32) -> i32\n\tparam n\n\tcall __t0 deep 1\n\tret __t0\n",
+        )
+        .unwrap();
+        let jit = Jit::new(&module).unwrap();
+        let machine = |name: &str, args: &[Value]| Machine::new(&module).call(name, args);
+
+        assert_eq!(jit.run(), Machine::new(&module).run());
+        assert_eq!(jit.run(), Ok(Some(Value::Int32(-2147483630))));
+
+        let half = jit.get("half").unwrap();
+        assert_eq!(half.params(), &[Type::Flt32, Type::Int64]);
+        assert_eq!(half.ret(), Some(&Type::Flt64));
+        let args = [Value::Flt64(7.25), Value::Int32(2)];
+        assert_eq!(half.call(&args), machine("half", &args));
+
+        for args in &[
+            [Value::Int64(i64::MIN), Value::Int64(-1)],
+            [Value::Int64(-7), Value::Int64(2)],
+            [Value::Int64(7), Value::Int64(0)],
+        ] {
+            assert_eq!(jit.call("div", args), machine("div", args));
+        }
+        for index in &[1, 2, -1] {
+            let args = [Value::Int32(*index)];
+            assert_eq!(jit.call("get", &args), machine("get", &args));
+        }
+        assert_eq!(jit.call("none", &[]), machine("none", &[]));
+        assert_eq!(
+            jit.call("deep", &[Value::Int32(0)]),
+            Err(RuntimeError::StackOverflow)
+        );
+
+        assert_eq!(
+            jit.call("get", &[]),
+            Err(RuntimeError::ArityMismatch {
+                func: "get".to_owned(),
+                expected: 1,
+                got: 0
+            })
+        );
+        assert_eq!(
+            jit.call("nope", &[]),
+            Err(RuntimeError::UndefinedFunction("nope".to_owned()))
+        );
+    }
+}
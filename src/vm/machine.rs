@@ -0,0 +1,537 @@
+use std::collections::HashMap;
+
+use crate::error::RuntimeError;
+use crate::interp::{RunResult, Value};
+use crate::ir::{Function, Inst, Module, Operand};
+use crate::sym::Type;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Stats {
+    /// Executed instructions, labels excluded.
+    pub steps: usize,
+    /// Taken jumps, conditional or not.
+    pub jumps: usize,
+    pub calls: usize,
+    pub max_depth: usize,
+    /// Executed instructions per opcode.
+    pub opcodes: HashMap<String, usize>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum State {
+    Running,
+    Halted(Option<Value>),
+}
+
+#[derive(Debug)]
+struct Frame<'a> {
+    func: &'a Function,
+    pc: usize,
+    locals: HashMap<usize, Value>,
+    temps: HashMap<usize, Value>,
+    ret: Option<Operand>,
+}
+
+impl<'a> Frame<'a> {
+    fn load(&self, operand: &Operand) -> Value {
+        match operand {
+            Operand::Ident(ident) => self
+                .locals
+                .get(&ident.offset)
+                .cloned()
+                .unwrap_or_else(|| Value::zero(&ident.tp)),
+            Operand::Temp(temp) => self
+                .temps
+                .get(&temp.id)
+                .cloned()
+                .unwrap_or_else(|| Value::zero(&temp.tp)),
+            Operand::Cons(cons) => Value::from_cons(cons),
+        }
+    }
+
+    fn store(&mut self, dst: &Operand, value: Value) -> RunResult<()> {
+        let value = value.cast(&dst.get_tp())?;
+        match dst {
+            Operand::Ident(ident) => self.locals.insert(ident.offset, value),
+            Operand::Temp(temp) => self.temps.insert(temp.id, value),
+            Operand::Cons(cons) => panic!("Cannot assign to {}", cons),
+        };
+
+        Ok(())
+    }
+}
+
+/**
+ * Register machine for the three-address code of a `Module`. Locals live in
+ * the frame at their `Ident.offset`, temporaries at their `Temp.id`.
+ */
+#[derive(Debug)]
+pub struct Machine<'a> {
+    module: &'a Module,
+    labels: HashMap<&'a str, HashMap<usize, usize>>,
+    frames: Vec<Frame<'a>>,
+    params: Vec<Value>,
+    stats: Stats,
+}
+
+impl<'a> Machine<'a> {
+    const MAX_DEPTH: usize = 4096;
+
+    pub fn new(module: &'a Module) -> Self {
+        let labels = module
+            .funcs
+            .iter()
+            .map(|func| {
+                let labels = func
+                    .code
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(pc, inst)| match inst {
+                        Inst::Label(label) => Some((*label, pc)),
+                        _ => None,
+                    })
+                    .collect();
+
+                (func.name.as_str(), labels)
+            })
+            .collect();
+
+        Self {
+            module,
+            labels,
+            frames: Vec::new(),
+            params: Vec::new(),
+            stats: Stats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    /// Function and instruction that the next `step` executes.
+    pub fn position(&self) -> Option<(&str, usize)> {
+        self.frames
+            .last()
+            .map(|frame| (frame.func.name.as_str(), frame.pc))
+    }
+
+    pub fn run(&mut self) -> RunResult<Option<Value>> {
+        self.call("main", &[])
+    }
+
+    pub fn call(&mut self, name: &str, args: &[Value]) -> RunResult<Option<Value>> {
+        self.start(name, args)?;
+        loop {
+            if let State::Halted(value) = self.step()? {
+                return Ok(value);
+            }
+        }
+    }
+
+    /// Resets the machine so that the following `step`s execute `name`.
+    pub fn start(&mut self, name: &str, args: &[Value]) -> RunResult<()> {
+        self.frames.clear();
+        self.params.clear();
+        self.enter(name, args.to_vec(), None)
+    }
+
+    fn enter(&mut self, name: &str, args: Vec<Value>, ret: Option<Operand>) -> RunResult<()> {
+        let module = self.module;
+        let func = module
+            .get(name)
+            .ok_or_else(|| RuntimeError::UndefinedFunction(name.to_owned()))?;
+
+        if args.len() != func.params.len() {
+            return Err(RuntimeError::ArityMismatch {
+                func: name.to_owned(),
+                expected: func.params.len(),
+                got: args.len(),
+            });
+        }
+
+        if self.frames.len() >= Self::MAX_DEPTH {
+            return Err(RuntimeError::StackOverflow);
+        }
+
+        let mut locals = HashMap::new();
+        for local in &func.locals {
+            locals.insert(local.offset, Value::zero(&local.tp));
+        }
+
+        for (param, arg) in func.params.iter().zip(args) {
+            locals.insert(param.offset, arg.cast(&param.tp)?);
+        }
+
+        self.frames.push(Frame {
+            func,
+            pc: 0,
+            locals,
+            temps: HashMap::new(),
+            ret,
+        });
+
+        self.stats.calls += 1;
+        self.stats.max_depth = self.stats.max_depth.max(self.frames.len());
+        Ok(())
+    }
+
+    fn leave(&mut self, value: Option<Value>) -> RunResult<State> {
+        let frame = self.frames.pop().expect("No frame to leave");
+        let value = match (&frame.func.ret, value) {
+            (None, _) => None,
+            (Some(tp), Some(value)) => Some(value.cast(tp)?),
+            (Some(_), None) => return Err(RuntimeError::MissingReturn(frame.func.name.clone())),
+        };
+
+        match (self.frames.last_mut(), &frame.ret) {
+            (None, _) => Ok(State::Halted(value)),
+            (Some(caller), Some(dst)) => {
+                let value =
+                    value.ok_or_else(|| RuntimeError::MissingReturn(frame.func.name.clone()))?;
+                caller.store(dst, value)?;
+                Ok(State::Running)
+            }
+            (Some(_), None) => Ok(State::Running),
+        }
+    }
+
+    fn jump(&mut self, label: usize) {
+        let frame = self.frames.last_mut().unwrap();
+        frame.pc = self.labels[frame.func.name.as_str()][&label];
+        self.stats.jumps += 1;
+    }
+
+    fn element(array: &Operand) -> Type {
+        match array.get_tp() {
+            tp @ Type::Array { .. } => tp.get_scalar().clone(),
+            tp => panic!("Cannot index into {}", tp),
+        }
+    }
+
+    fn position_in(len: usize, index: &Value) -> RunResult<usize> {
+        let index = index.as_index()?;
+        if index < 0 || index as usize >= len {
+            Err(RuntimeError::IndexOutOfBounds { index, len })
+        } else {
+            Ok(index as usize)
+        }
+    }
+
+    /// Executes the next instruction, labels are skipped over.
+    pub fn step(&mut self) -> RunResult<State> {
+        let inst = loop {
+            let frame = match self.frames.last_mut() {
+                Some(frame) => frame,
+                None => return Ok(State::Halted(None)),
+            };
+
+            match frame.func.code.get(frame.pc) {
+                Some(Inst::Label(_)) => frame.pc += 1,
+                Some(inst) => {
+                    frame.pc += 1;
+                    break inst;
+                }
+                None => return self.leave(None),
+            }
+        };
+
+        self.stats.steps += 1;
+        *self.stats.opcodes.entry(inst.get_opcode()).or_insert(0) += 1;
+
+        let frame = self.frames.last_mut().unwrap();
+        match inst {
+            Inst::Label(_) => unreachable!(),
+
+            Inst::Binary { op, dst, lhs, rhs } => {
+                let (lhs, rhs) = (frame.load(lhs), frame.load(rhs));
+                let value = if op.is_rel() {
+                    Value::Bool(Value::compare(&op.get_token(), &lhs, &rhs)?)
+                } else {
+                    Value::arithm(&op.get_token(), &lhs, &rhs)?
+                };
+
+                frame.store(dst, value)?;
+            }
+
+            Inst::Inv { dst, src } => {
+                let value = frame.load(src).negate()?;
+                frame.store(dst, value)?;
+            }
+
+            Inst::Not { dst, src } => {
+                let value = Value::Bool(!frame.load(src).as_bool()?);
+                frame.store(dst, value)?;
+            }
+
+            Inst::Mov { dst, src } => {
+                let value = frame.load(src);
+                frame.store(dst, value)?;
+            }
+
+            // Reads the element in place, an array is never copied out
+            Inst::Idx { dst, index, array } => {
+                let index = frame.load(index);
+                let value = match array {
+                    Operand::Ident(ident) => match frame.locals.get(&ident.offset) {
+                        Some(Value::Array(elems)) => {
+                            elems[Self::position_in(elems.len(), &index)?].clone()
+                        }
+                        _ => {
+                            Self::position_in(ident.tp.get_len(), &index)?;
+                            Value::zero(&Self::element(array))
+                        }
+                    },
+                    other => panic!("Cannot index into {}", other),
+                };
+
+                frame.store(dst, value)?;
+            }
+
+            Inst::Sto { array, index, src } => {
+                let value = frame.load(src).cast(&Self::element(array))?;
+                let index = frame.load(index);
+
+                let offset = match array {
+                    Operand::Ident(ident) => ident.offset,
+                    other => panic!("Cannot index into {}", other),
+                };
+
+                let array = frame
+                    .locals
+                    .entry(offset)
+                    .or_insert_with(|| Value::zero(&array.get_tp()));
+
+                if let Value::Array(elems) = array {
+                    let pos = Self::position_in(elems.len(), &index)?;
+                    elems[pos] = value;
+                }
+            }
+
+            Inst::Chk { index, len } => {
+                Self::position_in(*len, &frame.load(index))?;
+            }
+
+            Inst::Jmp { label } => self.jump(*label),
+
+            Inst::JmpT { label, test } => {
+                if frame.load(test).as_bool()? {
+                    self.jump(*label);
+                }
+            }
+
+            Inst::JmpF { label, test } => {
+                if !frame.load(test).as_bool()? {
+                    self.jump(*label);
+                }
+            }
+
+            Inst::Param { src } => {
+                let value = frame.load(src);
+                self.params.push(value);
+            }
+
+            Inst::Call { dst, func, nargs } => {
+                let at = self.params.len().saturating_sub(*nargs);
+                let args = self.params.split_off(at);
+                self.enter(func, args, dst.clone())?;
+            }
+
+            Inst::Ret { src } => {
+                let value = src.as_ref().map(|src| frame.load(src));
+                return self.leave(value);
+            }
+
+            Inst::Phi { .. } => panic!("Cannot execute phi, translate out of SSA first"),
+        }
+
+        Ok(State::Running)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::ast::node::tests::Buffer;
+    use crate::ast::{Expr, Index, Program, Stmt};
+    use crate::interp::interpreter::tests::{
+        arithm, elem, fact_program, ident, int, loops_program, main, matrix_program, set, var,
+    };
+    use crate::interp::Interpreter;
+    use crate::ir;
+    use crate::lex::Token;
+    use crate::opt::{OptLevel, PassManager};
+
+    pub(crate) fn compile(program: &Program) -> Module {
+        let buf = Buffer::default();
+        program.gen(&mut buf.visitor());
+        ir::parse_module(&buf.contents()).expect("Generated invalid code")
+    }
+
+    /**
+     * Compiles each sample program at `O0` and `O2`, and hands `check` a name
+     * for the module, the module and the result of the interpreter, for the
+     * backends to compare with theirs.
+     */
+    pub(crate) fn for_each_program(mut check: impl FnMut(&str, &Module, Option<Value>)) {
+        let programs = [
+            ("fact", fact_program()),
+            ("loops", loops_program()),
+            ("matrix", matrix_program()),
+        ];
+
+        for (name, program) in &programs {
+            let expected = Interpreter::new(program).run().expect("Sample failed");
+
+            for level in &[OptLevel::O0, OptLevel::O2] {
+                let mut module = compile(program);
+                PassManager::new(*level)
+                    .run(&mut module)
+                    .expect("Optimization failed");
+                check(&format!("{}-{:?}", name, level), &module, expected.clone());
+            }
+        }
+    }
+
+    #[test]
+    fn matches_interpreter() {
+        for_each_program(|_, module, expected| {
+            assert_eq!(Machine::new(module).run(), Ok(expected));
+        });
+    }
+
+    #[test]
+    fn evaluation_order() {
+        let a = ident("a", "[2]f64".parse().unwrap(), 0);
+        let x = ident("x", Type::Int32, 16);
+        let div = || arithm(Token::Divide, int(1), var(&x));
+        let run = |stmt| {
+            let program = Program {
+                funcs: vec![main(vec![a.clone(), x.clone()], vec![stmt])],
+            };
+            let result = Machine::new(&compile(&program)).run();
+            assert_eq!(result, Interpreter::new(&program).run());
+            result
+        };
+
+        // The index first, then the value, then the bounds
+        assert_eq!(
+            run(Stmt::SetElem {
+                index: elem(&a, div()),
+                expr: Expr::Index(elem(&a, int(5))),
+            }),
+            Err(RuntimeError::DivisionByZero)
+        );
+        assert_eq!(
+            run(Stmt::SetElem {
+                index: elem(&a, int(5)),
+                expr: div(),
+            }),
+            Err(RuntimeError::DivisionByZero)
+        );
+        assert_eq!(
+            run(Stmt::SetElem {
+                index: elem(&a, int(5)),
+                expr: Expr::Index(elem(&a, int(1))),
+            }),
+            Err(RuntimeError::IndexOutOfBounds { index: 5, len: 2 })
+        );
+    }
+
+    #[test]
+    fn inner_index_bounds() {
+        let m = ident("m", "[3][4]i32".parse().unwrap(), 0);
+        let j = ident("j", Type::Int32, 48);
+        let run = |stmt| {
+            let program = Program {
+                funcs: vec![main(
+                    vec![m.clone(), j.clone()],
+                    vec![set(&j, int(5)), stmt, Stmt::Return(Some(int(0)))],
+                )],
+            };
+            let expected = Interpreter::new(&program).run();
+
+            for level in &[OptLevel::O0, OptLevel::O2] {
+                let mut module = compile(&program);
+                PassManager::new(*level).run(&mut module).unwrap();
+                assert_eq!(Machine::new(&module).run(), expected);
+            }
+            expected
+        };
+
+        // m[0][5] is m[1][1] once flattened, but 5 is past the end of a row
+        let error = Err(RuntimeError::IndexOutOfBounds { index: 5, len: 4 });
+        let at = |col| Index::new(&m, &[int(0), col]);
+        assert_eq!(run(set(&j, Expr::Index(at(var(&j))))), error);
+        assert_eq!(run(set(&j, Expr::Index(at(int(5))))), error);
+        assert_eq!(
+            run(Stmt::SetElem {
+                index: at(var(&j)),
+                expr: int(1),
+            }),
+            error
+        );
+        assert_eq!(
+            run(set(&j, Expr::Index(at(int(3))))),
+            Ok(Some(Value::Int64(0)))
+        );
+    }
+
+    #[test]
+    fn step_and_stats() -> RunResult<()> {
+        let module = compile(&fact_program());
+        let mut machine = Machine::new(&module);
+        machine.start("fact", &[Value::Int32(4)])?;
+
+        let mut steps = 0;
+        let value = loop {
+            assert!(machine.position().is_some());
+            steps += 1;
+            if let State::Halted(value) = machine.step()? {
+                break value;
+            }
+        };
+
+        assert_eq!(value, Some(Value::Int32(24)));
+        assert_eq!(machine.position(), None);
+        assert_eq!(machine.stats().steps, steps);
+        assert_eq!(machine.stats().calls, 4);
+        assert_eq!(machine.stats().max_depth, 4);
+        assert_eq!(machine.stats().opcodes["call"], 3);
+        assert_eq!(machine.stats().opcodes["ret"], 4);
+
+        Ok(())
+    }
+
+    #[test]
+    fn runtime_errors() -> Result<(), Box<dyn std::error::Error>> {
+        let code = "fn div(a: i32, b: i32) -> i32\n\tdiv __t0 a b\n\tret __t0\n\
+                    fn get(i: i32) -> f32\n\tvar v: [2]f32\n\tsto v 1 2.5\n\tidx __t0 i v\n\tret __t0\n\
+                    fn none() -> i32\n\tret\n";
+        let module = ir::parse_module(code)?;
+        let mut machine = Machine::new(&module);
+
+        assert_eq!(
+            machine.call("div", &[Value::Int32(7), Value::Int32(2)])?,
+            Some(Value::Int32(3))
+        );
+        assert_eq!(
+            machine.call("div", &[Value::Int32(7), Value::Int32(0)]),
+            Err(RuntimeError::DivisionByZero)
+        );
+        assert_eq!(
+            machine.call("get", &[Value::Int32(1)])?,
+            Some(Value::Flt32(2.5))
+        );
+        assert_eq!(
+            machine.call("get", &[Value::Int32(2)]),
+            Err(RuntimeError::IndexOutOfBounds { index: 2, len: 2 })
+        );
+        assert_eq!(
+            machine.call("none", &[]),
+            Err(RuntimeError::MissingReturn("none".to_owned()))
+        );
+
+        Ok(())
+    }
+}
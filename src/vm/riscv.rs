@@ -0,0 +1,792 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::num::FpCategory;
+
+use crate::codegen::{emit_riscv_image, CodegenResult, RegClass};
+use crate::error::{CodegenError, RuntimeError, SimError};
+use crate::interp::Value;
+use crate::ir::Module;
+use crate::sym::Type;
+
+pub type SimResult<T> = Result<T, SimError>;
+
+/// Where the code is loaded, lower addresses are unmapped.
+const BASE: u64 = 0x1_0000;
+/// Return address of the outermost call, the simulation stops when it jumps there.
+const HALT: u64 = 0;
+const STACK_SIZE: usize = 1 << 20;
+/// Instructions a call may run before it's stopped.
+const STEP_LIMIT: u64 = 1 << 32;
+
+/// Single precision values are NaN-boxed in the 64 bits registers.
+const BOX: u64 = 0xffff_ffff_0000_0000;
+const CANONICAL_NAN: u32 = 0x7fc0_0000;
+
+const RA: usize = 1;
+const SP: usize = 2;
+const A0: usize = 10;
+const FA0: usize = 10;
+
+#[derive(Debug, Clone)]
+struct Signature {
+    params: Vec<Type>,
+    ret: Option<Type>,
+}
+
+fn sext32(num: u64) -> u64 {
+    num as i32 as i64 as u64
+}
+
+fn min(a: f64, b: f64, max: bool) -> f64 {
+    match (a.is_nan(), b.is_nan()) {
+        (true, true) => f64::NAN,
+        (true, false) => b,
+        (false, true) => a,
+        // -0.0 is below 0.0
+        _ if a == b && (a.is_sign_negative() != max) => a,
+        _ if a == b => b,
+        _ if max => a.max(b),
+        _ => a.min(b),
+    }
+}
+
+/// Bit of `fclass` for a value of category `category`.
+fn class_bit(category: FpCategory, negative: bool, quiet: bool) -> u64 {
+    let pos = match (category, negative) {
+        (FpCategory::Infinite, true) => 0,
+        (FpCategory::Normal, true) => 1,
+        (FpCategory::Subnormal, true) => 2,
+        (FpCategory::Zero, true) => 3,
+        (FpCategory::Zero, false) => 4,
+        (FpCategory::Subnormal, false) => 5,
+        (FpCategory::Normal, false) => 6,
+        (FpCategory::Infinite, false) => 7,
+        (FpCategory::Nan, _) if quiet => 9,
+        (FpCategory::Nan, _) => 8,
+    };
+
+    1 << pos
+}
+
+/**
+ * Simulator of an RV64IMFD hart running the functions of a `Module`, as
+ * compiled by `emit_riscv`. The code is loaded at a fixed address, followed
+ * by the stack, and every call starts from fresh registers. Division by zero
+ * stops it with a trap, and stores outside of the stack with a memory fault.
+ *
+ * Floating point arithmetic always rounds to nearest, only conversions to
+ * integers honour the rounding mode of the instruction. There are no CSRs,
+ * `dyn` stands for round to nearest.
+ */
+pub struct Simulator {
+    funcs: HashMap<String, Signature>,
+    symbols: HashMap<String, u64>,
+    /// Code then stack, from `BASE`.
+    mem: Vec<u8>,
+    code_end: u64,
+    x: [u64; 32],
+    f: [u64; 32],
+    pc: u64,
+    steps: u64,
+    limit: u64,
+}
+
+impl Simulator {
+    pub fn new(module: &Module) -> CodegenResult<Self> {
+        let image = emit_riscv_image(module)?;
+
+        let mut funcs = HashMap::new();
+        for func in &module.funcs {
+            let params: Vec<_> = func.params.iter().map(|param| param.tp.clone()).collect();
+            if let Some(tp) = params
+                .iter()
+                .chain(&func.ret)
+                .find(|tp| RegClass::of(tp).is_none())
+            {
+                return Err(CodegenError::new(&format!(
+                    "{} takes or returns values of type {}, they don't fit a register",
+                    func.name, tp
+                )));
+            }
+
+            let signature = Signature {
+                params,
+                ret: func.ret.clone(),
+            };
+            funcs.insert(func.name.clone(), signature);
+        }
+
+        let code_end = BASE + image.code.len() as u64;
+        let mut mem = image.code;
+        mem.resize(mem.len().div_ceil(16) * 16 + STACK_SIZE, 0);
+
+        Ok(Self {
+            funcs,
+            symbols: image.symbols,
+            mem,
+            code_end,
+            x: [0; 32],
+            f: [0; 32],
+            pc: HALT,
+            steps: 0,
+            limit: STEP_LIMIT,
+        })
+    }
+
+    /// Instructions run by the last call.
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    pub fn set_step_limit(&mut self, limit: u64) {
+        self.limit = limit;
+    }
+
+    pub fn run(&mut self) -> SimResult<Option<Value>> {
+        self.call("main", &[])
+    }
+
+    /// Calls a function, `args` are cast to the parameter types as the VM
+    /// does when passing them.
+    pub fn call(&mut self, name: &str, args: &[Value]) -> SimResult<Option<Value>> {
+        let signature = self
+            .funcs
+            .get(name)
+            .cloned()
+            .ok_or_else(|| RuntimeError::UndefinedFunction(name.to_owned()))?;
+        if args.len() != signature.params.len() {
+            return Err(RuntimeError::ArityMismatch {
+                func: name.to_owned(),
+                expected: signature.params.len(),
+                got: args.len(),
+            }
+            .into());
+        }
+
+        self.x = [0; 32];
+        self.f = [0; 32];
+
+        // `emit_riscv` only compiles functions with arguments in registers
+        let (mut ints, mut floats) = (A0, FA0);
+        for (arg, tp) in args.iter().zip(&signature.params) {
+            match arg.cast(tp)? {
+                Value::Flt32(num) => self.f[floats] = BOX | num.to_bits() as u64,
+                Value::Flt64(num) => self.f[floats] = num.to_bits(),
+                Value::Int32(num) => self.x[ints] = num as i64 as u64,
+                Value::Int64(num) => self.x[ints] = num as u64,
+                Value::Char(chr) => self.x[ints] = chr as u64,
+                Value::Bool(value) => self.x[ints] = value as u64,
+                value => {
+                    return Err(RuntimeError::TypeMismatch {
+                        expected: tp.clone(),
+                        got: value.to_string(),
+                    }
+                    .into())
+                }
+            }
+
+            match RegClass::of(tp) {
+                Some(RegClass::Float) => floats += 1,
+                _ => ints += 1,
+            }
+        }
+
+        self.x[RA] = HALT;
+        self.x[SP] = BASE + self.mem.len() as u64;
+        self.pc = BASE + self.symbols[&format!("ez_{}", name)];
+        self.steps = 0;
+
+        while self.pc != HALT {
+            if self.steps == self.limit {
+                return Err(SimError::StepLimit(self.steps));
+            }
+            self.step()?;
+            self.steps += 1;
+        }
+
+        let (int, float) = (self.x[A0], self.f[FA0]);
+        Ok(signature.ret.map(|tp| match tp {
+            Type::Int32 => Value::Int32(int as i32),
+            Type::Int64 => Value::Int64(int as i64),
+            Type::Char => Value::Char(int as u8 as char),
+            Type::Bool => Value::Bool(int != 0),
+            Type::Flt32 => Value::Flt32(f32::from_bits(float as u32)),
+            Type::Flt64 => Value::Flt64(f64::from_bits(float)),
+            tp => unreachable!("{} doesn't fit a register", tp),
+        }))
+    }
+
+    /// Index in `mem` of `size` bytes at `addr`, only the stack is writable.
+    fn index(&self, addr: u64, size: u64, write: bool) -> SimResult<usize> {
+        let start = if write { self.code_end } else { BASE };
+        let end = BASE + self.mem.len() as u64;
+        match addr.checked_add(size) {
+            Some(last) if addr >= start && last <= end => Ok((addr - BASE) as usize),
+            _ => Err(SimError::MemoryFault { pc: self.pc, addr }),
+        }
+    }
+
+    fn load(&self, addr: u64, size: u64) -> SimResult<u64> {
+        let index = self.index(addr, size, false)?;
+        let mut bytes = [0; 8];
+        bytes[..size as usize].copy_from_slice(&self.mem[index..index + size as usize]);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    fn store(&mut self, addr: u64, size: u64, value: u64) -> SimResult<()> {
+        let index = self.index(addr, size, true)?;
+        self.mem[index..index + size as usize]
+            .copy_from_slice(&value.to_le_bytes()[..size as usize]);
+        Ok(())
+    }
+
+    fn set_x(&mut self, reg: usize, value: u64) {
+        if reg != 0 {
+            self.x[reg] = value;
+        }
+    }
+
+    /// Value of a floating point register, widened to `f64` when it's single
+    /// precision. Single precision values that aren't NaN-boxed read as NaN.
+    fn get_f(&self, reg: usize, double: bool) -> f64 {
+        if double {
+            f64::from_bits(self.f[reg])
+        } else {
+            self.get_s(reg) as f64
+        }
+    }
+
+    fn get_s(&self, reg: usize) -> f32 {
+        f32::from_bits(self.get_raw_s(reg))
+    }
+
+    fn get_raw_s(&self, reg: usize) -> u32 {
+        match self.f[reg] {
+            bits if bits & BOX == BOX => bits as u32,
+            _ => CANONICAL_NAN,
+        }
+    }
+
+    /// Stores `value` in a floating point register, rounded to `f32` when
+    /// it's single precision.
+    fn set_f(&mut self, reg: usize, double: bool, value: f64) {
+        self.f[reg] = if double {
+            value.to_bits()
+        } else {
+            BOX | (value as f32).to_bits() as u64
+        };
+    }
+
+    fn step(&mut self) -> SimResult<()> {
+        let pc = self.pc;
+        if pc < BASE || pc >= self.code_end || pc & 3 != 0 {
+            return Err(SimError::MemoryFault { pc, addr: pc });
+        }
+        let word = self.load(pc, 4)? as u32;
+
+        let illegal = SimError::IllegalInstruction { pc, word };
+        let rd = (word >> 7 & 31) as usize;
+        let funct3 = word >> 12 & 7;
+        let rs1 = (word >> 15 & 31) as usize;
+        let rs2 = (word >> 20 & 31) as usize;
+        let funct7 = word >> 25;
+        let (a, b) = (self.x[rs1], self.x[rs2]);
+
+        let imm_i = (word as i32 >> 20) as u64;
+        let imm_s = ((word as i32 >> 25) << 5 | (word >> 7 & 31) as i32) as u64;
+        let imm_b = ((word as i32 >> 31) << 12
+            | ((word >> 7 & 1) << 11) as i32
+            | ((word >> 25 & 0x3f) << 5) as i32
+            | ((word >> 8 & 0xf) << 1) as i32) as u64;
+        let imm_u = (word & 0xffff_f000) as i32 as u64;
+        let imm_j = ((word as i32 >> 31) << 20
+            | (word & 0xf_f000) as i32
+            | ((word >> 20 & 1) << 11) as i32
+            | ((word >> 21 & 0x3ff) << 1) as i32) as u64;
+
+        let mut next = pc.wrapping_add(4);
+        match word & 0x7f {
+            0x37 => self.set_x(rd, imm_u),
+            0x17 => self.set_x(rd, pc.wrapping_add(imm_u)),
+            0x6f => {
+                self.set_x(rd, next);
+                next = pc.wrapping_add(imm_j);
+            }
+            0x67 if funct3 == 0 => {
+                self.set_x(rd, next);
+                next = a.wrapping_add(imm_i) & !1;
+            }
+
+            0x63 => {
+                let taken = match funct3 {
+                    0 => a == b,
+                    1 => a != b,
+                    4 => (a as i64) < (b as i64),
+                    5 => (a as i64) >= (b as i64),
+                    6 => a < b,
+                    7 => a >= b,
+                    _ => return Err(illegal),
+                };
+                if taken {
+                    next = pc.wrapping_add(imm_b);
+                }
+            }
+
+            0x03 => {
+                let addr = a.wrapping_add(imm_i);
+                let value = match funct3 {
+                    0 => self.load(addr, 1)? as i8 as u64,
+                    1 => self.load(addr, 2)? as i16 as u64,
+                    2 => sext32(self.load(addr, 4)?),
+                    3 => self.load(addr, 8)?,
+                    4 => self.load(addr, 1)?,
+                    5 => self.load(addr, 2)?,
+                    6 => self.load(addr, 4)?,
+                    _ => return Err(illegal),
+                };
+                self.set_x(rd, value);
+            }
+
+            0x23 if funct3 < 4 => self.store(a.wrapping_add(imm_s), 1 << funct3, b)?,
+
+            0x13 => {
+                let shamt = word >> 20 & 63;
+                let value = match (funct3, word >> 26) {
+                    (0, _) => a.wrapping_add(imm_i),
+                    (2, _) => ((a as i64) < (imm_i as i64)) as u64,
+                    (3, _) => (a < imm_i) as u64,
+                    (4, _) => a ^ imm_i,
+                    (6, _) => a | imm_i,
+                    (7, _) => a & imm_i,
+                    (1, 0) => a << shamt,
+                    (5, 0) => a >> shamt,
+                    (5, 0x10) => ((a as i64) >> shamt) as u64,
+                    _ => return Err(illegal),
+                };
+                self.set_x(rd, value);
+            }
+
+            0x1b => {
+                let shamt = rs2 as u32;
+                let value = match (funct3, funct7) {
+                    (0, _) => a.wrapping_add(imm_i),
+                    (1, 0) => (a as u32).wrapping_shl(shamt) as u64,
+                    (5, 0) => (a as u32 >> shamt) as u64,
+                    (5, 0x20) => (a as i32 >> shamt) as u64,
+                    _ => return Err(illegal),
+                };
+                self.set_x(rd, sext32(value));
+            }
+
+            0x33 => {
+                let (sa, sb) = (a as i64, b as i64);
+                let value = match (funct7, funct3) {
+                    (0, 0) => a.wrapping_add(b),
+                    (0x20, 0) => a.wrapping_sub(b),
+                    (0, 1) => a << (b & 63),
+                    (0, 2) => (sa < sb) as u64,
+                    (0, 3) => (a < b) as u64,
+                    (0, 4) => a ^ b,
+                    (0, 5) => a >> (b & 63),
+                    (0x20, 5) => (sa >> (b & 63)) as u64,
+                    (0, 6) => a | b,
+                    (0, 7) => a & b,
+                    (1, 0) => a.wrapping_mul(b),
+                    (1, 1) => ((sa as i128 * sb as i128) >> 64) as u64,
+                    (1, 2) => ((sa as i128 * b as i128) >> 64) as u64,
+                    (1, 3) => ((a as u128 * b as u128) >> 64) as u64,
+                    (1, 4) if b == 0 => u64::MAX,
+                    (1, 4) => sa.wrapping_div(sb) as u64,
+                    (1, 5) => a.checked_div(b).unwrap_or(u64::MAX),
+                    (1, 6) if b == 0 => a,
+                    (1, 6) => sa.wrapping_rem(sb) as u64,
+                    (1, 7) => a.checked_rem(b).unwrap_or(a),
+                    _ => return Err(illegal),
+                };
+                self.set_x(rd, value);
+            }
+
+            0x3b => {
+                let (wa, wb) = (a as u32, b as u32);
+                let value = match (funct7, funct3) {
+                    (0, 0) => wa.wrapping_add(wb),
+                    (0x20, 0) => wa.wrapping_sub(wb),
+                    (0, 1) => wa << (wb & 31),
+                    (0, 5) => wa >> (wb & 31),
+                    (0x20, 5) => (wa as i32 >> (wb & 31)) as u32,
+                    (1, 0) => wa.wrapping_mul(wb),
+                    (1, 4) if wb == 0 => u32::MAX,
+                    (1, 4) => (wa as i32).wrapping_div(wb as i32) as u32,
+                    (1, 5) => wa.checked_div(wb).unwrap_or(u32::MAX),
+                    (1, 6) if wb == 0 => wa,
+                    (1, 6) => (wa as i32).wrapping_rem(wb as i32) as u32,
+                    (1, 7) => wa.checked_rem(wb).unwrap_or(wa),
+                    _ => return Err(illegal),
+                };
+                self.set_x(rd, sext32(value as u64));
+            }
+
+            0x07 if funct3 == 2 => self.f[rd] = BOX | self.load(a.wrapping_add(imm_i), 4)?,
+            0x07 if funct3 == 3 => self.f[rd] = self.load(a.wrapping_add(imm_i), 8)?,
+            0x27 if funct3 == 2 || funct3 == 3 => {
+                self.store(a.wrapping_add(imm_s), 1 << funct3, self.f[rs2])?
+            }
+
+            opcode @ (0x43 | 0x47 | 0x4b | 0x4f) if funct7 & 3 < 2 => {
+                let double = funct7 & 1 == 1;
+                let rs3 = (word >> 27) as usize;
+                let (x, y, z) = (
+                    self.get_f(rs1, double),
+                    self.get_f(rs2, double),
+                    self.get_f(rs3, double),
+                );
+                let (x, z) = match opcode {
+                    0x43 => (x, z),
+                    0x47 => (x, -z),
+                    0x4b => (-x, z),
+                    _ => (-x, -z),
+                };
+
+                // Fused in the precision of the operation, rounded once
+                let value = if double {
+                    x.mul_add(y, z)
+                } else {
+                    (x as f32).mul_add(y as f32, z as f32) as f64
+                };
+                self.set_f(rd, double, value);
+            }
+
+            0x53 => self.float(word, rd, funct3, rs1, rs2, funct7)?,
+
+            // Fences don't matter with a single hart
+            0x0f => {}
+
+            0x73 if word == 0x73 || word == 0x0010_0073 => return Err(SimError::Trap { pc }),
+
+            _ => return Err(illegal),
+        }
+
+        self.pc = next;
+        Ok(())
+    }
+
+    /// Executes the `OP-FP` instruction `word`. Single precision operations are
+    /// computed in double precision, which rounds the same for all of them.
+    fn float(
+        &mut self,
+        word: u32,
+        rd: usize,
+        funct3: u32,
+        rs1: usize,
+        rs2: usize,
+        funct7: u32,
+    ) -> SimResult<()> {
+        let illegal = SimError::IllegalInstruction { pc: self.pc, word };
+        if funct7 & 3 > 1 {
+            return Err(illegal);
+        }
+
+        let double = funct7 & 1 == 1;
+        let (x, y) = (self.get_f(rs1, double), self.get_f(rs2, double));
+        let int = self.x[rs1];
+
+        match (funct7 >> 2, funct3, rs2) {
+            (0x00, _, _) => self.set_f(rd, double, x + y),
+            (0x01, _, _) => self.set_f(rd, double, x - y),
+            (0x02, _, _) => self.set_f(rd, double, x * y),
+            (0x03, _, _) => self.set_f(rd, double, x / y),
+            (0x0b, _, 0) => self.set_f(rd, double, x.sqrt()),
+
+            (0x04, 0..=2, _) => {
+                let (bits, sign) = if double {
+                    (self.f[rs1], 1 << 63)
+                } else {
+                    (self.get_raw_s(rs1) as u64, 1 << 31)
+                };
+                let other = if double {
+                    self.f[rs2]
+                } else {
+                    self.get_raw_s(rs2) as u64
+                };
+
+                let sign = match funct3 {
+                    0 => other & sign,
+                    1 => !other & sign,
+                    _ => (bits ^ other) & sign,
+                };
+                let bits = bits & !(if double { 1 << 63 } else { 1 << 31 }) | sign;
+                self.f[rd] = if double { bits } else { BOX | bits };
+            }
+
+            (0x05, 0..=1, _) => self.set_f(rd, double, min(x, y, funct3 == 1)),
+
+            // fcvt.s.d and fcvt.d.s
+            (0x08, _, 1) if !double => self.set_f(rd, false, self.get_f(rs1, true)),
+            (0x08, _, 0) if double => self.set_f(rd, true, self.get_f(rs1, false)),
+
+            (0x14, 0..=2, _) => {
+                let result = match funct3 {
+                    0 => x <= y,
+                    1 => x < y,
+                    _ => x == y,
+                };
+                self.set_x(rd, result as u64);
+            }
+
+            (0x18, _, 0..=3) => {
+                let rounded = match funct3 {
+                    0 | 7 => x.round_ties_even(),
+                    1 => x.trunc(),
+                    2 => x.floor(),
+                    3 => x.ceil(),
+                    4 => x.round(),
+                    _ => return Err(illegal),
+                };
+
+                // Out of range values saturate, NaN goes to the maximum
+                let nan = x.is_nan();
+                let value = match rs2 {
+                    0 if nan => i32::MAX as u64,
+                    0 => rounded as i32 as u64,
+                    1 if nan => u64::MAX,
+                    1 => sext32(rounded as u32 as u64),
+                    2 if nan => i64::MAX as u64,
+                    2 => rounded as i64 as u64,
+                    _ if nan => u64::MAX,
+                    _ => rounded as u64,
+                };
+                self.set_x(rd, value);
+            }
+
+            (0x1a, _, 0..=3) => {
+                let value = match (rs2, double) {
+                    (0, true) => int as i32 as f64,
+                    (1, true) => int as u32 as f64,
+                    (2, true) => int as i64 as f64,
+                    (3, true) => int as f64,
+                    (0, false) => int as i32 as f32 as f64,
+                    (1, false) => int as u32 as f32 as f64,
+                    (2, false) => int as i64 as f32 as f64,
+                    _ => int as f32 as f64,
+                };
+                self.set_f(rd, double, value);
+            }
+
+            (0x1c, 0, 0) => {
+                let value = if double {
+                    self.f[rs1]
+                } else {
+                    sext32(self.f[rs1])
+                };
+                self.set_x(rd, value);
+            }
+
+            (0x1c, 1, 0) => {
+                let bit = if double {
+                    let value = f64::from_bits(self.f[rs1]);
+                    let quiet = self.f[rs1] >> 51 & 1 == 1;
+                    class_bit(value.classify(), value.is_sign_negative(), quiet)
+                } else {
+                    let value = self.get_s(rs1);
+                    let quiet = self.get_raw_s(rs1) >> 22 & 1 == 1;
+                    class_bit(value.classify(), value.is_sign_negative(), quiet)
+                };
+                self.set_x(rd, bit);
+            }
+
+            (0x1e, 0, 0) => {
+                self.f[rd] = if double { int } else { BOX | int as u32 as u64 };
+            }
+
+            _ => return Err(illegal),
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Simulator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Simulator")
+            .field("symbols", &self.symbols)
+            .field("pc", &self.pc)
+            .field("steps", &self.steps)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::parse_module;
+    use crate::vm::machine::tests::for_each_program;
+    use crate::vm::Machine;
+
+    #[test]
+    fn compiled_programs() {
+        for_each_program(|_, module, expected| {
+            assert_eq!(Simulator::new(module).unwrap().run(), Ok(expected));
+        });
+    }
+
+    #[test]
+    fn casts_and_traps() {
+        // Floats saturate into i64 before they're truncated, and where the VM
+        // fails on an index past its own dimension or a missing return, the
+        // code traps
+        let module = parse_module(
+            "fn trunc(x: f64) -> i32\n\tvar y: i32\n\tmov y x\n\tret y\n\
+             fn get(i: i32, j: i32) -> i32\n\tvar m: [3][4]i32\n\
+             \tchk i 3\n\tchk j 4\n\tmul __t0 i 4\n\tadd __t1 __t0 j\n\
+             \tidx __t2 __t1 m\n\tret __t2\n\
+             fn none(x: i32) -> i32\n\tlt __t0 x 0\n\tjmpf L1 __t0\n\tret x\nL1\tret\n",
+        )
+        .unwrap();
+        let mut sim = Simulator::new(&module).unwrap();
+        let machine = |name: &str, args: &[Value]| {
+            Machine::new(&module)
+                .call(name, args)
+                .map_err(SimError::from)
+        };
+
+        for num in &[1e19, -1e19, 1e10, -3.7, f64::NAN] {
+            let args = [Value::Flt64(*num)];
+            assert_eq!(sim.call("trunc", &args), machine("trunc", &args));
+        }
+
+        let args = [Value::Int32(2), Value::Int32(3)];
+        assert_eq!(sim.call("get", &args), machine("get", &args));
+        let args = [Value::Int32(-1)];
+        assert_eq!(sim.call("none", &args), machine("none", &args));
+
+        let traps = [
+            ("get", vec![Value::Int32(0), Value::Int32(5)]),
+            ("none", vec![Value::Int32(1)]),
+        ];
+        for (name, args) in &traps {
+            assert!(machine(name, args).is_err());
+            assert!(matches!(sim.call(name, args), Err(SimError::Trap { .. })));
+        }
+    }
+
+    #[test]
+    fn machine_semantics() {
+        let module = parse_module(
+            "fn half(x: f32, n: i64) -> f64\n\tdiv __t0 x n\n\tret __t0\n\
+             fn main() -> i32\n\tvar a: [3]char\n\tvar big: i32\n\
+             \tmov big 2147483647\n\tadd big big 2\n\
+             \tsto a 2 300\n\tidx __t1 2 a\n\
+             \tparam 5.5\n\tparam 2\n\tcall __t2 half 2\n\
+             \tinv __t3 __t2\n\tmul __t4 __t3 10\n\
+             \tadd __t5 big __t1\n\tadd __t6 __t5 __t4\n\tret __t6\n\
+             fn div(a: i64, b: i64) -> i64\n\tdiv __t0 a b\n\tret __t0\n\
+             fn nan(x: f64) -> i32\n\tdiv __t0 x 0.0\n\tne __t1 __t0 __t0\n\
+             \tlt __t2 __t0 __t0\n\tmov __t3 __t0\n\tjmpf L1 __t1\n\tjmpt L1 __t2\n\
+             \tadd __t4 __t3 1\n\tret __t4\nL1\tret 0\n\
+             fn deep(n: i32) -> i32\n\tparam n\n\tcall __t0 deep 1\n\tret __t0\n",
+        )
+        .unwrap();
+        let mut sim = Simulator::new(&module).unwrap();
+        let machine = |name: &str, args: &[Value]| {
+            Machine::new(&module)
+                .call(name, args)
+                .map_err(SimError::from)
+        };
+
+        assert_eq!(
+            sim.run(),
+            Machine::new(&module).run().map_err(SimError::from)
+        );
+        assert_eq!(sim.run(), Ok(Some(Value::Int32(-2147483630))));
+
+        let args = [Value::Flt64(7.25), Value::Int32(2)];
+        assert_eq!(sim.call("half", &args), machine("half", &args));
+
+        for args in &[
+            [Value::Int64(i64::MIN), Value::Int64(-1)],
+            [Value::Int64(-7), Value::Int64(2)],
+        ] {
+            assert_eq!(sim.call("div", args), machine("div", args));
+        }
+        let zero = [Value::Int64(7), Value::Int64(0)];
+        assert!(matches!(sim.call("div", &zero), Err(SimError::Trap { .. })));
+
+        for x in &[0.0, 1.0, -1.0] {
+            let args = [Value::Flt64(*x)];
+            assert_eq!(sim.call("nan", &args), machine("nan", &args));
+        }
+
+        assert!(matches!(
+            sim.call("deep", &[Value::Int32(0)]),
+            Err(SimError::MemoryFault { .. })
+        ));
+        assert_eq!(
+            sim.call("half", &[]),
+            Err(SimError::Runtime(RuntimeError::ArityMismatch {
+                func: "half".to_owned(),
+                expected: 2,
+                got: 0
+            }))
+        );
+        assert_eq!(
+            sim.call("nope", &[]),
+            Err(SimError::Runtime(RuntimeError::UndefinedFunction(
+                "nope".to_owned()
+            )))
+        );
+    }
+
+    #[test]
+    fn out_of_bounds() {
+        // Where the VM fails on an index out of bounds, reads and writes trap
+        let module = parse_module(
+            "fn get(i: i32) -> f64\n\tvar a: [5]f64\n\tsto a 4 1.5\n\tidx __t0 i a\n\tret __t0\n\
+             fn put(i: i32) -> i32\n\tvar a: [5]char\n\tsto a i 7\n\tret 0\n",
+        )
+        .unwrap();
+        let mut sim = Simulator::new(&module).unwrap();
+        let machine = |name: &str, args: &[Value]| {
+            Machine::new(&module)
+                .call(name, args)
+                .map_err(SimError::from)
+        };
+
+        for index in &[0, 4] {
+            let args = [Value::Int32(*index)];
+            assert_eq!(sim.call("get", &args), machine("get", &args));
+            assert_eq!(sim.call("put", &args), machine("put", &args));
+        }
+        for index in &[5, -1] {
+            let args = [Value::Int32(*index)];
+            for name in &["get", "put"] {
+                assert!(machine(name, &args).is_err());
+                assert!(matches!(sim.call(name, &args), Err(SimError::Trap { .. })));
+            }
+        }
+    }
+
+    #[test]
+    fn float_spills() {
+        // More floats live at once than callee-saved registers, across a call
+        let mut text =
+            String::from("fn sq(x: f32) -> f32\n\tmul __t0 x x\n\tret __t0\nfn main() -> f64\n");
+        for n in 0..16 {
+            text.push_str(&format!("\tadd __t{} {}.5 0.25\n", n, n));
+        }
+        text.push_str("\tparam __t3\n\tcall __t16 sq 1\n\tadd __t17 __t16 0.0\n");
+        for n in 0..16 {
+            text.push_str(&format!("\tadd __t{} __t{} __t{}\n", n + 18, n + 17, n));
+        }
+        text.push_str("\tret __t33\n");
+
+        let module = parse_module(&text).unwrap();
+        let mut sim = Simulator::new(&module).unwrap();
+        assert_eq!(
+            sim.run(),
+            Machine::new(&module).run().map_err(SimError::from)
+        );
+
+        sim.set_step_limit(10);
+        assert_eq!(sim.run(), Err(SimError::StepLimit(10)));
+    }
+}